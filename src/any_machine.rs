@@ -0,0 +1,223 @@
+use fnv::FnvHashSet;
+
+use crate::{
+    Machine,
+    MachineError,
+    driver::{self, RunSummary},
+    explorer::{self, FinalState},
+    machine_memory::{MemorySubsystem, mixed::MixedMemory, promising::PromisingMemory, sc::ScMemory},
+    machine_thread::{CodeInstruction, ThreadState},
+    trace::Trace,
+    value::Value,
+};
+#[cfg(test)]
+use crate::{MachineEvent, MachineStep};
+
+/// A [`Machine`] with the memory subsystem picked at runtime.
+pub enum AnyMachine<'a> {
+    Sc(Machine<'a, ScMemory>),
+    Promising(Machine<'a, PromisingMemory>),
+    Mixed(Machine<'a, MixedMemory>),
+}
+
+/// Expands `$body` with `$machine` bound to the inner machine.
+macro_rules! dispatch {
+    ($self:expr, $machine:ident => $body:expr) => {
+        match $self {
+            AnyMachine::Sc($machine) => $body,
+            AnyMachine::Promising($machine) => $body,
+            AnyMachine::Mixed($machine) => $body,
+        }
+    };
+}
+
+impl<'a> AnyMachine<'a> {
+    /// Names of the memory subsystems [`from_name`](AnyMachine::from_name) accepts.
+    pub fn names() -> [&'static str; 3] {
+        [ScMemory::name(), PromisingMemory::name(), <MixedMemory>::name()]
+    }
+
+    pub fn from_name(
+        name: &str,
+        program: &'a [Vec<CodeInstruction>],
+        memory_size: usize,
+    ) -> Result<Self, MachineError> {
+        match name {
+            x if x == ScMemory::name() => Ok(AnyMachine::Sc(Machine::new(program, memory_size)?)),
+            x if x == PromisingMemory::name() => Ok(AnyMachine::Promising(Machine::new(program, memory_size)?)),
+            x if x == <MixedMemory>::name() => Ok(AnyMachine::Mixed(Machine::new(program, memory_size)?)),
+            _ => Err(MachineError::UnknownSubsystem { name: name.to_owned() }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnyMachine::Sc(_) => ScMemory::name(),
+            AnyMachine::Promising(_) => PromisingMemory::name(),
+            AnyMachine::Mixed(_) => <MixedMemory>::name(),
+        }
+    }
+
+    pub fn thread(&self, tid: usize) -> Option<&ThreadState<'a>> {
+        dispatch!(self, machine => machine.threads().get(tid))
+    }
+
+    pub fn thread_count(&self) -> usize {
+        dispatch!(self, machine => machine.threads().len())
+    }
+
+    pub fn memory(&self) -> &[Value] {
+        dispatch!(self, machine => machine.memory().global().cells())
+    }
+
+    /// Caps the histories of the subsystems which keep them, see
+    /// [`PromisingMemory::set_history_cap`]. The SC subsystem keeps none.
+    pub fn set_history_cap(&mut self, cap: Option<usize>) {
+        match self {
+            AnyMachine::Sc(_) => (),
+            AnyMachine::Promising(machine) => machine.memory_mut().subsystem_mut().set_history_cap(cap),
+            AnyMachine::Mixed(machine) => machine.memory_mut().subsystem_mut().set_history_cap(cap),
+        }
+    }
+
+    /// See [`PromisingMemory::set_certification_depth`]. The other subsystems
+    /// don't certify promises.
+    pub fn set_certification_depth(&mut self, depth: usize) {
+        if let AnyMachine::Promising(machine) = self {
+            machine.memory_mut().subsystem_mut().set_certification_depth(depth);
+        }
+    }
+
+    /// Turns on or off the self-check of the SC subsystem, see
+    /// [`ScMemory::set_coherence_check`].
+    pub fn set_coherence_check(&mut self, enabled: bool) {
+        if let AnyMachine::Sc(machine) = self {
+            machine.memory_mut().subsystem_mut().set_coherence_check(enabled);
+        }
+    }
+
+    /// Lets weak CASes fail spuriously, see [`ScMemory::set_spurious_failures`].
+    /// Only the SC subsystem fails them.
+    pub fn set_spurious_failures(&mut self, enabled: bool) {
+        if let AnyMachine::Sc(machine) = self {
+            machine.memory_mut().subsystem_mut().set_spurious_failures(enabled);
+        }
+    }
+
+    /// Steps thread `tid`.
+    #[cfg(test)]
+    pub fn step_thread(&mut self, tid: usize) -> Result<MachineEvent, MachineError> {
+        dispatch!(self, machine => machine.step(MachineStep::Thread(tid)))
+    }
+
+    pub fn run_round_robin(&mut self, max_steps: usize, trace: &mut Trace) -> Result<RunSummary, MachineError> {
+        dispatch!(self, machine => driver::run_round_robin(machine, max_steps, trace))
+    }
+
+    #[cfg(test)]
+    pub fn reset(&mut self) {
+        dispatch!(self, machine => machine.reset())
+    }
+
+    /// See [`explorer::explore`].
+    pub fn explore(self, max_depth: usize) -> Result<FnvHashSet<FinalState>, MachineError> {
+        dispatch!(self, machine => explorer::explore(|| Ok(machine), max_depth))
+    }
+
+    /// Moves the inner machine behind a [`RunnableMachine`] trait object.
+    #[cfg(test)]
+    pub fn into_runnable(self) -> Box<dyn RunnableMachine + 'a> {
+        dispatch!(self, machine => Box::new(machine))
+    }
+}
+
+/// A machine with the memory subsystem erased behind a trait object, for when
+/// even the set of subsystems [`AnyMachine`] knows shouldn't be fixed.
+#[cfg(test)]
+pub trait RunnableMachine {
+    /// Steps thread `tid`.
+    fn step_thread(&mut self, tid: usize) -> Result<MachineEvent, MachineError>;
+
+    /// Runs the machine with the round-robin driver for at most `max_steps`
+    /// steps and returns the events of the run.
+    fn run(&mut self, max_steps: usize) -> Result<Vec<MachineEvent>, MachineError>;
+
+    fn final_state(&self) -> FinalState;
+}
+
+#[cfg(test)]
+impl<'a, Mem: MemorySubsystem> RunnableMachine for Machine<'a, Mem> {
+    fn step_thread(&mut self, tid: usize) -> Result<MachineEvent, MachineError> {
+        self.step(MachineStep::Thread(tid))
+    }
+
+    fn run(&mut self, max_steps: usize) -> Result<Vec<MachineEvent>, MachineError> {
+        let mut trace = Trace::default();
+        driver::run_round_robin(self, max_steps, &mut trace)?;
+
+        Ok(trace.events)
+    }
+
+    fn final_state(&self) -> FinalState {
+        FinalState::of(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{litmus::{self, FAI_STRESS_COUNTER}, machine_memory::AccessMode};
+
+    use super::*;
+
+    #[test]
+    fn sc_is_picked_by_name() {
+        let program = litmus::fai_stress(2, 3, AccessMode::SeqCst);
+        let mut machine = AnyMachine::from_name("SC", &program, FAI_STRESS_COUNTER + 1).unwrap();
+        assert_eq!(machine.name(), "SC");
+
+        let summary = machine.run_round_robin(100, &mut Trace::default()).unwrap();
+        assert_eq!(summary.threads_halted, 2);
+        assert_eq!(machine.memory()[FAI_STRESS_COUNTER], Value(6));
+    }
+
+    #[test]
+    fn unknown_name_is_rejected() {
+        let program = litmus::fai_stress(1, 1, AccessMode::SeqCst);
+
+        assert!(matches!(
+            AnyMachine::from_name("TSO", &program, 1),
+            Err(MachineError::UnknownSubsystem { name }) if name == "TSO",
+        ));
+    }
+
+    #[test]
+    fn stepping_and_resetting_by_name() {
+        let program = litmus::fai_stress(1, 1, AccessMode::SeqCst);
+        let mut machine = AnyMachine::from_name("Mixed", &program, FAI_STRESS_COUNTER + 1).unwrap();
+
+        while !machine.thread(0).unwrap().is_halted() {
+            machine.step_thread(0).unwrap();
+        }
+        assert_eq!(machine.memory()[FAI_STRESS_COUNTER], Value(1));
+        machine.reset();
+        assert_eq!(machine.thread(0).unwrap().pc(), 0);
+        assert_eq!(machine.memory()[FAI_STRESS_COUNTER], Value(0));
+    }
+
+    #[test]
+    fn boxed_machines_of_different_subsystems() {
+        let program = litmus::fai_stress(2, 3, AccessMode::SeqCst);
+        let mut machines: Vec<Box<dyn RunnableMachine>> = vec![
+            Box::new(Machine::<ScMemory>::new(&program, FAI_STRESS_COUNTER + 1).unwrap()),
+            Box::new(Machine::<MixedMemory>::new(&program, FAI_STRESS_COUNTER + 1).unwrap()),
+            AnyMachine::from_name("Promising", &program, FAI_STRESS_COUNTER + 1).unwrap().into_runnable(),
+        ];
+
+        for machine in &mut machines {
+            assert!(machine.step_thread(0).is_ok());
+            let events = machine.run(100).unwrap();
+            assert!(!events.is_empty());
+            assert_eq!(machine.final_state().memory[FAI_STRESS_COUNTER], Value(6));
+        }
+    }
+}
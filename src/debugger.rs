@@ -0,0 +1,188 @@
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::{
+    Machine,
+    MachineError,
+    MachineEvent,
+    MachineStep,
+    machine_memory::{ErasedMemoryError, MemorySubsystem},
+    machine_thread::Instruction,
+};
+
+/// The outcome of [`Debugger::step_thread`].
+#[derive(Debug, Clone, Copy)]
+pub enum DebugStep {
+    /// The thread has reached a breakpoint and hasn't been stepped.
+    Breakpoint {
+        tid: usize,
+        pc: usize,
+    },
+    Stepped(MachineEvent),
+}
+
+/// See [`Debugger::on_instruction`].
+type InstructionCallback<'a> = Box<dyn FnMut(usize, &Instruction) + 'a>;
+
+/// Steps a [`Machine`] thread by thread, pausing at breakpoints.
+pub struct Debugger<'a, Mem> {
+    machine: Machine<'a, Mem>,
+    /// `(tid, pc)` pairs to pause at.
+    pc_breakpoints: FnvHashSet<(usize, usize)>,
+    /// The PC of the breakpoint each thread is paused at. Stepping the thread
+    /// again resumes from it instead of reporting it once more.
+    paused_at: FnvHashMap<usize, usize>,
+    on_instruction: Option<InstructionCallback<'a>>,
+}
+
+impl<'a, Mem: MemorySubsystem> Debugger<'a, Mem> {
+    pub fn new(machine: Machine<'a, Mem>) -> Self {
+        Debugger {
+            machine,
+            pc_breakpoints: FnvHashSet::default(),
+            paused_at: FnvHashMap::default(),
+            on_instruction: None,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<'a, Mem> {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine<'a, Mem> {
+        self.machine
+    }
+
+    /// Pauses thread `tid` right before it executes the instruction at `pc`.
+    pub fn set_pc_breakpoint(&mut self, tid: usize, pc: usize) {
+        self.pc_breakpoints.insert((tid, pc));
+    }
+
+    pub fn remove_pc_breakpoint(&mut self, tid: usize, pc: usize) {
+        self.pc_breakpoints.remove(&(tid, pc));
+    }
+
+    /// Calls `callback` with the thread ID and the instruction each time
+    /// a thread is about to execute an instruction.
+    pub fn on_instruction(&mut self, callback: impl FnMut(usize, &Instruction) + 'a) {
+        self.on_instruction = Some(Box::new(callback));
+    }
+
+    /// The registers and the PC of thread `tid`, see [`ThreadState::dump`](crate::machine_thread::ThreadState::dump).
+    pub fn dump_thread(&self, tid: usize) -> Option<String> {
+        self.machine.threads().get(tid).map(|x| x.dump())
+    }
+
+    /// The global memory cells, one `addr = value` line each.
+    pub fn dump_memory(&self) -> String {
+        self.machine.memory()
+            .global()
+            .cells()
+            .iter()
+            .enumerate()
+            .map(|(addr, value)| format!("{addr} = {value}\n"))
+            .collect()
+    }
+
+    /// See [`MemorySubsystem::describe_state`].
+    pub fn dump_subsystem(&self) -> String {
+        self.machine.memory().subsystem().describe_state()
+    }
+
+    /// Every thread, the memory and the subsystem state (if there is any), each
+    /// under a heading.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+
+        for tid in 0..self.machine.threads().len() {
+            out.push_str(&format!("Thread {tid}:\n"));
+            out.push_str(&self.dump_thread(tid).unwrap_or_default());
+        }
+        out.push_str("Memory:\n");
+        out.push_str(&self.dump_memory());
+
+        let subsystem = self.dump_subsystem();
+        if !subsystem.is_empty() {
+            out.push_str(&format!("{}:\n{subsystem}", Mem::name()));
+        }
+
+        out
+    }
+
+    /// Steps thread `tid`, unless it has reached a breakpoint. A thread paused
+    /// at a breakpoint is stepped on the next call.
+    pub fn step_thread(&mut self, tid: usize) -> Result<DebugStep, MachineError> {
+        let thread = self.machine.threads()
+            .get(tid)
+            .ok_or(MachineError::Memory(ErasedMemoryError::BadTid { tid }))?;
+        let pc = thread.pc();
+
+        if self.pc_breakpoints.contains(&(tid, pc)) && self.paused_at.get(&tid) != Some(&pc) {
+            self.paused_at.insert(tid, pc);
+            return Ok(DebugStep::Breakpoint { tid, pc });
+        }
+        self.paused_at.remove(&tid);
+
+        if let (Some(callback), Some(instruction)) = (&mut self.on_instruction, thread.next_instruction()) {
+            callback(tid, instruction);
+        }
+
+        self.machine.step(MachineStep::Thread(tid)).map(DebugStep::Stepped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        machine_memory::{AccessMode, mixed::MixedMemory, sc::ScMemory},
+        machine_thread::builder::ProgramBuilder,
+    };
+
+    use super::*;
+
+    #[test]
+    fn pc_breakpoint_fires_at_the_instruction() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+                .set("b", 2)
+                .set("c", 3)
+            .build();
+        let mut debugger = Debugger::new(Machine::<ScMemory>::new(&program, 1).unwrap());
+        let executed = Rc::new(RefCell::new(Vec::new()));
+        let log = executed.clone();
+        debugger.on_instruction(move |_, instruction| log.borrow_mut().push(instruction.to_string()));
+        debugger.set_pc_breakpoint(0, 1);
+        debugger.set_pc_breakpoint(0, 2);
+        debugger.remove_pc_breakpoint(0, 1);
+
+        assert!(matches!(debugger.step_thread(0), Ok(DebugStep::Stepped(MachineEvent::Silent))));
+        assert!(matches!(debugger.step_thread(0), Ok(DebugStep::Stepped(_))));
+        assert!(matches!(debugger.step_thread(0), Ok(DebugStep::Breakpoint { tid: 0, pc: 2 })));
+        assert_eq!(debugger.machine().threads().get(0).unwrap().pc(), 2);
+        assert!(matches!(debugger.step_thread(0), Ok(DebugStep::Stepped(_))));
+        assert!(debugger.into_machine().threads().get(0).unwrap().is_halted());
+
+        assert_eq!(*executed.borrow(), ["a = 1", "b = 2", "c = 3"]);
+    }
+
+    #[test]
+    fn dump_shows_the_subsystem_views() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .store_imm(AccessMode::Rlx, "p", 1)
+            .build();
+        let mut debugger = Debugger::new(Machine::<MixedMemory>::new(&program, 1).unwrap());
+        debugger.step_thread(0).unwrap();
+        debugger.step_thread(0).unwrap();
+
+        assert_eq!(debugger.dump_subsystem(), "T0 sees ##0@1\n");
+        assert!(debugger.dump().ends_with("Memory:\n0 = 1\nMixed:\nT0 sees ##0@1\n"), "{}", debugger.dump());
+
+        let sc = Debugger::new(Machine::<ScMemory>::new(&program, 1).unwrap());
+        assert!(sc.dump_subsystem().is_empty());
+        assert!(sc.dump().ends_with("Memory:\n0 = 0\n"));
+    }
+}
@@ -0,0 +1,490 @@
+use crate::{Machine, MachineError, MachineStep, machine_memory::MemorySubsystem, machine_thread::Instruction, trace::Trace, value::Value};
+
+/// How a run of a driver has ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub steps_taken: usize,
+    /// Amount of threads which have halted.
+    pub threads_halted: usize,
+    /// Whether the run stopped with live threads and nothing to step.
+    pub deadlocked: bool,
+    /// Whether the run was cut short by `max_steps` with steps still enabled.
+    pub budget_exhausted: bool,
+    /// The memory cells at the end of the run.
+    pub final_memory: Vec<Value>,
+}
+
+/// Picks the steps of a run, see [`run_scheduler`].
+pub trait Scheduler<Mem: MemorySubsystem> {
+    /// Returns the index of the step in `enabled` to take next, or `None` to
+    /// stop the run. `enabled` is never empty.
+    fn next(&mut self, enabled: &[MachineStep<Mem>], machine: &Machine<Mem>) -> Option<usize>;
+}
+
+/// Interleaves the thread steps `pick` chooses with the independent memory
+/// steps: after every round of thread steps (as many steps as there are
+/// enabled threads) one pending memory step is taken, so that e.g. buffered
+/// stores eventually become visible to a thread spinning on them. `pick`
+/// receives the IDs of the enabled threads and returns the one to step.
+/// Gives the index of the step in `enabled`.
+fn interleave_memory_steps<Mem: MemorySubsystem>(
+    since_memory_step: &mut usize,
+    enabled: &[MachineStep<Mem>],
+    pick: impl FnOnce(&[usize]) -> usize,
+) -> Option<usize> {
+    let tids = enabled.iter()
+        .filter_map(|step| match step {
+            MachineStep::Thread(tid) => Some(*tid),
+            MachineStep::Memory(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let memory = enabled.iter().position(|x| matches!(x, MachineStep::Memory(_)));
+
+    match memory {
+        Some(idx) if *since_memory_step >= tids.len() => {
+            *since_memory_step = 0;
+            Some(idx)
+        },
+        _ if !tids.is_empty() => {
+            let tid = pick(&tids);
+            *since_memory_step += 1;
+            enabled.iter().position(|x| matches!(x, MachineStep::Thread(x) if *x == tid))
+        },
+        x => x,
+    }
+}
+
+/// The scheduler behind [`run_round_robin`]. The memory steps are interleaved
+/// as described in [`interleave_memory_steps`].
+#[derive(Debug, Clone, Default)]
+pub struct RoundRobin {
+    /// The thread to try first.
+    next: usize,
+    /// Amount of thread steps since the last memory step.
+    since_memory_step: usize,
+}
+
+impl<Mem: MemorySubsystem> Scheduler<Mem> for RoundRobin {
+    fn next(&mut self, enabled: &[MachineStep<Mem>], _: &Machine<Mem>) -> Option<usize> {
+        let next = &mut self.next;
+
+        interleave_memory_steps(&mut self.since_memory_step, enabled, |tids| {
+            let tid = tids.iter()
+                .copied()
+                .find(|tid| *tid >= *next)
+                .unwrap_or(tids[0]);
+            *next = tid + 1;
+
+            tid
+        })
+    }
+}
+
+/// The scheduler behind [`run_weighted`]. The memory steps are interleaved
+/// as described in [`interleave_memory_steps`].
+#[derive(Debug, Clone)]
+pub struct Weighted {
+    weights: Vec<u32>,
+    /// The current weights of smooth weighted round-robin.
+    current: Vec<i64>,
+    /// The zero weight thread to try first.
+    next_idle: usize,
+    /// Amount of thread steps since the last memory step.
+    since_memory_step: usize,
+}
+
+impl Weighted {
+    /// `weights` has a weight per thread.
+    pub fn new(weights: &[u32]) -> Self {
+        Weighted {
+            weights: weights.to_vec(),
+            current: vec![0; weights.len()],
+            next_idle: 0,
+            since_memory_step: 0,
+        }
+    }
+
+    fn pick(&mut self, tids: &[usize]) -> usize {
+        let weighted = tids.iter()
+            .copied()
+            .filter(|tid| self.weights[*tid] > 0)
+            .collect::<Vec<_>>();
+
+        if weighted.is_empty() {
+            let tid = tids.iter()
+                .copied()
+                .find(|tid| *tid >= self.next_idle)
+                .unwrap_or(tids[0]);
+            self.next_idle = tid + 1;
+
+            return tid;
+        }
+
+        let total = weighted.iter().map(|tid| self.weights[*tid] as i64).sum::<i64>();
+        for tid in &weighted {
+            self.current[*tid] += self.weights[*tid] as i64;
+        }
+
+        let tid = weighted.iter()
+            .copied()
+            .max_by_key(|tid| (self.current[*tid], std::cmp::Reverse(*tid)))
+            .unwrap();
+        self.current[tid] -= total;
+
+        tid
+    }
+}
+
+impl<Mem: MemorySubsystem> Scheduler<Mem> for Weighted {
+    fn next(&mut self, enabled: &[MachineStep<Mem>], _: &Machine<Mem>) -> Option<usize> {
+        let mut since_memory_step = self.since_memory_step;
+        let res = interleave_memory_steps(&mut since_memory_step, enabled, |tids| self.pick(tids));
+        self.since_memory_step = since_memory_step;
+
+        res
+    }
+}
+
+/// The scheduler behind [`run_cooperative`]. The memory steps are interleaved
+/// as described in [`interleave_memory_steps`].
+#[derive(Debug, Clone, Default)]
+pub struct Cooperative {
+    /// The thread being run.
+    current: usize,
+    /// Whether the last step of the current thread has been a yield.
+    yielded: bool,
+    /// Amount of thread steps since the last memory step.
+    since_memory_step: usize,
+}
+
+impl<Mem: MemorySubsystem> Scheduler<Mem> for Cooperative {
+    fn next(&mut self, enabled: &[MachineStep<Mem>], machine: &Machine<Mem>) -> Option<usize> {
+        let Cooperative { current, yielded, since_memory_step } = self;
+
+        interleave_memory_steps(since_memory_step, enabled, |tids| {
+            let from = if *yielded { *current + 1 } else { *current };
+            *current = tids.iter()
+                .copied()
+                .find(|tid| *tid >= from)
+                .unwrap_or(tids[0]);
+            *yielded = matches!(
+                machine.threads().get(*current).and_then(|x| x.next_instruction()),
+                Some(Instruction::Yield),
+            );
+
+            *current
+        })
+    }
+}
+
+/// Picks uniformly among the enabled steps with a xorshift generator, so a
+/// seed always gives the same schedule.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero
+        Random { state: seed.max(1) }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl<Mem: MemorySubsystem> Scheduler<Mem> for Random {
+    fn next(&mut self, enabled: &[MachineStep<Mem>], _: &Machine<Mem>) -> Option<usize> {
+        Some((self.next_u64() % enabled.len() as u64) as usize)
+    }
+}
+
+/// Runs the machine, taking the steps `scheduler` picks until it stops,
+/// nothing can be stepped or `max_steps` steps have been made. The events
+/// are recorded into `trace`, starting with the
+/// [`init_events`](Machine::init_events) if `trace` is empty, and the picks
+/// into [`Trace::choices`]. A pick past the enabled steps is reported as
+/// [`MachineError::NoSuchStep`].
+pub fn run_scheduler<Mem: MemorySubsystem>(
+    machine: &mut Machine<Mem>,
+    scheduler: &mut impl Scheduler<Mem>,
+    max_steps: usize,
+    trace: &mut Trace,
+) -> Result<RunSummary, MachineError> {
+    let mut steps_taken = 0;
+
+    if trace.events.is_empty() {
+        machine.init_events().into_iter().for_each(|x| trace.record(x));
+    }
+
+    while steps_taken < max_steps {
+        let mut enabled = machine.enabled_steps();
+        if enabled.is_empty() {
+            break;
+        }
+        let Some(idx) = scheduler.next(&enabled, machine) else { break };
+        if idx >= enabled.len() {
+            return Err(MachineError::NoSuchStep { choice: idx, enabled: enabled.len() });
+        }
+
+        trace.choices.push(idx);
+        trace.record(machine.step(enabled.swap_remove(idx))?);
+        steps_taken += 1;
+    }
+
+    Ok(summarize(machine, steps_taken, max_steps))
+}
+
+fn summarize<Mem: MemorySubsystem>(machine: &Machine<Mem>, steps_taken: usize, max_steps: usize) -> RunSummary {
+    let threads = machine.threads();
+
+    RunSummary {
+        steps_taken,
+        threads_halted: threads.len() - threads.live_tids().count(),
+        deadlocked: machine.is_deadlocked(),
+        budget_exhausted: steps_taken == max_steps && !machine.enabled_steps().is_empty(),
+        final_memory: machine.memory().global().cells().to_vec(),
+    }
+}
+
+/// Steps the live threads one after another. Halted threads are skipped, but
+/// every live thread (including one spinning on a flag) is stepped once per
+/// round, so no thread starves.
+pub fn run_round_robin<Mem: MemorySubsystem>(
+    machine: &mut Machine<Mem>,
+    max_steps: usize,
+    trace: &mut Trace,
+) -> Result<RunSummary, MachineError> {
+    run_scheduler(machine, &mut RoundRobin::default(), max_steps, trace)
+}
+
+/// Takes the steps picked by a [`Random`] scheduler seeded with `seed`. The
+/// seed is stored in the trace, so the run can be checked with [`Trace::replay`].
+pub fn run_random<Mem: MemorySubsystem>(
+    machine: &mut Machine<Mem>,
+    seed: u64,
+    max_steps: usize,
+    trace: &mut Trace,
+) -> Result<RunSummary, MachineError> {
+    trace.seed = Some(seed);
+
+    run_scheduler(machine, &mut Random::new(seed), max_steps, trace)
+}
+
+/// Steps the live threads proportionally to their `weights` using smooth
+/// weighted round-robin, so the schedule is deterministic. Threads with zero
+/// weight are only stepped (in round-robin order) when no weighted thread is live.
+/// There has to be exactly one weight per thread, see [`MachineError::WeightCountMismatch`].
+pub fn run_weighted<Mem: MemorySubsystem>(
+    machine: &mut Machine<Mem>,
+    weights: &[u32],
+    max_steps: usize,
+    trace: &mut Trace,
+) -> Result<RunSummary, MachineError> {
+    if weights.len() != machine.threads().len() {
+        return Err(MachineError::WeightCountMismatch { weights: weights.len(), threads: machine.threads().len() });
+    }
+
+    run_scheduler(machine, &mut Weighted::new(weights), max_steps, trace)
+}
+
+/// Keeps stepping the same thread until it executes a
+/// [`Yield`](crate::machine_thread::Instruction::Yield), halts or starts waiting,
+/// then switches to the next live thread in round-robin order. A thread which
+/// never yields runs to completion before the others, which makes it easy to
+/// produce a precise interleaving.
+pub fn run_cooperative<Mem: MemorySubsystem>(
+    machine: &mut Machine<Mem>,
+    max_steps: usize,
+    trace: &mut Trace,
+) -> Result<RunSummary, MachineError> {
+    run_scheduler(machine, &mut Cooperative::default(), max_steps, trace)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MachineEvent,
+        latency::LatencyModel,
+        machine_memory::{AccessMode, mixed::MixedMemory, sc::ScMemory},
+        machine_thread::{BinOp, builder::ProgramBuilder},
+        register::RegisterRef,
+    };
+
+    use super::*;
+
+    #[test]
+    fn weighted_run_follows_the_weights() {
+        let spin = |builder: ProgramBuilder| builder
+            .thread()
+                .set("one", 1)
+                .label("spin")
+                .branch("one", "spin");
+        let program = spin(spin(ProgramBuilder::new())).build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.set_latency_model(LatencyModel::uniform());
+
+        let summary = run_weighted(&mut machine, &[3, 1], 400, &mut Trace::default()).unwrap();
+
+        assert!(summary.budget_exhausted);
+        assert_eq!(machine.cycles().per_thread, [300, 100]);
+    }
+
+    #[test]
+    fn weight_per_thread_is_required() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+            .thread()
+                .set("b", 1)
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+
+        assert!(matches!(
+            run_weighted(&mut machine, &[1], 10, &mut Trace::default()),
+            Err(MachineError::WeightCountMismatch { weights: 1, threads: 2 }),
+        ));
+    }
+
+    #[test]
+    fn consumer_progresses_after_the_store() {
+        // The producer works for a while, so the consumer spins before the flag is set
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("flag", 0)
+                .repeat(5, |block| block.set("work", 0))
+                .store_imm(AccessMode::Rlx, "flag", 1)
+            .thread()
+                .set("flag", 0)
+                .set("one", 1)
+                .label("wait")
+                .load(AccessMode::Rlx, "flag", "seen")
+                .bop("unset", BinOp::Sub, "one", "seen")
+                .branch("unset", "wait")
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, 1).unwrap();
+
+        let summary = run_round_robin(&mut machine, 200, &mut Trace::default()).unwrap();
+        assert_eq!(summary.threads_halted, 2);
+        assert!(!summary.budget_exhausted);
+        assert_eq!(machine.threads().get(1).unwrap().get_register(RegisterRef::new("seen")).unwrap(), Value(1));
+    }
+
+    #[test]
+    fn summary_of_halted_and_deadlocked_runs() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .store_imm(AccessMode::SeqCst, "p", 3)
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        let summary = run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+        assert_eq!(summary, RunSummary {
+            steps_taken: 2,
+            threads_halted: 1,
+            deadlocked: false,
+            budget_exhausted: false,
+            final_memory: vec![Value(3)],
+        });
+
+        // The second thread never comes to the barrier
+        let program = ProgramBuilder::new()
+            .thread()
+                .barrier(0, 2)
+                .set("r", 1)
+            .thread()
+                .set("r", 1)
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        let summary = run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+        assert_eq!(summary, RunSummary {
+            steps_taken: 2,
+            threads_halted: 1,
+            deadlocked: true,
+            budget_exhausted: false,
+            final_memory: vec![Value(0)],
+        });
+    }
+
+    #[test]
+    fn yield_exposes_the_intermediate_store() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::SeqCst, "x", 1)
+                .yield_now()
+                .store_imm(AccessMode::SeqCst, "x", 2)
+            .thread()
+                .set("x", 0)
+                .load(AccessMode::SeqCst, "x", "seen")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+
+        let summary = run_cooperative(&mut machine, 100, &mut Trace::default()).unwrap();
+        assert_eq!(summary.final_memory, [Value(2)]);
+        assert_eq!(machine.threads().get(1).unwrap().get_register(RegisterRef::new("seen")).unwrap(), Value(1));
+    }
+
+    #[test]
+    fn custom_scheduler_prefers_the_highest_thread() {
+        struct HighestFirst;
+
+        impl<Mem: MemorySubsystem> Scheduler<Mem> for HighestFirst {
+            fn next(&mut self, enabled: &[MachineStep<Mem>], _: &Machine<Mem>) -> Option<usize> {
+                enabled.iter()
+                    .enumerate()
+                    .max_by_key(|(_, step)| match step {
+                        MachineStep::Thread(tid) => *tid,
+                        MachineStep::Memory(_) => 0,
+                    })
+                    .map(|(idx, _)| idx)
+            }
+        }
+
+        let writer = |builder: ProgramBuilder, value| builder
+            .thread()
+                .set("p", 0)
+                .store_imm(AccessMode::SeqCst, "p", value);
+        let program = writer(writer(ProgramBuilder::new(), 1), 2).build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        let mut trace = Trace::default();
+
+        let summary = machine.run_with(&mut HighestFirst, 100, &mut trace).unwrap();
+        assert_eq!(summary.final_memory, [Value(1)]);
+        let writers = trace.events.iter()
+            .filter_map(|x| match x {
+                MachineEvent::Write { tid, .. } => Some(*tid),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(writers, [1, 0]);
+    }
+
+    #[test]
+    fn scheduler_picking_past_the_enabled_steps_is_an_error() {
+        struct PastTheEnd;
+
+        impl<Mem: MemorySubsystem> Scheduler<Mem> for PastTheEnd {
+            fn next(&mut self, enabled: &[MachineStep<Mem>], _: &Machine<Mem>) -> Option<usize> {
+                Some(enabled.len())
+            }
+        }
+
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+
+        assert!(matches!(
+            machine.run_with(&mut PastTheEnd, 100, &mut Trace::default()),
+            Err(MachineError::NoSuchStep { choice: 1, enabled: 1 }),
+        ));
+    }
+}
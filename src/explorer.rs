@@ -0,0 +1,738 @@
+use std::{collections::BTreeSet, hash::Hash, ops::Range};
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::{Machine, MachineError, StateKey, MachineEvent, MachineStep, trace::Trace, any_machine::AnyMachine, machine_memory::MemorySubsystem, machine_thread::CodeInstruction, register::{Register, RegisterRef}, value::Value};
+
+/// The observable state of a machine that has finished running.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FinalState {
+    /// Registers of every thread, sorted by name.
+    pub registers: Vec<Vec<(Register, Value)>>,
+    pub memory: Vec<Value>,
+    /// See [`Machine::output`].
+    pub output: Vec<Value>,
+}
+
+impl FinalState {
+    pub fn of<Mem: MemorySubsystem>(machine: &Machine<Mem>) -> Self {
+        FinalState {
+            registers: machine.threads()
+                .iter()
+                .map(|thread| thread.registers()
+                    .into_iter()
+                    .map(|(register, value)| (register.clone(), value))
+                    .collect()
+                )
+                .collect(),
+            memory: machine.memory().global().cells().to_vec(),
+            output: machine.output().to_vec(),
+        }
+    }
+}
+
+/// How many schedules lead to each final state.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    pub outcomes: FnvHashMap<FinalState, usize>,
+    /// `false` if the node budget ran out before the state space was exhausted,
+    /// or some schedules were cut by the memory event bound.
+    pub complete: bool,
+}
+
+/// The result of [`compare_models`].
+#[derive(Debug, Default)]
+pub struct ComparisonReport {
+    /// The compared models.
+    pub models: Vec<&'static str>,
+    /// The outcomes allowed by some of the models but not by the others,
+    /// along with the models which allow them.
+    pub differences: FnvHashMap<FinalState, Vec<&'static str>>,
+}
+
+impl ComparisonReport {
+    /// Whether all the models allow exactly the same outcomes.
+    pub fn is_equivalent(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+/// How an explored schedule has ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TerminalKind {
+    /// All the threads have halted.
+    Halted,
+    /// Some threads haven't halted, but nothing can be stepped.
+    Deadlock,
+    /// The schedule has returned to a state it has already been in, so it
+    /// can go around the cycle forever without making progress.
+    Livelock,
+}
+
+/// Lazily produces the distinct final states of every schedule of at most
+/// `max_depth` steps, one depth-first leaf at a time. A machine state is only
+/// expanded again if it is reached in fewer steps than before, as then more of
+/// its schedules fit into `max_depth`. So the programs which loop without changing
+/// their state (e.g. spin on a register) don't make the exploration diverge. The
+/// iteration stops after the first error.
+///
+/// The independent steps of the subsystem are branched over like the thread
+/// steps, so each of the [read candidates](MemorySubsystem::read_candidates)
+/// of a load gets a branch where the load reads it.
+pub struct Explorer<'a, Mem> {
+    stack: Vec<(Machine<'a, Mem>, usize)>,
+    /// The least depth each state has been expanded at.
+    visited: FnvHashMap<StateKey, usize>,
+    outcomes: FnvHashSet<FinalState>,
+    max_depth: usize,
+}
+
+impl<'a, Mem: MemorySubsystem + Clone + Hash> Explorer<'a, Mem> {
+    pub fn new(machine: Machine<'a, Mem>, max_depth: usize) -> Self {
+        Explorer {
+            stack: vec![(machine, 0)],
+            visited: FnvHashMap::default(),
+            outcomes: FnvHashSet::default(),
+            max_depth,
+        }
+    }
+
+    /// Amount of distinct machine states expanded so far.
+    pub fn visited(&self) -> usize {
+        self.visited.len()
+    }
+}
+
+impl<'a, Mem: MemorySubsystem + Clone + Hash> Iterator for Explorer<'a, Mem> {
+    type Item = Result<FinalState, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((machine, depth)) = self.stack.pop() {
+            let least_depth = self.visited.entry(machine.state_key()).or_insert(usize::MAX);
+            if *least_depth <= depth {
+                continue;
+            }
+            *least_depth = depth;
+
+            let steps = machine.enabled_steps();
+            if steps.is_empty() {
+                let outcome = FinalState::of(&machine);
+                if self.outcomes.insert(outcome.clone()) {
+                    return Some(Ok(outcome));
+                }
+                continue;
+            }
+            if depth >= self.max_depth {
+                continue;
+            }
+
+            for step in steps {
+                let mut next = machine.clone();
+                if let Err(err) = next.step(step) {
+                    self.stack.clear();
+                    return Some(Err(err));
+                }
+                self.stack.push((next, depth + 1));
+            }
+        }
+
+        None
+    }
+}
+
+/// Collects the outcomes of an [`Explorer`].
+pub fn explore<'a, Mem: MemorySubsystem + Clone + Hash>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    max_depth: usize,
+) -> Result<FnvHashSet<FinalState>, MachineError> {
+    Explorer::new(factory()?, max_depth).collect()
+}
+
+/// Like [`explore`], but only collects the distinct final values of register
+/// `reg` of thread `tid`. The outcomes where the thread doesn't have the register
+/// are skipped.
+pub fn explore_register_values<'a, Mem: MemorySubsystem + Clone + Hash>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    tid: usize,
+    reg: &RegisterRef,
+    max_depth: usize,
+) -> Result<BTreeSet<Value>, MachineError> {
+    let mut values = BTreeSet::new();
+
+    for state in Explorer::new(factory()?, max_depth) {
+        let value = state?.registers.get(tid)
+            .and_then(|regs| regs.iter().find(|(name, _)| name.as_ref() == reg))
+            .map(|(_, value)| *value);
+        values.extend(value);
+    }
+
+    Ok(values)
+}
+
+/// What a step may touch that other threads can observe, used to decide
+/// whether two steps commute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Footprint {
+    /// The step only changes its thread.
+    Local,
+    /// The step accesses the cells in `cells`, writing them if `write` is set.
+    Access { cells: Range<usize>, write: bool },
+    /// The step may interact with anything, e.g. a fence or a barrier.
+    Opaque,
+}
+
+impl Footprint {
+    fn of<Mem: MemorySubsystem>(machine: &Machine<Mem>, step: &MachineStep<Mem>, event: &MachineEvent) -> Self {
+        let MachineStep::Thread(tid) = step else { return Footprint::Opaque };
+        let local = machine.threads().get(*tid)
+            .and_then(|x| x.next_instruction())
+            .is_some_and(|x| x.is_local());
+        if local {
+            return Footprint::Local;
+        }
+
+        match *event {
+            MachineEvent::Read { location, .. } => Footprint::Access { cells: location..location + 1, write: false },
+            MachineEvent::Write { location, .. } => Footprint::Access { cells: location..location + 1, write: true },
+            MachineEvent::Rmw { location, op, .. } => Footprint::Access { cells: location..location + 1, write: op.writes() },
+            MachineEvent::ReadN { location, count, .. } => Footprint::Access { cells: location..location + count, write: false },
+            MachineEvent::WriteN { location, count, .. } => Footprint::Access { cells: location..location + count, write: true },
+            _ => Footprint::Opaque,
+        }
+    }
+
+    fn conflicts(&self, other: &Footprint) -> bool {
+        match (self, other) {
+            (Footprint::Local, _) | (_, Footprint::Local) => false,
+            (Footprint::Opaque, _) | (_, Footprint::Opaque) => true,
+            (
+                Footprint::Access { cells: l, write: l_write },
+                Footprint::Access { cells: r, write: r_write },
+            ) => (*l_write || *r_write) && l.start < r.end && r.start < l.end,
+        }
+    }
+}
+
+/// The outcomes of [`explore_dpor`] along with the amount of states expanded.
+#[derive(Debug, Clone)]
+pub struct ReducedExploration {
+    pub outcomes: FnvHashSet<FinalState>,
+    pub visited: usize,
+}
+
+/// Like [`explore`], but skips the schedules which only differ from an already
+/// explored one in the order of independent steps. Two thread steps are
+/// independent if they are made by different threads and don't conflict:
+/// at most one of them accesses the shared memory, or they access disjoint
+/// cells, or they both only read. The dependency is found dynamically, from
+/// the events the steps produce. Fences, barriers and the independent memory
+/// steps conflict with every memory access.
+///
+/// The reduction combines persistent sets and sleep sets. When a thread is
+/// about to make a local step, only that step is explored from the state.
+/// After a step has been explored from a state, the sibling steps explored
+/// later carry it asleep until a step dependent with it is taken. A state is
+/// only expanded again if it is reached in fewer steps or with a sleep set which
+/// isn't a superset of one it has been expanded with at that depth or a lesser one.
+/// The outcomes are the same as those of [`explore`].
+pub fn explore_dpor<'a, Mem: MemorySubsystem + Clone + Hash>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    max_depth: usize,
+) -> Result<ReducedExploration, MachineError> {
+    // Sleeping threads along with the footprints of their next steps
+    type Sleep = Vec<(usize, Footprint)>;
+
+    let mut stack: Vec<(Machine<Mem>, usize, Sleep)> = vec![(factory()?, 0, Vec::new())];
+    let mut expanded = FnvHashMap::<StateKey, Vec<(usize, Vec<usize>)>>::default();
+    let mut outcomes = FnvHashSet::default();
+    let mut visited = 0;
+
+    while let Some((machine, depth, sleep)) = stack.pop() {
+        let mut asleep = sleep.iter().map(|(tid, _)| *tid).collect::<Vec<_>>();
+        asleep.sort_unstable();
+
+        let previous = expanded.entry(machine.state_key()).or_default();
+        let covered = previous.iter().any(|(at, x)| *at <= depth
+            && x.iter().all(|tid| asleep.binary_search(tid).is_ok())
+        );
+        if covered {
+            continue;
+        }
+        previous.push((depth, asleep));
+        visited += 1;
+
+        let steps = machine.enabled_steps();
+        if steps.is_empty() {
+            outcomes.insert(FinalState::of(&machine));
+            continue;
+        }
+        if depth >= max_depth {
+            continue;
+        }
+
+        // A local step commutes with everything and stays enabled, so it is
+        // enough to explore it alone
+        let local = steps.iter().position(|step| matches!(step, MachineStep::Thread(tid)
+            if !sleep.iter().any(|(x, _)| x == tid)
+                && machine.threads().get(*tid).and_then(|x| x.next_instruction()).is_some_and(|x| x.is_local())
+        ));
+        let steps = match local {
+            Some(idx) => vec![steps[idx].clone()],
+            None => steps,
+        };
+
+        let mut done: Sleep = Vec::new();
+        let mut children = Vec::new();
+        for step in steps {
+            let tid = match step {
+                MachineStep::Thread(tid) => Some(tid),
+                MachineStep::Memory(_) => None,
+            };
+            if tid.is_some_and(|tid| sleep.iter().any(|(x, _)| *x == tid)) {
+                continue;
+            }
+
+            let mut next = machine.clone();
+            let event = next.step(step.clone())?;
+            let footprint = Footprint::of(&machine, &step, &event);
+            let child_sleep = sleep.iter()
+                .chain(&done)
+                .filter(|(x, other)| Some(*x) != tid && !footprint.conflicts(other))
+                .cloned()
+                .collect();
+
+            children.push((next, depth + 1, child_sleep));
+            if let Some(tid) = tid {
+                done.push((tid, footprint));
+            }
+        }
+        stack.extend(children.into_iter().rev());
+    }
+
+    Ok(ReducedExploration { outcomes, visited })
+}
+
+/// Runs every schedule of at most `max_depth` steps in a depth-first manner and counts
+/// the schedules that terminate in each final state. Schedules that are still running
+/// after `max_depth` steps are dropped. When more than `node_budget` states have been
+/// visited, the exploration stops and the partial histogram is returned.
+///
+/// Schedules with more than `max_memory_events` non-silent events are dropped too,
+/// which bounds the exploration by the memory operations rather than all steps.
+/// Unlike the depth bound, this makes the histogram incomplete.
+pub fn explore_histogram<'a, Mem: MemorySubsystem + Clone>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    max_depth: usize,
+    node_budget: Option<usize>,
+    max_memory_events: Option<usize>,
+) -> Result<Histogram, MachineError> {
+    let mut histogram = Histogram::default();
+    let mut stack = vec![(factory()?, 0, 0)];
+    let mut visited = 0;
+    let mut pruned = false;
+
+    while let Some((machine, depth, memory_events)) = stack.pop() {
+        if node_budget.is_some_and(|budget| visited >= budget) {
+            return Ok(histogram);
+        }
+        visited += 1;
+
+        let steps = machine.enabled_steps();
+        if steps.is_empty() {
+            *histogram.outcomes.entry(FinalState::of(&machine)).or_default() += 1;
+            continue;
+        }
+        if depth >= max_depth {
+            continue;
+        }
+
+        for step in steps {
+            let mut next = machine.clone();
+            let event = next.step(step)?;
+            let memory_events = memory_events + !matches!(event, MachineEvent::Silent) as usize;
+
+            if max_memory_events.is_some_and(|bound| memory_events > bound) {
+                pruned = true;
+                continue;
+            }
+            stack.push((next, depth + 1, memory_events));
+        }
+    }
+
+    histogram.complete = !pruned;
+    Ok(histogram)
+}
+
+/// The verdict of [`check_termination`]. The counterexamples carry the events
+/// of the offending schedule.
+#[derive(Debug, Clone)]
+pub enum TerminationResult {
+    /// Every schedule halts all the threads within the step bound.
+    Terminates,
+    /// The schedule has got stuck with live threads and nothing to step.
+    Deadlock { schedule: Trace },
+    /// The schedule has come back to a state it has been in, so it can loop forever.
+    Loops { schedule: Trace },
+    /// The schedule hasn't halted within the step bound.
+    TooLong { schedule: Trace },
+}
+
+impl TerminationResult {
+    pub fn terminates(&self) -> bool {
+        matches!(self, TerminationResult::Terminates)
+    }
+}
+
+/// Checks that every schedule halts all the threads within `max_depth` steps,
+/// returning the first schedule found which doesn't.
+pub fn check_termination<'a, Mem: MemorySubsystem + Clone + Hash>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    max_depth: usize,
+) -> Result<TerminationResult, MachineError> {
+    enum Visit<'a, Mem> {
+        Enter(Box<Machine<'a, Mem>>, Trace),
+        Leave(StateKey),
+    }
+
+    // The greatest depth each state has been checked at
+    let mut visited = FnvHashMap::<StateKey, usize>::default();
+    let mut path = FnvHashSet::default();
+    let mut stack = vec![Visit::Enter(Box::new(factory()?), Trace::default())];
+
+    while let Some(visit) = stack.pop() {
+        let (machine, schedule) = match visit {
+            Visit::Enter(machine, schedule) => (*machine, schedule),
+            Visit::Leave(key) => {
+                path.remove(&key);
+                continue;
+            },
+        };
+
+        let key = machine.state_key();
+        if path.contains(&key) {
+            return Ok(TerminationResult::Loops { schedule });
+        }
+        // A state seen off the path has had all its schedules checked already,
+        // but they may not fit into the steps left if it is reached later now
+        let depth = schedule.events.len();
+        if visited.get(&key).is_some_and(|x| *x >= depth) {
+            continue;
+        }
+        visited.insert(key.clone(), depth);
+
+        let steps = machine.enabled_steps();
+        if steps.is_empty() {
+            if machine.threads().iter().all(|x| x.is_halted()) {
+                continue;
+            }
+            return Ok(TerminationResult::Deadlock { schedule });
+        }
+        if depth >= max_depth {
+            return Ok(TerminationResult::TooLong { schedule });
+        }
+
+        path.insert(key.clone());
+        stack.push(Visit::Leave(key));
+        for step in steps {
+            let mut next = machine.clone();
+            let mut schedule = schedule.clone();
+            schedule.record(next.step(step)?);
+            stack.push(Visit::Enter(Box::new(next), schedule));
+        }
+    }
+
+    Ok(TerminationResult::Terminates)
+}
+
+/// Like [`explore`], but also tells how each schedule has ended. A schedule
+/// which comes back to a state on its own path is cut there and reported as a
+/// [`TerminalKind::Livelock`] of that state.
+pub fn explore_terminals<'a, Mem: MemorySubsystem + Clone + Hash>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    max_depth: usize,
+) -> Result<FnvHashSet<(FinalState, TerminalKind)>, MachineError> {
+    enum Visit<'a, Mem> {
+        Enter(Box<Machine<'a, Mem>>, usize),
+        Leave(StateKey),
+    }
+
+    let mut outcomes = FnvHashSet::default();
+    let mut visited = FnvHashMap::<StateKey, usize>::default();
+    let mut path = FnvHashSet::default();
+    let mut stack = vec![Visit::Enter(Box::new(factory()?), 0)];
+
+    while let Some(visit) = stack.pop() {
+        let (machine, depth) = match visit {
+            Visit::Enter(machine, depth) => (*machine, depth),
+            Visit::Leave(key) => {
+                path.remove(&key);
+                continue;
+            },
+        };
+
+        let key = machine.state_key();
+        if path.contains(&key) {
+            outcomes.insert((FinalState::of(&machine), TerminalKind::Livelock));
+            continue;
+        }
+        // Like in the `Explorer`, a state reached in fewer steps has more schedules to explore
+        let least_depth = visited.entry(key.clone()).or_insert(usize::MAX);
+        if *least_depth <= depth {
+            continue;
+        }
+        *least_depth = depth;
+
+        let steps = machine.enabled_steps();
+        if steps.is_empty() {
+            let kind = if machine.threads().iter().all(|x| x.is_halted()) {
+                TerminalKind::Halted
+            } else {
+                TerminalKind::Deadlock
+            };
+            outcomes.insert((FinalState::of(&machine), kind));
+            continue;
+        }
+        if depth >= max_depth {
+            continue;
+        }
+
+        path.insert(key.clone());
+        stack.push(Visit::Leave(key));
+        for step in steps {
+            let mut next = machine.clone();
+            next.step(step)?;
+            stack.push(Visit::Enter(Box::new(next), depth + 1));
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// Explores `program` under each of the named memory subsystems (see
+/// [`AnyMachine::names`]) and reports the outcomes on which they disagree.
+pub fn compare_models(
+    program: &[Vec<CodeInstruction>],
+    memory_size: usize,
+    max_depth: usize,
+    models: &[&str],
+) -> Result<ComparisonReport, MachineError> {
+    let mut report = ComparisonReport::default();
+    let mut allowed = FnvHashMap::<FinalState, Vec<&'static str>>::default();
+
+    for model in models {
+        let machine = AnyMachine::from_name(model, program, memory_size)?;
+        let name = machine.name();
+
+        report.models.push(name);
+        for outcome in machine.explore(max_depth)? {
+            allowed.entry(outcome).or_default().push(name);
+        }
+    }
+
+    report.differences = allowed.into_iter()
+        .filter(|(_, x)| x.len() < report.models.len())
+        .collect();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{litmus::{self, LITMUS_MEMORY_SIZE}, machine_memory::{AccessMode, mixed::MixedMemory, sc::ScMemory}, machine_thread::{BinOp, builder::ProgramBuilder}};
+
+    use super::*;
+
+    fn register(state: &FinalState, tid: usize, name: &str) -> Value {
+        state.registers[tid].iter().find(|(x, _)| x.as_str() == name).unwrap().1
+    }
+
+    #[test]
+    fn every_read_candidate_is_explored() {
+        let (program, _, _) = litmus::mp();
+        let outcomes = explore(|| Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE), 50).unwrap();
+
+        let read = |state: &FinalState, name: &str| {
+            state.registers[1].iter().find(|(x, _)| x.as_str() == name).unwrap().1
+        };
+        let reads = outcomes.iter()
+            .map(|state| (read(state, "r1"), read(state, "r2")))
+            .collect::<FnvHashSet<_>>();
+        assert!(reads.contains(&(Value(1), Value(0))));
+        assert!(reads.contains(&(Value(1), Value(1))));
+    }
+
+    #[test]
+    fn relaxed_store_buffering_differs_from_sc() {
+        let (program, _, postcondition) = litmus::sb();
+        let report = compare_models(&program, LITMUS_MEMORY_SIZE, 50, &["SC", "Mixed"]).unwrap();
+
+        assert!(!report.is_equivalent());
+        assert!(report.differences.iter().any(|(state, models)| postcondition.holds(state) && models == &["Mixed"]));
+    }
+
+    #[test]
+    fn histogram_of_store_buffering_under_sc() {
+        let (program, _, _) = litmus::sb();
+        let histogram = explore_histogram(|| Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE), 50, None, None).unwrap();
+
+        assert!(histogram.complete);
+        let keys = histogram.outcomes.keys()
+            .map(|state| (register(state, 0, "r"), register(state, 1, "r")))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(keys, BTreeSet::from([(Value(0), Value(1)), (Value(1), Value(0)), (Value(1), Value(1))]));
+        assert!(histogram.outcomes.values().all(|x| *x > 0));
+    }
+
+    #[test]
+    fn bounded_loop_visits_each_state_once() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("n", 3)
+                .set("one", 1)
+                .label("loop")
+                .bop("n", BinOp::Sub, "n", "one")
+                .branch("n", "loop")
+            .build();
+        let mut explorer = Explorer::new(Machine::<ScMemory>::new(&program, 1).unwrap(), 100);
+
+        assert_eq!(explorer.by_ref().count(), 1);
+        // The initial state and one per step: two sets and three iterations of two steps
+        assert_eq!(explorer.visited(), 9);
+    }
+
+    #[test]
+    fn spinning_thread_does_not_diverge() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("one", 1)
+                .label("spin")
+                .branch("one", "spin")
+            .build();
+        let mut explorer = Explorer::new(Machine::<ScMemory>::new(&program, 1).unwrap(), usize::MAX);
+
+        assert_eq!(explorer.by_ref().count(), 0);
+        // Branching back to the spin doesn't change the state
+        assert_eq!(explorer.visited(), 2);
+    }
+
+    #[test]
+    fn terminals_of_each_kind() {
+        let kinds = |program: Vec<Vec<CodeInstruction>>| {
+            explore_terminals(|| Machine::<ScMemory>::new(&program, 1), 50).unwrap()
+                .into_iter()
+                .map(|(_, kind)| kind)
+                .collect::<Vec<_>>()
+        };
+
+        let halts = ProgramBuilder::new()
+            .thread()
+                .set("r", 1)
+            .build();
+        assert_eq!(kinds(halts), [TerminalKind::Halted]);
+
+        // The other thread halts without ever coming to the barrier
+        let waits = ProgramBuilder::new()
+            .thread()
+                .barrier(0, 2)
+                .set("r", 1)
+            .thread()
+                .set("s", 1)
+            .build();
+        assert_eq!(kinds(waits), [TerminalKind::Deadlock]);
+
+        let spins = ProgramBuilder::new()
+            .thread()
+                .set("one", 1)
+                .label("spin")
+                .branch("one", "spin")
+            .build();
+        assert_eq!(kinds(spins), [TerminalKind::Livelock]);
+    }
+
+    #[test]
+    fn memory_event_bound_makes_the_histogram_incomplete() {
+        // Every schedule has two writes and two reads
+        let (program, _, _) = litmus::sb();
+        let histogram = |bound| {
+            explore_histogram(|| Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE), 50, None, Some(bound)).unwrap()
+        };
+
+        let bounded = histogram(3);
+        assert!(!bounded.complete);
+        assert!(bounded.outcomes.is_empty());
+
+        let unbounded = histogram(4);
+        assert!(unbounded.complete);
+        assert_eq!(unbounded.outcomes.len(), 3);
+    }
+
+    #[test]
+    fn first_outcomes_of_the_iterator_are_explored_ones() {
+        let (program, _, _) = litmus::sb();
+        let factory = || Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE);
+        let all = explore(factory, 50).unwrap();
+        assert_eq!(all.len(), 4);
+
+        let first = Explorer::new(factory().unwrap(), 50)
+            .take(2)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(first.len(), 2);
+        assert_ne!(first[0], first[1]);
+        assert!(first.iter().all(|x| all.contains(x)));
+
+        let lazy = Explorer::new(factory().unwrap(), 50)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lazy[..2], first[..]);
+        assert_eq!(lazy.into_iter().collect::<FnvHashSet<_>>(), all);
+    }
+
+    #[test]
+    fn register_values_of_load_buffering() {
+        let (program, _, _) = litmus::lb();
+        let values = |reg| {
+            explore_register_values(|| Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE), 0, &RegisterRef::new(reg), 50).unwrap()
+        };
+
+        assert_eq!(values("r"), BTreeSet::from([Value(0), Value(1)]));
+        assert!(values("missing").is_empty());
+    }
+
+    #[test]
+    fn dpor_agrees_with_the_naive_exploration() {
+        let (program, _, _) = litmus::sb();
+        let factory = || Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE);
+
+        let mut naive = Explorer::new(factory().unwrap(), 50);
+        let outcomes = naive.by_ref().collect::<Result<FnvHashSet<_>, _>>().unwrap();
+        let reduced = explore_dpor(factory, 50).unwrap();
+
+        assert_eq!(reduced.outcomes, outcomes);
+        assert_eq!(outcomes.len(), 3);
+        assert!(reduced.visited < naive.visited(), "{} >= {}", reduced.visited, naive.visited());
+    }
+
+    #[test]
+    fn termination_of_straight_and_spinning_programs() {
+        let (program, _, _) = litmus::sb();
+        let result = check_termination(|| Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE), 50).unwrap();
+        assert!(result.terminates());
+
+        // The spinner waits for a flag nobody sets
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("flag", 0)
+                .set("one", 1)
+                .label("wait")
+                .load(AccessMode::SeqCst, "flag", "seen")
+                .bop("unset", BinOp::Sub, "one", "seen")
+                .branch("unset", "wait")
+            .build();
+        let result = check_termination(|| Machine::<ScMemory>::new(&program, 1), 50).unwrap();
+        let TerminationResult::Loops { schedule } = result else {
+            panic!("the spinner is reported as {result:?}");
+        };
+        assert!(schedule.events.iter().any(|x| matches!(x, MachineEvent::Read { .. })));
+    }
+}
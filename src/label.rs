@@ -1,3 +1,11 @@
+use crate::register::{NameError, validate_name};
 
 pub type Label = byteyarn::Yarn;
-pub type LabelRef<'a> = byteyarn::YarnRef<'a, str>;
\ No newline at end of file
+pub type LabelRef<'a> = byteyarn::YarnRef<'a, str>;
+
+/// Makes a label out of a well-formed name.
+pub fn parse(name: &str) -> Result<Label, NameError> {
+    validate_name(name)?;
+
+    Ok(LabelRef::new(name).to_box().immortalize())
+}
@@ -0,0 +1,127 @@
+use crate::machine_thread::Instruction;
+
+/// How many cycles each kind of step costs, see [`Machine::cycles`](crate::Machine::cycles).
+/// The costs don't affect the behaviour of the machine in any way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyModel {
+    /// Instructions which don't touch the global memory.
+    pub register_op: u64,
+    pub load: u64,
+    pub store: u64,
+    pub rmw: u64,
+    pub fence: u64,
+    /// Added to the cost of a fence for draining the store buffer.
+    pub drain_penalty: u64,
+    /// An independent step of the memory subsystem, e.g. a buffered store
+    /// becoming visible. It isn't charged to any thread.
+    pub memory_step: u64,
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        LatencyModel {
+            register_op: 1,
+            load: 4,
+            store: 4,
+            rmw: 8,
+            fence: 2,
+            drain_penalty: 8,
+            memory_step: 1,
+        }
+    }
+}
+
+impl LatencyModel {
+    /// A model where every step costs a single cycle, so the cycles count the steps.
+    pub fn uniform() -> Self {
+        LatencyModel {
+            register_op: 1,
+            load: 1,
+            store: 1,
+            rmw: 1,
+            fence: 1,
+            drain_penalty: 0,
+            memory_step: 1,
+        }
+    }
+
+    /// The cost of a thread executing `instruction`.
+    pub fn instruction_cost(&self, instruction: &Instruction) -> u64 {
+        match instruction {
+            Instruction::Load { .. } |
+            Instruction::LoadLinked { .. } |
+            Instruction::LoadN { .. } |
+            Instruction::LoadPair { .. } |
+            Instruction::LoadBank { .. } => self.load,
+            Instruction::Store { .. } |
+            Instruction::StoreImm { .. } |
+            Instruction::StoreConditional { .. } |
+            Instruction::StoreN { .. } |
+            Instruction::StoreBank { .. } => self.store,
+            Instruction::Cas { .. } |
+            Instruction::CasWeak { .. } |
+            Instruction::Fai { .. } |
+            Instruction::TestAndSetBit { .. } |
+            Instruction::TestAndClearBit { .. } |
+            Instruction::Fas { .. } |
+            Instruction::Fmax { .. } => self.rmw,
+            Instruction::Fence { .. } => self.fence + self.drain_penalty,
+            Instruction::Set { .. } |
+            Instruction::Bop { .. } |
+            Instruction::Branch { .. } |
+            Instruction::BranchReg { .. } |
+            Instruction::CMov { .. } |
+            Instruction::LoadLocal { .. } |
+            Instruction::StoreLocal { .. } |
+            Instruction::GetPc { .. } |
+            Instruction::Assert { .. } |
+            Instruction::Barrier { .. } |
+            Instruction::Yield |
+            Instruction::Print { .. } => self.register_op,
+        }
+    }
+}
+
+/// The cycles spent by a machine under a [`LatencyModel`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cycles {
+    /// All the cycles spent, including the memory steps.
+    pub total: u64,
+    /// The cycles spent by each thread, indexed by thread ID.
+    pub per_thread: Vec<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Machine,
+        driver,
+        machine_memory::{AccessMode, FenceKind, sc::ScMemory},
+        machine_thread::builder::ProgramBuilder,
+        trace::Trace,
+    };
+
+    use super::*;
+
+    #[test]
+    fn cycles_under_the_default_model() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .set("v", 1)
+                .store(AccessMode::SeqCst, "p", "v")
+                .fence(AccessMode::SeqCst, FenceKind::Full)
+            .thread()
+                .set("p", 0)
+                .load(AccessMode::SeqCst, "p", "a")
+                .fai(AccessMode::SeqCst, "p", "old")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        assert_eq!(machine.latency_model(), &LatencyModel::default());
+
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+
+        // 1 + 1 + 4 + (2 + 8) and 1 + 4 + 8, the SC memory has no steps of its own
+        assert_eq!(machine.cycles(), &Cycles { total: 29, per_thread: vec![16, 13] });
+    }
+}
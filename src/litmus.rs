@@ -0,0 +1,574 @@
+use std::{collections::VecDeque, fmt, hash::Hash};
+
+use fnv::FnvHashMap;
+
+use crate::{
+    Machine,
+    MachineError,
+    MachineEvent,
+    MachineStep,
+    driver,
+    explorer::{Explorer, FinalState},
+    machine_memory::{AccessMode, MemorySubsystem},
+    machine_thread::{BinOp, CodeInstruction, Instruction, builder::ProgramBuilder},
+    register::{self, Register},
+    trace::{self, Trace},
+    value::Value,
+};
+
+/// The address of the counter in [`fai_stress`].
+pub const FAI_STRESS_COUNTER: usize = 0;
+
+/// `threads` threads, each incrementing the counter at [`FAI_STRESS_COUNTER`]
+/// `iterations` times with `Fai` in `mode`. With atomic increments the counter
+/// ends up equal to `threads * iterations`. With no iterations the threads
+/// only do their setup.
+pub fn fai_stress(threads: usize, iterations: u64, mode: AccessMode) -> Vec<Vec<CodeInstruction>> {
+    let mut builder = ProgramBuilder::new();
+
+    for _ in 0..threads {
+        builder = builder
+            .thread()
+            .set("counter", FAI_STRESS_COUNTER as u64);
+        if iterations == 0 {
+            continue;
+        }
+
+        builder = builder
+            .set("left", iterations)
+            .set("one", 1)
+            .label("loop")
+            .fai(mode, "counter", "old")
+            .bop("left", BinOp::Sub, "left", "one")
+            .branch("left", "loop");
+    }
+
+    builder.build()
+}
+
+/// Runs [`fai_stress`] to completion with the round-robin driver and returns
+/// the final value of the counter.
+pub fn run_fai_stress<Mem: MemorySubsystem>(
+    threads: usize,
+    iterations: u64,
+    mode: AccessMode,
+) -> Result<Value, MachineError> {
+    let program = fai_stress(threads, iterations, mode);
+    let mut machine = Machine::<Mem>::new(&program, FAI_STRESS_COUNTER + 1)?;
+    // Every iteration takes 3 steps, plus 3 steps of setup per thread
+    let max_steps = threads * (3 * iterations as usize + 3);
+
+    driver::run_round_robin(&mut machine, max_steps, &mut Trace::skipping_silent())?;
+
+    Ok(machine.memory().global().cells()[FAI_STRESS_COUNTER])
+}
+
+/// The address of `x` in the classic litmus tests.
+pub const X: usize = 0;
+/// The address of `y` in the classic litmus tests.
+pub const Y: usize = 1;
+/// How many cells the classic litmus tests need.
+pub const LITMUS_MEMORY_SIZE: usize = 2;
+
+/// The initial values of the memory cells, see [`Machine::set_initial`].
+pub type Preamble = Vec<(usize, Value)>;
+
+/// A litmus test: the program, the initial memory and the outcome the test is about.
+pub type Litmus = (Vec<Vec<CodeInstruction>>, Preamble, Postcondition);
+
+/// A conjunction of final register values, the outcome a litmus test looks for.
+/// The classic tests look for the weak outcome, the one SC forbids.
+#[derive(Debug, Clone)]
+pub struct Postcondition {
+    /// Thread, register and the value it must end with.
+    pub registers: Vec<(usize, Register, Value)>,
+}
+
+impl Postcondition {
+    fn new(registers: &[(usize, &str, u64)]) -> Self {
+        Postcondition {
+            registers: registers.iter()
+                .map(|(tid, name, value)| (*tid, register::parse(name).unwrap(), Value(*value)))
+                .collect(),
+        }
+    }
+
+    pub fn holds(&self, state: &FinalState) -> bool {
+        self.registers.iter().all(|(tid, name, value)| {
+            state.registers.get(*tid)
+                .and_then(|regs| regs.iter().find(|(x, _)| x == name))
+                .is_some_and(|(_, x)| x == value)
+        })
+    }
+}
+
+/// Store buffering.
+///
+/// ```
+/// T0: x = 1; r = y    T1: y = 1; r = x
+/// ```
+///
+/// Looks for `0:r = 0 && 1:r = 0`: forbidden under SC, allowed under TSO and
+/// with relaxed C11 accesses.
+pub fn sb() -> Litmus {
+    let program = ProgramBuilder::new()
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .store_imm(AccessMode::Rlx, "x", 1)
+            .load(AccessMode::Rlx, "y", "r")
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .store_imm(AccessMode::Rlx, "y", 1)
+            .load(AccessMode::Rlx, "x", "r")
+        .build();
+
+    (program, Vec::new(), Postcondition::new(&[(0, "r", 0), (1, "r", 0)]))
+}
+
+/// Message passing.
+///
+/// ```
+/// T0: x = 1; y = 1    T1: r1 = y; r2 = x
+/// ```
+///
+/// Looks for `1:r1 = 1 && 1:r2 = 0`: forbidden under SC and TSO, allowed with
+/// relaxed C11 accesses.
+pub fn mp() -> Litmus {
+    let program = ProgramBuilder::new()
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .store_imm(AccessMode::Rlx, "x", 1)
+            .store_imm(AccessMode::Rlx, "y", 1)
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "y", "r1")
+            .load(AccessMode::Rlx, "x", "r2")
+        .build();
+
+    (program, Vec::new(), Postcondition::new(&[(1, "r1", 1), (1, "r2", 0)]))
+}
+
+/// Load buffering.
+///
+/// ```
+/// T0: r = x; y = 1    T1: r = y; x = 1
+/// ```
+///
+/// Looks for `0:r = 1 && 1:r = 1`: forbidden under SC and TSO, allowed with
+/// relaxed C11 accesses.
+pub fn lb() -> Litmus {
+    let program = ProgramBuilder::new()
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "x", "r")
+            .store_imm(AccessMode::Rlx, "y", 1)
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "y", "r")
+            .store_imm(AccessMode::Rlx, "x", 1)
+        .build();
+
+    (program, Vec::new(), Postcondition::new(&[(0, "r", 1), (1, "r", 1)]))
+}
+
+/// Independent reads of independent writes.
+///
+/// ```
+/// T0: x = 1    T1: y = 1    T2: r1 = x; r2 = y    T3: r1 = y; r2 = x
+/// ```
+///
+/// Looks for the readers disagreeing on the order of the writes,
+/// `2:r1 = 1 && 2:r2 = 0 && 3:r1 = 1 && 3:r2 = 0`: forbidden under SC and TSO,
+/// allowed with release/acquire and relaxed C11 accesses.
+pub fn iriw() -> Litmus {
+    let program = ProgramBuilder::new()
+        .thread()
+            .set("x", X as u64)
+            .store_imm(AccessMode::Rlx, "x", 1)
+        .thread()
+            .set("y", Y as u64)
+            .store_imm(AccessMode::Rlx, "y", 1)
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "x", "r1")
+            .load(AccessMode::Rlx, "y", "r2")
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "y", "r1")
+            .load(AccessMode::Rlx, "x", "r2")
+        .build();
+
+    let postcondition = Postcondition::new(&[(2, "r1", 1), (2, "r2", 0), (3, "r1", 1), (3, "r2", 0)]);
+
+    (program, Vec::new(), postcondition)
+}
+
+/// Write-to-read causality.
+///
+/// ```
+/// T0: x = 1    T1: r1 = x; y = 1    T2: r1 = y; r2 = x
+/// ```
+///
+/// Looks for `1:r1 = 1 && 2:r1 = 1 && 2:r2 = 0`: forbidden under SC and TSO,
+/// allowed with relaxed C11 accesses.
+pub fn wrc() -> Litmus {
+    let program = ProgramBuilder::new()
+        .thread()
+            .set("x", X as u64)
+            .store_imm(AccessMode::Rlx, "x", 1)
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "x", "r1")
+            .store_imm(AccessMode::Rlx, "y", 1)
+        .thread()
+            .set("x", X as u64)
+            .set("y", Y as u64)
+            .load(AccessMode::Rlx, "y", "r1")
+            .load(AccessMode::Rlx, "x", "r2")
+        .build();
+
+    (program, Vec::new(), Postcondition::new(&[(1, "r1", 1), (2, "r1", 1), (2, "r2", 0)]))
+}
+
+/// Explores every schedule of at most `max_depth` steps of the litmus test and
+/// tells whether its postcondition holds in any of the outcomes.
+pub fn is_observable<Mem: MemorySubsystem + Clone + Hash>(
+    (program, preamble, postcondition): &Litmus,
+    max_depth: usize,
+) -> Result<bool, MachineError> {
+    let mut machine = Machine::<Mem>::new(program, LITMUS_MEMORY_SIZE)?;
+    for (addr, value) in preamble {
+        machine.set_initial(*addr, *value)?;
+    }
+
+    for state in Explorer::new(machine, max_depth) {
+        if postcondition.holds(&state?) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// A relation between two events of an execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    /// Program order: both events are by the same thread, the first one earlier.
+    Po,
+    /// Reads-from: the read has taken its value from the write.
+    Rf,
+    /// Coherence order: both are writes to the same location, the first one earlier.
+    Co,
+    /// From-read: the read has taken its value from a write coherence-before this write.
+    Fr,
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Relation::Po => write!(f, "po"),
+            Relation::Rf => write!(f, "rf"),
+            Relation::Co => write!(f, "co"),
+            Relation::Fr => write!(f, "fr"),
+        }
+    }
+}
+
+/// Why an outcome is or isn't reachable, see [`explain_outcome`].
+#[derive(Debug, Clone)]
+pub enum Explanation {
+    /// The outcome is reachable and `witness` is a run reaching it.
+    Allowed {
+        witness: Trace,
+    },
+    /// The outcome is unreachable. `events` is the candidate execution
+    /// producing it and `cycle` the shortest cycle in its program order,
+    /// reads-from, coherence order and from-read: each entry is an index into
+    /// `events` along with the relation to the next entry (the last one leads
+    /// back to the first). SC forbids any such cycle.
+    Forbidden {
+        events: Vec<MachineEvent>,
+        cycle: Vec<(usize, Relation)>,
+    },
+    /// The outcome hasn't been reached, but the candidate execution has no cycle.
+    /// Either the exploration has been too shallow or the outcome is forbidden
+    /// for a reason other than ordering.
+    Unexplained,
+}
+
+/// Renders the witness one event per line or the cycle as
+/// `T0: W ##0 = 1 [RLX] -po-> T0: R ##1 = 0 [RLX] -fr-> ...`.
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Explanation::Allowed { witness } => {
+                writeln!(f, "Allowed, for example by:")?;
+                for event in witness.events.iter().filter(|x| !matches!(x, MachineEvent::Silent)) {
+                    writeln!(f, "    {event}")?;
+                }
+
+                Ok(())
+            },
+            Explanation::Forbidden { events, cycle } => {
+                write!(f, "Forbidden by the cycle ")?;
+                for (idx, relation) in cycle {
+                    write!(f, "{} -{relation}-> ", events[*idx])?;
+                }
+
+                writeln!(f, "{}", events[cycle[0].0])
+            },
+            Explanation::Unexplained => writeln!(f, "Not reached, but no ordering cycle forbids it"),
+        }
+    }
+}
+
+/// Explains whether the outcome `postcondition` describes is reachable on the
+/// machine `factory` makes within `max_depth` steps.
+///
+/// If it is, the first run found reaching it is the witness. Otherwise, the
+/// candidate execution is built by running each thread on its own and making
+/// the loads into the registers `postcondition` mentions return the values it
+/// requires. Reads-from links a read to the first write of the same value to
+/// the same location (or the initial value) and the coherence order is the
+/// order of the threads, so the cycle is only precise for the straight-line
+/// programs which never write the same value to a location twice, like the
+/// classic litmus tests.
+pub fn explain_outcome<'a, Mem: MemorySubsystem + Clone>(
+    factory: impl FnOnce() -> Result<Machine<'a, Mem>, MachineError>,
+    postcondition: &Postcondition,
+    max_depth: usize,
+) -> Result<Explanation, MachineError> {
+    let machine = factory()?;
+
+    if let Some(witness) = find_witness(machine.clone(), postcondition, max_depth)? {
+        return Ok(Explanation::Allowed { witness });
+    }
+
+    let events = candidate_execution(&machine, postcondition, max_depth)?;
+    let explanation = match shortest_cycle(&relations(&events)) {
+        Some(cycle) => Explanation::Forbidden { events, cycle },
+        None => Explanation::Unexplained,
+    };
+
+    Ok(explanation)
+}
+
+fn find_witness<Mem: MemorySubsystem + Clone>(
+    machine: Machine<Mem>,
+    postcondition: &Postcondition,
+    max_depth: usize,
+) -> Result<Option<Trace>, MachineError> {
+    let mut stack = vec![(machine, Trace::default())];
+
+    while let Some((machine, trace)) = stack.pop() {
+        let steps = machine.enabled_steps();
+        if steps.is_empty() {
+            if postcondition.holds(&FinalState::of(&machine)) {
+                return Ok(Some(trace));
+            }
+            continue;
+        }
+        if trace.events.len() >= max_depth {
+            continue;
+        }
+
+        for step in steps {
+            let mut next = machine.clone();
+            let mut trace = trace.clone();
+            trace.record(next.step(step)?);
+            stack.push((next, trace));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The memory events of every thread run on its own, with the loads forced
+/// to the values `postcondition` requires.
+fn candidate_execution<Mem: MemorySubsystem + Clone>(
+    machine: &Machine<Mem>,
+    postcondition: &Postcondition,
+    max_depth: usize,
+) -> Result<Vec<MachineEvent>, MachineError> {
+    let mut events = Vec::new();
+
+    for tid in 0..machine.threads().len() {
+        let mut machine = machine.clone();
+
+        for _ in 0..max_depth {
+            let Some(thread) = machine.threads().get(tid) else { break };
+            let enabled = machine.enabled_steps()
+                .iter()
+                .any(|x| matches!(x, MachineStep::Thread(x) if *x == tid));
+            if thread.is_halted() || !enabled {
+                break;
+            }
+
+            let forced = match thread.next_instruction() {
+                Some(Instruction::Load { dest, .. } | Instruction::LoadBank { dest, .. }) => postcondition.registers.iter()
+                    .find(|(x, name, _)| *x == tid && name == dest)
+                    .map(|(_, name, value)| (name.clone(), *value)),
+                _ => None,
+            };
+
+            let mut event = machine.step(MachineStep::Thread(tid))?;
+            if let (MachineEvent::Read { value, .. }, Some((dest, forced))) = (&mut event, forced) {
+                *value = forced;
+                if let Some(thread) = machine.thread_mut(tid) {
+                    thread.set_register(dest.as_ref(), forced)
+                        .map_err(|err| MachineError::Thread { tid, err })?;
+                }
+            }
+            if !matches!(event, MachineEvent::Silent) {
+                events.push(event);
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+/// The edges of po, rf, co and fr between the events.
+fn relations(events: &[MachineEvent]) -> Vec<Vec<(usize, Relation)>> {
+    let mut edges = vec![Vec::new(); events.len()];
+    let mut writes = FnvHashMap::<usize, Vec<(usize, Value)>>::default();
+    let mut last_of_thread = FnvHashMap::<usize, usize>::default();
+
+    for (idx, event) in events.iter().enumerate() {
+        if let Some(tid) = trace::event_tid(event) {
+            if let Some(prev) = last_of_thread.insert(tid, idx) {
+                edges[prev].push((idx, Relation::Po));
+            }
+        }
+        if let Some((location, value)) = trace::written(event) {
+            let chain = writes.entry(location).or_default();
+            if let Some((prev, _)) = chain.last() {
+                edges[*prev].push((idx, Relation::Co));
+            }
+            chain.push((idx, value));
+        }
+    }
+
+    for (idx, event) in events.iter().enumerate() {
+        for (location, value) in trace::reads(event) {
+            let chain = writes.get(&location).map(Vec::as_slice).unwrap_or_default();
+
+            // The writes coherence-after the source, which is the initial value if none matches
+            let later = match chain.iter().position(|(_, x)| *x == value) {
+                Some(source) => {
+                    if chain[source].0 != idx {
+                        edges[chain[source].0].push((idx, Relation::Rf));
+                    }
+                    &chain[source + 1..]
+                },
+                None => chain,
+            };
+            for (write, _) in later {
+                if *write != idx {
+                    edges[idx].push((*write, Relation::Fr));
+                }
+            }
+        }
+    }
+
+    edges
+}
+
+/// The shortest cycle in the graph, found by a breadth-first search from every node.
+fn shortest_cycle(edges: &[Vec<(usize, Relation)>]) -> Option<Vec<(usize, Relation)>> {
+    let mut best: Option<Vec<(usize, Relation)>> = None;
+
+    for start in 0..edges.len() {
+        let mut parent = vec![None; edges.len()];
+        let mut queue = VecDeque::from([start]);
+        let mut closing = None;
+
+        'search: while let Some(node) = queue.pop_front() {
+            for (to, relation) in &edges[node] {
+                if *to == start {
+                    closing = Some((node, *relation));
+                    break 'search;
+                }
+                if parent[*to].is_none() {
+                    parent[*to] = Some((node, *relation));
+                    queue.push_back(*to);
+                }
+            }
+        }
+
+        let Some((mut node, relation)) = closing else { continue };
+        let mut cycle = vec![(node, relation)];
+        while node != start {
+            let (prev, relation) = parent[node].unwrap();
+            cycle.push((prev, relation));
+            node = prev;
+        }
+        cycle.reverse();
+
+        if best.as_ref().is_none_or(|x| cycle.len() < x.len()) {
+            best = Some(cycle);
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine_memory::sc::ScMemory;
+
+    use super::*;
+
+    #[test]
+    fn fai_stress_counts_every_increment() {
+        let counter = run_fai_stress::<ScMemory>(3, 4, AccessMode::SeqCst).unwrap();
+
+        assert_eq!(counter, Value(12));
+    }
+
+    #[test]
+    fn fai_stress_without_iterations_leaves_the_counter_alone() {
+        let counter = run_fai_stress::<ScMemory>(2, 0, AccessMode::SeqCst).unwrap();
+
+        assert_eq!(counter, Value(0));
+    }
+
+    #[test]
+    fn weak_outcomes_are_forbidden_under_sc() {
+        for (name, litmus) in [("SB", sb()), ("MP", mp()), ("LB", lb()), ("IRIW", iriw()), ("WRC", wrc())] {
+            assert!(!is_observable::<ScMemory>(&litmus, 100).unwrap(), "{name}");
+        }
+
+        // Store buffering still has SC outcomes, e.g. the first thread reading the second one's store
+        let (program, preamble, _) = sb();
+        let sc_outcome = (program, preamble, Postcondition::new(&[(0, "r", 1), (1, "r", 0)]));
+        assert!(is_observable::<ScMemory>(&sc_outcome, 100).unwrap());
+    }
+
+    #[test]
+    fn store_buffering_is_explained_by_a_cycle() {
+        let (program, _, postcondition) = sb();
+        let factory = || Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE);
+
+        let Explanation::Forbidden { events, cycle } = explain_outcome(factory, &postcondition, 100).unwrap() else {
+            panic!("SB isn't forbidden under SC");
+        };
+        assert_eq!(cycle.iter().map(|(_, x)| *x).collect::<Vec<_>>(), [Relation::Po, Relation::Fr, Relation::Po, Relation::Fr]);
+        // Each thread's store leads to its own read, which misses the other thread's store
+        for (idx, _) in cycle.iter().step_by(2) {
+            assert!(matches!(events[*idx], MachineEvent::Write { value: Value(1), .. }));
+        }
+
+        let sc_outcome = Postcondition::new(&[(0, "r", 1), (1, "r", 1)]);
+        assert!(matches!(explain_outcome(factory, &sc_outcome, 100).unwrap(), Explanation::Allowed { .. }));
+    }
+}
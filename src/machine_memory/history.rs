@@ -0,0 +1,502 @@
+//! The machinery shared by the subsystems where a thread may read other writes
+//! than the last one: the history of every location and the views of the threads.
+
+use std::{collections::BTreeMap, hash::{Hash, Hasher}};
+
+use tracing::debug;
+
+use crate::{MachineEvent, machine_thread::{BinOp, ThreadState}, register::RegisterRef, value::Value};
+
+use super::{AccessMode, FenceKind, RmwKind, MemorySubsystem, GlobalMemory, MemoryError, MemoryQuery, Threads};
+
+/// A write in the history of a location.
+#[derive(Debug, Clone)]
+pub(super) struct Message {
+    pub value: Value,
+    /// The thread which has written the message. `None` for the initial value.
+    pub tid: Option<usize>,
+    /// Whether the message is a promise its thread hasn't fulfilled yet.
+    pub promised: bool,
+    /// The timestamp of the write event, see [`MemorySubsystem::restamp`]. `0`
+    /// for the initial value.
+    pub stamp: u64,
+    /// The view of the thread at a releasing write, which the threads
+    /// acquiring the message catch up with. Keyed by the address.
+    pub released: Option<BTreeMap<usize, usize>>,
+}
+
+/// The timestamp depends on the schedule which has led to the state, so it
+/// isn't hashed.
+impl Hash for Message {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.hash(state);
+        self.tid.hash(state);
+        self.promised.hash(state);
+        self.released.hash(state);
+    }
+}
+
+/// The messages of every location, in modification order, and the view of
+/// every thread: the message of each location its loads read.
+#[derive(Debug, Clone, Default, Hash)]
+pub(super) struct Histories {
+    /// The messages of each location, indexed by their timestamps. A location
+    /// gets its initial message on the first access.
+    pub history: BTreeMap<usize, Vec<Message>>,
+    /// The timestamp of the message each thread is at in each location,
+    /// keyed by `(tid, addr)`. `0` if missing.
+    pub views: BTreeMap<(usize, usize), usize>,
+    /// How many messages of a location are kept before pruning, `None` for all.
+    pub cap: Option<usize>,
+}
+
+impl Histories {
+    /// Drops the messages and the views, but keeps the [cap](Histories::cap).
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.views.clear();
+    }
+
+    /// Once a location has more than [`cap`](Histories::cap) messages, drops
+    /// the ones before the oldest view of the live threads, shifting every
+    /// timestamp of the location (in the views and in the released views) to
+    /// match. The history may still exceed the cap while some thread lags
+    /// behind, as its loads may read any message from its view on.
+    pub fn prune<Mem: MemorySubsystem>(&mut self, threads: &Threads<Mem>) {
+        let Some(cap) = self.cap else {
+            return;
+        };
+
+        let over_cap = self.history.iter()
+            .filter(|(_, history)| history.len() > cap)
+            .map(|(&addr, _)| addr)
+            .collect::<Vec<_>>();
+        for addr in over_cap {
+            let oldest = threads.live_tids()
+                .map(|tid| self.view(tid, addr))
+                .min()
+                .unwrap_or(self.history[&addr].len() - 1);
+            if oldest == 0 {
+                continue;
+            }
+
+            debug!("Pruning {oldest} messages of ##{addr}");
+            self.history.get_mut(&addr).unwrap().drain(..oldest);
+            let shift = |(view_addr, ts): (&usize, &mut usize)| if *view_addr == addr {
+                *ts = ts.saturating_sub(oldest);
+            };
+            self.views.iter_mut()
+                .map(|((_, view_addr), ts)| (view_addr, ts))
+                .for_each(shift);
+            self.history.values_mut()
+                .flatten()
+                .filter_map(|x| x.released.as_mut())
+                .flat_map(|x| x.iter_mut())
+                .for_each(shift);
+        }
+    }
+
+    pub fn get_mut<Mem: MemorySubsystem>(&mut self, memory: &GlobalMemory<Mem>, addr: usize) -> &mut Vec<Message> {
+        self.history.entry(addr).or_insert_with(|| vec![Message {
+            value: memory.cells()[addr],
+            tid: None,
+            promised: false,
+            stamp: 0,
+            released: None,
+        }])
+    }
+
+    pub fn view(&self, tid: usize, addr: usize) -> usize {
+        self.views.get(&(tid, addr)).copied().unwrap_or(0)
+    }
+
+    /// Checks that thread `tid` may move its view of `addr` to the message
+    /// `ts`: the thread and the location exist, and the message does too and
+    /// isn't behind the view, which only moves forward.
+    pub fn check_observe<Mem: MemorySubsystem>(
+        &self,
+        tid: usize,
+        addr: usize,
+        ts: usize,
+        threads: &Threads<Mem>,
+        memory: &GlobalMemory<Mem>,
+    ) -> Result<(), MemoryError<Mem::Err>> {
+        check_location(tid, addr, threads, memory)?;
+        let len = self.history.get(&addr).map_or(1, Vec::len);
+        if ts >= len || ts < self.view(tid, addr) {
+            return Err(MemoryError::BadTimestamp { tid, addr, ts });
+        }
+
+        Ok(())
+    }
+
+    /// The timestamps and values of the messages of `addr` a load of thread
+    /// `tid` may read: the one the thread is at and the later ones, except the
+    /// promises of the thread itself.
+    pub fn read_candidates<Mem: MemorySubsystem>(
+        &self,
+        tid: usize,
+        addr: usize,
+        memory: &GlobalMemory<Mem>,
+    ) -> Vec<(usize, Value)> {
+        let Some(history) = self.history.get(&addr) else {
+            return memory.cells().get(addr).map(|x| (0, *x)).into_iter().collect();
+        };
+
+        history.iter()
+            .enumerate()
+            .skip(self.view(tid, addr))
+            .filter(|(_, x)| !(x.promised && x.tid == Some(tid)))
+            .map(|(ts, x)| (ts, x.value))
+            .collect()
+    }
+
+    /// Attaches the view of thread `tid` to the last message of `addr`, see
+    /// [`Message::released`].
+    pub fn release(&mut self, tid: usize, addr: usize) {
+        let view = self.views.range((tid, 0)..=(tid, usize::MAX))
+            .map(|((_, addr), ts)| (*addr, *ts))
+            .collect();
+        if let Some(message) = self.history.get_mut(&addr).and_then(|x| x.last_mut()) {
+            message.released = Some(view);
+        }
+    }
+
+    /// Joins the view released with the message `from` of `addr` into the one
+    /// of its last message, a read-modify-write of it which continues its
+    /// release sequence.
+    pub fn continue_release(&mut self, addr: usize, from: usize) {
+        let Some(history) = self.history.get_mut(&addr) else {
+            return;
+        };
+        let Some(inherited) = history[from].released.clone() else {
+            return;
+        };
+
+        let released = history.last_mut().unwrap().released.get_or_insert_with(BTreeMap::new);
+        for (addr, ts) in inherited {
+            let view = released.entry(addr).or_insert(0);
+            *view = (*view).max(ts);
+        }
+    }
+
+    /// Moves thread `tid` forward to the view released with the message `ts`
+    /// of `addr`, if there is one.
+    pub fn acquire(&mut self, tid: usize, addr: usize, ts: usize) {
+        let released = self.history.get(&addr)
+            .and_then(|x| x[ts].released.clone())
+            .unwrap_or_default();
+        for (addr, ts) in released {
+            let view = self.views.entry((tid, addr)).or_insert(0);
+            *view = (*view).max(ts);
+        }
+    }
+
+    /// Places a write of thread `tid` at the end of the history of `addr` and,
+    /// unless it is `promised`, moves the thread there.
+    pub fn push<Mem: MemorySubsystem>(
+        &mut self,
+        tid: usize,
+        addr: usize,
+        value: Value,
+        promised: bool,
+        memory: &mut GlobalMemory<Mem>,
+    ) {
+        let history = self.get_mut(memory, addr);
+        history.push(Message { value, tid: Some(tid), promised, stamp: 0, released: None });
+        memory.mem[addr] = value;
+        let ts = history.len() - 1;
+        if !promised {
+            self.views.insert((tid, addr), ts);
+        }
+    }
+
+    /// Reads the last message of `addr` and, unless `modify` gives `None`,
+    /// places the new value right after it. Returns the old and the new value.
+    pub fn rmw<Mem: MemorySubsystem>(
+        &mut self,
+        tid: usize,
+        addr: usize,
+        memory: &mut GlobalMemory<Mem>,
+        modify: impl FnOnce(Value) -> Result<Option<Value>, MemoryError<Mem::Err>>,
+    ) -> Result<(Value, Option<Value>), MemoryError<Mem::Err>> {
+        let old = self.get_mut(memory, addr).last().unwrap().value;
+        let new = modify(old)?;
+        match new {
+            Some(value) => {
+                self.push(tid, addr, value, false, memory);
+            },
+            None => {
+                let ts = self.history[&addr].len() - 1;
+                self.views.insert((tid, addr), ts);
+            },
+        }
+
+        Ok((old, new))
+    }
+
+    /// See [`MemorySubsystem::coherence_order`].
+    pub fn coherence_order(&self, addr: usize) -> Vec<Value> {
+        self.history.get(&addr)
+            .map(|x| x.iter().map(|x| x.value).collect())
+            .unwrap_or_default()
+    }
+
+    /// See [`MemorySubsystem::restamp`]. A read gets the timestamp of the
+    /// message its thread is at, which is the one it has read, and the new
+    /// messages get the timestamps of their writes.
+    pub fn restamp(&mut self, event: &mut MachineEvent) {
+        match event {
+            MachineEvent::Read { tid, location, ts, .. } |
+            MachineEvent::Rmw { tid, location, ts, op: RmwKind::CompareSwap { succeeded: false }, .. } => {
+                let view = self.view(*tid, *location);
+                if let Some(message) = self.history.get(location).and_then(|x| x.get(view)) {
+                    *ts = message.stamp;
+                }
+            },
+            MachineEvent::Write { location, ts, .. } |
+            MachineEvent::Rmw { location, ts, .. } => self.stamp_last(*location, *ts),
+            MachineEvent::WriteN { location, count, ts, .. } => {
+                for (cell, ts) in (*location..*location + *count).zip(*ts..) {
+                    self.stamp_last(cell, ts);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Gives the last message of `addr` timestamp `ts`, unless it already has
+    /// one, like a promise fulfilled by a group write.
+    fn stamp_last(&mut self, addr: usize, ts: u64) {
+        let last = self.history.get_mut(&addr)
+            .and_then(|x| x.last_mut())
+            .filter(|x| x.tid.is_some() && x.stamp == 0);
+        if let Some(message) = last {
+            message.stamp = ts;
+        }
+    }
+
+    /// The message each thread is at, one per line.
+    pub fn describe_views(&self) -> String {
+        self.views.iter()
+            .map(|((tid, addr), ts)| format!("T{tid} sees ##{addr}@{ts}\n"))
+            .collect()
+    }
+}
+
+/// What differs between the subsystems built on [`Histories`]. The queries are
+/// served by [`serve`] in terms of these.
+pub(super) trait HistoryMemory: MemorySubsystem {
+    fn histories(&mut self) -> &mut Histories;
+
+    /// Reads `addr` with `mode` for thread `tid`, moving its view to the
+    /// message read.
+    fn load(&mut self, tid: usize, addr: usize, mode: AccessMode, memory: &GlobalMemory<Self>) -> Value;
+
+    /// Writes `value` to `addr` with `mode` on behalf of thread `tid`, moving
+    /// its view to the message written. Returns `true` if the write has been
+    /// made visible before (e.g. promised), so it produces no event now.
+    fn store(&mut self, tid: usize, addr: usize, value: Value, mode: AccessMode, memory: &mut GlobalMemory<Self>) -> bool;
+
+    /// Reads the last message of `addr` and places the new value right after
+    /// it, see [`Histories::rmw`].
+    fn rmw(
+        &mut self,
+        tid: usize,
+        addr: usize,
+        _mode: AccessMode,
+        memory: &mut GlobalMemory<Self>,
+        modify: impl FnOnce(Value) -> Result<Option<Value>, MemoryError<Self::Err>>,
+    ) -> Result<(Value, Option<Value>), MemoryError<Self::Err>> {
+        self.histories().rmw(tid, addr, memory, modify)
+    }
+
+    fn fence(&mut self, _tid: usize, _mode: AccessMode, _kind: FenceKind) {}
+
+    /// The error for a query the subsystem doesn't support.
+    fn unsupported(name: &'static str) -> Self::Err;
+}
+
+/// Checks that thread `tid` and the cell `addr` exist, for the independent
+/// steps naming them.
+pub(super) fn check_location<Mem: MemorySubsystem>(
+    tid: usize,
+    addr: usize,
+    threads: &Threads<Mem>,
+    memory: &GlobalMemory<Mem>,
+) -> Result<(), MemoryError<Mem::Err>> {
+    threads.get(tid).ok_or(MemoryError::BadTid { tid })?;
+    memory.cells().get(addr).ok_or(MemoryError::AddressOutOfRange { addr })?;
+
+    Ok(())
+}
+
+/// Checks an access to `addr` of `bank` and gives the cell.
+fn access<Mem: MemorySubsystem>(
+    memory: &mut GlobalMemory<Mem>,
+    bank: usize,
+    addr: usize,
+    mode: AccessMode,
+    (reads, writes): (u64, u64),
+) -> Result<usize, MemoryError<Mem::Err>> {
+    let cell = memory.resolve(bank, addr)?;
+    memory.check_mode(cell, mode)?;
+    if writes > 0 {
+        memory.check_writable(cell)?;
+    }
+    memory.count_access(cell, reads, writes);
+
+    Ok(cell)
+}
+
+fn set_register<E>(
+    tid: usize,
+    thread_state: &mut ThreadState,
+    dest: RegisterRef,
+    value: Value,
+) -> Result<(), MemoryError<E>> {
+    thread_state.set_register(dest, value)
+        .map_err(|error| MemoryError::ThreadStateError { tid, error })
+}
+
+/// Serves `query` of thread `tid`. Load-linked, store-conditional and barriers
+/// are [unsupported](HistoryMemory::unsupported).
+pub(super) fn serve<Mem: HistoryMemory>(
+    subsystem: &mut Mem,
+    tid: usize,
+    query: MemoryQuery,
+    thread_state: &mut ThreadState,
+    memory: &mut GlobalMemory<Mem>,
+) -> Result<MachineEvent, MemoryError<Mem::Err>> {
+    let event = match query {
+        MemoryQuery::Store { bank, addr, value, mode } => {
+            let cell = access(memory, bank, addr, mode, (0, 1))?;
+            if subsystem.store(tid, cell, value, mode, memory) {
+                return Ok(MachineEvent::Silent);
+            }
+
+            MachineEvent::Write { tid, location: cell, value, mode, ts: 0, promoted: false }
+        },
+        MemoryQuery::Load { bank, addr, dest, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 0))?;
+            let value = subsystem.load(tid, cell, mode, memory);
+            set_register(tid, thread_state, dest, value)?;
+
+            MachineEvent::Read { tid, location: cell, value, mode, ts: 0 }
+        },
+        MemoryQuery::Cas { bank, addr, expected, new_value, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 1))?;
+            let (read_value, written) = subsystem.rmw(tid, cell, mode, memory, |val| {
+                Ok((val == expected).then_some(new_value))
+            })?;
+
+            MachineEvent::Rmw {
+                tid,
+                location: cell,
+                read_value,
+                write_value: written.unwrap_or(read_value),
+                mode,
+                op: RmwKind::CompareSwap { succeeded: written.is_some() },
+                ts: 0,
+            }
+        },
+        MemoryQuery::CasWeak { bank, addr, expected, new_value, ok, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 1))?;
+            let (read_value, written) = subsystem.rmw(tid, cell, mode, memory, |val| {
+                Ok((val == expected).then_some(new_value))
+            })?;
+            set_register(tid, thread_state, ok, Value::from_bool(written.is_some()))?;
+
+            MachineEvent::Rmw {
+                tid,
+                location: cell,
+                read_value,
+                write_value: written.unwrap_or(read_value),
+                mode,
+                op: RmwKind::CompareSwap { succeeded: written.is_some() },
+                ts: 0,
+            }
+        },
+        MemoryQuery::Fai { bank, addr, dest, policy, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 1))?;
+            let (read_value, written) = subsystem.rmw(tid, cell, mode, memory, |value| {
+                BinOp::Add.eval_with_policy(value, Value::ONE, policy)
+                    .map(Some)
+                    .map_err(|_| MemoryError::RmwOverflow { addr: cell, value, operand: Value::ONE })
+            })?;
+            set_register(tid, thread_state, dest, read_value)?;
+
+            MachineEvent::Rmw { tid, location: cell, read_value, write_value: written.unwrap(), mode, op: RmwKind::FetchAdd { overflowed: read_value.0.checked_add(1).is_none() }, ts: 0 }
+        },
+        MemoryQuery::TestAndModifyBit { bank, addr, bit, set, dest, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 1))?;
+            let mask = 1 << bit;
+            let (read_value, written) = subsystem.rmw(tid, cell, mode, memory, |val| {
+                Ok(Some(Value(if set { val.0 | mask } else { val.0 & !mask })))
+            })?;
+            set_register(tid, thread_state, dest, Value((read_value.0 >> bit) & 1))?;
+
+            MachineEvent::Rmw { tid, location: cell, read_value, write_value: written.unwrap(), mode, op: RmwKind::TestAndModifyBit { set }, ts: 0 }
+        },
+        MemoryQuery::Fas { bank, addr, dest, operand, policy, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 1))?;
+            let (read_value, written) = subsystem.rmw(tid, cell, mode, memory, |value| {
+                BinOp::Sub.eval_with_policy(value, operand, policy)
+                    .map(Some)
+                    .map_err(|_| MemoryError::RmwUnderflow { addr: cell, value, operand })
+            })?;
+            set_register(tid, thread_state, dest, read_value)?;
+
+            MachineEvent::Rmw { tid, location: cell, read_value, write_value: written.unwrap(), mode, op: RmwKind::FetchSub { overflowed: read_value.0.checked_sub(operand.0).is_none() }, ts: 0 }
+        },
+        MemoryQuery::Fmax { bank, addr, dest, operand, mode } => {
+            let cell = access(memory, bank, addr, mode, (1, 1))?;
+            let (read_value, written) = subsystem.rmw(tid, cell, mode, memory, |val| Ok(Some(val.max(operand))))?;
+            set_register(tid, thread_state, dest, read_value)?;
+
+            MachineEvent::Rmw { tid, location: cell, read_value, write_value: written.unwrap(), mode, op: RmwKind::FetchMax, ts: 0 }
+        },
+        MemoryQuery::LoadN { addr, dests, mode } => {
+            memory.check_range(addr, dests.len())?;
+            for (cell, dest) in (addr..).zip(dests) {
+                access(memory, 0, cell, mode, (1, 0))?;
+                let value = subsystem.load(tid, cell, mode, memory);
+                set_register(tid, thread_state, dest.as_ref(), value)?;
+            }
+
+            MachineEvent::ReadN { tid, location: addr, count: dests.len(), mode }
+        },
+        MemoryQuery::LoadPair { addrs, dests, mode } => {
+            let mut values = [Value::ZERO; 2];
+            for ((addr, dest), value) in addrs.into_iter().zip(dests).zip(&mut values) {
+                access(memory, 0, addr, mode, (1, 0))?;
+                *value = subsystem.load(tid, addr, mode, memory);
+                set_register(tid, thread_state, dest, *value)?;
+            }
+
+            MachineEvent::ReadPair { tid, locations: addrs, values, mode }
+        },
+        MemoryQuery::StoreN { addr, values, mode } => {
+            memory.check_range(addr, values.len())?;
+            // Either all the cells are written or none
+            for cell in addr..addr + values.len() {
+                access(memory, 0, cell, mode, (0, 1))?;
+            }
+            for (cell, value) in (addr..).zip(&values) {
+                subsystem.store(tid, cell, *value, mode, memory);
+            }
+
+            MachineEvent::WriteN { tid, location: addr, count: values.len(), mode, ts: 0 }
+        },
+        MemoryQuery::Fence { mode, kind } => {
+            subsystem.fence(tid, mode, kind);
+
+            MachineEvent::Fence { tid, mode }
+        },
+        MemoryQuery::LoadLinked { .. } => return Err(Mem::unsupported("Load-linked").into()),
+        MemoryQuery::StoreConditional { .. } => return Err(Mem::unsupported("Store-conditional").into()),
+        MemoryQuery::Barrier { .. } => return Err(Mem::unsupported("Barrier").into()),
+    };
+
+    Ok(event)
+}
@@ -0,0 +1,612 @@
+use std::{collections::BTreeSet, fmt, hash::{Hash, Hasher}, marker::PhantomData};
+
+use thiserror::Error;
+use tracing::debug;
+
+use crate::{MachineEvent, value::Value};
+
+use super::{AccessMode, FenceKind, MemorySubsystem, MemoryStep, Threads, GlobalMemory, MemoryError, history::{self, Histories, HistoryMemory}};
+
+#[derive(Debug, Clone)]
+pub enum IndependentStep {
+    /// Thread `tid` catches up with the write `ts` of `addr`, so its relaxed
+    /// loads from there read that write until it moves on again.
+    Observe {
+        tid: usize,
+        addr: usize,
+        ts: usize,
+    },
+}
+
+impl fmt::Display for IndependentStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndependentStep::Observe { tid, addr, ts } => write!(f, "T{tid} observes ##{addr}@{ts}"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{name} is not supported by the mixed subsystem")]
+    Unsupported {
+        name: &'static str,
+    },
+}
+
+/// Decides which accesses of a [`MixedMemory`] are sequentially consistent.
+pub trait MixedPolicy {
+    /// The name of the subsystem, see [`MemorySubsystem::name`].
+    fn name() -> &'static str;
+
+    /// Whether the accesses (and fences) with `mode` take part in the total order.
+    fn is_sc(mode: AccessMode) -> bool;
+}
+
+/// Only the `SeqCst` accesses are sequentially consistent, like in C11.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeqCstOnly;
+
+impl MixedPolicy for SeqCstOnly {
+    fn name() -> &'static str { "Mixed" }
+
+    fn is_sc(mode: AccessMode) -> bool {
+        mode.is_seqcst()
+    }
+}
+
+/// A subsystem where the policy `P` picks, by the access mode, which accesses
+/// are sequentially consistent. The rest are relaxed.
+///
+/// Every location keeps the history of the writes to it, in modification
+/// order, and every thread has a view: the write of each location its relaxed
+/// loads read. The view only moves forward, by the thread's own accesses or by
+/// an [`Observe`](IndependentStep::Observe) step. The sequentially consistent
+/// loads and the read-modify-writes always read the latest write, and a
+/// sequentially consistent fence (of a [kind](FenceKind::orders_store_load)
+/// ordering the stores with the loads) brings the thread up to date with every
+/// location. So the accesses `P` picks are totally ordered, while the relaxed
+/// ones only respect the coherence of each location. Loads can't read from
+/// the future, so there's no load buffering, see
+/// [`PromisingMemory`](super::promising::PromisingMemory) for that.
+///
+/// Independently of `P`, the writes with release semantics carry the view of
+/// their thread, which the reads with acquire semantics reading them catch up
+/// with. Like in C11, a relaxed write after a release fence (with no other
+/// fence in between) releases as well, see
+/// [`MachineEvent::Write::promoted`](crate::MachineEvent::Write::promoted),
+/// and the read-modify-writes continue the release sequence of the write they
+/// read, whatever their mode. Acquire fences have no effect of their own.
+///
+/// Like in [`PromisingMemory`](super::promising::PromisingMemory), the
+/// histories may be [capped](MixedMemory::set_history_cap).
+///
+/// Load-linked, store-conditional and barriers are not supported.
+pub struct MixedMemory<P = SeqCstOnly> {
+    histories: Histories,
+    /// The threads whose last fence has been a release one.
+    release_fenced: BTreeSet<usize>,
+    _policy: PhantomData<fn() -> P>,
+}
+
+impl<P> Default for MixedMemory<P> {
+    fn default() -> Self {
+        MixedMemory {
+            histories: Histories::default(),
+            release_fenced: BTreeSet::new(),
+            _policy: PhantomData,
+        }
+    }
+}
+
+impl<P> Clone for MixedMemory<P> {
+    fn clone(&self) -> Self {
+        MixedMemory {
+            histories: self.histories.clone(),
+            release_fenced: self.release_fenced.clone(),
+            _policy: PhantomData,
+        }
+    }
+}
+
+impl<P> Hash for MixedMemory<P> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.histories.hash(state);
+        self.release_fenced.hash(state);
+    }
+}
+
+impl<P: MixedPolicy> MixedMemory<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`Histories::prune`].
+    pub fn set_history_cap(&mut self, cap: Option<usize>) {
+        self.histories.cap = cap;
+    }
+
+    /// Whether a write of thread `tid` with `mode` releases its view.
+    fn releases(&self, tid: usize, mode: AccessMode) -> bool {
+        mode.is_at_least_release() || (mode == AccessMode::Rlx && self.is_release_fenced(tid))
+    }
+}
+
+impl<P: MixedPolicy> MemorySubsystem for MixedMemory<P> {
+    type Err = Error;
+    type Independent = IndependentStep;
+
+    fn name() -> &'static str { P::name() }
+
+    fn init(memory_size: usize) -> (Self, GlobalMemory<Self>) {
+        (MixedMemory::new(), GlobalMemory::new(memory_size))
+    }
+
+    fn reset(&mut self) {
+        self.histories.clear();
+        self.release_fenced.clear();
+    }
+
+    /// Every write a running thread is behind.
+    fn pending_independent(
+        &self,
+        threads: &Threads<Self>,
+        _memory: &GlobalMemory<Self>,
+    ) -> Vec<Self::Independent> {
+        threads.live_tids()
+            .flat_map(|tid| self.histories.history.iter().flat_map(move |(&addr, history)| {
+                (self.histories.view(tid, addr) + 1..history.len())
+                    .map(move |ts| IndependentStep::Observe { tid, addr, ts })
+            }))
+            .collect()
+    }
+
+    fn coherence_order(&self, addr: usize) -> Option<Vec<Value>> {
+        Some(self.histories.coherence_order(addr))
+    }
+
+    /// Only the latest write if `mode` is sequentially consistent.
+    fn read_candidates(&self, tid: usize, addr: usize, mode: AccessMode, memory: &GlobalMemory<Self>) -> Vec<Value> {
+        let mut candidates = self.histories.read_candidates(tid, addr, memory);
+        if P::is_sc(mode) {
+            candidates.drain(..candidates.len().saturating_sub(1));
+        }
+
+        candidates.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// An [`Observe`](IndependentStep::Observe) of the candidate.
+    fn pin_read(
+        &self,
+        tid: usize,
+        addr: usize,
+        mode: AccessMode,
+        candidate: usize,
+        memory: &GlobalMemory<Self>,
+    ) -> Option<Self::Independent> {
+        if P::is_sc(mode) {
+            return None;
+        }
+
+        let (ts, _) = *self.histories.read_candidates(tid, addr, memory).get(candidate)?;
+        (ts != self.histories.view(tid, addr)).then_some(IndependentStep::Observe { tid, addr, ts })
+    }
+
+    fn is_release_fenced(&self, tid: usize) -> bool {
+        self.release_fenced.contains(&tid)
+    }
+
+    fn restamp(&mut self, event: &mut MachineEvent) {
+        self.histories.restamp(event);
+    }
+
+    fn describe_state(&self) -> String {
+        self.histories.describe_views()
+    }
+
+    fn execute_step(
+        &mut self,
+        step: MemoryStep<Self::Independent>,
+        threads: &mut Threads<Self>,
+        memory: &mut GlobalMemory<Self>,
+    ) -> Result<MachineEvent, MemoryError<Self::Err>> {
+        debug!("Step: {step:?}");
+
+        let event = match step {
+            MemoryStep::Independent(IndependentStep::Observe { tid, addr, ts }) => {
+                self.histories.check_observe(tid, addr, ts, threads, memory)?;
+                self.histories.views.insert((tid, addr), ts);
+
+                MachineEvent::Silent
+            },
+            MemoryStep::ThreadRequest { tid, query } => {
+                let thread_state = threads.get_thread_mut(tid)?;
+
+                history::serve(self, tid, query, thread_state, memory)?
+            },
+        };
+        self.histories.prune(threads);
+
+        Ok(event)
+    }
+}
+
+impl<P: MixedPolicy> HistoryMemory for MixedMemory<P> {
+    fn histories(&mut self) -> &mut Histories {
+        &mut self.histories
+    }
+
+    /// The latest write if `mode` is sequentially consistent, the write the
+    /// thread is at otherwise.
+    fn load(&mut self, tid: usize, addr: usize, mode: AccessMode, memory: &GlobalMemory<Self>) -> Value {
+        let view = self.histories.view(tid, addr);
+        let history = self.histories.get_mut(memory, addr);
+        let ts = match P::is_sc(mode) {
+            true => history.len() - 1,
+            false => view,
+        };
+        let value = history[ts].value;
+        self.histories.views.insert((tid, addr), ts);
+        if mode.is_at_least_acquire() {
+            self.histories.acquire(tid, addr, ts);
+        }
+
+        value
+    }
+
+    /// Places the write at the end of the history of `addr`.
+    fn store(&mut self, tid: usize, addr: usize, value: Value, mode: AccessMode, memory: &mut GlobalMemory<Self>) -> bool {
+        self.histories.push(tid, addr, value, false, memory);
+        if self.releases(tid, mode) {
+            self.histories.release(tid, addr);
+        }
+
+        false
+    }
+
+    fn rmw(
+        &mut self,
+        tid: usize,
+        addr: usize,
+        mode: AccessMode,
+        memory: &mut GlobalMemory<Self>,
+        modify: impl FnOnce(Value) -> Result<Option<Value>, MemoryError<Self::Err>>,
+    ) -> Result<(Value, Option<Value>), MemoryError<Self::Err>> {
+        let (old, new) = self.histories.rmw(tid, addr, memory, modify)?;
+        // The thread is at the message it has written, right after the one it has read
+        let read = self.histories.view(tid, addr) - new.is_some() as usize;
+        if mode.is_at_least_acquire() {
+            self.histories.acquire(tid, addr, read);
+        }
+        if new.is_some() {
+            if self.releases(tid, mode) {
+                self.histories.release(tid, addr);
+            }
+            self.histories.continue_release(addr, read);
+        }
+
+        Ok((old, new))
+    }
+
+    /// A sequentially consistent fence which orders the stores before it with
+    /// the loads after it brings the thread up to date. A release fence makes
+    /// the following relaxed writes release.
+    fn fence(&mut self, tid: usize, mode: AccessMode, kind: FenceKind) {
+        if mode.is_at_least_release() {
+            self.release_fenced.insert(tid);
+        } else {
+            self.release_fenced.remove(&tid);
+        }
+        if P::is_sc(mode) && kind.orders_store_load() {
+            for (addr, history) in &self.histories.history {
+                self.histories.views.insert((tid, *addr), history.len() - 1);
+            }
+        }
+    }
+
+    fn unsupported(name: &'static str) -> Error {
+        Error::Unsupported { name }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{
+        Machine,
+        MachineError,
+        MachineStep,
+        explorer::explore,
+        litmus::{self, LITMUS_MEMORY_SIZE, X, Y},
+        machine_memory::ErasedMemoryError,
+        machine_thread::{CodeInstruction, builder::ProgramBuilder},
+        register::RegisterRef,
+    };
+
+    use super::*;
+
+    /// Message passing where `publish` sets the flag `y`, read with acquire.
+    fn mp(publish: impl Fn(ProgramBuilder) -> ProgramBuilder) -> Vec<Vec<CodeInstruction>> {
+        let writer = ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .store_imm(AccessMode::Rlx, "x", 1);
+
+        publish(writer)
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .load(AccessMode::Acq, "y", "r1")
+                .load(AccessMode::Rlx, "x", "r2")
+            .build()
+    }
+
+    /// The final values of `r1` and `r2` of the reader, the last thread.
+    fn outcomes(program: &[Vec<CodeInstruction>]) -> BTreeSet<(Value, Value)> {
+        explore(|| Machine::<MixedMemory>::new(program, LITMUS_MEMORY_SIZE), 50).unwrap()
+            .into_iter()
+            .map(|state| {
+                let register = |name| state.registers.last().unwrap().iter().find(|(x, _)| x.as_str() == name).unwrap().1;
+
+                (register("r1"), register("r2"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn release_fence_promotes_relaxed_store() {
+        let release_store = mp(|writer| writer.store_imm(AccessMode::Rel, "y", 1));
+        let fenced_store = mp(|writer| writer
+            .fence(AccessMode::Rel, FenceKind::Full)
+            .store_imm(AccessMode::Rlx, "y", 1)
+        );
+
+        for program in [release_store, fenced_store] {
+            let outcomes = outcomes(&program);
+            assert!(outcomes.contains(&(Value(1), Value(1))));
+            assert!(!outcomes.contains(&(Value(1), Value(0))));
+        }
+    }
+
+    #[test]
+    fn fenced_relaxed_store_is_marked_promoted() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .fence(AccessMode::Rel, FenceKind::Full)
+                .store_imm(AccessMode::Rlx, "x", 1)
+                .fence(AccessMode::Rlx, FenceKind::Full)
+                .store_imm(AccessMode::Rlx, "x", 2)
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        let promoted = |machine: &mut Machine<MixedMemory>| machine.run_schedule(&vec![MachineStep::Thread(0); 5])
+            .unwrap()
+            .into_iter()
+            .filter_map(|x| match x {
+                MachineEvent::Write { promoted, .. } => Some(promoted),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(promoted(&mut machine), [true, false]);
+        machine.reset();
+        assert_eq!(promoted(&mut machine), [true, false]);
+    }
+
+    #[test]
+    fn relaxed_store_does_not_release() {
+        let relaxed_store = mp(|writer| writer.store_imm(AccessMode::Rlx, "y", 1));
+
+        assert!(outcomes(&relaxed_store).contains(&(Value(1), Value(0))));
+    }
+
+    #[test]
+    fn pinned_load_reads_older_write() {
+        let (program, _, _) = litmus::mp();
+        let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        for tid in [0, 0, 0, 0, 1, 1] {
+            machine.step(MachineStep::Thread(tid)).unwrap();
+        }
+
+        let pin = |machine: &Machine<MixedMemory>, addr, candidate| {
+            let memory = machine.memory();
+            let candidates = memory.subsystem().read_candidates(1, addr, AccessMode::Rlx, memory.global());
+            assert_eq!(candidates, [Value(0), Value(1)]);
+
+            memory.subsystem().pin_read(1, addr, AccessMode::Rlx, candidate, memory.global())
+        };
+        let observe = pin(&machine, Y, 1).unwrap();
+        machine.step(MachineStep::Memory(observe)).unwrap();
+        machine.step(MachineStep::Thread(1)).unwrap();
+        assert!(pin(&machine, X, 0).is_none());
+        machine.step(MachineStep::Thread(1)).unwrap();
+
+        let reader = machine.threads().get(1).unwrap();
+        assert_eq!(reader.get_register(RegisterRef::new("r1")).unwrap(), Value(1));
+        assert_eq!(reader.get_register(RegisterRef::new("r2")).unwrap(), Value(0));
+    }
+
+    #[test]
+    fn acquire_synchronizes_through_release_sequence() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .store_imm(AccessMode::Rlx, "x", 1)
+                .store_imm(AccessMode::Rel, "y", 5)
+            .thread()
+                .set("y", Y as u64)
+                .fai(AccessMode::Rlx, "y", "old")
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .load(AccessMode::Acq, "y", "r1")
+                .load(AccessMode::Rlx, "x", "r2")
+            .build();
+
+        let outcomes = outcomes(&program);
+        // The increment of the released 5 continues its release sequence
+        assert!(outcomes.contains(&(Value(6), Value(1))));
+        assert!(outcomes.iter().all(|(r1, r2)| r1.0 < 5 || *r2 == Value(1)));
+    }
+
+    #[test]
+    fn only_store_load_ordering_fences_forbid_store_buffering() {
+        let sb = |kind| ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .store_imm(AccessMode::Rlx, "x", 1)
+                .fence(AccessMode::SeqCst, kind)
+                .load(AccessMode::Rlx, "y", "r")
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .store_imm(AccessMode::Rlx, "y", 1)
+                .fence(AccessMode::SeqCst, kind)
+                .load(AccessMode::Rlx, "x", "r")
+            .build();
+        let both_zero = |program: &[Vec<CodeInstruction>]| {
+            explore(|| Machine::<MixedMemory>::new(program, LITMUS_MEMORY_SIZE), 50).unwrap()
+                .iter()
+                .any(|state| state.registers.iter().all(|regs| regs.iter().any(|(x, v)| x.as_str() == "r" && *v == Value(0))))
+        };
+
+        assert!(both_zero(&sb(FenceKind::StoreStore)));
+        assert!(!both_zero(&sb(FenceKind::Full)));
+        assert!(!both_zero(&sb(FenceKind::StoreLoad)));
+    }
+
+    #[test]
+    fn coherence_order_follows_the_schedule() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .thread()
+                .set("x", X as u64)
+                .store_imm(AccessMode::Rlx, "x", 2)
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        machine.run_schedule(&[1, 1, 0, 0].map(MachineStep::Thread)).unwrap();
+
+        let order = machine.memory().subsystem().coherence_order(X);
+        assert_eq!(order, Some(vec![Value(0), Value(2), Value(1)]));
+    }
+
+    #[test]
+    fn fence_strengths_differ() {
+        let mp_weak = |mode| {
+            let program = mp(|writer| writer
+                .fence(mode, FenceKind::Full)
+                .store_imm(AccessMode::Rlx, "y", 1)
+            );
+            outcomes(&program).contains(&(Value(1), Value(0)))
+        };
+        let sb_weak = |mode| {
+            let thread = |builder: ProgramBuilder, to: &str, from: &str| builder
+                .thread()
+                    .set("x", X as u64)
+                    .set("y", Y as u64)
+                    .store_imm(AccessMode::Rlx, to, 1)
+                    .fence(mode, FenceKind::Full)
+                    .load(AccessMode::Rlx, from, "r");
+            let program = thread(thread(ProgramBuilder::new(), "x", "y"), "y", "x").build();
+
+            explore(|| Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE), 50).unwrap()
+                .iter()
+                .any(|state| state.registers.iter().all(|regs| regs.iter().any(|(x, v)| x.as_str() == "r" && *v == Value(0))))
+        };
+
+        // An acquire fence orders nothing on the writer's side
+        assert!(mp_weak(AccessMode::Acq));
+        assert!(sb_weak(AccessMode::Acq));
+        // A release fence publishes the earlier store, but doesn't order it before a later load
+        assert!(!mp_weak(AccessMode::Rel));
+        assert!(sb_weak(AccessMode::Rel));
+        // A sequentially consistent fence does both
+        assert!(!mp_weak(AccessMode::SeqCst));
+        assert!(!sb_weak(AccessMode::SeqCst));
+    }
+
+    #[test]
+    fn seqcst_and_relaxed_store_buffering() {
+        let sb = |modes: [AccessMode; 2]| {
+            let thread = |builder: ProgramBuilder, mode, (own, other): (&str, &str)| builder
+                .thread()
+                    .set("x", X as u64)
+                    .set("y", Y as u64)
+                    .store_imm(mode, own, 1)
+                    .load(mode, other, "r");
+            let program = thread(thread(ProgramBuilder::new(), modes[0], ("x", "y")), modes[1], ("y", "x")).build();
+
+            explore(|| Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE), 50).unwrap()
+                .into_iter()
+                .any(|state| state.registers.iter().all(|x| x.iter().any(|(name, value)| name.as_str() == "r" && *value == Value(0))))
+        };
+
+        assert!(!sb([AccessMode::SeqCst, AccessMode::SeqCst]));
+        assert!(sb([AccessMode::SeqCst, AccessMode::Rlx]));
+        assert!(sb([AccessMode::Rlx, AccessMode::Rlx]));
+    }
+
+    #[test]
+    fn malformed_observe_is_rejected() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+                .store_imm(AccessMode::Rlx, "x", 2)
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, 1).unwrap();
+        machine.run_schedule(&[0, 0, 0].map(MachineStep::Thread)).unwrap();
+        let observe = |tid, addr, ts| MachineStep::Memory(IndependentStep::Observe { tid, addr, ts });
+
+        for (step, expected) in [
+            (observe(1, 0, 1), ErasedMemoryError::BadTid { tid: 1 }),
+            (observe(0, 1, 0), ErasedMemoryError::AddressOutOfRange { addr: 1 }),
+            (observe(0, 0, 3), ErasedMemoryError::BadTimestamp { tid: 0, addr: 0, ts: 3 }),
+            (observe(0, 0, 1), ErasedMemoryError::BadTimestamp { tid: 0, addr: 0, ts: 1 }),
+        ] {
+            let Err(MachineError::Memory(err)) = machine.step(step) else {
+                panic!("{expected} hasn't been reported");
+            };
+            assert_eq!(err.to_string(), expected.to_string());
+        }
+        assert_eq!(machine.memory().subsystem().histories.view(0, 0), 2);
+    }
+
+    #[test]
+    fn pruning_shifts_the_released_views() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .repeat(20, |block| block.fai(AccessMode::Rlx, "x", "old"))
+                .store_imm(AccessMode::Rel, "y", 1)
+            .thread()
+                .set("x", X as u64)
+                .set("y", Y as u64)
+                .load(AccessMode::SeqCst, "x", "r0")
+                .load(AccessMode::SeqCst, "y", "r1")
+                .load(AccessMode::Rlx, "x", "r2")
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        machine.memory_mut().subsystem_mut().set_history_cap(Some(4));
+
+        // The second thread holds the history of `x` back until it catches up
+        machine.run_schedule(&vec![MachineStep::Thread(0); 23]).unwrap();
+        assert_eq!(machine.memory().subsystem().coherence_order(X).unwrap().len(), 21);
+        machine.run_schedule(&vec![MachineStep::Thread(1); 3]).unwrap();
+        assert_eq!(machine.memory().subsystem().coherence_order(X).unwrap(), [Value(20)]);
+
+        // Acquiring the flag brings the thread to the write released with it,
+        // which is now the first message of `x`
+        machine.run_schedule(&vec![MachineStep::Thread(1); 2]).unwrap();
+        let thread = machine.threads().get(1).unwrap();
+        assert_eq!(thread.get_register(RegisterRef::new("r1")).unwrap(), Value(1));
+        assert_eq!(thread.get_register(RegisterRef::new("r2")).unwrap(), Value(20));
+    }
+}
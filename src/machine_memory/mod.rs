@@ -1,9 +1,15 @@
-mod sc;
+pub mod sc;
+pub mod promising;
+pub mod mixed;
+mod history;
 
-use std::{fmt::{self, Debug}, error::Error, marker::PhantomData};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::{collections::BTreeSet, fmt::{self, Debug}, error::Error, marker::PhantomData, hash::{Hash, Hasher}, sync::Arc};
 use thiserror::Error;
 
-use crate::{value::Value, register::RegisterRef, machine_thread::{ThreadState, ThreadStateError}};
+use smallvec::SmallVec;
+
+use crate::{MachineEvent, trace::{Trace, ValidationError}, value::Value, register::{Register, RegisterRef}, machine_thread::{InitPolicy, OverflowPolicy, ThreadState, ThreadStateError}};
 
 /// Memory access mode.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -13,12 +19,48 @@ pub enum AccessMode {
     SeqCst,
     /// Release
     Rel,
-    // Acquire
+    /// Acquire
     Acq,
-    // Release-Acquire
+    /// Release-Acquire
     RelAcq,
-    // Relaxed
+    /// Relaxed
     Rlx,
+    /// Non-atomic (plain) access
+    NonAtomic,
+}
+
+impl AccessMode {
+    /// Whether the mode has acquire semantics (`SeqCst`, `RelAcq` or `Acq`).
+    pub fn is_at_least_acquire(self) -> bool {
+        matches!(self, AccessMode::SeqCst | AccessMode::RelAcq | AccessMode::Acq)
+    }
+
+    /// Whether the mode has release semantics (`SeqCst`, `RelAcq` or `Rel`).
+    pub fn is_at_least_release(self) -> bool {
+        matches!(self, AccessMode::SeqCst | AccessMode::RelAcq | AccessMode::Rel)
+    }
+
+    /// Whether the access is atomic, i.e. anything but `NonAtomic`.
+    pub fn is_atomic(self) -> bool {
+        self != AccessMode::NonAtomic
+    }
+
+    pub fn is_seqcst(self) -> bool {
+        self == AccessMode::SeqCst
+    }
+
+    /// Splits the mode of a read-modify-write into the modes of its read
+    /// and its write parts. E.g. `RelAcq` becomes an `Acq` read and a `Rel` write.
+    pub fn split_rmw(self) -> (AccessMode, AccessMode) {
+        match self {
+            AccessMode::SeqCst => (AccessMode::SeqCst, AccessMode::SeqCst),
+            AccessMode::RelAcq => (AccessMode::Acq, AccessMode::Rel),
+            AccessMode::Acq => (AccessMode::Acq, AccessMode::Rlx),
+            AccessMode::Rel => (AccessMode::Rlx, AccessMode::Rel),
+            AccessMode::Rlx => (AccessMode::Rlx, AccessMode::Rlx),
+            AccessMode::NonAtomic => (AccessMode::NonAtomic, AccessMode::NonAtomic),
+        }
+    }
 }
 
 impl fmt::Display for AccessMode {
@@ -29,44 +71,233 @@ impl fmt::Display for AccessMode {
             AccessMode::Acq => write!(f, "ACQ"),
             AccessMode::RelAcq => write!(f, "REL_ACQ"),
             AccessMode::Rlx => write!(f, "RLX"),
+            AccessMode::NonAtomic => write!(f, "NA"),
+        }
+    }
+}
+
+/// The kind of a barrier, in the spirit of SPARC's `membar`. `XY` orders
+/// the accesses of kind `X` before the fence with the accesses of kind `Y`
+/// after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FenceKind {
+    /// Orders all accesses.
+    Full,
+    LoadLoad,
+    LoadStore,
+    StoreLoad,
+    StoreStore,
+}
+
+impl FenceKind {
+    /// Whether the fence requires all the preceding stores to be visible
+    /// before the following loads (e.g. forces a store buffer drain).
+    pub fn orders_store_load(self) -> bool {
+        matches!(self, FenceKind::Full | FenceKind::StoreLoad)
+    }
+}
+
+impl fmt::Display for FenceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FenceKind::Full => write!(f, "FULL"),
+            FenceKind::LoadLoad => write!(f, "LOAD_LOAD"),
+            FenceKind::LoadStore => write!(f, "LOAD_STORE"),
+            FenceKind::StoreLoad => write!(f, "STORE_LOAD"),
+            FenceKind::StoreStore => write!(f, "STORE_STORE"),
+        }
+    }
+}
+
+/// The operation of a read-modify-write, see [`MachineEvent::Rmw`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RmwKind {
+    /// A strong or weak compare-and-swap. A failed one only reads, so its
+    /// event has the value read as the value written.
+    CompareSwap {
+        succeeded: bool,
+    },
+    /// `overflowed` tells whether the result has wrapped around or saturated.
+    FetchAdd {
+        overflowed: bool,
+    },
+    /// `overflowed` tells whether the result has wrapped around or saturated.
+    FetchSub {
+        overflowed: bool,
+    },
+    FetchMax,
+    /// Sets (or clears, if `set` is `false`) a single bit.
+    TestAndModifyBit {
+        set: bool,
+    },
+}
+
+impl RmwKind {
+    /// Whether the operation has written the memory, which all but a failed
+    /// compare-and-swap do.
+    pub fn writes(&self) -> bool {
+        !matches!(self, RmwKind::CompareSwap { succeeded: false })
+    }
+}
+
+/// The failed compare-and-swaps and the wrapped around or saturated
+/// arithmetic are marked, e.g. `CAS, failed`.
+impl fmt::Display for RmwKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RmwKind::CompareSwap { succeeded: true } => write!(f, "CAS"),
+            RmwKind::CompareSwap { succeeded: false } => write!(f, "CAS, failed"),
+            RmwKind::FetchAdd { overflowed: false } => write!(f, "FAI"),
+            RmwKind::FetchAdd { overflowed: true } => write!(f, "FAI, overflowed"),
+            RmwKind::FetchSub { overflowed: false } => write!(f, "FAS"),
+            RmwKind::FetchSub { overflowed: true } => write!(f, "FAS, overflowed"),
+            RmwKind::FetchMax => write!(f, "FMAX"),
+            RmwKind::TestAndModifyBit { set: true } => write!(f, "BTS"),
+            RmwKind::TestAndModifyBit { set: false } => write!(f, "BTC"),
         }
     }
 }
 
 /// The query for the memory subsystem.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum MemoryQuery<'a> {
     /// A query to write some value.
     Store {
+        /// The memory bank `addr` is in, see [`GlobalMemory::set_banks`].
+        bank: usize,
         addr: usize,
         value: Value,
         mode: AccessMode,
     },
     /// A query to read a value into a register.
     Load {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
         addr: usize,
         dest: RegisterRef<'a>,
         mode: AccessMode,
     },
     /// Compare-and-swap
     Cas {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
         addr: usize,
         expected: Value,
         new_value: Value,
         mode: AccessMode,
     },
+    /// Compare-and-swap which may fail spuriously. The outcome is put into `ok`.
+    CasWeak {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
+        addr: usize,
+        expected: Value,
+        new_value: Value,
+        ok: RegisterRef<'a>,
+        mode: AccessMode,
+    },
     /// Fetch-and-increment
     Fai {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
+        addr: usize,
+        dest: RegisterRef<'a>,
+        /// How an overflow of the new value is handled.
+        policy: OverflowPolicy,
+        mode: AccessMode,
+    },
+    /// Bit-test-and-set (if `set`) or bit-test-and-clear. The old bit goes into `dest`.
+    TestAndModifyBit {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
         addr: usize,
+        bit: u32,
+        set: bool,
         dest: RegisterRef<'a>,
         mode: AccessMode,
     },
+    /// Fetch-and-subtract
+    Fas {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
+        addr: usize,
+        dest: RegisterRef<'a>,
+        operand: Value,
+        /// How an underflow of the new value is handled.
+        policy: OverflowPolicy,
+        mode: AccessMode,
+    },
+    /// Fetch-and-max
+    Fmax {
+        /// See [`MemoryQuery::Store::bank`].
+        bank: usize,
+        addr: usize,
+        dest: RegisterRef<'a>,
+        operand: Value,
+        mode: AccessMode,
+    },
+    /// Load-linked: a load which places a reservation on the address.
+    LoadLinked {
+        addr: usize,
+        dest: RegisterRef<'a>,
+        mode: AccessMode,
+    },
+    /// Store-conditional: a store which only happens if the reservation
+    /// on the address is intact. The outcome (`1` or `0`) goes into `ok`.
+    StoreConditional {
+        addr: usize,
+        value: Value,
+        ok: RegisterRef<'a>,
+        mode: AccessMode,
+    },
+    /// A group load of `dests.len()` consecutive cells starting at `addr`.
+    LoadN {
+        addr: usize,
+        dests: &'a [Register],
+        mode: AccessMode,
+    },
+    /// A load of two cells in one access, see [`Instruction::LoadPair`](crate::machine_thread::Instruction::LoadPair).
+    LoadPair {
+        addrs: [usize; 2],
+        dests: [RegisterRef<'a>; 2],
+        mode: AccessMode,
+    },
+    /// A group store of `values` into consecutive cells starting at `addr`.
+    StoreN {
+        addr: usize,
+        values: SmallVec<[Value; 4]>,
+        mode: AccessMode,
+    },
     /// Instructing the memory subsystem to perform a fence.
     Fence {
         mode: AccessMode,
+        kind: FenceKind,
+    },
+    /// The thread has arrived at barrier `id`, which releases once
+    /// `participants` threads have.
+    Barrier {
+        id: u64,
+        participants: usize,
     },
 }
 
+impl<'a> MemoryQuery<'a> {
+    /// Whether the query is a read-modify-write, which must be served as a single
+    /// atomic event (see [`MemorySubsystem::execute_step`]).
+    pub fn is_rmw(&self) -> bool {
+        matches!(
+            self,
+            MemoryQuery::Cas { .. } |
+            MemoryQuery::CasWeak { .. } |
+            MemoryQuery::Fai { .. } |
+            MemoryQuery::TestAndModifyBit { .. } |
+            MemoryQuery::Fas { .. } |
+            MemoryQuery::Fmax { .. }
+        )
+    }
+}
+
 #[derive(Debug)]
 pub enum MemoryStep<'a, S> {
     Independent(S),
@@ -93,17 +324,448 @@ pub enum MemoryError<E> {
         tid: usize,
         error: ThreadStateError,
     },
+    #[error("Subtracting {operand} from {value} at address {addr} has underflown")]
+    RmwUnderflow {
+        addr: usize,
+        value: Value,
+        operand: Value,
+    },
+    #[error("Adding {operand} to {value} at address {addr} has overflown")]
+    RmwOverflow {
+        addr: usize,
+        value: Value,
+        operand: Value,
+    },
+    #[error("Non-atomic access to atomic-only address {addr}")]
+    NonAtomicAccessToAtomicLocation {
+        addr: usize,
+    },
+    #[error("Write to read-only address {addr}")]
+    WriteToReadOnly {
+        addr: usize,
+    },
+    #[error("Memory bank {bank} doesn't exist")]
+    BankOutOfRange {
+        bank: usize,
+    },
+    #[error("Address {addr} is initialised by two regions")]
+    OverlappingInit {
+        addr: usize,
+    },
+    #[error("Address {base} displaced by {offset} doesn't fit into the address space")]
+    AddressOverflow {
+        base: usize,
+        offset: usize,
+    },
+    #[error("Thread {tid} can't move its view of address {addr} to timestamp {ts}")]
+    BadTimestamp {
+        tid: usize,
+        addr: usize,
+        ts: usize,
+    },
     #[error("Memory system failed with implementation specific error")]
     Other(#[from] E),
 }
 
+/// Adds `offset` to the address `base`. Every address computation goes through
+/// here, so a wrap around is always reported as [`MemoryError::AddressOverflow`].
+pub fn checked_address_add<E>(base: usize, offset: usize) -> Result<usize, MemoryError<E>> {
+    base.checked_add(offset).ok_or(MemoryError::AddressOverflow { base, offset })
+}
+
+/// Names of memory regions, each being a range of cells.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLayout {
+    regions: FnvHashMap<String, (usize, usize)>,
+}
+
+impl MemoryLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names `len` cells starting at `start`. Redeclaring a name overwrites it.
+    pub fn declare(&mut self, name: &str, start: usize, len: usize) {
+        self.regions.insert(name.to_owned(), (start, len));
+    }
+
+    /// The start and the length of the region named `name`.
+    pub fn get(&self, name: &str) -> Option<(usize, usize)> {
+        self.regions.get(name).copied()
+    }
+
+    /// The regions sorted by their start.
+    pub fn regions(&self) -> Vec<(&str, usize, usize)> {
+        let mut regions = self.regions.iter()
+            .map(|(name, (start, len))| (name.as_str(), *start, *len))
+            .collect::<Vec<_>>();
+        regions.sort_unstable_by_key(|(name, start, _)| (*start, *name));
+
+        regions
+    }
+}
+
+/// Amount of reads and writes per address.
+pub type AccessStats = FnvHashMap<usize, (u64, u64)>;
+
+impl<E: fmt::Display> MemoryError<E> {
+    /// Replaces the implementation specific error with its message, so errors
+    /// of different subsystems can be handled uniformly.
+    pub fn erase(self) -> ErasedMemoryError {
+        match self {
+            MemoryError::AddressOutOfRange { addr } => ErasedMemoryError::AddressOutOfRange { addr },
+            MemoryError::BadTid { tid } => ErasedMemoryError::BadTid { tid },
+            MemoryError::ThreadStateError { tid, error } => ErasedMemoryError::ThreadStateError { tid, error },
+            MemoryError::RmwUnderflow { addr, value, operand } => ErasedMemoryError::RmwUnderflow { addr, value, operand },
+            MemoryError::RmwOverflow { addr, value, operand } => ErasedMemoryError::RmwOverflow { addr, value, operand },
+            MemoryError::NonAtomicAccessToAtomicLocation { addr } => ErasedMemoryError::NonAtomicAccessToAtomicLocation { addr },
+            MemoryError::WriteToReadOnly { addr } => ErasedMemoryError::WriteToReadOnly { addr },
+            MemoryError::BankOutOfRange { bank } => ErasedMemoryError::BankOutOfRange { bank },
+            MemoryError::OverlappingInit { addr } => ErasedMemoryError::OverlappingInit { addr },
+            MemoryError::AddressOverflow { base, offset } => ErasedMemoryError::AddressOverflow { base, offset },
+            MemoryError::BadTimestamp { tid, addr, ts } => ErasedMemoryError::BadTimestamp { tid, addr, ts },
+            MemoryError::Other(e) => ErasedMemoryError::Other(e.to_string()),
+        }
+    }
+}
+
+/// [`MemoryError`] without the subsystem specific payload.
+#[derive(Debug, Clone, Error)]
+pub enum ErasedMemoryError {
+    #[error("Address {addr} out of range")]
+    AddressOutOfRange {
+        addr: usize,
+    },
+    #[error("Thread ID {tid} is incorrect")]
+    BadTid {
+        tid: usize,
+    },
+    #[error("Call to thread state {tid} API has returned an error")]
+    ThreadStateError {
+        tid: usize,
+        error: ThreadStateError,
+    },
+    #[error("Subtracting {operand} from {value} at address {addr} has underflown")]
+    RmwUnderflow {
+        addr: usize,
+        value: Value,
+        operand: Value,
+    },
+    #[error("Adding {operand} to {value} at address {addr} has overflown")]
+    RmwOverflow {
+        addr: usize,
+        value: Value,
+        operand: Value,
+    },
+    #[error("Non-atomic access to atomic-only address {addr}")]
+    NonAtomicAccessToAtomicLocation {
+        addr: usize,
+    },
+    #[error("Write to read-only address {addr}")]
+    WriteToReadOnly {
+        addr: usize,
+    },
+    #[error("Memory bank {bank} doesn't exist")]
+    BankOutOfRange {
+        bank: usize,
+    },
+    #[error("Address {addr} is initialised by two regions")]
+    OverlappingInit {
+        addr: usize,
+    },
+    #[error("Address {base} displaced by {offset} doesn't fit into the address space")]
+    AddressOverflow {
+        base: usize,
+        offset: usize,
+    },
+    #[error("Thread {tid} can't move its view of address {addr} to timestamp {ts}")]
+    BadTimestamp {
+        tid: usize,
+        addr: usize,
+        ts: usize,
+    },
+    #[error("Memory system failed with implementation specific error: {0}")]
+    Other(String),
+}
+
 pub struct GlobalMemory<Mem> {
     mem: Vec<Value>,
+    /// The contents the memory was created with.
+    initial: Arc<[Value]>,
+    /// `None` when access counting is disabled.
+    stats: Option<AccessStats>,
+    /// Addresses which may only be accessed atomically.
+    atomic: Arc<FnvHashSet<usize>>,
+    /// Addresses which may only be read.
+    read_only: Arc<FnvHashSet<usize>>,
+    /// The amount of cells in each bank.
+    bank_size: usize,
+    /// Addresses set by [`init_region`](GlobalMemory::init_region).
+    region_initialised: Arc<FnvHashSet<usize>>,
+    /// Cells accessed since the last [`reset`](GlobalMemory::reset).
+    touched: BTreeSet<usize>,
     _phantom: PhantomData<fn(&Mem) -> ()>,
 }
 
 impl<Mem: MemorySubsystem> GlobalMemory<Mem> {
-    pub fn fetch(&mut self, addr: usize) -> Result<&mut Value, MemoryError<Mem::Err>> {
+    /// Creates a zero-initialised memory of `size` cells.
+    pub fn new(size: usize) -> Self {
+        let mem = vec![Value::ZERO; size];
+
+        GlobalMemory {
+            initial: mem.as_slice().into(),
+            mem,
+            stats: None,
+            atomic: Arc::default(),
+            read_only: Arc::default(),
+            bank_size: size,
+            region_initialised: Arc::default(),
+            touched: BTreeSet::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Declares `addr` atomic-only: from now on any [`AccessMode::NonAtomic`]
+    /// access to it fails with [`MemoryError::NonAtomicAccessToAtomicLocation`].
+    pub fn set_atomic(&mut self, addr: usize) {
+        Arc::make_mut(&mut self.atomic).insert(addr);
+    }
+
+    /// Splits the memory into `banks` banks with independent address spaces,
+    /// each as large as the whole memory has been. The new banks are zeroed.
+    /// Cell `addr` of bank `bank` is cell `bank * bank_size + addr` of
+    /// [`cells`](GlobalMemory::cells). The accesses without a bank go to bank `0`.
+    ///
+    /// # Panics
+    /// Panics if `banks` is `0`.
+    pub fn set_banks(&mut self, banks: usize) {
+        assert!(banks > 0, "The memory needs at least one bank");
+
+        let size = self.bank_size * banks;
+        self.mem.resize(size, Value::ZERO);
+        let mut initial = self.initial.to_vec();
+        initial.resize(size, Value::ZERO);
+        self.initial = initial.into();
+    }
+
+    pub fn bank_size(&self) -> usize {
+        self.bank_size
+    }
+
+    /// Turns cell `addr` of bank `bank` into an index into [`cells`](GlobalMemory::cells).
+    pub fn resolve(&self, bank: usize, addr: usize) -> Result<usize, MemoryError<Mem::Err>> {
+        if addr >= self.bank_size {
+            return Err(MemoryError::AddressOutOfRange { addr });
+        }
+
+        let base = bank.checked_mul(self.bank_size)
+            .ok_or(MemoryError::BankOutOfRange { bank })?;
+
+        match checked_address_add(base, addr)? {
+            cell if cell < self.mem.len() => Ok(cell),
+            _ => Err(MemoryError::BankOutOfRange { bank }),
+        }
+    }
+
+    /// Declares `addr` read-only: from now on any write or read-modify-write
+    /// of it fails with [`MemoryError::WriteToReadOnly`]. Reads are still allowed.
+    pub fn set_read_only(&mut self, addr: usize) {
+        Arc::make_mut(&mut self.read_only).insert(addr);
+    }
+
+    fn check_writable(&self, addr: usize) -> Result<(), MemoryError<Mem::Err>> {
+        if self.read_only.contains(&addr) {
+            return Err(MemoryError::WriteToReadOnly { addr });
+        }
+
+        Ok(())
+    }
+
+    fn check_mode(&self, addr: usize, mode: AccessMode) -> Result<(), MemoryError<Mem::Err>> {
+        if !mode.is_atomic() && self.atomic.contains(&addr) {
+            return Err(MemoryError::NonAtomicAccessToAtomicLocation { addr });
+        }
+
+        Ok(())
+    }
+
+    /// Restores the contents the memory was created with. The access counts
+    /// and the touched cells are reset too.
+    pub fn reset(&mut self) {
+        self.mem.copy_from_slice(&self.initial);
+        self.touched.clear();
+        if let Some(stats) = &mut self.stats {
+            stats.clear();
+        }
+    }
+
+    /// Turns counting of reads and writes per address on or off. Turning
+    /// it off discards the collected counts.
+    pub fn set_access_stats(&mut self, enabled: bool) {
+        match (enabled, &self.stats) {
+            (true, None) => self.stats = Some(AccessStats::default()),
+            (false, _) => self.stats = None,
+            _ => (),
+        }
+    }
+
+    /// Amount of reads and writes per address. Empty when counting is disabled.
+    pub fn access_stats(&self) -> AccessStats {
+        self.stats.clone().unwrap_or_default()
+    }
+
+    fn count_access(&mut self, addr: usize, reads: u64, writes: u64) {
+        self.touched.insert(addr);
+        if let Some(stats) = &mut self.stats {
+            let entry = stats.entry(addr).or_default();
+            entry.0 += reads;
+            entry.1 += writes;
+        }
+    }
+
+    /// Cells read or written since the memory has been created or last
+    /// [`reset`](GlobalMemory::reset), whether access counting is enabled or
+    /// not. Setting the initial values doesn't touch the cells.
+    pub fn touched(&self) -> &BTreeSet<usize> {
+        &self.touched
+    }
+
+    /// All the memory cells.
+    pub fn cells(&self) -> &[Value] {
+        &self.mem
+    }
+
+    /// The contents [`reset`](GlobalMemory::reset) restores.
+    pub fn initial(&self) -> &[Value] {
+        &self.initial
+    }
+
+    /// Sets the initial value of the cell at `addr`, both now and after a
+    /// [`reset`](GlobalMemory::reset). Like [`corrupt`](GlobalMemory::corrupt),
+    /// this is not an access.
+    pub fn set_initial(&mut self, addr: usize, value: Value) -> Result<(), MemoryError<Mem::Err>> {
+        *self.cell_mut(addr)? = value;
+
+        let mut initial = self.initial.to_vec();
+        initial[addr] = value;
+        self.initial = initial.into();
+
+        Ok(())
+    }
+
+    /// Sets the initial values of the cells starting at `start` to `values`,
+    /// like [`set_initial`](GlobalMemory::set_initial) does for a single cell.
+    /// Fails without changing anything if the region doesn't fit into the memory
+    /// or shares a cell with a region initialised before.
+    pub fn init_region(&mut self, start: usize, values: &[Value]) -> Result<(), MemoryError<Mem::Err>> {
+        let end = checked_address_add(start, values.len())?;
+        if end > self.mem.len() {
+            return Err(MemoryError::AddressOutOfRange { addr: start.max(self.mem.len()) });
+        }
+        if let Some(addr) = (start..end).find(|x| self.region_initialised.contains(x)) {
+            return Err(MemoryError::OverlappingInit { addr });
+        }
+
+        self.mem[start..end].copy_from_slice(values);
+        let mut initial = self.initial.to_vec();
+        initial[start..end].copy_from_slice(values);
+        self.initial = initial.into();
+        Arc::make_mut(&mut self.region_initialised).extend(start..end);
+
+        Ok(())
+    }
+
+    /// Addresses at which the two memories disagree, along with the value in
+    /// `self` and the value in `other`. Only the cells both memories have are compared.
+    pub fn diff(&self, other: &Self) -> Vec<(usize, Value, Value)> {
+        self.mem.iter()
+            .zip(&other.mem)
+            .enumerate()
+            .filter(|(_, (l, r))| l != r)
+            .map(|(addr, (l, r))| (addr, *l, *r))
+            .collect()
+    }
+
+    /// Reads the value at `addr` of bank `0` without going through the memory subsystem.
+    pub fn peek(&self, addr: usize) -> Option<Value> {
+        self.peek_in(0, addr)
+    }
+
+    /// Like [`peek`](GlobalMemory::peek), but reads from bank `bank`.
+    pub fn peek_in(&self, bank: usize, addr: usize) -> Option<Value> {
+        let cell = self.resolve(bank, addr).ok()?;
+
+        self.mem.get(cell).copied()
+    }
+
+    /// Checks that `count` cells starting at `addr` are all in range.
+    pub fn check_range(&self, addr: usize, count: usize) -> Result<(), MemoryError<Mem::Err>> {
+        match checked_address_add(addr, count)? {
+            end if end <= self.bank_size => Ok(()),
+            _ => Err(MemoryError::AddressOutOfRange {
+                addr: addr.max(self.bank_size),
+            }),
+        }
+    }
+
+    /// Reads the value at `addr` of bank `0`.
+    pub fn read(&mut self, addr: usize, mode: AccessMode) -> Result<Value, MemoryError<Mem::Err>> {
+        self.read_in(0, addr, mode)
+    }
+
+    /// Reads the value at `addr` of bank `bank`. The access is recorded for
+    /// the cell [`resolve`](GlobalMemory::resolve) gives.
+    pub fn read_in(&mut self, bank: usize, addr: usize, mode: AccessMode) -> Result<Value, MemoryError<Mem::Err>> {
+        let cell = self.resolve(bank, addr)?;
+        self.check_mode(cell, mode)?;
+        let val = self.mem[cell];
+        self.count_access(cell, 1, 0);
+
+        Ok(val)
+    }
+
+    /// Writes the value at `addr` of bank `0`.
+    pub fn write(&mut self, addr: usize, value: Value, mode: AccessMode) -> Result<(), MemoryError<Mem::Err>> {
+        self.write_in(0, addr, value, mode)
+    }
+
+    /// Like [`read_in`](GlobalMemory::read_in), but writes.
+    pub fn write_in(&mut self, bank: usize, addr: usize, value: Value, mode: AccessMode) -> Result<(), MemoryError<Mem::Err>> {
+        let cell = self.resolve(bank, addr)?;
+        self.check_mode(cell, mode)?;
+        self.check_writable(cell)?;
+        self.mem[cell] = value;
+        self.count_access(cell, 0, 1);
+
+        Ok(())
+    }
+
+    /// Gives access to the cell at `addr` of bank `0` for a read-modify-write,
+    /// which counts as both a read and a write.
+    pub fn fetch(&mut self, addr: usize, mode: AccessMode) -> Result<&mut Value, MemoryError<Mem::Err>> {
+        self.fetch_in(0, addr, mode)
+    }
+
+    /// Like [`fetch`](GlobalMemory::fetch), but in bank `bank`.
+    pub fn fetch_in(&mut self, bank: usize, addr: usize, mode: AccessMode) -> Result<&mut Value, MemoryError<Mem::Err>> {
+        let cell = self.resolve(bank, addr)?;
+        self.check_mode(cell, mode)?;
+        self.check_writable(cell)?;
+        self.count_access(cell, 1, 1);
+
+        Ok(&mut self.mem[cell])
+    }
+
+    /// XORs `mask` into the cell at `addr` and returns the new value. This is
+    /// not an access, so it is neither counted nor checked for atomicity.
+    pub fn corrupt(&mut self, addr: usize, mask: u64) -> Result<Value, MemoryError<Mem::Err>> {
+        let cell = self.cell_mut(addr)?;
+        cell.0 ^= mask;
+
+        Ok(*cell)
+    }
+
+    fn cell_mut(&mut self, addr: usize) -> Result<&mut Value, MemoryError<Mem::Err>> {
         match self.mem.get_mut(addr) {
             Some(x) => Ok(x),
             None => Err(MemoryError::AddressOutOfRange { addr }),
@@ -111,35 +773,373 @@ impl<Mem: MemorySubsystem> GlobalMemory<Mem> {
     }
 }
 
+impl<Mem> Clone for GlobalMemory<Mem> {
+    fn clone(&self) -> Self {
+        GlobalMemory {
+            mem: self.mem.clone(),
+            initial: self.initial.clone(),
+            stats: self.stats.clone(),
+            atomic: self.atomic.clone(),
+            read_only: self.read_only.clone(),
+            bank_size: self.bank_size,
+            region_initialised: self.region_initialised.clone(),
+            touched: self.touched.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<Mem> Hash for GlobalMemory<Mem> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mem.hash(state);
+    }
+}
+
 // TODO probably should move out
 pub struct Threads<'prog, Mem> {
     threads: Vec<ThreadState<'prog>>,
     _phantom: PhantomData<fn(&Mem) -> ()>,
 }
 
+impl<'prog, Mem> Clone for Threads<'prog, Mem> {
+    fn clone(&self) -> Self {
+        Threads {
+            threads: self.threads.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'prog, Mem> Hash for Threads<'prog, Mem> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.threads.hash(state);
+    }
+}
+
 impl<'prog, Mem: MemorySubsystem> Threads<'prog, Mem> {
+    pub fn new(threads: Vec<ThreadState<'prog>>) -> Self {
+        Threads {
+            threads,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn get(&self, tid: usize) -> Option<&ThreadState<'prog>> {
+        self.threads.get(tid)
+    }
+
     pub fn get_thread_mut(&mut self, tid: usize) -> Result<&mut ThreadState<'prog>, MemoryError<Mem::Err>> {
         match self.threads.get_mut(tid) {
             Some(x) => Ok(x),
             None => Err(MemoryError::BadTid { tid }),
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ThreadState<'prog>> + '_ {
+        self.threads.iter()
+    }
+
+    pub fn reset(&mut self) {
+        self.threads.iter_mut().for_each(ThreadState::reset);
+    }
+
+    pub fn set_signed_mode(&mut self, signed_mode: bool) {
+        self.threads.iter_mut().for_each(|x| x.set_signed_mode(signed_mode));
+    }
+
+    pub fn set_division_trap(&mut self, trap: Option<Value>) {
+        self.threads.iter_mut().for_each(|x| x.set_division_trap(trap));
+    }
+
+    pub fn set_init_policy(&mut self, policy: InitPolicy) {
+        self.threads.iter_mut().for_each(|x| x.set_init_policy(policy));
+    }
+
+    /// See [`ThreadState::set_local_memory_size`].
+    pub fn set_local_memory_size(&mut self, size: usize) {
+        self.threads.iter_mut().for_each(|x| x.set_local_memory_size(size));
+    }
+
+    /// IDs of the threads that haven't halted yet.
+    pub fn live_tids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.threads.iter()
+            .enumerate()
+            .filter(|(_, thread)| !thread.is_halted())
+            .map(|(tid, _)| tid)
+    }
 }
 
 pub trait MemorySubsystem: Sized {
-    type Err: Error;
-    type Independent: Debug;
+    type Err: Error + Send + Sync + 'static;
+    /// The steps the subsystem can take on its own, without a thread request.
+    /// Usually an enum with a variant per kind of step (e.g. flushing a store
+    /// buffer). `Display` is used to describe them in schedules.
+    type Independent: Debug + Clone + fmt::Display;
 
     fn name() -> &'static str;
+    /// Creates the subsystem together with its backing memory of `memory_size` cells.
+    fn init(memory_size: usize) -> (Self, GlobalMemory<Self>);
+    /// Brings the subsystem back to the state right after [`init`](MemorySubsystem::init),
+    /// keeping its settings: drops what the runs have left (e.g. reservations or
+    /// histories), but not e.g. a check that has been turned on.
+    fn reset(&mut self);
+    /// Independent steps the subsystem can currently take. Drivers pick
+    /// from these when scheduling [`MemoryStep::Independent`].
+    fn pending_independent(
+        &self,
+        threads: &Threads<Self>,
+        memory: &GlobalMemory<Self>,
+    ) -> Vec<Self::Independent>;
+    /// The values written to `addr` in modification order. Subsystems that
+    /// don't track the history of locations return `None`.
+    fn coherence_order(&self, _addr: usize) -> Option<Vec<Value>> {
+        None
+    }
+    /// The values a load of `addr` with `mode` by thread `tid` may currently
+    /// read, in modification order. By default the load reads the global
+    /// memory, so that's the only candidate.
+    fn read_candidates(&self, _tid: usize, addr: usize, _mode: AccessMode, memory: &GlobalMemory<Self>) -> Vec<Value> {
+        memory.cells().get(addr).copied().into_iter().collect()
+    }
+    /// The independent step which makes the next load of `addr` with `mode` by
+    /// thread `tid` read the `candidate`-th of the [`read_candidates`](MemorySubsystem::read_candidates).
+    /// `None` if the load reads it already or the subsystem has no such step.
+    fn pin_read(
+        &self,
+        _tid: usize,
+        _addr: usize,
+        _mode: AccessMode,
+        _candidate: usize,
+        _memory: &GlobalMemory<Self>,
+    ) -> Option<Self::Independent> {
+        None
+    }
+    /// Whether thread `tid` is waiting (e.g. at a barrier) and can't be stepped
+    /// until some other thread makes progress.
+    fn is_waiting(&self, _tid: usize) -> bool {
+        false
+    }
+    /// Whether the relaxed writes of thread `tid` release, because its last
+    /// fence has been a release one, see [`MachineEvent::Write::promoted`].
+    /// Subsystems where fences don't affect synchronization return `false`.
+    fn is_release_fenced(&self, _tid: usize) -> bool {
+        false
+    }
+    /// Called by [`Machine::step`](crate::Machine::step) with each event once it
+    /// has given it the timestamps. The reads are stamped with the last write to
+    /// their address, so the subsystems whose reads may see older writes correct
+    /// them here, remembering the timestamps of the writes to do so.
+    fn restamp(&mut self, _event: &mut MachineEvent) {}
+    /// A human readable description of the subsystem's own state, such as the
+    /// contents of the store buffers. Empty if there is nothing beyond the
+    /// global memory to show.
+    fn describe_state(&self) -> String {
+        String::new()
+    }
+
+    /// Performs a step of the memory subsystem.
+    ///
+    /// # Atomicity
+    /// A read-modify-write query ([`MemoryQuery::is_rmw`]) must be served
+    /// entirely within one call: its read and its write must not be split
+    /// across steps, so no independent step can happen in between. Such a
+    /// query must produce a single [`MachineEvent::Rmw`], even if it didn't
+    /// write (a failed CAS, see [`RmwKind::CompareSwap`]).
+    /// [`Machine::step`](crate::Machine::step) checks this in debug builds.
     fn execute_step(
         &mut self,
         step: MemoryStep<Self::Independent>,
         threads: &mut Threads<Self>,
         memory: &mut GlobalMemory<Self>,
-    ) -> Result<(), MemoryError<Self::Err>>;
+    ) -> Result<MachineEvent, MemoryError<Self::Err>>;
+
+    /// Checks that `trace` could have been produced by this subsystem. By
+    /// default only the consistency of the trace is checked (see [`Trace::is_consistent`]),
+    /// which is what the weakest models require.
+    fn validate_trace(&self, trace: &Trace) -> Result<(), ValidationError> {
+        if !trace.is_consistent() {
+            return Err(ValidationError::Inconsistent);
+        }
+
+        Ok(())
+    }
 }
 
+#[derive(Clone, Hash)]
 pub struct Memory<T> {
     subsystem: T,
     global: GlobalMemory<T>,
-}
\ No newline at end of file
+}
+
+impl<T: MemorySubsystem> Memory<T> {
+    pub fn new(memory_size: usize) -> Self {
+        let (subsystem, global) = T::init(memory_size);
+
+        Memory { subsystem, global }
+    }
+
+    pub fn subsystem(&self) -> &T {
+        &self.subsystem
+    }
+
+    pub fn subsystem_mut(&mut self) -> &mut T {
+        &mut self.subsystem
+    }
+
+    pub fn global(&self) -> &GlobalMemory<T> {
+        &self.global
+    }
+
+    pub fn global_mut(&mut self) -> &mut GlobalMemory<T> {
+        &mut self.global
+    }
+
+    /// Resets the subsystem (keeping its settings) and restores the initial memory contents.
+    pub fn reset(&mut self) {
+        self.subsystem.reset();
+        self.global.reset();
+    }
+
+    pub fn execute_step(
+        &mut self,
+        step: MemoryStep<T::Independent>,
+        threads: &mut Threads<T>,
+    ) -> Result<MachineEvent, MemoryError<T::Err>> {
+        self.subsystem.execute_step(step, threads, &mut self.global)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Machine, driver, litmus::{self, FAI_STRESS_COUNTER}, trace::Trace};
+
+    use super::{sc::{self, ScMemory}, *};
+
+    #[test]
+    fn access_stats_count_a_contended_cell() {
+        let program = litmus::fai_stress(2, 3, AccessMode::SeqCst);
+        let mut machine = Machine::<ScMemory>::new(&program, FAI_STRESS_COUNTER + 1).unwrap();
+        assert!(machine.memory().global().access_stats().is_empty());
+
+        machine.memory_mut().global_mut().set_access_stats(true);
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+
+        let stats = machine.memory().global().access_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[&FAI_STRESS_COUNTER], (6, 6));
+
+        machine.memory_mut().global_mut().set_access_stats(false);
+        assert!(machine.memory().global().access_stats().is_empty());
+    }
+
+    #[test]
+    fn erasing_keeps_the_messages() {
+        let error = MemoryError::<sc::Error>::AddressOutOfRange { addr: 5 };
+        let message = error.to_string();
+        let erased = error.erase();
+        assert!(matches!(erased, ErasedMemoryError::AddressOutOfRange { addr: 5 }));
+        assert_eq!(erased.to_string(), message);
+
+        let inner = sc::Error::CoherenceViolation { tid: 1, addr: 2, expected: Value(3), actual: Value(4) };
+        let inner_message = inner.to_string();
+        let erased = MemoryError::Other(inner).erase();
+        assert!(matches!(&erased, ErasedMemoryError::Other(x) if *x == inner_message));
+        assert!(erased.to_string().ends_with(&inner_message));
+    }
+
+    #[test]
+    fn mode_strength_predicates() {
+        use AccessMode::*;
+
+        let modes = [SeqCst, RelAcq, Acq, Rel, Rlx, NonAtomic];
+        let table = modes.map(|x| (x.is_at_least_acquire(), x.is_at_least_release(), x.is_seqcst()));
+        assert_eq!(table, [
+            (true, true, true),
+            (true, true, false),
+            (true, false, false),
+            (false, true, false),
+            (false, false, false),
+            (false, false, false),
+        ]);
+    }
+
+    #[test]
+    fn rmw_modes_split_into_read_and_write() {
+        use AccessMode::*;
+
+        let modes = [SeqCst, RelAcq, Acq, Rel, Rlx, NonAtomic];
+        assert_eq!(modes.map(AccessMode::split_rmw), [
+            (SeqCst, SeqCst),
+            (Acq, Rel),
+            (Acq, Rlx),
+            (Rlx, Rel),
+            (Rlx, Rlx),
+            (NonAtomic, NonAtomic),
+        ]);
+    }
+
+    #[test]
+    fn diff_lists_the_differing_cells() {
+        let mut l = GlobalMemory::<ScMemory>::new(5);
+        let mut r = l.clone();
+        l.init_region(1, &[Value(3)]).unwrap();
+        r.init_region(3, &[Value(4), Value(5)]).unwrap();
+
+        assert_eq!(l.diff(&r), [(1, Value(3), Value(0)), (3, Value(0), Value(4)), (4, Value(0), Value(5))]);
+        assert!(l.diff(&l).is_empty());
+    }
+
+    #[test]
+    fn read_only_cell_rejects_writes() {
+        let mut memory = GlobalMemory::<ScMemory>::new(8);
+        memory.init_region(7, &[Value(3)]).unwrap();
+        memory.set_read_only(7);
+
+        assert_eq!(memory.read(7, AccessMode::SeqCst).unwrap(), Value(3));
+        assert!(matches!(memory.write(7, Value(4), AccessMode::SeqCst), Err(MemoryError::WriteToReadOnly { addr: 7 })));
+        assert!(matches!(memory.fetch(7, AccessMode::SeqCst), Err(MemoryError::WriteToReadOnly { addr: 7 })));
+        assert_eq!(memory.read(7, AccessMode::SeqCst).unwrap(), Value(3));
+        memory.write(6, Value(4), AccessMode::SeqCst).unwrap();
+    }
+
+    #[test]
+    fn region_init_in_range_out_of_range_and_overlapping() {
+        let mut memory = GlobalMemory::<ScMemory>::new(6);
+        memory.init_region(0, &[Value(1), Value(2)]).unwrap();
+        memory.init_region(2, &[Value(3)]).unwrap();
+        assert_eq!(memory.cells(), [Value(1), Value(2), Value(3), Value(0), Value(0), Value(0)]);
+
+        assert!(matches!(memory.init_region(4, &[Value(5); 3]), Err(MemoryError::AddressOutOfRange { addr: 6 })));
+        assert!(matches!(memory.init_region(7, &[Value(5)]), Err(MemoryError::AddressOutOfRange { addr: 7 })));
+        assert!(matches!(memory.init_region(3, &[Value(4), Value(4)]), Ok(())));
+        assert!(matches!(memory.init_region(1, &[Value(9)]), Err(MemoryError::OverlappingInit { addr: 1 })));
+        assert!(matches!(memory.init_region(4, &[Value(9), Value(9)]), Err(MemoryError::OverlappingInit { addr: 4 })));
+
+        // The failed calls have left the memory alone
+        assert_eq!(memory.cells(), [Value(1), Value(2), Value(3), Value(4), Value(4), Value(0)]);
+    }
+
+    #[test]
+    fn address_arithmetic_near_the_top() {
+        assert!(matches!(checked_address_add::<sc::Error>(usize::MAX - 2, 2), Ok(usize::MAX)));
+        assert!(matches!(
+            checked_address_add::<sc::Error>(usize::MAX - 2, 3),
+            Err(MemoryError::AddressOverflow { base, offset: 3 }) if base == usize::MAX - 2,
+        ));
+
+        let mut memory = GlobalMemory::<ScMemory>::new(4);
+        assert!(matches!(
+            memory.init_region(usize::MAX, &[Value(1), Value(2)]),
+            Err(MemoryError::AddressOverflow { base: usize::MAX, offset: 2 }),
+        ));
+        assert!(memory.cells().iter().all(|x| *x == Value::ZERO));
+    }
+}
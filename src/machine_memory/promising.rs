@@ -0,0 +1,451 @@
+use std::fmt;
+
+use thiserror::Error;
+use tracing::debug;
+
+use crate::{MachineEvent, machine_thread::ThreadState, value::Value};
+
+use super::{AccessMode, MemorySubsystem, MemoryStep, Threads, GlobalMemory, MemoryError, MemoryQuery, history::{self, Histories, HistoryMemory}};
+
+#[derive(Debug, Clone)]
+pub enum IndependentStep {
+    /// Thread `tid` promises to write `value` to `addr` later. The write
+    /// becomes visible to the other threads right away.
+    Promise {
+        tid: usize,
+        addr: usize,
+        value: Value,
+    },
+    /// Thread `tid` catches up with the message `ts` of `addr`, so its loads
+    /// from there read that message until it moves on again.
+    Observe {
+        tid: usize,
+        addr: usize,
+        ts: usize,
+    },
+}
+
+impl fmt::Display for IndependentStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndependentStep::Promise { tid, addr, value } => write!(f, "T{tid} promises ##{addr} = {value}"),
+            IndependentStep::Observe { tid, addr, ts } => write!(f, "T{tid} observes ##{addr}@{ts}"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{name} is not supported by the promising subsystem")]
+    Unsupported {
+        name: &'static str,
+    },
+}
+
+/// What the run of a thread in isolation has done, see [`PromisingMemory::certify`].
+struct Certification {
+    /// The cells the thread has written, along with the values, in order.
+    writes: Vec<(usize, Value)>,
+    /// Whether the thread had no outstanding promises at some point of the run.
+    fulfilled: bool,
+}
+
+/// A simplified promising semantics for relaxed accesses. Every location keeps
+/// the history of the messages written to it, in modification order, and every
+/// thread has a view: the message of each location its loads read. The view
+/// only moves forward, by the thread's own writes or by an
+/// [`Observe`](IndependentStep::Observe) step.
+///
+/// A thread may [`Promise`](IndependentStep::Promise) a write ahead of time, as
+/// long as it is certified: the thread running alone from its current state
+/// performs the write. The thread then fulfills the promise when it actually
+/// executes the write. A thread with outstanding promises may only observe
+/// messages after which it can still fulfill them. This gives the load
+/// buffering outcomes without the out-of-thin-air ones.
+///
+/// The simplifications are:
+/// * every access is treated as relaxed and the fences do nothing;
+/// * new messages are always placed at the end of the history;
+/// * a thread has at most one outstanding promise and can't promise the write
+///   of a read-modify-write;
+/// * load-linked, store-conditional and barriers are not supported.
+///
+/// The global memory cells hold the value of the last message of each location.
+/// With a [history cap](PromisingMemory::set_history_cap) the messages no
+/// running thread can read any more are dropped, which also drops them from
+/// the [`coherence_order`](MemorySubsystem::coherence_order).
+#[derive(Debug, Clone, Hash)]
+pub struct PromisingMemory {
+    histories: Histories,
+    /// How many steps a thread is run for when certifying its promises.
+    certification_depth: usize,
+}
+
+impl Default for PromisingMemory {
+    fn default() -> Self {
+        PromisingMemory {
+            histories: Histories::default(),
+            certification_depth: 32,
+        }
+    }
+}
+
+impl PromisingMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many steps a thread may take to fulfill its promises. A
+    /// promise which takes longer is never offered.
+    pub fn set_certification_depth(&mut self, depth: usize) {
+        self.certification_depth = depth;
+    }
+
+    /// See [`Histories::prune`].
+    pub fn set_history_cap(&mut self, cap: Option<usize>) {
+        self.histories.cap = cap;
+    }
+
+    fn has_promises(&self, tid: usize) -> bool {
+        self.histories.history.values()
+            .flatten()
+            .any(|x| x.promised && x.tid == Some(tid))
+    }
+
+    /// Runs thread `tid` alone from its current state on a copy of the memory,
+    /// until it halts, fails, reaches a read-modify-write (whose value depends
+    /// on the other threads) or takes `certification_depth` steps. The thread's
+    /// path only depends on its view, so the real run follows it as long as the
+    /// thread observes nothing new.
+    fn certify(&self, tid: usize, thread: &ThreadState, memory: &GlobalMemory<Self>) -> Certification {
+        let mut subsystem = self.clone();
+        let mut memory = memory.clone();
+        let mut thread = thread.clone();
+        let mut writes = Vec::new();
+        let mut fulfilled = !subsystem.has_promises(tid);
+
+        for _ in 0..self.certification_depth {
+            if thread.is_halted() {
+                break;
+            }
+            let query = match thread.step() {
+                Ok(Some(query)) => query,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+            if query.is_rmw() || matches!(
+                query,
+                MemoryQuery::LoadLinked { .. } | MemoryQuery::StoreConditional { .. } | MemoryQuery::Barrier { .. }
+            ) {
+                break;
+            }
+
+            let written = match &query {
+                MemoryQuery::Store { bank, addr, value, .. } => memory.resolve(*bank, *addr)
+                    .map(|cell| vec![(cell, *value)])
+                    .unwrap_or_default(),
+                MemoryQuery::StoreN { addr, values, .. } => memory.check_range(*addr, values.len())
+                    .map(|_| (*addr..).zip(values.iter().copied()).collect())
+                    .unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            if history::serve(&mut subsystem, tid, query, &mut thread, &mut memory).is_err() {
+                break;
+            }
+            writes.extend(written);
+            fulfilled |= !subsystem.has_promises(tid);
+        }
+
+        Certification { writes, fulfilled }
+    }
+}
+
+impl MemorySubsystem for PromisingMemory {
+    type Err = Error;
+    type Independent = IndependentStep;
+
+    fn name() -> &'static str { "Promising" }
+
+    fn init(memory_size: usize) -> (Self, GlobalMemory<Self>) {
+        (PromisingMemory::new(), GlobalMemory::new(memory_size))
+    }
+
+    fn reset(&mut self) {
+        self.histories.clear();
+    }
+
+    /// A promise of every write a thread without promises performs alone (the
+    /// first one to each location), and every message a thread may observe.
+    fn pending_independent(
+        &self,
+        threads: &Threads<Self>,
+        memory: &GlobalMemory<Self>,
+    ) -> Vec<Self::Independent> {
+        let mut res = Vec::new();
+
+        for tid in threads.live_tids() {
+            let thread = threads.get(tid).unwrap();
+            let promising = self.has_promises(tid);
+
+            for (&addr, history) in &self.histories.history {
+                let observable = history.iter()
+                    .enumerate()
+                    .skip(self.histories.view(tid, addr) + 1)
+                    .filter(|(_, x)| !(x.promised && x.tid == Some(tid)));
+
+                for (ts, _) in observable {
+                    let mut observed = self.clone();
+                    observed.histories.views.insert((tid, addr), ts);
+                    if !promising || observed.certify(tid, thread, memory).fulfilled {
+                        res.push(IndependentStep::Observe { tid, addr, ts });
+                    }
+                }
+            }
+
+            if promising {
+                continue;
+            }
+            let mut promised = Vec::<usize>::new();
+            for (addr, value) in self.certify(tid, thread, memory).writes {
+                if !promised.contains(&addr) {
+                    promised.push(addr);
+                    res.push(IndependentStep::Promise { tid, addr, value });
+                }
+            }
+        }
+
+        res
+    }
+
+    fn coherence_order(&self, addr: usize) -> Option<Vec<Value>> {
+        Some(self.histories.coherence_order(addr))
+    }
+
+    fn read_candidates(&self, tid: usize, addr: usize, _mode: AccessMode, memory: &GlobalMemory<Self>) -> Vec<Value> {
+        self.histories.read_candidates(tid, addr, memory)
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// An [`Observe`](IndependentStep::Observe) of the candidate. A thread with
+    /// an outstanding promise may not be allowed to take it, see
+    /// [`pending_independent`](MemorySubsystem::pending_independent).
+    fn pin_read(
+        &self,
+        tid: usize,
+        addr: usize,
+        _mode: AccessMode,
+        candidate: usize,
+        memory: &GlobalMemory<Self>,
+    ) -> Option<Self::Independent> {
+        let (ts, _) = *self.histories.read_candidates(tid, addr, memory).get(candidate)?;
+
+        (ts != self.histories.view(tid, addr)).then_some(IndependentStep::Observe { tid, addr, ts })
+    }
+
+    fn restamp(&mut self, event: &mut MachineEvent) {
+        self.histories.restamp(event);
+    }
+
+    fn describe_state(&self) -> String {
+        let mut out = String::new();
+
+        for (addr, history) in &self.histories.history {
+            for (ts, message) in history.iter().enumerate() {
+                if let (true, Some(tid)) = (message.promised, message.tid) {
+                    out.push_str(&format!("T{tid} promises ##{addr}@{ts} = {}\n", message.value));
+                }
+            }
+        }
+        out.push_str(&self.histories.describe_views());
+
+        out
+    }
+
+    fn execute_step(
+        &mut self,
+        step: MemoryStep<Self::Independent>,
+        threads: &mut Threads<Self>,
+        memory: &mut GlobalMemory<Self>,
+    ) -> Result<MachineEvent, MemoryError<Self::Err>> {
+        debug!("Step: {step:?}");
+
+        let event = match step {
+            MemoryStep::Independent(IndependentStep::Promise { tid, addr, value }) => {
+                history::check_location(tid, addr, threads, memory)?;
+                self.histories.push(tid, addr, value, true, memory);
+
+                MachineEvent::Write { tid, location: addr, value, mode: AccessMode::Rlx, ts: 0, promoted: false }
+            },
+            MemoryStep::Independent(IndependentStep::Observe { tid, addr, ts }) => {
+                self.histories.check_observe(tid, addr, ts, threads, memory)?;
+                self.histories.views.insert((tid, addr), ts);
+
+                MachineEvent::Silent
+            },
+            MemoryStep::ThreadRequest { tid, query } => {
+                let thread_state = threads.get_thread_mut(tid)?;
+
+                history::serve(self, tid, query, thread_state, memory)?
+            },
+        };
+        self.histories.prune(threads);
+
+        Ok(event)
+    }
+}
+
+impl HistoryMemory for PromisingMemory {
+    fn histories(&mut self) -> &mut Histories {
+        &mut self.histories
+    }
+
+    /// Every access is relaxed, so it reads the message the thread is at.
+    fn load(&mut self, tid: usize, addr: usize, _mode: AccessMode, memory: &GlobalMemory<Self>) -> Value {
+        let view = self.histories.view(tid, addr);
+
+        self.histories.get_mut(memory, addr)[view].value
+    }
+
+    /// Fulfills a promise of the same write if the thread has one.
+    fn store(&mut self, tid: usize, addr: usize, value: Value, _mode: AccessMode, memory: &mut GlobalMemory<Self>) -> bool {
+        let view = self.histories.view(tid, addr);
+        let promise = self.histories.get_mut(memory, addr)
+            .iter()
+            .enumerate()
+            .position(|(ts, x)| ts > view && x.promised && x.tid == Some(tid) && x.value == value);
+
+        match promise {
+            Some(ts) => {
+                debug!("T{tid} fulfills ##{addr}@{ts}");
+                self.histories.history.get_mut(&addr).unwrap()[ts].promised = false;
+                self.histories.views.insert((tid, addr), ts);
+            },
+            None => {
+                self.histories.push(tid, addr, value, false, memory);
+            },
+        }
+
+        promise.is_some()
+    }
+
+    fn unsupported(name: &'static str) -> Error {
+        Error::Unsupported { name }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Machine,
+        MachineError,
+        MachineEvent,
+        MachineStep,
+        litmus::{self, Explanation, LITMUS_MEMORY_SIZE},
+        machine_memory::{AccessMode, mixed::MixedMemory},
+        machine_thread::builder::ProgramBuilder,
+        register::RegisterRef,
+    };
+
+    use super::*;
+
+    #[test]
+    fn independent_steps_are_described() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .thread()
+                .set("x", 0)
+                .load(AccessMode::Rlx, "x", "r")
+            .build();
+        let mut machine = Machine::<PromisingMemory>::new(&program, 1).unwrap();
+        let memory_step = |machine: &Machine<PromisingMemory>| machine.enabled_steps()
+            .into_iter()
+            .find(|x| matches!(x, MachineStep::Memory(_)))
+            .unwrap();
+
+        let promise = memory_step(&machine);
+        assert_eq!(promise.to_string(), "Promising T0 promises ##0 = 1");
+        machine.step(promise).unwrap();
+        let observe = memory_step(&machine);
+        assert_eq!(observe.to_string(), "Promising T1 observes ##0@1");
+        machine.step(observe).unwrap();
+
+        machine.run_schedule(&[1, 1, 0, 0].map(MachineStep::Thread)).unwrap();
+        assert_eq!(machine.threads().get(1).unwrap().get_register(RegisterRef::new("r")).unwrap(), Value(1));
+        assert!(!machine.memory().subsystem().has_promises(0));
+    }
+
+    #[test]
+    fn malformed_promise_is_rejected() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .build();
+        let mut machine = Machine::<PromisingMemory>::new(&program, 1).unwrap();
+
+        for step in [
+            IndependentStep::Promise { tid: 1, addr: 0, value: Value(1) },
+            IndependentStep::Promise { tid: 0, addr: 1, value: Value(1) },
+            IndependentStep::Observe { tid: 0, addr: 0, ts: 1 },
+        ] {
+            assert!(matches!(machine.step(MachineStep::Memory(step)), Err(MachineError::Memory(_))));
+        }
+        assert!(machine.memory().subsystem().histories.history.is_empty());
+    }
+
+    #[test]
+    fn load_buffering_needs_a_promise() {
+        let lb = litmus::lb();
+        assert!(litmus::is_observable::<PromisingMemory>(&lb, 100).unwrap());
+        assert!(!litmus::is_observable::<MixedMemory>(&lb, 100).unwrap());
+
+        // Both stores come after the loads in program order, so one of them
+        // has to be promised before any load reads it
+        let (program, _, postcondition) = lb;
+        let factory = || Machine::<PromisingMemory>::new(&program, LITMUS_MEMORY_SIZE);
+        let Explanation::Allowed { witness } = litmus::explain_outcome(factory, &postcondition, 100).unwrap() else {
+            panic!("LB isn't reachable under the promising subsystem");
+        };
+        let first_write = witness.events.iter().position(|x| matches!(x, MachineEvent::Write { .. })).unwrap();
+        let first_read = witness.events.iter().position(|x| matches!(x, MachineEvent::Read { .. })).unwrap();
+        assert!(first_write < first_read);
+
+        // Without the steps to certify it no promise is ever made
+        let uncertified = || {
+            let mut machine = factory()?;
+            machine.memory_mut().subsystem_mut().set_certification_depth(0);
+            Ok(machine)
+        };
+        let explanation = litmus::explain_outcome(uncertified, &postcondition, 100).unwrap();
+        assert!(!matches!(explanation, Explanation::Allowed { .. }));
+    }
+
+    #[test]
+    fn history_cap_bounds_the_messages() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .repeat(20, |block| block.fai(AccessMode::Rlx, "x", "old"))
+            .build();
+        let mut machine = Machine::<PromisingMemory>::new(&program, 1).unwrap();
+        machine.memory_mut().subsystem_mut().set_history_cap(Some(4));
+
+        let mut read = Vec::new();
+        while !machine.threads().get(0).unwrap().is_halted() {
+            if let MachineEvent::Rmw { read_value, .. } = machine.step(MachineStep::Thread(0)).unwrap() {
+                read.push(read_value.0);
+            }
+            let history = machine.memory().subsystem().coherence_order(0).unwrap_or_default();
+            assert!(history.len() <= 4, "{history:?}");
+        }
+
+        // Every increment has still read the latest message
+        assert_eq!(read, (0..20).collect::<Vec<_>>());
+        assert_eq!(machine.memory().global().cells(), [Value(20)]);
+    }
+}
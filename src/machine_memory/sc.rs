@@ -1,79 +1,390 @@
 
+use std::{collections::{BTreeMap, BTreeSet}, fmt};
+
 use thiserror::Error;
 use tracing::debug;
 
-use super::{MemorySubsystem, MemoryStep, Threads, GlobalMemory, MemoryError, MemoryQuery};
+use crate::{MachineEvent, trace::{self, Trace, ValidationError}, machine_thread::{BinOp, Instruction}, value::Value};
+
+use super::{AccessMode, RmwKind, MemorySubsystem, MemoryStep, Threads, GlobalMemory, MemoryError, MemoryQuery};
 
-#[derive(Debug)]
-pub enum IndependentStep {}
+#[derive(Debug, Clone)]
+pub enum IndependentStep {
+    /// Makes the next weak CAS of the thread fail regardless of the values.
+    /// Only enabled with [`ScMemory::set_spurious_failures`].
+    SpuriousCasFailure {
+        tid: usize,
+    },
+}
+
+impl fmt::Display for IndependentStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndependentStep::SpuriousCasFailure { tid } => write!(f, "spurious CAS failure of T{tid}"),
+        }
+    }
+}
 
 #[derive(Debug, Error)]
-pub enum Error {}
+pub enum Error {
+    #[error("Thread {tid} has read {actual} at address {addr}, but its own last write there was {expected}")]
+    CoherenceViolation {
+        tid: usize,
+        addr: usize,
+        expected: Value,
+        actual: Value,
+    },
+    #[error("Thread {tid} can't fail its weak CAS spuriously, as spurious failures are disabled")]
+    SpuriousFailuresDisabled {
+        tid: usize,
+    },
+}
 
-pub struct ScMemory;
+#[derive(Default, Clone, Hash)]
+pub struct ScMemory {
+    /// The address reserved by the last load-linked of each thread.
+    reservations: Vec<Option<usize>>,
+    /// The last value each thread has written to each address, as long as no other
+    /// thread has overwritten it. `None` when the coherence check is disabled.
+    own_writes: Option<BTreeMap<(usize, usize), Value>>,
+    /// Whether weak CASes may fail spuriously.
+    spurious_failures: bool,
+    /// Threads whose next weak CAS is going to fail.
+    doomed_cas: BTreeSet<usize>,
+    /// The threads waiting at each barrier.
+    barriers: BTreeMap<u64, BTreeSet<usize>>,
+}
 
 impl ScMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turns on or off a self-check, which makes sure that a thread never reads
+    /// anything but its own last write to an address, unless another thread has
+    /// written there since. A violation is reported as [`Error::CoherenceViolation`].
+    pub fn set_coherence_check(&mut self, enabled: bool) {
+        match (enabled, &self.own_writes) {
+            (true, None) => self.own_writes = Some(BTreeMap::new()),
+            (false, _) => self.own_writes = None,
+            _ => (),
+        }
+    }
+
+    /// Allows weak CASes to fail spuriously. A failure is offered as an
+    /// [`IndependentStep::SpuriousCasFailure`] before each weak CAS, so exploration
+    /// covers both outcomes. Off by default, as the drivers would take these steps
+    /// and could fail a retry loop forever.
+    pub fn set_spurious_failures(&mut self, enabled: bool) {
+        self.spurious_failures = enabled;
+        if !enabled {
+            self.doomed_cas.clear();
+        }
+    }
+
+    fn on_read(&self, tid: usize, addr: usize, actual: Value) -> Result<(), MemoryError<Error>> {
+        let expected = self.own_writes.as_ref()
+            .and_then(|x| x.get(&(tid, addr)));
+
+        match expected {
+            Some(&expected) if expected != actual => Err(Error::CoherenceViolation {
+                tid,
+                addr,
+                expected,
+                actual,
+            }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_write(&mut self, tid: usize, addr: usize, value: Value) {
+        self.invalidate_reservations(addr);
+
+        if let Some(own_writes) = &mut self.own_writes {
+            own_writes.retain(|(_, x), _| *x != addr);
+            own_writes.insert((tid, addr), value);
+        }
+    }
+
+    fn reservation_mut(&mut self, tid: usize) -> &mut Option<usize> {
+        if self.reservations.len() <= tid {
+            self.reservations.resize(tid + 1, None);
+        }
+
+        &mut self.reservations[tid]
+    }
+
+    /// Breaks all the reservations on `addr`.
+    fn invalidate_reservations(&mut self, addr: usize) {
+        self.reservations.iter_mut()
+            .filter(|x| **x == Some(addr))
+            .for_each(|x| *x = None);
+    }
+
+    /// Atomically replaces the value at `addr` of `bank` with `modify(old)`.
+    /// Returns the old and the new value.
+    fn fetch_modify(
+        &self,
+        tid: usize,
+        memory: &mut GlobalMemory<Self>,
+        bank: usize,
+        addr: usize,
+        mode: AccessMode,
+        modify: impl FnOnce(Value) -> Result<Value, MemoryError<Error>>,
+    ) -> Result<(Value, Value), MemoryError<Error>> {
+        let cell = memory.resolve(bank, addr)?;
+        let val = memory.fetch_in(bank, addr, mode)?;
+        let old_val = *val;
+        let new_val = modify(old_val)?;
+        self.on_read(tid, cell, old_val)?;
+        *val = new_val;
+
+        Ok((old_val, new_val))
+    }
+
     fn serve_thread_request(
         &mut self,
         tid: usize,
         query: MemoryQuery,
         threads: &mut Threads<Self>,
         memory: &mut GlobalMemory<Self>,
-    ) -> Result<(), MemoryError<Error>> {
+    ) -> Result<MachineEvent, MemoryError<Error>> {
         let thread_state = threads.get_thread_mut(tid)?;
-        match query {
+        let event = match query {
             super::MemoryQuery::Store {
+                bank,
                 addr,
                 value,
-                ..
+                mode,
             } => {
-                let target = memory.fetch(addr)?;
-                *target = value;
+                memory.write_in(bank, addr, value, mode)?;
+                let addr = memory.resolve(bank, addr)?;
 
-                Ok(())
+                MachineEvent::Write { tid, location: addr, value, mode, ts: 0, promoted: false }
             },
             super::MemoryQuery::Load {
+                bank,
                 addr,
                 dest,
-                ..
+                mode,
             } => {
-                let val = memory.fetch(addr)?;
-                thread_state.set_register(dest, *val)
+                let val = memory.read_in(bank, addr, mode)?;
+                let addr = memory.resolve(bank, addr)?;
+                self.on_read(tid, addr, val)?;
+                thread_state.set_register(dest, val)
                     .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
 
-                Ok(())
+                MachineEvent::Read { tid, location: addr, value: val, mode, ts: 0 }
             },
             super::MemoryQuery::Cas {
+                bank,
                 addr,
                 expected,
                 new_value,
-                ..
+                mode,
             } => {
-                let val = memory.fetch(addr)?;
-                if expected != *val {
+                let val = memory.read_in(bank, addr, mode)?;
+                let location = memory.resolve(bank, addr)?;
+                self.on_read(tid, location, val)?;
+                let succeeded = expected == val;
+                if succeeded {
+                    memory.write_in(bank, addr, new_value, mode)?;
+                } else {
                     debug!("CAS fail");
-                    return Ok(());
                 }
+                let write_value = if succeeded { new_value } else { val };
+
+                MachineEvent::Rmw { tid, location, read_value: val, write_value, mode, op: RmwKind::CompareSwap { succeeded }, ts: 0 }
+            },
+            super::MemoryQuery::CasWeak {
+                bank,
+                addr,
+                expected,
+                new_value,
+                ok,
+                mode,
+            } => {
+                let val = memory.read_in(bank, addr, mode)?;
+                let location = memory.resolve(bank, addr)?;
+                self.on_read(tid, location, val)?;
+                let success = !self.doomed_cas.remove(&tid) && expected == val;
+                thread_state.set_register(ok, Value::from_bool(success))
+                    .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+
+                if success {
+                    memory.write_in(bank, addr, new_value, mode)?;
+                } else {
+                    debug!("Weak CAS fail");
+                }
+                let write_value = if success { new_value } else { val };
 
-                *val = new_value;
-                Ok(())
+                MachineEvent::Rmw { tid, location, read_value: val, write_value, mode, op: RmwKind::CompareSwap { succeeded: success }, ts: 0 }
             },
             super::MemoryQuery::Fai {
+                bank,
                 addr,
                 dest,
-                ..
+                policy,
+                mode,
+            } => {
+                let location = memory.resolve(bank, addr)?;
+                let (read_value, write_value) = self.fetch_modify(tid, memory, bank, addr, mode, |value| {
+                    BinOp::Add.eval_with_policy(value, Value(1), policy)
+                        .map_err(|_| MemoryError::RmwOverflow { addr: location, value, operand: Value(1) })
+                })?;
+                thread_state.set_register(dest, read_value)
+                    .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                MachineEvent::Rmw { tid, location, read_value, write_value, mode, op: RmwKind::FetchAdd { overflowed: read_value.0.checked_add(1).is_none() }, ts: 0 }
+            },
+            super::MemoryQuery::TestAndModifyBit {
+                bank,
+                addr,
+                bit,
+                set,
+                dest,
+                mode,
+            } => {
+                let location = memory.resolve(bank, addr)?;
+                let mask = 1 << bit;
+                let (read_value, write_value) = self.fetch_modify(tid, memory, bank, addr, mode, |val| {
+                    Ok(Value(if set { val.0 | mask } else { val.0 & !mask }))
+                })?;
+                thread_state.set_register(dest, Value((read_value.0 >> bit) & 1))
+                    .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                MachineEvent::Rmw { tid, location, read_value, write_value, mode, op: RmwKind::TestAndModifyBit { set }, ts: 0 }
+            },
+            super::MemoryQuery::Fas {
+                bank,
+                addr,
+                dest,
+                operand,
+                policy,
+                mode,
+            } => {
+                let location = memory.resolve(bank, addr)?;
+                let (read_value, write_value) = self.fetch_modify(tid, memory, bank, addr, mode, |value| {
+                    BinOp::Sub.eval_with_policy(value, operand, policy)
+                        .map_err(|_| MemoryError::RmwUnderflow { addr: location, value, operand })
+                })?;
+                thread_state.set_register(dest, read_value)
+                    .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                MachineEvent::Rmw { tid, location, read_value, write_value, mode, op: RmwKind::FetchSub { overflowed: read_value.0.checked_sub(operand.0).is_none() }, ts: 0 }
+            },
+            super::MemoryQuery::Fmax {
+                bank,
+                addr,
+                dest,
+                operand,
+                mode,
+            } => {
+                let location = memory.resolve(bank, addr)?;
+                let (read_value, write_value) = self.fetch_modify(tid, memory, bank, addr, mode, |val| {
+                    Ok(val.max(operand))
+                })?;
+                thread_state.set_register(dest, read_value)
+                    .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                MachineEvent::Rmw { tid, location, read_value, write_value, mode, op: RmwKind::FetchMax, ts: 0 }
+            },
+            super::MemoryQuery::LoadLinked {
+                addr,
+                dest,
+                mode,
+            } => {
+                let val = memory.read(addr, mode)?;
+                self.on_read(tid, addr, val)?;
+                thread_state.set_register(dest, val)
+                    .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                *self.reservation_mut(tid) = Some(addr);
+
+                MachineEvent::Read { tid, location: addr, value: val, mode, ts: 0 }
+            },
+            super::MemoryQuery::StoreConditional {
+                addr,
+                value,
+                ok,
+                mode,
             } => {
-                let val = memory.fetch(addr)?;
-                thread_state.set_register(dest, *val)
+                let reserved = self.reservation_mut(tid).take() == Some(addr);
+                thread_state.set_register(ok, Value::from_bool(reserved))
                     .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
 
-                // TODO probbaly shouldn't peek into "val" internals
-                val.0 += 1;
+                if !reserved {
+                    debug!("SC fail");
+                    return Ok(MachineEvent::Silent);
+                }
 
-                Ok(())
+                memory.write(addr, value, mode)?;
+                MachineEvent::Write { tid, location: addr, value, mode, ts: 0, promoted: false }
             },
-            super::MemoryQuery::Fence { .. } => Ok(()),
+            super::MemoryQuery::LoadN {
+                addr,
+                dests,
+                mode,
+            } => {
+                memory.check_range(addr, dests.len())?;
+                for (cell, dest) in (addr..).zip(dests) {
+                    let val = memory.read(cell, mode)?;
+                    self.on_read(tid, cell, val)?;
+                    thread_state.set_register(dest.as_ref(), val)
+                        .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                }
+
+                MachineEvent::ReadN { tid, location: addr, count: dests.len(), mode }
+            },
+            super::MemoryQuery::LoadPair {
+                addrs,
+                dests,
+                mode,
+            } => {
+                let values = [memory.read(addrs[0], mode)?, memory.read(addrs[1], mode)?];
+                for ((addr, value), dest) in addrs.into_iter().zip(values).zip(dests) {
+                    self.on_read(tid, addr, value)?;
+                    thread_state.set_register(dest, value)
+                        .map_err(|error| MemoryError::ThreadStateError { tid, error })?;
+                }
+
+                MachineEvent::ReadPair { tid, locations: addrs, values, mode }
+            },
+            super::MemoryQuery::StoreN {
+                addr,
+                values,
+                mode,
+            } => {
+                memory.check_range(addr, values.len())?;
+                // Either all the cells are written or none
+                for cell in addr..addr + values.len() {
+                    memory.check_writable(cell)?;
+                    memory.check_mode(cell, mode)?;
+                }
+                for (cell, value) in (addr..).zip(&values) {
+                    memory.write(cell, *value, mode)?;
+                    self.on_write(tid, cell, *value);
+                }
+
+                MachineEvent::WriteN { tid, location: addr, count: values.len(), mode, ts: 0 }
+            },
+            super::MemoryQuery::Fence { mode, .. } => MachineEvent::Fence { tid, mode },
+            super::MemoryQuery::Barrier { id, participants } => {
+                let arrived = self.barriers.entry(id).or_default();
+                arrived.insert(tid);
+
+                if arrived.len() >= participants {
+                    debug!("Barrier {id} released");
+                    self.barriers.remove(&id);
+                }
+
+                MachineEvent::Silent
+            },
+        };
+
+        // The reads are checked before they get into the registers
+        match event {
+            MachineEvent::Write { location, value, .. } => self.on_write(tid, location, value),
+            MachineEvent::Rmw { location, write_value, op, .. } if op.writes() => self.on_write(tid, location, write_value),
+            _ => (),
         }
+
+        Ok(event)
     }
 }
 
@@ -83,16 +394,90 @@ impl MemorySubsystem for ScMemory {
 
     fn name() -> &'static str { "SC" }
 
+    fn init(memory_size: usize) -> (Self, GlobalMemory<Self>) {
+        (ScMemory::new(), GlobalMemory::new(memory_size))
+    }
+
+    fn reset(&mut self) {
+        self.reservations.clear();
+        if let Some(own_writes) = &mut self.own_writes {
+            own_writes.clear();
+        }
+        self.doomed_cas.clear();
+        self.barriers.clear();
+    }
+
+    fn pending_independent(
+        &self,
+        threads: &Threads<Self>,
+        _memory: &GlobalMemory<Self>,
+    ) -> Vec<Self::Independent> {
+        if !self.spurious_failures {
+            return Vec::new();
+        }
+
+        threads.iter()
+            .enumerate()
+            .filter(|(tid, thread)| !self.doomed_cas.contains(tid) &&
+                matches!(thread.next_instruction(), Some(Instruction::CasWeak { .. }))
+            )
+            .map(|(tid, _)| IndependentStep::SpuriousCasFailure { tid })
+            .collect()
+    }
+
+    fn is_waiting(&self, tid: usize) -> bool {
+        self.barriers.values().any(|x| x.contains(&tid))
+    }
+
+    /// Every read must observe the latest write to its address.
+    fn validate_trace(&self, trace: &Trace) -> Result<(), ValidationError> {
+        // `None` for the cells written by group writes, whose values aren't known
+        let mut latest = BTreeMap::<usize, Option<Value>>::new();
+
+        for (event, x) in trace.events.iter().enumerate() {
+            for (location, actual) in trace::reads(x) {
+                let expected = latest.get(&location).copied().unwrap_or(Some(Value(0)));
+                match expected {
+                    Some(expected) if expected != actual => return Err(ValidationError::StaleRead {
+                        event,
+                        location,
+                        expected,
+                        actual,
+                    }),
+                    _ => (),
+                }
+            }
+
+            if let Some((location, value)) = trace::written(x) {
+                latest.insert(location, Some(value));
+            }
+            if let MachineEvent::WriteN { location, count, .. } = *x {
+                (location..location + count).for_each(|cell| {
+                    latest.insert(cell, None);
+                });
+            }
+        }
+
+        Ok(())
+    }
+
     fn execute_step(
         &mut self,
         step: MemoryStep<Self::Independent>,
         threads: &mut Threads<Self>,
         memory: &mut GlobalMemory<Self>,
-    ) -> Result<(), MemoryError<Self::Err>> {
+    ) -> Result<MachineEvent, MemoryError<Self::Err>> {
         debug!("Step: {step:?}");
 
         match step {
-            MemoryStep::Independent(x) => match x {},
+            MemoryStep::Independent(IndependentStep::SpuriousCasFailure { tid }) => {
+                if !self.spurious_failures {
+                    return Err(Error::SpuriousFailuresDisabled { tid }.into());
+                }
+                self.doomed_cas.insert(tid);
+
+                Ok(MachineEvent::Silent)
+            },
             MemoryStep::ThreadRequest { tid, query } => self.serve_thread_request(
                 tid,
                 query,
@@ -101,4 +486,480 @@ impl MemorySubsystem for ScMemory {
             ),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{
+        Machine,
+        MachineError,
+        MachineEvent,
+        MachineStep,
+        driver,
+        explorer::Explorer,
+        litmus::{self, LITMUS_MEMORY_SIZE, X, Y},
+        machine_memory::{ErasedMemoryError, mixed::MixedMemory},
+        machine_thread::{CodeInstruction, OverflowPolicy, ThreadStateError, builder::ProgramBuilder, parser::parse_program},
+        register::RegisterRef,
+    };
+
+    use super::*;
+
+    /// Runs `program` to the end with the round-robin driver, starting with
+    /// the memory holding `initial`.
+    fn run<'a>(program: &'a [Vec<CodeInstruction>], initial: &[Value]) -> Result<(Machine<'a, ScMemory>, Trace), MachineError> {
+        let mut machine = Machine::new(program, initial.len())?;
+        machine.init_region(0, initial)?;
+        let mut trace = Trace::skipping_silent();
+        driver::run_round_robin(&mut machine, 1000, &mut trace)?;
+
+        Ok((machine, trace))
+    }
+
+    #[test]
+    fn no_independent_steps_by_default() {
+        let (program, _, _) = litmus::mp();
+        let mut machine = Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+
+        while let Some(tid) = machine.threads().live_tids().last() {
+            let pending = machine.memory().subsystem().pending_independent(machine.threads(), machine.memory().global());
+            assert!(pending.is_empty());
+            machine.step(MachineStep::Thread(tid)).unwrap();
+        }
+    }
+
+    #[test]
+    fn fetch_and_sub_and_max() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .set("q", 1)
+                .set("three", 3)
+                .fas(AccessMode::SeqCst, "p", "three", "old_p")
+                .fmax(AccessMode::SeqCst, "q", "three", "old_q")
+            .build();
+
+        let (machine, _) = run(&program, &[Value(5), Value(2)]).unwrap();
+        let thread = machine.threads().get(0).unwrap();
+        assert_eq!(machine.memory().global().cells(), [Value(2), Value(3)]);
+        assert_eq!(thread.get_register(RegisterRef::new("old_p")).unwrap(), Value(5));
+        assert_eq!(thread.get_register(RegisterRef::new("old_q")).unwrap(), Value(2));
+    }
+
+    #[test]
+    fn fetch_and_sub_underflow_fails() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .set("three", 3)
+                .fas(AccessMode::SeqCst, "p", "three", "old")
+            .build();
+
+        let err = run(&program, &[Value(2)]).err().unwrap();
+        assert!(matches!(
+            err,
+            MachineError::Memory(ErasedMemoryError::RmwUnderflow { addr: 0, value: Value(2), operand: Value(3) }),
+        ));
+    }
+
+    #[test]
+    fn init_sizes_the_memory() {
+        let (subsystem, global) = ScMemory::init(3);
+        assert!(subsystem.reservations.is_empty());
+        assert_eq!(global.cells(), [Value(0); 3]);
+
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 2)
+                .store_imm(AccessMode::SeqCst, "p", 7)
+            .build();
+        let (machine, _) = run(&program, &[Value(0); 3]).unwrap();
+        assert_eq!(machine.memory().global().cells(), [Value(0), Value(0), Value(7)]);
+    }
+
+    #[test]
+    fn store_conditional_fails_after_another_write() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .set("new", 5)
+                .load_linked(AccessMode::SeqCst, "x", "old")
+                .store_conditional(AccessMode::SeqCst, "x", "new", "ok")
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::SeqCst, "x", 9)
+            .build();
+        let outcome = |interfere: bool| {
+            let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+            let schedule: &[usize] = if interfere { &[0, 0, 0, 1, 1, 0] } else { &[0, 0, 0, 0, 1, 1] };
+            for tid in schedule {
+                machine.step(MachineStep::Thread(*tid)).unwrap();
+            }
+            let ok = machine.threads().get(0).unwrap().get_register(RegisterRef::new("ok")).unwrap();
+
+            (ok, machine.memory().global().cells()[0])
+        };
+
+        assert_eq!(outcome(false), (Value(1), Value(9)));
+        assert_eq!(outcome(true), (Value(0), Value(9)));
+    }
+
+    #[test]
+    fn load_n_fills_consecutive_registers() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 1)
+                .load_n(AccessMode::SeqCst, "p", &["a", "b"])
+            .build();
+
+        let (machine, trace) = run(&program, &[Value(3), Value(4), Value(5)]).unwrap();
+        let register = |name| machine.threads().get(0).unwrap().get_register(RegisterRef::new(name)).unwrap();
+        assert_eq!((register("a"), register("b")), (Value(4), Value(5)));
+        assert!(matches!(trace.events.last(), Some(MachineEvent::ReadN { location: 1, count: 2, .. })));
+
+        let err = run(&program, &[Value(3), Value(4)]).err().unwrap();
+        assert!(matches!(err, MachineError::Memory(ErasedMemoryError::AddressOutOfRange { .. })));
+    }
+
+    #[test]
+    fn coherence_check_passes_a_correct_run() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::SeqCst, "x", 1)
+                .load(AccessMode::SeqCst, "x", "r")
+                .fai(AccessMode::SeqCst, "x", "old")
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::SeqCst, "x", 2)
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.memory_mut().subsystem_mut().set_coherence_check(true);
+
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+        let subsystem = machine.memory().subsystem();
+        assert!(subsystem.on_read(0, 0, machine.memory().global().cells()[0]).is_ok());
+        assert!(matches!(
+            subsystem.on_read(0, 0, Value(42)),
+            Err(MemoryError::Other(Error::CoherenceViolation { tid: 0, addr: 0, actual: Value(42), .. })),
+        ));
+    }
+
+    #[test]
+    fn store_imm_writes_the_constant() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 1)
+                .store_imm(AccessMode::SeqCst, "p", 42)
+                .load(AccessMode::SeqCst, "p", "r")
+            .build();
+        let store = &program[0][1].instruction;
+        assert_eq!(store.to_string(), "store SEQ_CST ##p =42");
+
+        let (machine, _) = run(&program, &[Value(0); 2]).unwrap();
+        assert_eq!(machine.threads().get(0).unwrap().get_register(RegisterRef::new("r")).unwrap(), Value(42));
+        assert_eq!(machine.memory().global().cells(), [Value(0), Value(42)]);
+    }
+
+    #[test]
+    fn non_atomic_access_to_atomic_address_fails() {
+        let program = |mode| ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .load(mode, "p", "r")
+            .build();
+        let run = |program: &[Vec<CodeInstruction>]| {
+            let mut machine = Machine::<ScMemory>::new(program, 1).unwrap();
+            machine.memory_mut().global_mut().set_atomic(0);
+            driver::run_round_robin(&mut machine, 10, &mut Trace::default())
+        };
+
+        assert!(run(&program(AccessMode::Rlx)).is_ok());
+        assert!(matches!(
+            run(&program(AccessMode::NonAtomic)),
+            Err(MachineError::Memory(ErasedMemoryError::NonAtomicAccessToAtomicLocation { addr: 0 })),
+        ));
+    }
+
+    #[test]
+    fn weak_cas_may_fail_spuriously() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .set("expected", 0)
+                .set("new", 1)
+                .cas_weak(AccessMode::SeqCst, "p", "expected", "new", "ok")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.memory_mut().subsystem_mut().set_spurious_failures(true);
+
+        let outcomes = Explorer::new(machine, 20)
+            .map(|x| {
+                let state = x.unwrap();
+                let ok = state.registers[0].iter().find(|(x, _)| x.as_str() == "ok").unwrap().1;
+                (ok, state.memory[0])
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(outcomes, BTreeSet::from([(Value(0), Value(0)), (Value(1), Value(1))]));
+    }
+
+    #[test]
+    fn spurious_failure_is_rejected_when_disabled() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .set("expected", 0)
+                .set("new", 1)
+                .cas_weak(AccessMode::SeqCst, "p", "expected", "new", "ok")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.run_schedule(&[0, 0, 0].map(MachineStep::Thread)).unwrap();
+
+        let err = machine.step(MachineStep::Memory(IndependentStep::SpuriousCasFailure { tid: 0 })).unwrap_err();
+        let expected = Error::SpuriousFailuresDisabled { tid: 0 }.to_string();
+        assert!(matches!(err, MachineError::Memory(ErasedMemoryError::Other(x)) if x == expected));
+        // The weak CAS still succeeds
+        machine.step(MachineStep::Thread(0)).unwrap();
+        assert_eq!(machine.memory().global().cells(), [Value(1)]);
+    }
+
+    #[test]
+    fn relaxed_store_buffering_trace_is_rejected() {
+        let (program, _, _) = litmus::sb();
+        let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        // Neither thread observes the other's store before its load
+        let events = machine.run_schedule(&[0, 0, 0, 1, 1, 1, 0, 1].map(MachineStep::Thread)).unwrap();
+        let trace = Trace::new(events);
+
+        assert!(machine.memory().subsystem().validate_trace(&trace).is_ok());
+        assert!(matches!(
+            ScMemory::new().validate_trace(&trace),
+            Err(ValidationError::StaleRead { location: 1, expected: Value(1), actual: Value(0), .. }),
+        ));
+    }
+
+    #[test]
+    fn fai_overflow_policies() {
+        let fai = |policy| {
+            let program = ProgramBuilder::new()
+                .thread()
+                    .set("p", 0)
+                    .fai_with_policy(AccessMode::SeqCst, "p", "old", policy)
+                .build();
+            let (machine, _) = run(&program, &[Value::MAX])?;
+            let old = machine.threads().get(0).unwrap().get_register(RegisterRef::new("old")).unwrap();
+
+            Ok((old, machine.memory().global().cells()[0]))
+        };
+
+        assert!(matches!(
+            fai(OverflowPolicy::Checked),
+            Err(MachineError::Memory(ErasedMemoryError::RmwOverflow { addr: 0, value: Value::MAX, operand: Value(1) })),
+        ));
+        assert_eq!(fai(OverflowPolicy::Wrapping).unwrap(), (Value::MAX, Value::ZERO));
+        assert_eq!(fai(OverflowPolicy::Saturating).unwrap(), (Value::MAX, Value::MAX));
+    }
+
+    #[test]
+    fn barrier_orders_the_store_before_the_read() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::SeqCst, "x", 1)
+                .barrier(7, 2)
+                .set("done", 1)
+            .thread()
+                .set("x", 0)
+                .barrier(7, 2)
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+
+        let reads = Explorer::new(Machine::<ScMemory>::new(&program, 1).unwrap(), 50)
+            .map(|x| x.unwrap().registers[1].iter().find(|(x, _)| x.as_str() == "r").unwrap().1)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(reads, BTreeSet::from([Value(1)]));
+    }
+
+    #[test]
+    fn load_from_base_plus_offset() {
+        let program = |base| ProgramBuilder::new()
+            .thread()
+                .set("base", base)
+                .load_offset(AccessMode::SeqCst, "base", 2, "r")
+            .build();
+        let memory = [Value(0), Value(0), Value(0), Value(9)];
+        let in_range = program(1);
+        assert_eq!(in_range[0][1].instruction.to_string(), "load SEQ_CST ##base+2 r");
+
+        let (machine, _) = run(&in_range, &memory).unwrap();
+        assert_eq!(machine.threads().get(0).unwrap().get_register(RegisterRef::new("r")).unwrap(), Value(9));
+
+        let err = run(&program(u64::MAX), &memory).err().unwrap();
+        assert!(matches!(err, MachineError::Thread { tid: 0, err: ThreadStateError::AddressOverflow { .. } }));
+    }
+
+    #[test]
+    fn write_timestamps_increase() {
+        let program = litmus::fai_stress(3, 2, AccessMode::SeqCst);
+        let (_, trace) = run(&program, &[Value(10)]).unwrap();
+
+        let stamps = trace.events.iter()
+            .filter_map(|x| match x {
+                MachineEvent::Write { ts, .. } | MachineEvent::Rmw { ts, .. } => Some(*ts),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(stamps, (1..=6).collect::<Vec<_>>());
+        // The initial value comes first, as if stamped `0`
+        assert!(matches!(trace.events[0], MachineEvent::Init { location: 0, value: Value(10) }));
+    }
+
+    #[test]
+    fn banks_have_separate_cells() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 1)
+                .set("one", 1)
+                .set("two", 2)
+                .store(AccessMode::SeqCst, "p", "one")
+                .store_bank(AccessMode::SeqCst, 1, "p", "two")
+                .load_bank(AccessMode::SeqCst, 0, "p", "in_0")
+                .load_bank(AccessMode::SeqCst, 1, "p", "in_1")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 2).unwrap();
+        machine.set_banks(2);
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+
+        let global = machine.memory().global();
+        assert_eq!((global.peek_in(0, 1), global.peek_in(1, 1)), (Some(Value(1)), Some(Value(2))));
+        assert_eq!(global.cells(), [Value(0), Value(1), Value(0), Value(2)]);
+        let thread = machine.threads().get(0).unwrap();
+        assert_eq!(thread.get_register(RegisterRef::new("in_0")).unwrap(), Value(1));
+        assert_eq!(thread.get_register(RegisterRef::new("in_1")).unwrap(), Value(2));
+        assert!(global.peek_in(2, 1).is_none());
+    }
+
+    #[test]
+    fn banked_rmws_leave_the_other_banks_alone() {
+        fn run<Mem: MemorySubsystem>() -> (Value, Value) {
+            let program = parse_program("
+                thread
+                    p = 1
+                    three = 3
+                    faib SEQ_CST 1 ##p a
+                    fmaxb SEQ_CST 1 ##p three a
+                    btsb SEQ_CST 1 ##p 4 a
+                    load SEQ_CST ##p in_0
+                    loadb SEQ_CST 1 ##p in_1
+            ").unwrap();
+            let mut machine = Machine::<Mem>::new(&program, 2).unwrap();
+            machine.set_banks(2);
+            driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+
+            let thread = machine.threads().get(0).unwrap();
+            (thread.get_register(RegisterRef::new("in_0")).unwrap(), thread.get_register(RegisterRef::new("in_1")).unwrap())
+        }
+
+        assert_eq!(run::<ScMemory>(), (Value(0), Value(19)));
+        assert_eq!(run::<MixedMemory>(), (Value(0), Value(19)));
+    }
+
+    #[test]
+    fn pair_load_is_a_snapshot() {
+        let reader = |builder: ProgramBuilder, pair: bool| {
+            let builder = builder
+                .thread()
+                    .set("x", X as u64)
+                    .set("y", Y as u64);
+            match pair {
+                true => builder.load_pair(AccessMode::SeqCst, "x", "y", "rx", "ry"),
+                false => builder
+                    .load(AccessMode::SeqCst, "x", "rx")
+                    .load(AccessMode::SeqCst, "y", "ry"),
+            }
+        };
+        let outcomes = |pair| {
+            let writer = ProgramBuilder::new()
+                .thread()
+                    .set("x", X as u64)
+                    .set("y", Y as u64)
+                    .store_imm(AccessMode::SeqCst, "x", 1)
+                    .store_imm(AccessMode::SeqCst, "y", 1);
+            let program = reader(writer, pair).build();
+            let explorer = Explorer::new(Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap(), 50);
+
+            explorer
+                .map(|state| {
+                    let state = state.unwrap();
+                    let read = |name| state.registers[1].iter().find(|(x, _)| x.as_str() == name).unwrap().1;
+                    (read("rx"), read("ry"))
+                })
+                .collect::<BTreeSet<_>>()
+        };
+
+        // Two loads may read x before the writer and y after it
+        let torn = (Value(0), Value(1));
+        assert!(outcomes(false).contains(&torn));
+        assert_eq!(outcomes(true), BTreeSet::from([(Value(0), Value(0)), (Value(1), Value(0)), (Value(1), Value(1))]));
+    }
+
+    #[test]
+    fn bit_test_and_set_and_clear() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .test_and_set_bit(AccessMode::SeqCst, "p", 1, "set_1")
+                .test_and_set_bit(AccessMode::SeqCst, "p", 0, "set_0")
+                .test_and_clear_bit(AccessMode::SeqCst, "p", 2, "clear_2")
+                .test_and_clear_bit(AccessMode::SeqCst, "p", 3, "clear_3")
+            .build();
+
+        let (machine, trace) = run(&program, &[Value(0b0101)]).unwrap();
+        let thread = machine.threads().get(0).unwrap();
+        let old = ["set_1", "set_0", "clear_2", "clear_3"].map(|x| thread.get_register(RegisterRef::new(x)).unwrap());
+        assert_eq!(old, [Value(0), Value(1), Value(1), Value(0)]);
+        let cells = trace.events.iter()
+            .filter_map(|x| match x {
+                MachineEvent::Rmw { write_value, .. } => Some(*write_value),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cells, [Value(0b0111), Value(0b0111), Value(0b0011), Value(0b0011)]);
+
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .test_and_set_bit(AccessMode::SeqCst, "p", 64, "old")
+            .build();
+        let err = run(&program, &[Value(0)]).err().unwrap();
+        assert!(matches!(err, MachineError::Thread { tid: 0, err: ThreadStateError::BitOutOfRange { bit: 64 } }));
+    }
+
+    #[test]
+    fn rmw_events_name_the_operation() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .set("one", 1)
+                .set("five", 5)
+                .fai(AccessMode::SeqCst, "p", "old")
+                .cas(AccessMode::SeqCst, "p", "one", "five")
+                .cas(AccessMode::SeqCst, "p", "one", "five")
+            .build();
+
+        let (_, trace) = run(&program, &[Value(0)]).unwrap();
+        let ops = trace.events.iter()
+            .filter_map(|x| match x {
+                MachineEvent::Rmw { op, read_value, write_value, .. } => Some((*op, read_value.0, write_value.0)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ops, [
+            (RmwKind::FetchAdd { overflowed: false }, 0, 1),
+            (RmwKind::CompareSwap { succeeded: true }, 1, 5),
+            (RmwKind::CompareSwap { succeeded: false }, 5, 5),
+        ]);
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 use crate::value::Value;
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum BinOpError {
     #[error("Operation {op:?} with operands {l:?} and {r:?} has overflown")]
     Overflow {
@@ -32,6 +32,36 @@ pub enum BinOp {
     Mul,
     /// Division.
     Div,
+    /// Addition, wrapping around the value range.
+    WrappingAdd,
+    /// Substraction, wrapping around the value range.
+    WrappingSub,
+    /// Multiplication, wrapping around the value range.
+    WrappingMul,
+}
+
+/// How [`BinOp::eval_with_policy`] handles results that don't fit into a [`Value`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Report overflow and underflow as errors.
+    #[default]
+    Checked,
+    /// Wrap around the value range.
+    Wrapping,
+    /// Clamp to the value range.
+    Saturating,
+}
+
+/// Only the non-default policies have a name, as `Checked` is implied
+/// when no policy is written out.
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::Checked => Ok(()),
+            OverflowPolicy::Wrapping => write!(f, "WRAP"),
+            OverflowPolicy::Saturating => write!(f, "SAT"),
+        }
+    }
 }
 
 impl fmt::Display for BinOp {
@@ -41,19 +71,87 @@ impl fmt::Display for BinOp {
             BinOp::Sub => write!(f, "-"),
             BinOp::Mul => write!(f, "*"),
             BinOp::Div => write!(f, "/"),
+            BinOp::WrappingAdd => write!(f, "+%"),
+            BinOp::WrappingSub => write!(f, "-%"),
+            BinOp::WrappingMul => write!(f, "*%"),
         }
     }
 }
 
 impl BinOp {
     pub fn eval(self, l: Value, r: Value) -> Result<Value, BinOpError> {
+        self.eval_with_policy(l, r, OverflowPolicy::Checked)
+    }
+
+    /// Evaluates the operation. The wrapping operations wrap regardless of `policy`.
+    pub fn eval_with_policy(self, l: Value, r: Value, policy: OverflowPolicy) -> Result<Value, BinOpError> {
         let op = self;
 
+        match (op, policy) {
+            (BinOp::Div, _) => l.0.checked_div(r.0).ok_or(BinOpError::DivisionByZero),
+            (BinOp::WrappingAdd, _) => Ok(l.0.wrapping_add(r.0)),
+            (BinOp::WrappingSub, _) => Ok(l.0.wrapping_sub(r.0)),
+            (BinOp::WrappingMul, _) => Ok(l.0.wrapping_mul(r.0)),
+            (_, OverflowPolicy::Checked) => Self::eval_checked(op, l, r),
+            (BinOp::Add, OverflowPolicy::Wrapping) => Ok(l.0.wrapping_add(r.0)),
+            (BinOp::Sub, OverflowPolicy::Wrapping) => Ok(l.0.wrapping_sub(r.0)),
+            (BinOp::Mul, OverflowPolicy::Wrapping) => Ok(l.0.wrapping_mul(r.0)),
+            (BinOp::Add, OverflowPolicy::Saturating) => Ok(l.0.saturating_add(r.0)),
+            (BinOp::Sub, OverflowPolicy::Saturating) => Ok(l.0.saturating_sub(r.0)),
+            (BinOp::Mul, OverflowPolicy::Saturating) => Ok(l.0.saturating_mul(r.0)),
+        }.map(Value)
+    }
+
+    /// Like [`eval`](BinOp::eval), but a division by zero gives `trap` instead
+    /// of an error. The flag tells whether that has happened.
+    pub fn eval_trapping(self, l: Value, r: Value, trap: Value) -> Result<(Value, bool), BinOpError> {
+        match self.eval(l, r) {
+            Err(BinOpError::DivisionByZero) => Ok((trap, true)),
+            x => x.map(|x| (x, false)),
+        }
+    }
+
+    fn eval_checked(op: BinOp, l: Value, r: Value) -> Result<u64, BinOpError> {
         match op {
             BinOp::Add => l.0.checked_add(r.0).ok_or(BinOpError::Overflow { l, r, op }),
             BinOp::Sub => l.0.checked_sub(r.0).ok_or(BinOpError::Underflow { l, r, op }),
             BinOp::Mul => l.0.checked_mul(r.0).ok_or(BinOpError::Overflow { l, r, op }),
             BinOp::Div => l.0.checked_div(r.0).ok_or(BinOpError::DivisionByZero),
-        }.map(Value)
+            BinOp::WrappingAdd => Ok(l.0.wrapping_add(r.0)),
+            BinOp::WrappingSub => Ok(l.0.wrapping_sub(r.0)),
+            BinOp::WrappingMul => Ok(l.0.wrapping_mul(r.0)),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_at_the_boundaries() {
+        let add = |policy| BinOp::Add.eval_with_policy(Value::MAX, Value::ONE, policy);
+        let sub = |policy| BinOp::Sub.eval_with_policy(Value::ZERO, Value::ONE, policy);
+
+        assert!(matches!(add(OverflowPolicy::Checked), Err(BinOpError::Overflow { op: BinOp::Add, .. })));
+        assert_eq!(add(OverflowPolicy::Wrapping).unwrap(), Value::ZERO);
+        assert_eq!(add(OverflowPolicy::Saturating).unwrap(), Value::MAX);
+
+        assert!(matches!(sub(OverflowPolicy::Checked), Err(BinOpError::Underflow { op: BinOp::Sub, .. })));
+        assert_eq!(sub(OverflowPolicy::Wrapping).unwrap(), Value::MAX);
+        assert_eq!(sub(OverflowPolicy::Saturating).unwrap(), Value::ZERO);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wrapping_ops_wrap_where_checked_ones_fail() {
+        assert_eq!(BinOp::WrappingAdd.eval(Value::MAX, Value(2)).unwrap(), Value(1));
+        assert_eq!(BinOp::WrappingSub.eval(Value::ZERO, Value(2)).unwrap(), Value(u64::MAX - 1));
+        assert_eq!(BinOp::WrappingMul.eval(Value(1 << 63), Value(2)).unwrap(), Value::ZERO);
+
+        assert!(matches!(BinOp::Add.eval(Value::MAX, Value(2)), Err(BinOpError::Overflow { .. })));
+        assert!(matches!(BinOp::Sub.eval(Value::ZERO, Value(2)), Err(BinOpError::Underflow { .. })));
+        assert!(matches!(BinOp::Mul.eval(Value(1 << 63), Value(2)), Err(BinOpError::Overflow { .. })));
+
+        let symbols = [BinOp::WrappingAdd, BinOp::WrappingSub, BinOp::WrappingMul].map(|x| x.to_string());
+        assert_eq!(symbols, ["+%", "-%", "*%"]);
+    }
+}
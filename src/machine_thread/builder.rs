@@ -0,0 +1,391 @@
+use byteyarn::{Yarn, YarnRef};
+use fnv::FnvHashMap;
+
+use crate::{value::Value, machine_memory::{AccessMode, FenceKind}, register, label};
+
+use super::{CodeInstruction, Register, Label, Span, binop::{BinOp, OverflowPolicy}, instruction::Instruction};
+
+fn reg(name: &str) -> Register {
+    register::parse(name).unwrap_or_else(|err| panic!("Bad register name: {err}"))
+}
+
+fn label_named(name: &str) -> Label {
+    label::parse(name).unwrap_or_else(|err| panic!("Bad label name: {err}"))
+}
+
+/// Names of memory cells, as bound by [`ProgramBuilder::variable`].
+pub type Symbols = FnvHashMap<Yarn, usize>;
+
+/// A helper for writing programs in Rust.
+///
+/// Instructions are appended to the thread started by the latest call
+/// to [`thread`](ProgramBuilder::thread). A label set with
+/// [`label`](ProgramBuilder::label) is attached to the next pushed instruction.
+/// All the methods panic on an empty register or label name or one with whitespace.
+///
+/// ```
+/// let program = ProgramBuilder::new()
+///     .thread()
+///         .set("one", 1)
+///         .label("loop")
+///         .branch("one", "loop")
+///     .build();
+/// ```
+#[derive(Debug, Default)]
+pub struct ProgramBuilder {
+    threads: Vec<Vec<CodeInstruction>>,
+    pending_label: Option<Label>,
+    pending_span: Option<Span>,
+    symbols: Symbols,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new thread.
+    pub fn thread(mut self) -> Self {
+        self.threads.push(Vec::new());
+        self.pending_label = None;
+        self.pending_span = None;
+        self
+    }
+
+    /// Binds `name` to the memory cell `addr`, so that it can be used in
+    /// [`set_addr`](ProgramBuilder::set_addr). Rebinding a name overwrites it.
+    pub fn variable(mut self, name: &str, addr: usize) -> Self {
+        self.symbols.insert(YarnRef::new(name).to_box().immortalize(), addr);
+        self
+    }
+
+    /// Labels the next pushed instruction.
+    pub fn label(mut self, label: &str) -> Self {
+        self.pending_label = Some(label_named(label));
+        self
+    }
+
+    /// Sets the [`span`](CodeInstruction::span) of the next pushed instruction.
+    pub fn span(mut self, span: Span) -> Self {
+        self.pending_span = Some(span);
+        self
+    }
+
+    /// Appends an arbitrary instruction to the current thread.
+    ///
+    /// # Panics
+    /// Panics if no thread has been started yet.
+    pub fn push(mut self, instruction: Instruction) -> Self {
+        let label = self.pending_label.take();
+        let span = self.pending_span.take();
+        self.threads.last_mut()
+            .expect("ProgramBuilder::thread must be called before pushing instructions")
+            .push(CodeInstruction { label, fallthrough: None, instruction, span });
+        self
+    }
+
+    /// Like [`push`](ProgramBuilder::push), but also takes the label and the
+    /// span of the instruction, if it has them.
+    pub fn push_code(mut self, code_instruction: CodeInstruction) -> Self {
+        if let Some(label) = code_instruction.label {
+            self.pending_label = Some(label);
+        }
+        if let Some(span) = code_instruction.span {
+            self.pending_span = Some(span);
+        }
+
+        self.push(code_instruction.instruction)
+    }
+
+    pub fn set(self, dest: &str, value: u64) -> Self {
+        self.push(Instruction::Set { dest: reg(dest), value: Value(value) })
+    }
+
+    /// Stores the address of the cell named `name` in `dest` register.
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = &name
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `name` hasn't been bound with [`variable`](ProgramBuilder::variable).
+    pub fn set_addr(self, dest: &str, name: &str) -> Self {
+        let addr = *self.symbols.get(name)
+            .unwrap_or_else(|| panic!("Variable \"{name}\" is not bound"));
+
+        self.set(dest, addr as u64)
+    }
+
+    pub fn bop(self, dest: &str, binop: BinOp, src_l: &str, src_r: &str) -> Self {
+        self.push(Instruction::Bop {
+            dest: reg(dest),
+            binop,
+            src_l: reg(src_l),
+            src_r: reg(src_r),
+        })
+    }
+
+    pub fn branch(self, src: &str, label: &str) -> Self {
+        self.push(Instruction::Branch { src: reg(src), label: label_named(label) })
+    }
+
+    pub fn branch_reg(self, target: &str) -> Self {
+        self.push(Instruction::BranchReg { target: reg(target) })
+    }
+
+    pub fn cmov(self, dest: &str, cond: &str, src: &str) -> Self {
+        self.push(Instruction::CMov { dest: reg(dest), cond: reg(cond), src: reg(src) })
+    }
+
+    pub fn load(self, mode: AccessMode, addr: &str, dest: &str) -> Self {
+        self.load_offset(mode, addr, 0, dest)
+    }
+
+    /// A [`load`](ProgramBuilder::load) from `R[addr] + offset`.
+    pub fn load_offset(self, mode: AccessMode, addr: &str, offset: u64, dest: &str) -> Self {
+        self.push(Instruction::Load { mode, addr: reg(addr), offset: Value(offset), dest: reg(dest) })
+    }
+
+    pub fn store(self, mode: AccessMode, addr: &str, src: &str) -> Self {
+        self.store_offset(mode, addr, 0, src)
+    }
+
+    /// A [`store`](ProgramBuilder::store) to `R[addr] + offset`.
+    pub fn store_offset(self, mode: AccessMode, addr: &str, offset: u64, src: &str) -> Self {
+        self.push(Instruction::Store { mode, addr: reg(addr), offset: Value(offset), src: reg(src) })
+    }
+
+    pub fn store_imm(self, mode: AccessMode, addr: &str, value: u64) -> Self {
+        self.push(Instruction::StoreImm { mode, addr: reg(addr), value: Value(value) })
+    }
+
+    pub fn cas(self, mode: AccessMode, addr: &str, expected: &str, new_value: &str) -> Self {
+        self.push(Instruction::Cas {
+            mode,
+            bank: 0,
+            addr: reg(addr),
+            expected: reg(expected),
+            new_value: reg(new_value),
+        })
+    }
+
+    pub fn cas_weak(self, mode: AccessMode, addr: &str, expected: &str, new_value: &str, ok: &str) -> Self {
+        self.push(Instruction::CasWeak {
+            mode,
+            bank: 0,
+            addr: reg(addr),
+            expected: reg(expected),
+            new_value: reg(new_value),
+            ok: reg(ok),
+        })
+    }
+
+    pub fn fai(self, mode: AccessMode, addr: &str, dest: &str) -> Self {
+        self.fai_with_policy(mode, addr, dest, OverflowPolicy::Checked)
+    }
+
+    pub fn fai_with_policy(self, mode: AccessMode, addr: &str, dest: &str, policy: OverflowPolicy) -> Self {
+        self.push(Instruction::Fai { mode, bank: 0, addr: reg(addr), dest: reg(dest), policy })
+    }
+
+    pub fn test_and_set_bit(self, mode: AccessMode, addr: &str, bit: u32, dest: &str) -> Self {
+        self.push(Instruction::TestAndSetBit { mode, bank: 0, addr: reg(addr), bit, dest: reg(dest) })
+    }
+
+    pub fn test_and_clear_bit(self, mode: AccessMode, addr: &str, bit: u32, dest: &str) -> Self {
+        self.push(Instruction::TestAndClearBit { mode, bank: 0, addr: reg(addr), bit, dest: reg(dest) })
+    }
+
+    pub fn fas(self, mode: AccessMode, addr: &str, src: &str, dest: &str) -> Self {
+        self.fas_with_policy(mode, addr, src, dest, OverflowPolicy::Checked)
+    }
+
+    pub fn fas_with_policy(self, mode: AccessMode, addr: &str, src: &str, dest: &str, policy: OverflowPolicy) -> Self {
+        self.push(Instruction::Fas { mode, bank: 0, addr: reg(addr), src: reg(src), dest: reg(dest), policy })
+    }
+
+    pub fn fmax(self, mode: AccessMode, addr: &str, src: &str, dest: &str) -> Self {
+        self.push(Instruction::Fmax { mode, bank: 0, addr: reg(addr), src: reg(src), dest: reg(dest) })
+    }
+
+    pub fn load_linked(self, mode: AccessMode, addr: &str, dest: &str) -> Self {
+        self.push(Instruction::LoadLinked { mode, addr: reg(addr), dest: reg(dest) })
+    }
+
+    pub fn store_conditional(self, mode: AccessMode, addr: &str, src: &str, ok: &str) -> Self {
+        self.push(Instruction::StoreConditional { mode, addr: reg(addr), src: reg(src), ok: reg(ok) })
+    }
+
+    pub fn load_pair(self, mode: AccessMode, addr1: &str, addr2: &str, dest1: &str, dest2: &str) -> Self {
+        self.push(Instruction::LoadPair {
+            mode,
+            addr1: reg(addr1),
+            addr2: reg(addr2),
+            dest1: reg(dest1),
+            dest2: reg(dest2),
+        })
+    }
+
+    pub fn load_n(self, mode: AccessMode, addr: &str, dests: &[&str]) -> Self {
+        self.push(Instruction::LoadN { mode, addr: reg(addr), dests: dests.iter().map(|x| reg(x)).collect() })
+    }
+
+    pub fn store_n(self, mode: AccessMode, addr: &str, srcs: &[&str]) -> Self {
+        self.push(Instruction::StoreN { mode, addr: reg(addr), srcs: srcs.iter().map(|x| reg(x)).collect() })
+    }
+
+    pub fn load_local(self, addr: &str, dest: &str) -> Self {
+        self.push(Instruction::LoadLocal { addr: reg(addr), dest: reg(dest) })
+    }
+
+    pub fn store_local(self, addr: &str, src: &str) -> Self {
+        self.push(Instruction::StoreLocal { addr: reg(addr), src: reg(src) })
+    }
+
+    pub fn load_bank(self, mode: AccessMode, bank: usize, addr: &str, dest: &str) -> Self {
+        self.push(Instruction::LoadBank { mode, bank, addr: reg(addr), dest: reg(dest) })
+    }
+
+    pub fn store_bank(self, mode: AccessMode, bank: usize, addr: &str, src: &str) -> Self {
+        self.push(Instruction::StoreBank { mode, bank, addr: reg(addr), src: reg(src) })
+    }
+
+    pub fn get_pc(self, dest: &str) -> Self {
+        self.push(Instruction::GetPc { dest: reg(dest) })
+    }
+
+    pub fn assert(self, src: &str, expected: u64) -> Self {
+        self.push(Instruction::Assert { src: reg(src), expected: Value(expected) })
+    }
+
+    pub fn fence(self, mode: AccessMode, kind: FenceKind) -> Self {
+        self.push(Instruction::Fence { mode, kind })
+    }
+
+    pub fn barrier(self, id: u64, participants: usize) -> Self {
+        self.push(Instruction::Barrier { id, participants })
+    }
+
+    pub fn yield_now(self) -> Self {
+        self.push(Instruction::Yield)
+    }
+
+    pub fn print(self, src: &str) -> Self {
+        self.push(Instruction::Print { src: reg(src) })
+    }
+
+    /// Appends `count` copies of the block produced by `block`. Labels defined
+    /// inside the block get the copy number as a suffix (`L` becomes `L_0`, `L_1`, ...)
+    /// and the branches inside the block are retargeted accordingly, so the
+    /// copies don't clash with each other. The copies keep the spans of the
+    /// instructions.
+    ///
+    /// # Panics
+    /// Panics if no thread has been started yet.
+    pub fn repeat(mut self, count: usize, block: impl Fn(ProgramBuilder) -> ProgramBuilder) -> Self {
+        let inner = ProgramBuilder {
+            symbols: self.symbols.clone(),
+            ..ProgramBuilder::new()
+        };
+        let body = block(inner.thread()).build()
+            .pop()
+            .unwrap_or_default();
+        let defined = body.iter()
+            .filter_map(|x| x.label.as_ref())
+            .collect::<Vec<_>>();
+        let suffixed = |label: &Label, copy: usize| if defined.contains(&label) {
+            Label::from_fmt(format_args!("{label}_{copy}"))
+        } else {
+            label.clone()
+        };
+
+        for copy in 0..count {
+            for code_instruction in &body {
+                let mut instruction = code_instruction.instruction.clone();
+                if let Instruction::Branch { label, .. } = &mut instruction {
+                    *label = suffixed(label, copy);
+                }
+
+                if let Some(label) = &code_instruction.label {
+                    self.pending_label = Some(suffixed(label, copy));
+                }
+                self.pending_span = code_instruction.span.or(self.pending_span);
+                self = self.push(instruction);
+            }
+        }
+
+        self
+    }
+
+    pub fn build(self) -> Vec<Vec<CodeInstruction>> {
+        self.threads
+    }
+
+    /// Like [`build`](ProgramBuilder::build), but also returns the variable
+    /// bindings, so that the final memory can be inspected by name.
+    pub fn build_with_symbols(self) -> (Vec<Vec<CodeInstruction>>, Symbols) {
+        (self.threads, self.symbols)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::{Machine, driver, machine_memory::sc::ScMemory, machine_thread::ThreadState, register::RegisterRef, trace::Trace};
+
+    use super::*;
+
+    #[test]
+    fn builds_and_runs_store_buffering() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .set("y", 1)
+                .store_imm(AccessMode::SeqCst, "x", 1)
+                .load(AccessMode::SeqCst, "y", "r")
+            .thread()
+                .set("x", 0)
+                .set("y", 1)
+                .store_imm(AccessMode::SeqCst, "y", 1)
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+        assert_eq!(program.iter().map(Vec::len).collect::<Vec<_>>(), [4, 4]);
+
+        let mut machine = Machine::<ScMemory>::new(&program, 2).unwrap();
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+
+        let r = |tid| machine.threads().get(tid).unwrap().get_register(RegisterRef::new("r")).unwrap();
+        assert_eq!(machine.memory().global().cells(), [Value(1), Value(1)]);
+        assert!(r(0) == Value(1) || r(1) == Value(1));
+    }
+
+    #[test]
+    fn repeat_suffixes_the_labels_of_each_copy() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .label("outer")
+                .set("one", 1)
+                .repeat(3, |block| block
+                    .label("inner")
+                    .branch("one", "inner")
+                    .branch("one", "outer")
+                )
+            .build();
+
+        assert!(ThreadState::new(&program[0]).is_ok());
+        let branches = program[0].iter()
+            .filter_map(|x| match &x.instruction {
+                Instruction::Branch { label, .. } => Some((x.label.as_ref().map(|x| x.to_string()), label.to_string())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let owned = |label: Option<&str>, target: &str| (label.map(str::to_owned), target.to_owned());
+        assert_eq!(branches, [
+            owned(Some("inner_0"), "inner_0"),
+            owned(None, "outer"),
+            owned(Some("inner_1"), "inner_1"),
+            owned(None, "outer"),
+            owned(Some("inner_2"), "inner_2"),
+            owned(None, "outer"),
+        ]);
+    }
+}
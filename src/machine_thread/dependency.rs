@@ -0,0 +1,201 @@
+use std::{collections::BTreeSet, fmt};
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::{label::Label, register::RegisterRef};
+
+use super::{CodeInstruction, Instruction};
+
+/// How a later instruction depends on an earlier one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DepKind {
+    /// A register written by the earlier instruction is an input of the later one.
+    Data,
+    /// A register written by the earlier instruction is the address of the
+    /// later memory access.
+    Address,
+    /// The earlier instruction is a branch deciding whether the later memory
+    /// access is executed.
+    Control,
+}
+
+impl fmt::Display for DepKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DepKind::Data => write!(f, "data"),
+            DepKind::Address => write!(f, "addr"),
+            DepKind::Control => write!(f, "ctrl"),
+        }
+    }
+}
+
+/// An edge of the [`DepGraph`] between two instruction addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dependency {
+    pub from: usize,
+    pub to: usize,
+    pub kind: DepKind,
+}
+
+/// The static intra-thread dependencies of a program, see
+/// [`ThreadState::dependency_graph`](super::ThreadState::dependency_graph).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DepGraph {
+    edges: BTreeSet<Dependency>,
+}
+
+impl DepGraph {
+    /// The edges sorted by `from`, then `to`, then kind.
+    pub fn edges(&self) -> impl Iterator<Item = &Dependency> + '_ {
+        self.edges.iter()
+    }
+
+    pub fn contains(&self, from: usize, to: usize, kind: DepKind) -> bool {
+        self.edges.contains(&Dependency { from, to, kind })
+    }
+
+    /// The edges going into instruction `to`.
+    pub fn dependencies_of(&self, to: usize) -> impl Iterator<Item = &Dependency> + '_ {
+        self.edges.iter().filter(move |x| x.to == to)
+    }
+}
+
+impl fmt::Display for DepGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for Dependency { from, to, kind } in &self.edges {
+            writeln!(f, "{from:0>5} -> {to:0>5} [{kind}]")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The instructions the control may go to after instruction `addr`. A
+/// register-indirect jump may go anywhere.
+fn successors(
+    program: &[CodeInstruction],
+    label_map: &FnvHashMap<Label, usize>,
+    addr: usize,
+) -> Vec<usize> {
+    let instruction = &program[addr].instruction;
+    let mut res = match instruction.referenced_label() {
+        Some(label) => label_map.get(&label.to_box()).copied().into_iter().collect(),
+        None => Vec::new(),
+    };
+
+    match instruction {
+        Instruction::BranchReg { .. } => res.extend(0..program.len()),
+        _ => res.push(addr + 1),
+    }
+    res.retain(|x| *x < program.len());
+
+    res
+}
+
+/// The definitions reaching each instruction: the registers along with the
+/// addresses of the instructions which may have last written them. The
+/// initial register values aren't definitions.
+fn reaching_definitions<'a>(
+    program: &'a [CodeInstruction],
+    succs: &[Vec<usize>],
+) -> Vec<FnvHashSet<(RegisterRef<'a>, usize)>> {
+    let mut reaching = vec![FnvHashSet::<(RegisterRef, usize)>::default(); program.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for (addr, code_instruction) in program.iter().enumerate() {
+            let instruction = &code_instruction.instruction;
+            let written = instruction.written_registers();
+            // A conditional move may keep the old value
+            let kills = !matches!(instruction, Instruction::CMov { .. });
+            let out = reaching[addr].iter()
+                .copied()
+                .filter(|(register, _)| !kills || !written.contains(register))
+                .chain(written.iter().map(|register| (*register, addr)))
+                .collect::<Vec<_>>();
+
+            for succ in &succs[addr] {
+                for def in &out {
+                    changed |= reaching[*succ].insert(*def);
+                }
+            }
+        }
+    }
+
+    reaching
+}
+
+/// The first memory access whose address register isn't written on any path
+/// leading to it, unless it is in `preset`. Gives the address of the access
+/// and the register.
+pub(super) fn unset_address_register<'a>(
+    program: &'a [CodeInstruction],
+    label_map: &FnvHashMap<Label, usize>,
+    preset: impl Fn(RegisterRef) -> bool,
+) -> Option<(usize, RegisterRef<'a>)> {
+    let succs = (0..program.len())
+        .map(|addr| successors(program, label_map, addr))
+        .collect::<Vec<_>>();
+    let reaching = reaching_definitions(program, &succs);
+
+    program.iter()
+        .enumerate()
+        .flat_map(|(at, x)| x.instruction.address_registers().into_iter().map(move |register| (at, register)))
+        .find(|(at, register)| !preset(*register) && !reaching[*at].iter().any(|(x, _)| x == register))
+}
+
+/// Computes the dependency graph with a reaching definitions analysis. The
+/// initial register values aren't definitions, so reading them gives no edge.
+pub(super) fn dependency_graph(
+    program: &[CodeInstruction],
+    label_map: &FnvHashMap<Label, usize>,
+) -> DepGraph {
+    let succs = (0..program.len())
+        .map(|addr| successors(program, label_map, addr))
+        .collect::<Vec<_>>();
+    let reaching = reaching_definitions(program, &succs);
+
+    let mut edges = BTreeSet::new();
+    for (to, code_instruction) in program.iter().enumerate() {
+        let instruction = &code_instruction.instruction;
+        let address = instruction.address_registers();
+
+        for (register, from) in &reaching[to] {
+            let as_address = address.iter().filter(|x| *x == register).count();
+            if as_address > 0 {
+                edges.insert(Dependency { from: *from, to, kind: DepKind::Address });
+            }
+            let is_data = instruction.read_registers()
+                .iter()
+                .filter(|x| *x == register)
+                .count() > as_address;
+            if is_data {
+                edges.insert(Dependency { from: *from, to, kind: DepKind::Data });
+            }
+        }
+    }
+
+    for (from, code_instruction) in program.iter().enumerate() {
+        if !matches!(code_instruction.instruction, Instruction::Branch { .. } | Instruction::BranchReg { .. }) {
+            continue;
+        }
+
+        let mut seen = FnvHashSet::default();
+        let mut stack = succs[from].clone();
+        while let Some(to) = stack.pop() {
+            if !seen.insert(to) {
+                continue;
+            }
+
+            let instruction = &program[to].instruction;
+            if !instruction.is_local() && !instruction.address_registers().is_empty() {
+                edges.insert(Dependency { from, to, kind: DepKind::Control });
+            }
+            stack.extend(succs[to].iter().copied());
+        }
+    }
+
+    DepGraph { edges }
+}
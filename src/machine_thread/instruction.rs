@@ -1,8 +1,8 @@
 use std::fmt;
 
-use crate::{value::Value, machine_memory::{AccessMode, MemoryQuery}, register::RegisterRef};
+use crate::{value::Value, machine_memory::{AccessMode, FenceKind, MemoryQuery}, register::RegisterRef, label::LabelRef};
 
-use super::{Register, binop::BinOp, Label, ThreadState, ThreadStateError};
+use super::{Register, binop::{BinOp, OverflowPolicy}, Label, ThreadState, ThreadStateError};
 
 use smallvec::{SmallVec, smallvec};
 
@@ -33,46 +33,267 @@ pub enum Instruction {
     /// if(R[src] != 0) PC = label
     /// ```
     Branch { src: Register, label: Label },
-    /// Loads a value from address specified by [`addr`](Instruction::Load::addr) register into
+    /// Jumps to the instruction whose address is in [`target`](Instruction::BranchReg::target)
+    /// register, see [`GetPc`](Instruction::GetPc). Jumping right past the last
+    /// instruction halts the thread.
+    ///
+    /// # Semantics
+    /// ```
+    /// PC = R[target]
+    /// ```
+    BranchReg { target: Register },
+    /// Copies [`src`](Instruction::CMov::src) register into [`dest`](Instruction::CMov::dest)
+    /// register if [`cond`](Instruction::CMov::cond) register contains a non-zero value.
+    /// Doesn't jump, so the following instructions don't depend on `cond` through control.
+    ///
+    /// # Semantics
+    /// ```
+    /// if(R[cond] != 0) R[dest] = R[src]
+    /// ```
+    CMov { dest: Register, cond: Register, src: Register },
+    /// Loads a value from address specified by [`addr`](Instruction::Load::addr) register,
+    /// displaced by [`offset`](Instruction::Load::offset), into
     /// [`dest`](Instruction::Load::dest) register with access mode
     /// set to [`mode`](Instruction::Load::mode).
     ///
     /// # Semantics
     /// ```
-    /// R[dest] = M[R[addr]] with `mode`
+    /// R[dest] = M[R[addr] + offset] with `mode`
     /// ```
-    Load { mode: AccessMode, addr: Register, dest: Register },
-    /// Set the value stored at address specified by [`addr`](Instruction::Load::addr) register
-    /// to [`src`](Instruction::Load::dest) with access mode
-    /// set to [`mode`](Instruction::Load::mode).
+    Load { mode: AccessMode, addr: Register, offset: Value, dest: Register },
+    /// Set the value stored at address specified by [`addr`](Instruction::Store::addr) register,
+    /// displaced by [`offset`](Instruction::Store::offset), to [`src`](Instruction::Store::src)
+    /// with access mode set to [`mode`](Instruction::Store::mode).
     ///
     /// # Semantics
     /// ```
-    /// M[R[addr]] = R[src] with `mode`
+    /// M[R[addr] + offset] = R[src] with `mode`
     /// ```
-    Store { mode: AccessMode, addr: Register, src: Register },
+    Store { mode: AccessMode, addr: Register, offset: Value, src: Register },
+    /// Set the value stored at address specified by [`addr`](Instruction::StoreImm::addr) register
+    /// to the constant [`value`](Instruction::StoreImm::value) with access mode
+    /// set to [`mode`](Instruction::StoreImm::mode).
+    ///
+    /// # Semantics
+    /// ```
+    /// M[R[addr]] = value with `mode`
+    /// ```
+    StoreImm { mode: AccessMode, addr: Register, value: Value },
     /// Performs a compare-and-swap operation on the value stored at address specified by
     /// [`addr`](Instruction::Load::addr) register. The expected value is specified by
     /// [`expected`](Instruction::Cas::expected) register and the new value is specified by
     /// the [`new_value`](Instruction::Cas::new_value) register.
+    /// The address is in memory bank [`bank`](Instruction::Cas::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
     ///
     /// # Semantics
     /// ```
     /// if (M[R[addr]] == R[expected]) M[R[addr]] = R[src] with `mode`
     /// ```
-    Cas { mode: AccessMode, addr: Register, expected: Register, new_value: Register },
+    Cas { mode: AccessMode, bank: usize, addr: Register, expected: Register, new_value: Register },
+    /// Like [`Cas`](Instruction::Cas), but the memory subsystem may fail it spuriously,
+    /// even if the values match. [`ok`](Instruction::CasWeak::ok) register is set to `1`
+    /// if the new value has been written and to `0` otherwise.
+    /// The address is in memory bank [`bank`](Instruction::CasWeak::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
+    ///
+    /// # Semantics
+    /// ```
+    /// if (M[R[addr]] == R[expected] && !spurious) { M[R[addr]] = R[src] with `mode`; R[ok] = 1 } else R[ok] = 0
+    /// ```
+    CasWeak { mode: AccessMode, bank: usize, addr: Register, expected: Register, new_value: Register, ok: Register },
     /// Performs a fetch-and-increment operation on the value stored at address specified by
     /// [`addr`](Instruction::Fai::addr) register. The old value will be loaded into the
     /// [`dest`](Instruction::Fai::dest) register with access mode
-    /// set to [`mode`](Instruction::Load::mode).
+    /// set to [`mode`](Instruction::Load::mode). An overflow of the stored value is
+    /// handled according to [`policy`](Instruction::Fai::policy).
+    /// The address is in memory bank [`bank`](Instruction::Fai::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
     ///
     /// # Semantics
     /// ```
     /// R[dest] = M[R[addr]] then immediate M[R[addr]] = M[R[addr]] + 1 with `mode`
     /// ```
-    Fai { mode: AccessMode, addr: Register, dest: Register },
-    /// A memory fence with access mode set to [`mode`](Instruction::Fence::mode).
-    Fence { mode: AccessMode },
+    Fai { mode: AccessMode, bank: usize, addr: Register, dest: Register, policy: OverflowPolicy },
+    /// Atomically sets bit number [`bit`](Instruction::TestAndSetBit::bit) of the value
+    /// stored at address specified by [`addr`](Instruction::TestAndSetBit::addr) register.
+    /// The old value of the bit (`0` or `1`) will be loaded into the
+    /// [`dest`](Instruction::TestAndSetBit::dest) register with access mode set to
+    /// [`mode`](Instruction::TestAndSetBit::mode). The bit must be below 64.
+    /// The address is in memory bank [`bank`](Instruction::TestAndSetBit::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = (M[R[addr]] >> bit) & 1 then immediate M[R[addr]] = M[R[addr]] | (1 << bit) with `mode`
+    /// ```
+    TestAndSetBit { mode: AccessMode, bank: usize, addr: Register, bit: u32, dest: Register },
+    /// Like [`TestAndSetBit`](Instruction::TestAndSetBit), but clears the bit.
+    /// The address is in memory bank [`bank`](Instruction::TestAndClearBit::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = (M[R[addr]] >> bit) & 1 then immediate M[R[addr]] = M[R[addr]] & !(1 << bit) with `mode`
+    /// ```
+    TestAndClearBit { mode: AccessMode, bank: usize, addr: Register, bit: u32, dest: Register },
+    /// Performs a fetch-and-subtract operation on the value stored at address specified by
+    /// [`addr`](Instruction::Fas::addr) register, subtracting the value of
+    /// [`src`](Instruction::Fas::src) register. The old value will be loaded into the
+    /// [`dest`](Instruction::Fas::dest) register with access mode
+    /// set to [`mode`](Instruction::Fas::mode). An underflow of the stored value is
+    /// handled according to [`policy`](Instruction::Fas::policy).
+    /// The address is in memory bank [`bank`](Instruction::Fas::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = M[R[addr]] then immediate M[R[addr]] = M[R[addr]] - R[src] with `mode`
+    /// ```
+    Fas { mode: AccessMode, bank: usize, addr: Register, src: Register, dest: Register, policy: OverflowPolicy },
+    /// Performs a fetch-and-max operation on the value stored at address specified by
+    /// [`addr`](Instruction::Fmax::addr) register, storing the maximum of it and the value of
+    /// [`src`](Instruction::Fmax::src) register. The old value will be loaded into the
+    /// [`dest`](Instruction::Fmax::dest) register with access mode
+    /// set to [`mode`](Instruction::Fmax::mode).
+    /// The address is in memory bank [`bank`](Instruction::Fmax::bank), like for
+    /// [`LoadBank`](Instruction::LoadBank).
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = M[R[addr]] then immediate M[R[addr]] = max(M[R[addr]], R[src]) with `mode`
+    /// ```
+    Fmax { mode: AccessMode, bank: usize, addr: Register, src: Register, dest: Register },
+    /// Loads a value from address specified by [`addr`](Instruction::LoadLinked::addr) register
+    /// into [`dest`](Instruction::LoadLinked::dest) register and places a reservation on the address.
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = M[R[addr]] with `mode`, reserve R[addr]
+    /// ```
+    LoadLinked { mode: AccessMode, addr: Register, dest: Register },
+    /// Stores the value of [`src`](Instruction::StoreConditional::src) register at address
+    /// specified by [`addr`](Instruction::StoreConditional::addr) register, but only if the
+    /// reservation placed by the last [`LoadLinked`](Instruction::LoadLinked) of this thread
+    /// on that address hasn't been broken by a write. [`ok`](Instruction::StoreConditional::ok)
+    /// register is set to `1` on success and to `0` on failure.
+    ///
+    /// # Semantics
+    /// ```
+    /// if (reserved R[addr]) { M[R[addr]] = R[src] with `mode`; R[ok] = 1 } else R[ok] = 0
+    /// ```
+    StoreConditional { mode: AccessMode, addr: Register, src: Register, ok: Register },
+    /// Loads the values of consecutive cells starting at address specified by
+    /// [`addr`](Instruction::LoadN::addr) register into [`dests`](Instruction::LoadN::dests)
+    /// registers as a single access with access mode set to [`mode`](Instruction::LoadN::mode).
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dests[i]] = M[R[addr] + i] for all i, atomically with `mode`
+    /// ```
+    LoadN { mode: AccessMode, addr: Register, dests: Vec<Register> },
+    /// Loads the values at addresses specified by [`addr1`](Instruction::LoadPair::addr1)
+    /// and [`addr2`](Instruction::LoadPair::addr2) registers into
+    /// [`dest1`](Instruction::LoadPair::dest1) and [`dest2`](Instruction::LoadPair::dest2)
+    /// registers with access mode set to [`mode`](Instruction::LoadPair::mode).
+    /// Under SC both cells are read in one indivisible step, giving a consistent
+    /// snapshot. Weaker models may serve it as two independent loads.
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest1], R[dest2] = M[R[addr1]], M[R[addr2]], atomically with `mode`
+    /// ```
+    LoadPair { mode: AccessMode, addr1: Register, addr2: Register, dest1: Register, dest2: Register },
+    /// Stores the values of [`srcs`](Instruction::StoreN::srcs) registers into consecutive
+    /// cells starting at address specified by [`addr`](Instruction::StoreN::addr) register
+    /// as a single access with access mode set to [`mode`](Instruction::StoreN::mode).
+    ///
+    /// # Semantics
+    /// ```
+    /// M[R[addr] + i] = R[srcs[i]] for all i, atomically with `mode`
+    /// ```
+    StoreN { mode: AccessMode, addr: Register, srcs: Vec<Register> },
+    /// Loads a value from the thread's local memory at address specified by
+    /// [`addr`](Instruction::LoadLocal::addr) register into
+    /// [`dest`](Instruction::LoadLocal::dest) register. Other threads can't observe it.
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = L[R[addr]]
+    /// ```
+    LoadLocal { addr: Register, dest: Register },
+    /// Stores the value of [`src`](Instruction::StoreLocal::src) register in the thread's
+    /// local memory at address specified by [`addr`](Instruction::StoreLocal::addr) register.
+    ///
+    /// # Semantics
+    /// ```
+    /// L[R[addr]] = R[src]
+    /// ```
+    StoreLocal { addr: Register, src: Register },
+    /// Like [`Load`](Instruction::Load), but the address is in memory bank
+    /// [`bank`](Instruction::LoadBank::bank). The plain accesses go to bank `0`.
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = M[bank][R[addr]] with `mode`
+    /// ```
+    LoadBank { mode: AccessMode, bank: usize, addr: Register, dest: Register },
+    /// Like [`Store`](Instruction::Store), but the address is in memory bank
+    /// [`bank`](Instruction::StoreBank::bank). The plain accesses go to bank `0`.
+    ///
+    /// # Semantics
+    /// ```
+    /// M[bank][R[addr]] = R[src] with `mode`
+    /// ```
+    StoreBank { mode: AccessMode, bank: usize, addr: Register, src: Register },
+    /// Stores the address of the instruction following this one in
+    /// [`dest`](Instruction::GetPc::dest) register.
+    ///
+    /// # Semantics
+    /// ```
+    /// R[dest] = PC + 1
+    /// ```
+    GetPc { dest: Register },
+    /// Stops the machine with an error unless [`src`](Instruction::Assert::src) register
+    /// contains [`expected`](Instruction::Assert::expected).
+    ///
+    /// # Semantics
+    /// ```
+    /// if (R[src] != expected) fail
+    /// ```
+    Assert { src: Register, expected: Value },
+    /// A memory fence of kind [`kind`](Instruction::Fence::kind) with access mode
+    /// set to [`mode`](Instruction::Fence::mode).
+    Fence { mode: AccessMode, kind: FenceKind },
+    /// Waits until [`participants`](Instruction::Barrier::participants) threads
+    /// (this one included) have arrived at a barrier with the same
+    /// [`id`](Instruction::Barrier::id), then lets them all go.
+    Barrier { id: u64, participants: usize },
+    /// Does nothing, but marks a point where the thread is willing to give
+    /// way to the others. The machine reports it as [`MachineEvent::Yield`](crate::MachineEvent::Yield).
+    Yield,
+    /// Emits the value of [`src`](Instruction::Print::src) register to the
+    /// output of the machine, see [`Machine::output`](crate::Machine::output).
+    /// The machine reports it as [`MachineEvent::Output`](crate::MachineEvent::Output).
+    ///
+    /// # Semantics
+    /// ```
+    /// output(R[src])
+    /// ```
+    Print { src: Register },
+}
+
+/// The mode of a read-modify-write followed by its bank. Bank `0` is
+/// printed in the plain form, the others with a `b` suffix on the mnemonic.
+struct Banked(AccessMode, usize);
+
+impl fmt::Display for Banked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Banked(mode, 0) => write!(f, " {mode}"),
+            Banked(mode, bank) => write!(f, "b {mode} {bank}"),
+        }
+    }
 }
 
 impl fmt::Display for Instruction {
@@ -86,35 +307,228 @@ impl fmt::Display for Instruction {
                 src_r,
             } => write!(f, "{dest} = {src_l} {op} {src_r}"),
             Instruction::Branch { src, label } => write!(f, "if {src} goto {label}"),
+            Instruction::CMov { dest, cond, src } => write!(f, "{dest} = {src} if {cond}"),
+            Instruction::BranchReg { target } => write!(f, "goto *{target}"),
             Instruction::Load {
                 mode,
                 addr,
+                offset: Value(0),
                 dest,
             } => write!(f, "load {mode} ##{addr} {dest}"),
+            Instruction::Load {
+                mode,
+                addr,
+                offset,
+                dest,
+            } => write!(f, "load {mode} ##{addr}+{offset} {dest}"),
             Instruction::Store {
                 mode,
                 addr,
-                src ,
+                offset: Value(0),
+                src,
             } => write!(f, "store {mode} ##{addr} {src}"),
+            Instruction::Store {
+                mode,
+                addr,
+                offset,
+                src,
+            } => write!(f, "store {mode} ##{addr}+{offset} {src}"),
+            Instruction::StoreImm {
+                mode,
+                addr,
+                value,
+            } => write!(f, "store {mode} ##{addr} ={value}"),
             Instruction::Cas {
                 mode,
+                bank,
                 addr,
                 expected,
                 new_value: src,
-            } => write!(f, "cas {mode} ##{addr} {expected} {src}"),
+            } => write!(f, "cas{} ##{addr} {expected} {src}", Banked(*mode, *bank)),
+            Instruction::CasWeak {
+                mode,
+                bank,
+                addr,
+                expected,
+                new_value,
+                ok,
+            } => write!(f, "casw{} ##{addr} {expected} {new_value} {ok}", Banked(*mode, *bank)),
             Instruction::Fai {
                 mode,
+                bank,
+                addr,
+                dest,
+                policy: OverflowPolicy::Checked,
+            } => write!(f, "fai{} ##{addr} {dest}", Banked(*mode, *bank)),
+            Instruction::Fai {
+                mode,
+                bank,
+                addr,
+                dest,
+                policy,
+            } => write!(f, "fai{} ##{addr} {dest} {policy}", Banked(*mode, *bank)),
+            Instruction::TestAndSetBit {
+                mode,
+                bank,
+                addr,
+                bit,
+                dest,
+            } => write!(f, "bts{} ##{addr} {bit} {dest}", Banked(*mode, *bank)),
+            Instruction::TestAndClearBit {
+                mode,
+                bank,
+                addr,
+                bit,
+                dest,
+            } => write!(f, "btc{} ##{addr} {bit} {dest}", Banked(*mode, *bank)),
+            Instruction::Fas {
+                mode,
+                bank,
+                addr,
+                src,
+                dest,
+                policy: OverflowPolicy::Checked,
+            } => write!(f, "fas{} ##{addr} {src} {dest}", Banked(*mode, *bank)),
+            Instruction::Fas {
+                mode,
+                bank,
                 addr,
+                src,
                 dest,
-            } => write!(f, "fai {mode} ##{addr} {dest}"),
-            Instruction::Fence { mode } => write!(f, "fence {mode}"),
+                policy,
+            } => write!(f, "fas{} ##{addr} {src} {dest} {policy}", Banked(*mode, *bank)),
+            Instruction::Fmax {
+                mode,
+                bank,
+                addr,
+                src,
+                dest,
+            } => write!(f, "fmax{} ##{addr} {src} {dest}", Banked(*mode, *bank)),
+            Instruction::LoadLinked {
+                mode,
+                addr,
+                dest,
+            } => write!(f, "ll {mode} ##{addr} {dest}"),
+            Instruction::StoreConditional {
+                mode,
+                addr,
+                src,
+                ok,
+            } => write!(f, "sc {mode} ##{addr} {src} {ok}"),
+            Instruction::LoadN {
+                mode,
+                addr,
+                dests,
+            } => {
+                write!(f, "loadn {mode} ##{addr}")?;
+                dests.iter().try_for_each(|x| write!(f, " {x}"))
+            },
+            Instruction::LoadPair {
+                mode,
+                addr1,
+                addr2,
+                dest1,
+                dest2,
+            } => write!(f, "loadp {mode} ##{addr1} ##{addr2} {dest1} {dest2}"),
+            Instruction::StoreN {
+                mode,
+                addr,
+                srcs,
+            } => {
+                write!(f, "storen {mode} ##{addr}")?;
+                srcs.iter().try_for_each(|x| write!(f, " {x}"))
+            },
+            Instruction::LoadLocal { addr, dest } => write!(f, "loadl ##{addr} {dest}"),
+            Instruction::StoreLocal { addr, src } => write!(f, "storel ##{addr} {src}"),
+            Instruction::LoadBank { mode, bank, addr, dest } => write!(f, "loadb {mode} {bank} ##{addr} {dest}"),
+            Instruction::StoreBank { mode, bank, addr, src } => write!(f, "storeb {mode} {bank} ##{addr} {src}"),
+            Instruction::GetPc { dest } => write!(f, "{dest} = pc"),
+            Instruction::Assert { src, expected } => write!(f, "assert {src} == {expected}"),
+            Instruction::Fence { mode, kind } => write!(f, "fence {mode} {kind}"),
+            Instruction::Barrier { id, participants } => write!(f, "barrier {id} {participants}"),
+            Instruction::Yield => write!(f, "yield"),
+            Instruction::Print { src } => write!(f, "print {src}"),
         }
     }
 }
 
 impl Instruction {
+    /// A short name of the instruction kind, matching its mnemonic where it has one.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Set { .. } => "set",
+            Instruction::Bop { .. } => "bop",
+            Instruction::Branch { .. } => "branch",
+            Instruction::CMov { .. } => "cmov",
+            Instruction::BranchReg { .. } => "goto",
+            Instruction::Load { .. } => "load",
+            Instruction::Store { .. } => "store",
+            Instruction::StoreImm { .. } => "storeimm",
+            Instruction::Cas { .. } => "cas",
+            Instruction::CasWeak { .. } => "casw",
+            Instruction::Fai { .. } => "fai",
+            Instruction::TestAndSetBit { .. } => "bts",
+            Instruction::TestAndClearBit { .. } => "btc",
+            Instruction::Fas { .. } => "fas",
+            Instruction::Fmax { .. } => "fmax",
+            Instruction::LoadLinked { .. } => "ll",
+            Instruction::StoreConditional { .. } => "sc",
+            Instruction::LoadN { .. } => "loadn",
+            Instruction::LoadPair { .. } => "loadp",
+            Instruction::StoreN { .. } => "storen",
+            Instruction::LoadLocal { .. } => "loadl",
+            Instruction::StoreLocal { .. } => "storel",
+            Instruction::LoadBank { .. } => "loadb",
+            Instruction::StoreBank { .. } => "storeb",
+            Instruction::GetPc { .. } => "pc",
+            Instruction::Assert { .. } => "assert",
+            Instruction::Fence { .. } => "fence",
+            Instruction::Barrier { .. } => "barrier",
+            Instruction::Yield => "yield",
+            Instruction::Print { .. } => "print",
+        }
+    }
+
+    /// Whether the instruction executes without querying the memory subsystem,
+    /// so other threads can't observe it.
+    pub fn is_local(&self) -> bool {
+        match self {
+            Instruction::Set { .. } |
+            Instruction::Bop { .. } |
+            Instruction::Branch { .. } |
+            Instruction::CMov { .. } |
+            Instruction::BranchReg { .. } |
+            Instruction::LoadLocal { .. } |
+            Instruction::StoreLocal { .. } |
+            Instruction::GetPc { .. } |
+            Instruction::Assert { .. } |
+            Instruction::Yield => true,
+            Instruction::Load { .. } |
+            Instruction::Store { .. } |
+            Instruction::StoreImm { .. } |
+            Instruction::Cas { .. } |
+            Instruction::CasWeak { .. } |
+            Instruction::Fai { .. } |
+            Instruction::TestAndSetBit { .. } |
+            Instruction::TestAndClearBit { .. } |
+            Instruction::Fas { .. } |
+            Instruction::Fmax { .. } |
+            Instruction::LoadLinked { .. } |
+            Instruction::StoreConditional { .. } |
+            Instruction::LoadN { .. } |
+            Instruction::LoadPair { .. } |
+            Instruction::StoreN { .. } |
+            Instruction::LoadBank { .. } |
+            Instruction::StoreBank { .. } |
+            Instruction::Fence { .. } |
+            Instruction::Barrier { .. } => false,
+            // The order of the output is observable
+            Instruction::Print { .. } => false,
+        }
+    }
+
     // TODO returns yarn-refs. They can then be immortalised
-    pub(super) fn used_registers(&self) -> SmallVec<[RegisterRef; 3]> {
+    pub(super) fn used_registers(&self) -> SmallVec<[RegisterRef<'_>; 3]> {
         match self {
             Instruction::Set { dest, .. } => smallvec![
                 dest.as_ref()
@@ -126,6 +540,8 @@ impl Instruction {
                 ..
             } => smallvec![dest.as_ref(), src_l.as_ref(), src_r.as_ref()],
             Instruction::Branch { src, .. } => smallvec![src.as_ref()],
+            Instruction::CMov { dest, cond, src } => smallvec![dest.as_ref(), cond.as_ref(), src.as_ref()],
+            Instruction::BranchReg { target } => smallvec![target.as_ref()],
             Instruction::Load {
                 addr,
                 dest,
@@ -136,36 +552,237 @@ impl Instruction {
                 src,
                 ..
             } => smallvec![addr.as_ref(), src.as_ref()],
+            Instruction::StoreImm { addr, .. } => smallvec![addr.as_ref()],
             Instruction::Cas {
                 addr,
                 expected,
                 new_value: src,
                 ..
             } => smallvec![addr.as_ref(), expected.as_ref(), src.as_ref()],
+            Instruction::CasWeak {
+                addr,
+                expected,
+                new_value,
+                ok,
+                ..
+            } => smallvec![addr.as_ref(), expected.as_ref(), new_value.as_ref(), ok.as_ref()],
             Instruction::Fai {
                 addr,
                 dest,
                 ..
+            } |
+            Instruction::TestAndSetBit {
+                addr,
+                dest,
+                ..
+            } |
+            Instruction::TestAndClearBit {
+                addr,
+                dest,
+                ..
             } => smallvec![addr.as_ref(), dest.as_ref()],
-            Instruction::Fence { .. } => smallvec![],
+            Instruction::Fas {
+                addr,
+                src,
+                dest,
+                ..
+            } |
+            Instruction::Fmax {
+                addr,
+                src,
+                dest,
+                ..
+            } => smallvec![addr.as_ref(), src.as_ref(), dest.as_ref()],
+            Instruction::LoadLinked {
+                addr,
+                dest,
+                ..
+            } => smallvec![addr.as_ref(), dest.as_ref()],
+            Instruction::StoreConditional {
+                addr,
+                src,
+                ok,
+                ..
+            } => smallvec![addr.as_ref(), src.as_ref(), ok.as_ref()],
+            Instruction::LoadN { addr, dests: regs, .. } |
+            Instruction::StoreN { addr, srcs: regs, .. } => std::iter::once(addr)
+                .chain(regs)
+                .map(|x| x.as_ref())
+                .collect(),
+            Instruction::LoadPair {
+                addr1,
+                addr2,
+                dest1,
+                dest2,
+                ..
+            } => smallvec![addr1.as_ref(), addr2.as_ref(), dest1.as_ref(), dest2.as_ref()],
+            Instruction::LoadLocal { addr, dest } |
+            Instruction::LoadBank { addr, dest, .. } => smallvec![addr.as_ref(), dest.as_ref()],
+            Instruction::StoreLocal { addr, src } |
+            Instruction::StoreBank { addr, src, .. } => smallvec![addr.as_ref(), src.as_ref()],
+            Instruction::GetPc { dest } => smallvec![dest.as_ref()],
+            Instruction::Assert { src, .. } |
+            Instruction::Print { src } => smallvec![src.as_ref()],
+            Instruction::Fence { .. } |
+            Instruction::Barrier { .. } |
+            Instruction::Yield => smallvec![],
+        }
+    }
+
+    /// Registers whose value the instruction reads.
+    pub(super) fn read_registers(&self) -> SmallVec<[RegisterRef<'_>; 3]> {
+        match self {
+            Instruction::Set { .. } => smallvec![],
+            Instruction::Bop { src_l, src_r, .. } => smallvec![src_l.as_ref(), src_r.as_ref()],
+            Instruction::Branch { src, .. } => smallvec![src.as_ref()],
+            Instruction::CMov { cond, src, .. } => smallvec![cond.as_ref(), src.as_ref()],
+            Instruction::BranchReg { target } => smallvec![target.as_ref()],
+            Instruction::Load { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::Store { addr, src, .. } => smallvec![addr.as_ref(), src.as_ref()],
+            Instruction::StoreImm { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::Cas {
+                addr,
+                expected,
+                new_value,
+                ..
+            } |
+            Instruction::CasWeak {
+                addr,
+                expected,
+                new_value,
+                ..
+            } => smallvec![addr.as_ref(), expected.as_ref(), new_value.as_ref()],
+            Instruction::Fai { addr, .. } |
+            Instruction::TestAndSetBit { addr, .. } |
+            Instruction::TestAndClearBit { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::Fas { addr, src, .. } |
+            Instruction::Fmax { addr, src, .. } => smallvec![addr.as_ref(), src.as_ref()],
+            Instruction::LoadLinked { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::StoreConditional { addr, src, .. } => smallvec![addr.as_ref(), src.as_ref()],
+            Instruction::LoadN { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::LoadPair { addr1, addr2, .. } => smallvec![addr1.as_ref(), addr2.as_ref()],
+            Instruction::StoreN { addr, srcs, .. } => std::iter::once(addr)
+                .chain(srcs)
+                .map(|x| x.as_ref())
+                .collect(),
+            Instruction::LoadLocal { addr, .. } |
+            Instruction::LoadBank { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::StoreLocal { addr, src } |
+            Instruction::StoreBank { addr, src, .. } => smallvec![addr.as_ref(), src.as_ref()],
+            Instruction::GetPc { .. } => smallvec![],
+            Instruction::Assert { src, .. } |
+            Instruction::Print { src } => smallvec![src.as_ref()],
+            Instruction::Fence { .. } |
+            Instruction::Barrier { .. } |
+            Instruction::Yield => smallvec![],
+        }
+    }
+
+    /// Registers the instruction writes into.
+    pub(super) fn written_registers(&self) -> SmallVec<[RegisterRef<'_>; 1]> {
+        match self {
+            Instruction::Set { dest, .. } |
+            Instruction::Bop { dest, .. } |
+            Instruction::CMov { dest, .. } |
+            Instruction::Load { dest, .. } |
+            Instruction::Fai { dest, .. } |
+            Instruction::TestAndSetBit { dest, .. } |
+            Instruction::TestAndClearBit { dest, .. } |
+            Instruction::Fas { dest, .. } |
+            Instruction::Fmax { dest, .. } |
+            Instruction::LoadLinked { dest, .. } |
+            Instruction::LoadLocal { dest, .. } |
+            Instruction::LoadBank { dest, .. } |
+            Instruction::GetPc { dest } => smallvec![dest.as_ref()],
+            Instruction::StoreConditional { ok, .. } |
+            Instruction::CasWeak { ok, .. } => smallvec![ok.as_ref()],
+            Instruction::LoadN { dests, .. } => dests.iter().map(|x| x.as_ref()).collect(),
+            Instruction::LoadPair { dest1, dest2, .. } => [dest1, dest2].into_iter().map(|x| x.as_ref()).collect(),
+            Instruction::StoreN { .. } |
+            Instruction::StoreLocal { .. } |
+            Instruction::StoreBank { .. } |
+            Instruction::Assert { .. } |
+            Instruction::Print { .. } => smallvec![],
+            Instruction::Branch { .. } |
+            Instruction::BranchReg { .. } |
+            Instruction::Store { .. } |
+            Instruction::StoreImm { .. } |
+            Instruction::Cas { .. } |
+            Instruction::Fence { .. } |
+            Instruction::Barrier { .. } |
+            Instruction::Yield => smallvec![],
+        }
+    }
+
+    pub(super) fn referenced_label(&self) -> Option<LabelRef<'_>> {
+        match self {
+            Instruction::Branch { label, .. } => Some(label.as_ref()),
+            _ => None,
         }
     }
 
-    pub(super) fn execute(&self, state: &mut ThreadState) -> Result<Option<MemoryQuery>, ThreadStateError> {
+    /// The registers holding the addresses the instruction accesses. Empty
+    /// unless it accesses memory.
+    pub(super) fn address_registers(&self) -> SmallVec<[RegisterRef<'_>; 2]> {
+        match self {
+            Instruction::Load { addr, .. } |
+            Instruction::Store { addr, .. } |
+            Instruction::StoreImm { addr, .. } |
+            Instruction::Cas { addr, .. } |
+            Instruction::CasWeak { addr, .. } |
+            Instruction::Fai { addr, .. } |
+            Instruction::TestAndSetBit { addr, .. } |
+            Instruction::TestAndClearBit { addr, .. } |
+            Instruction::Fas { addr, .. } |
+            Instruction::Fmax { addr, .. } |
+            Instruction::LoadLinked { addr, .. } |
+            Instruction::StoreConditional { addr, .. } |
+            Instruction::LoadN { addr, .. } |
+            Instruction::StoreN { addr, .. } |
+            Instruction::LoadLocal { addr, .. } |
+            Instruction::StoreLocal { addr, .. } |
+            Instruction::LoadBank { addr, .. } |
+            Instruction::StoreBank { addr, .. } => smallvec![addr.as_ref()],
+            Instruction::LoadPair { addr1, addr2, .. } => smallvec![addr1.as_ref(), addr2.as_ref()],
+            Instruction::Set { .. } |
+            Instruction::Bop { .. } |
+            Instruction::Branch { .. } |
+            Instruction::BranchReg { .. } |
+            Instruction::CMov { .. } |
+            Instruction::GetPc { .. } |
+            Instruction::Assert { .. } |
+            Instruction::Fence { .. } |
+            Instruction::Barrier { .. } |
+            Instruction::Yield |
+            Instruction::Print { .. } => smallvec![],
+        }
+    }
+
+    pub(super) fn execute(&self, state: &mut ThreadState) -> Result<Option<MemoryQuery<'_>>, ThreadStateError> {
         match self {
             Instruction::Set { dest, value } => {
-                state.set_register(dest.as_ref(),*value);
+                state.set_register(dest.as_ref(), *value)?;
 
                 Ok(None)
             },
             Instruction::Bop { dest, binop, src_l, src_r } => {
                 let val_l = state.get_register(src_l.as_ref())?;
                 let val_r = state.get_register(src_r.as_ref())?;
-                let val = binop.eval(val_l, val_r)
-                    .map_err(|err| ThreadStateError::BinOpError {
-                        binop: *binop,
-                        err
-                    })?;
+                let val = match state.division_trap {
+                    Some(trap) => binop.eval_trapping(val_l, val_r, trap).map(|(val, trapped)| {
+                        state.trapped |= trapped;
+                        val
+                    }),
+                    None => binop.eval(val_l, val_r),
+                }.map_err(|err| ThreadStateError::BinOpError {
+                    binop: *binop,
+                    // The PC already points past the instruction
+                    pc: state.pc - 1,
+                    dest: dest.clone(),
+                    src_l: src_l.clone(),
+                    src_r: src_r.clone(),
+                    err
+                })?;
 
                 state.set_register(dest.as_ref(), val)?;
 
@@ -173,55 +790,253 @@ impl Instruction {
             },
             Instruction::Branch { src, label } => {
                 let val = state.get_register(src.as_ref())?;
-                if val.0 != 0 {
+                if val.is_truthy() {
                     state.goto_label(label.as_ref())?;
                 }
 
                 Ok(None)
             },
-            Instruction::Load { mode, addr, dest } => {
-                let addr = state.get_register(addr.as_ref())?.to_address();
+            Instruction::BranchReg { target } => {
+                let addr = state.get_address(target.as_ref())?;
+                state.set_pc(addr)?;
+
+                Ok(None)
+            },
+            Instruction::CMov { dest, cond, src } => {
+                let cond = state.get_register(cond.as_ref())?;
+                let val = state.get_register(src.as_ref())?;
+                if cond.is_truthy() {
+                    state.set_register(dest.as_ref(), val)?;
+                }
+
+                Ok(None)
+            },
+            Instruction::Load { mode, addr, offset, dest } => {
+                let addr = state.get_address_with_offset(addr.as_ref(), *offset)?;
 
                 Ok(Some(MemoryQuery::Load {
+                    bank: 0,
                     addr,
                     dest: dest.as_ref(),
                     mode: *mode
                 }))
             },
-            Instruction::Store { mode, addr, src } => {
-                let addr = state.get_register(addr.as_ref())?.to_address();
+            Instruction::LoadBank { mode, bank, addr, dest } => {
+                let addr = state.get_address(addr.as_ref())?;
+
+                Ok(Some(MemoryQuery::Load {
+                    bank: *bank,
+                    addr,
+                    dest: dest.as_ref(),
+                    mode: *mode
+                }))
+            },
+            Instruction::StoreBank { mode, bank, addr, src } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let value = state.get_register(src.as_ref())?;
+
+                Ok(Some(MemoryQuery::Store {
+                    bank: *bank,
+                    addr,
+                    value,
+                    mode: *mode
+                }))
+            },
+            Instruction::Store { mode, addr, offset, src } => {
+                let addr = state.get_address_with_offset(addr.as_ref(), *offset)?;
                 let value = state.get_register(src.as_ref())?;
 
                 Ok(Some(MemoryQuery::Store {
+                    bank: 0,
                     addr,
                     value,
                     mode: *mode
                 }))
             },
-            Instruction::Cas { mode, addr, expected, new_value } => {
-                let addr = state.get_register(addr.as_ref())?.to_address();
+            Instruction::StoreImm { mode, addr, value } => {
+                let addr = state.get_address(addr.as_ref())?;
+
+                Ok(Some(MemoryQuery::Store {
+                    bank: 0,
+                    addr,
+                    value: *value,
+                    mode: *mode
+                }))
+            },
+            Instruction::Cas { mode, bank, addr, expected, new_value } => {
+                let addr = state.get_address(addr.as_ref())?;
                 let expected = state.get_register(expected.as_ref())?;
                 let new_value = state.get_register(new_value.as_ref())?;
 
                 Ok(Some(MemoryQuery::Cas {
+                    bank: *bank,
+                    addr,
+                    expected,
+                    new_value,
+                    mode: *mode,
+                }))
+            },
+            Instruction::CasWeak { mode, bank, addr, expected, new_value, ok } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let expected = state.get_register(expected.as_ref())?;
+                let new_value = state.get_register(new_value.as_ref())?;
+
+                Ok(Some(MemoryQuery::CasWeak {
+                    bank: *bank,
                     addr,
                     expected,
                     new_value,
+                    ok: ok.as_ref(),
                     mode: *mode,
                 }))
             },
-            Instruction::Fai { mode, addr, dest } => {
-                let addr = state.get_register(addr.as_ref())?.to_address();
+            Instruction::Fai { mode, bank, addr, dest, policy } => {
+                let addr = state.get_address(addr.as_ref())?;
 
                 Ok(Some(MemoryQuery::Fai {
+                    bank: *bank,
                     addr,
                     dest: dest.as_ref(),
+                    policy: *policy,
+                    mode: *mode
+                }))
+            },
+            Instruction::TestAndSetBit { mode, bank, addr, bit, dest } |
+            Instruction::TestAndClearBit { mode, bank, addr, bit, dest } => {
+                if *bit >= 64 {
+                    return Err(ThreadStateError::BitOutOfRange { bit: *bit });
+                }
+                let addr = state.get_address(addr.as_ref())?;
+                let set = matches!(self, Instruction::TestAndSetBit { .. });
+
+                Ok(Some(MemoryQuery::TestAndModifyBit {
+                    bank: *bank,
+                    addr,
+                    bit: *bit,
+                    set,
+                    dest: dest.as_ref(),
+                    mode: *mode
+                }))
+            },
+            Instruction::Fas { mode, bank, addr, src, dest, policy } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let operand = state.get_register(src.as_ref())?;
+
+                Ok(Some(MemoryQuery::Fas {
+                    bank: *bank,
+                    addr,
+                    dest: dest.as_ref(),
+                    operand,
+                    policy: *policy,
+                    mode: *mode
+                }))
+            },
+            Instruction::Fmax { mode, bank, addr, src, dest } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let operand = state.get_register(src.as_ref())?;
+
+                Ok(Some(MemoryQuery::Fmax {
+                    bank: *bank,
+                    addr,
+                    dest: dest.as_ref(),
+                    operand,
+                    mode: *mode
+                }))
+            },
+            Instruction::LoadLinked { mode, addr, dest } => {
+                let addr = state.get_address(addr.as_ref())?;
+
+                Ok(Some(MemoryQuery::LoadLinked {
+                    addr,
+                    dest: dest.as_ref(),
+                    mode: *mode
+                }))
+            },
+            Instruction::StoreConditional { mode, addr, src, ok } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let value = state.get_register(src.as_ref())?;
+
+                Ok(Some(MemoryQuery::StoreConditional {
+                    addr,
+                    value,
+                    ok: ok.as_ref(),
                     mode: *mode
                 }))
             },
-            Instruction::Fence { mode } => Ok(Some(
-                MemoryQuery::Fence { mode: *mode }
+            Instruction::LoadN { mode, addr, dests } => {
+                let addr = state.get_address(addr.as_ref())?;
+
+                Ok(Some(MemoryQuery::LoadN {
+                    addr,
+                    dests,
+                    mode: *mode,
+                }))
+            },
+            Instruction::LoadPair { mode, addr1, addr2, dest1, dest2 } => {
+                let addr1 = state.get_address(addr1.as_ref())?;
+                let addr2 = state.get_address(addr2.as_ref())?;
+
+                Ok(Some(MemoryQuery::LoadPair {
+                    addrs: [addr1, addr2],
+                    dests: [dest1.as_ref(), dest2.as_ref()],
+                    mode: *mode,
+                }))
+            },
+            Instruction::StoreN { mode, addr, srcs } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let values = srcs.iter()
+                    .map(|x| state.get_register(x.as_ref()))
+                    .collect::<Result<_, _>>()?;
+
+                Ok(Some(MemoryQuery::StoreN {
+                    addr,
+                    values,
+                    mode: *mode,
+                }))
+            },
+            Instruction::LoadLocal { addr, dest } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let val = *state.local_cell_mut(addr)?;
+                state.set_register(dest.as_ref(), val)?;
+
+                Ok(None)
+            },
+            Instruction::StoreLocal { addr, src } => {
+                let addr = state.get_address(addr.as_ref())?;
+                let val = state.get_register(src.as_ref())?;
+                *state.local_cell_mut(addr)? = val;
+
+                Ok(None)
+            },
+            Instruction::GetPc { dest } => {
+                // The PC has already been advanced past this instruction
+                state.set_register(dest.as_ref(), Value(state.pc as u64))?;
+
+                Ok(None)
+            },
+            Instruction::Assert { src, expected } => {
+                let actual = state.get_register(src.as_ref())?;
+                if actual != *expected {
+                    return Err(ThreadStateError::AssertionFailed {
+                        register: src.clone(),
+                        expected: *expected,
+                        actual,
+                    });
+                }
+
+                Ok(None)
+            },
+            Instruction::Fence { mode, kind } => Ok(Some(
+                MemoryQuery::Fence { mode: *mode, kind: *kind }
             )),
+            Instruction::Barrier { id, participants } => Ok(Some(MemoryQuery::Barrier { id: *id, participants: *participants })),
+            Instruction::Yield => Ok(None),
+            Instruction::Print { src } => {
+                // The machine reads the value once the step succeeds
+                state.get_register(src.as_ref())?;
+
+                Ok(None)
+            },
         }
     }
 }
\ No newline at end of file
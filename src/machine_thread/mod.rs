@@ -1,43 +1,164 @@
 
 mod binop;
+#[allow(dead_code)] // Not every instruction the builder offers is used by the litmus tests
+pub mod builder;
+pub mod dependency;
+pub mod parser;
+#[cfg(test)]
+pub mod testkit;
 mod instruction;
 
+use std::{convert::Infallible, fmt, hash::{Hash, Hasher}};
+
 use tracing::{ debug, trace };
-use fnv::FnvHashMap;
-use instruction::Instruction;
+use fnv::{FnvHashMap, FnvHashSet};
+use smallvec::SmallVec;
+pub use instruction::Instruction;
 use thiserror::Error;
 
-use crate::{value::Value, register::{Register, RegisterRef}, label::{Label, LabelRef}, machine_memory::MemoryQuery};
+use crate::{value::{Value, AddressError}, register::{Register, RegisterRef}, label::{Label, LabelRef}, machine_memory::{MemoryQuery, checked_address_add}};
 
-use self::binop::{BinOpError, BinOp};
+pub use self::binop::{BinOpError, BinOp, OverflowPolicy};
+pub use self::parser::Span;
+pub use self::dependency::DepGraph;
 
 #[derive(Debug, Clone)]
 pub struct CodeInstruction {
     pub label: Option<Label>,
+    /// Documents where the control goes when the instruction doesn't jump.
+    /// Must name a label defined in the same program.
+    pub fallthrough: Option<Label>,
     pub instruction: Instruction,
+    /// Where the instruction (or its label) is in the source it has been parsed
+    /// from. Only used in the diagnostics.
+    pub span: Option<Span>,
+}
+
+/// What the registers hold before they are first written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InitPolicy {
+    /// The registers start at zero (or the value given to
+    /// [`new_with_registers`](ThreadState::new_with_registers)).
+    #[default]
+    ZeroInit,
+    /// Reading a register before writing it fails with
+    /// [`UninitializedRegister`](ThreadStateError::UninitializedRegister), unless
+    /// it has been given an initial value.
+    PoisonInit,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ThreadState<'a> {
     reg_map: FnvHashMap<Register, Value>,
     label_map: FnvHashMap<Label, usize>,
     program: &'a [CodeInstruction],
     pc: usize,
+    /// Where the thread starts and where [`reset`](ThreadState::reset) returns it.
+    initial_pc: usize,
+    /// Whether the register values are treated as signed when used as addresses.
+    signed_mode: bool,
+    /// How many times each kind of instruction has been executed.
+    instruction_counts: FnvHashMap<&'static str, u64>,
+    /// The registers which don't start at zero, along with their initial values.
+    initial_registers: FnvHashMap<Register, Value>,
+    /// Memory private to the thread. Empty unless sized with
+    /// [`set_local_memory_size`](ThreadState::set_local_memory_size).
+    local: Vec<Value>,
+    /// The value a division by zero gives. `None` makes it an error.
+    division_trap: Option<Value>,
+    /// Whether a division by zero has given the trap value.
+    trapped: bool,
+    init_policy: InitPolicy,
+    /// The registers which haven't been written yet under [`InitPolicy::PoisonInit`].
+    poisoned: FnvHashSet<Register>,
+}
+
+/// Only the registers (along with which are poisoned), the local memory, the trap
+/// flag and the PC are hashed, as the program and the labels never change and the
+/// rest is bookkeeping.
+impl<'a> Hash for ThreadState<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.registers().hash(state);
+        let mut poisoned = self.poisoned.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        poisoned.sort_unstable();
+        poisoned.hash(state);
+        self.local.hash(state);
+        self.trapped.hash(state);
+        self.pc.hash(state);
+    }
+}
+
+/// Formats the source locations of a [`DuplicateLabel`](ThreadStateCreationError::DuplicateLabel).
+fn fmt_spans(first: &Option<Span>, second: &Option<Span>) -> String {
+    match (first, second) {
+        (Some(first), Some(second)) => format!(" (at {first} and {second})"),
+        _ => String::new(),
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum ThreadStateCreationError {
     #[error("Received an empty program")]
     EmptyProgram,
-    #[error("Duplicate label \"{label}\" at instruction {first} and {second}")]
+    #[error("Duplicate label \"{label}\" at instruction {first} and {second}{}", fmt_spans(first_span, second_span))]
     DuplicateLabel {
         label: Label,
         first: usize,
         second: usize,
+        /// The [`span`](CodeInstruction::span) of instruction `first`.
+        first_span: Option<Span>,
+        /// The [`span`](CodeInstruction::span) of instruction `second`.
+        second_span: Option<Span>,
+    },
+    #[error("Instruction {at} refers to label \"{label}\", which is not defined")]
+    UndefinedLabelReference {
+        label: Label,
+        at: usize,
+    },
+    #[error("Instruction {at} uses register \"{register}\" as an address, but no path to it sets the register")]
+    AddressRegisterNeverSet {
+        register: Register,
+        at: usize,
+    },
+    #[error("Register \"{register}\" is initialised, but the program never uses it")]
+    UnusedRegisterInit {
+        register: Register,
+    },
+    #[error("Can't start at instruction {address}, the program has {len}")]
+    StartPcOutOfRange {
+        address: usize,
+        len: usize,
     },
 }
 
-#[derive(Debug, Error)]
+/// Suspicious register usage found by [`ThreadState::analyze`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterWarning {
+    /// The register is written to, but its value is never read.
+    WriteOnly {
+        register: Register,
+    },
+    /// The first (in program order) use of the register reads it, so it
+    /// may still hold its initial value.
+    ReadBeforeWrite {
+        register: Register,
+        at: usize,
+    },
+}
+
+impl fmt::Display for RegisterWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterWarning::WriteOnly { register } => write!(f, "Register {register} is never read"),
+            RegisterWarning::ReadBeforeWrite { register, at } => write!(
+                f,
+                "Register {register} is read at instruction {at} before being written",
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
 pub enum ThreadStateError {
     #[error("Register {register} is not used in this program")]
     UnboundRegister {
@@ -51,21 +172,60 @@ pub enum ThreadStateError {
     PcOutOfRange {
         address: usize,
     },
-    #[error("Binop \"{binop}\" has failed to execute")]
+    #[error("Binop \"{binop}\" has failed to execute at instruction {pc}: {dest} = {src_l} {binop} {src_r}")]
     BinOpError {
         binop: BinOp,
+        /// The address of the failed instruction.
+        pc: usize,
+        dest: Register,
+        src_l: Register,
+        src_r: Register,
         #[source] err: BinOpError,
     },
+    #[error("Assertion failed: expected {register} to be {expected}, found {actual}")]
+    AssertionFailed {
+        register: Register,
+        expected: Value,
+        actual: Value,
+    },
+    #[error("Register value can't be used as an address")]
+    AddressError {
+        #[source] err: AddressError,
+    },
+    #[error("Address {base} displaced by {offset} doesn't fit into the address space")]
+    AddressOverflow {
+        base: usize,
+        offset: Value,
+    },
+    #[error("Register {register} is read before being written")]
+    UninitializedRegister {
+        register: Register,
+    },
+    #[error("Bit {bit} doesn't fit into a value")]
+    BitOutOfRange {
+        bit: u32,
+    },
+    #[error("Local address {addr} out of range")]
+    LocalAddressOutOfRange {
+        addr: usize,
+    },
 }
 
 impl<'a> ThreadState<'a> {
-    fn add_label(label_map: &mut FnvHashMap<Label, usize>, label: Label, addr: usize) -> Result<(), ThreadStateCreationError> {
+    fn add_label(
+        label_map: &mut FnvHashMap<Label, usize>,
+        program: &[CodeInstruction],
+        label: Label,
+        addr: usize,
+    ) -> Result<(), ThreadStateCreationError> {
 
         if let Some(old_addr) = label_map.insert(label.clone(), addr) {
             return Err(ThreadStateCreationError::DuplicateLabel {
                 label,
                 first: addr,
-                second: old_addr
+                second: old_addr,
+                first_span: program[addr].span,
+                second_span: program[old_addr].span,
             });
         }
 
@@ -79,6 +239,21 @@ impl<'a> ThreadState<'a> {
             return Err(ThreadStateCreationError::EmptyProgram);
         }
 
+        Self::new_allow_empty(program)
+    }
+
+    /// Like [`new`](ThreadState::new), but an empty program is accepted and
+    /// gives a thread which is halted from the start.
+    pub fn new_allow_empty(program: &'a [CodeInstruction]) -> Result<Self, ThreadStateCreationError> {
+        let state = Self::build(program)?;
+        state.check_address_registers()?;
+
+        Ok(state)
+    }
+
+    /// Creates the thread without checking the address registers, see
+    /// [`check_address_registers`](ThreadState::check_address_registers).
+    fn build(program: &'a [CodeInstruction]) -> Result<Self, ThreadStateCreationError> {
         let mut reg_map = FnvHashMap::default();
         let mut label_map = FnvHashMap::default();
 
@@ -87,22 +262,121 @@ impl<'a> ThreadState<'a> {
 
             debug!("{addr:0>5}\t{:>32}\t{:?}", code_instruction.instruction, used_registers);
             used_registers.into_iter().for_each(|x| {
-                reg_map.insert(x.to_box().immortalize(), Value(0));
+                reg_map.insert(x.to_box().immortalize(), Value::ZERO);
             });
 
             if let Some(label) = &code_instruction.label {
-                Self::add_label(&mut label_map, label.to_owned(), addr)?;
+                Self::add_label(&mut label_map, program, label.to_owned(), addr)?;
+            }
+        }
+
+        Self::check_label_references(program, &label_map)?;
+
+        Ok(ThreadState {
+            reg_map,
+            label_map,
+            program,
+            pc: 0,
+            initial_pc: 0,
+            signed_mode: false,
+            instruction_counts: FnvHashMap::default(),
+            initial_registers: FnvHashMap::default(),
+            local: Vec::new(),
+            division_trap: None,
+            trapped: false,
+            init_policy: InitPolicy::ZeroInit,
+            poisoned: FnvHashSet::default(),
+        })
+    }
+
+    /// Like [`new`](ThreadState::new), but the registers in `inits` start with
+    /// the given values instead of zero. [`reset`](ThreadState::reset) restores
+    /// these values too.
+    pub fn new_with_registers(
+        program: &'a [CodeInstruction],
+        inits: &FnvHashMap<Register, Value>,
+    ) -> Result<Self, ThreadStateCreationError> {
+        if program.is_empty() {
+            return Err(ThreadStateCreationError::EmptyProgram);
+        }
+        let mut state = Self::build(program)?;
+
+        for (register, value) in inits {
+            match state.reg_map.get_mut(register) {
+                Some(x) => *x = *value,
+                None => return Err(ThreadStateCreationError::UnusedRegisterInit {
+                    register: register.clone(),
+                }),
             }
         }
+        state.initial_registers = inits.clone();
+        state.check_address_registers()?;
 
-        Ok(ThreadState { reg_map, label_map, program, pc: 0 })
+        Ok(state)
+    }
+
+    /// Checks that every register used as an address is either given an initial
+    /// value or written on some path to the access. Reading an address register
+    /// nobody sets most likely means a forgotten initialisation.
+    fn check_address_registers(&self) -> Result<(), ThreadStateCreationError> {
+        let unset = dependency::unset_address_register(self.program, &self.label_map, |register| {
+            self.initial_registers.contains_key(register.as_str())
+        });
+
+        match unset {
+            Some((at, register)) => Err(ThreadStateCreationError::AddressRegisterNeverSet {
+                register: register.to_box().immortalize(),
+                at,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`new`](ThreadState::new), but the thread starts executing at
+    /// instruction `pc`. [`reset`](ThreadState::reset) returns there too.
+    pub fn new_at(program: &'a [CodeInstruction], pc: usize) -> Result<Self, ThreadStateCreationError> {
+        let mut state = Self::new(program)?;
+
+        if pc > program.len() {
+            return Err(ThreadStateCreationError::StartPcOutOfRange { address: pc, len: program.len() });
+        }
+        state.pc = pc;
+        state.initial_pc = pc;
+
+        Ok(state)
+    }
+
+    fn check_label_references(
+        program: &[CodeInstruction],
+        label_map: &FnvHashMap<Label, usize>,
+    ) -> Result<(), ThreadStateCreationError> {
+        for (at, code_instruction) in program.iter().enumerate() {
+            let referenced = code_instruction.instruction.referenced_label()
+                .into_iter()
+                .chain(code_instruction.fallthrough.as_ref().map(|x| x.as_ref()));
+
+            for label in referenced {
+                if !label_map.contains_key(&label.to_box()) {
+                    return Err(ThreadStateCreationError::UndefinedLabelReference {
+                        label: label.to_box().immortalize(),
+                        at,
+                    });
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn set_register(&mut self, register: RegisterRef, val: Value) -> Result<(), ThreadStateError> {
         debug!("{register} <- {val}");
 
         match self.reg_map.get_mut(register.as_str()) {
-            Some(x) => { *x = val; Ok(()) },
+            Some(x) => {
+                *x = val;
+                self.poisoned.remove(register.as_str());
+                Ok(())
+            },
             None => Err(ThreadStateError::UnboundRegister {
                 register: register.to_box().immortalize(),
             }),
@@ -112,6 +386,12 @@ impl<'a> ThreadState<'a> {
     pub fn get_register(&self, register: RegisterRef) -> Result<Value, ThreadStateError> {
         debug!("{register} ->");
 
+        if self.poisoned.contains(register.as_str()) {
+            return Err(ThreadStateError::UninitializedRegister {
+                register: register.to_box().immortalize(),
+            });
+        }
+
         match self.reg_map.get(&register.to_box()) {
             Some(x) => Ok(*x),
             None => Err(ThreadStateError::UnboundRegister {
@@ -120,6 +400,90 @@ impl<'a> ThreadState<'a> {
         }
     }
 
+    /// In signed mode negative values are rejected when used as addresses
+    /// instead of being interpreted as huge unsigned ones.
+    pub fn set_signed_mode(&mut self, signed_mode: bool) {
+        self.signed_mode = signed_mode;
+    }
+
+    /// Sets what the registers hold before they are first written. Takes effect
+    /// right away, so it should be set before the thread is stepped.
+    pub fn set_init_policy(&mut self, policy: InitPolicy) {
+        self.init_policy = policy;
+        self.poison_registers();
+    }
+
+    fn poison_registers(&mut self) {
+        self.poisoned.clear();
+        if self.init_policy == InitPolicy::PoisonInit {
+            self.poisoned.extend(
+                self.reg_map.keys()
+                    .filter(|x| !self.initial_registers.contains_key(*x))
+                    .cloned()
+            );
+        }
+    }
+
+    /// Resizes the thread's local memory to `size` zeroed cells.
+    pub fn set_local_memory_size(&mut self, size: usize) {
+        self.local = vec![Value(0); size];
+    }
+
+    pub fn local_memory(&self) -> &[Value] {
+        &self.local
+    }
+
+    fn local_cell_mut(&mut self, addr: usize) -> Result<&mut Value, ThreadStateError> {
+        self.local.get_mut(addr)
+            .ok_or(ThreadStateError::LocalAddressOutOfRange { addr })
+    }
+
+    /// Makes a division by zero store `trap` into the destination and set the
+    /// [`trapped`](ThreadState::trapped) flag instead of failing. `None` restores
+    /// the default, which is failing with [`BinOpError::DivisionByZero`].
+    pub fn set_division_trap(&mut self, trap: Option<Value>) {
+        self.division_trap = trap;
+    }
+
+    /// Whether a division by zero has given the trap value since the last reset.
+    pub fn trapped(&self) -> bool {
+        self.trapped
+    }
+
+    /// Like [`get_address`](ThreadState::get_address), but adds `offset` to the address.
+    pub fn get_address_with_offset(&self, register: RegisterRef, offset: Value) -> Result<usize, ThreadStateError> {
+        let base = self.get_address(register)?;
+
+        offset.try_to_address().ok()
+            .and_then(|offset| checked_address_add::<Infallible>(base, offset).ok())
+            .ok_or(ThreadStateError::AddressOverflow { base, offset })
+    }
+
+    /// Reads the register and converts its value into an address.
+    pub fn get_address(&self, register: RegisterRef) -> Result<usize, ThreadStateError> {
+        let value = self.get_register(register)?;
+        let addr = if self.signed_mode {
+            value.try_to_address_signed()
+        } else {
+            value.try_to_address()
+        };
+
+        addr.map_err(|err| ThreadStateError::AddressError { err })
+    }
+
+    /// Moves the thread to instruction `addr`. Jumping right past the last
+    /// instruction is allowed and halts the thread.
+    pub fn set_pc(&mut self, addr: usize) -> Result<(), ThreadStateError> {
+        debug!("PC <- {addr}");
+
+        if addr > self.program.len() {
+            return Err(ThreadStateError::PcOutOfRange { address: addr });
+        }
+        self.pc = addr;
+
+        Ok(())
+    }
+
     pub fn goto_label(&mut self, label: LabelRef) -> Result<(), ThreadStateError> {
         debug!("GOTO {label:?}");
 
@@ -131,12 +495,182 @@ impl<'a> ThreadState<'a> {
         }
     }
 
+    /// Lints the register usage of the program. The warnings are sorted by register name.
+    pub fn analyze(&self) -> Vec<RegisterWarning> {
+        let mut first_read = FnvHashMap::<RegisterRef, usize>::default();
+        let mut first_write = FnvHashMap::<RegisterRef, usize>::default();
+
+        for (addr, code_instruction) in self.program.iter().enumerate() {
+            let instruction = &code_instruction.instruction;
+
+            for register in instruction.read_registers() {
+                first_read.entry(register).or_insert(addr);
+            }
+            for register in instruction.written_registers() {
+                first_write.entry(register).or_insert(addr);
+            }
+        }
+
+        let mut registers = self.reg_map.keys().collect::<Vec<_>>();
+        registers.sort_unstable_by(|l, r| l.as_str().cmp(r.as_str()));
+
+        registers.into_iter().filter_map(|register| {
+            let read = first_read.get(&register.as_ref()).copied();
+            let write = first_write.get(&register.as_ref()).copied();
+
+            match (read, write) {
+                (None, Some(_)) => Some(RegisterWarning::WriteOnly {
+                    register: register.clone(),
+                }),
+                (Some(at), None) => Some(RegisterWarning::ReadBeforeWrite {
+                    register: register.clone(),
+                    at,
+                }),
+                (Some(at), Some(write)) if at <= write => Some(RegisterWarning::ReadBeforeWrite {
+                    register: register.clone(),
+                    at,
+                }),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// The static dependencies between the instructions of the program. An
+    /// instruction depends on every instruction whose written register may
+    /// reach one of its inputs, with the [address](dependency::DepKind::Address) of a memory
+    /// access marked apart from the [data](dependency::DepKind::Data). A memory access
+    /// which may run after a branch has a [control](dependency::DepKind::Control)
+    /// dependency on it.
+    pub fn dependency_graph(&self) -> DepGraph {
+        dependency::dependency_graph(self.program, &self.label_map)
+    }
+
+    /// Brings the thread back to its initial state: the registers get their
+    /// initial values (zero unless given to [`new_with_registers`](ThreadState::new_with_registers))
+    /// and the PC points to the first instruction.
+    pub fn reset(&mut self) {
+        for (register, value) in self.reg_map.iter_mut() {
+            *value = self.initial_registers.get(register)
+                .copied()
+                .unwrap_or(Value::ZERO);
+        }
+        self.local.fill(Value(0));
+        self.trapped = false;
+        self.poison_registers();
+        self.pc = self.initial_pc;
+        self.instruction_counts.clear();
+    }
+
+    /// How many times each kind of instruction (see [`Instruction::name`]) has been executed.
+    pub fn instruction_counts(&self) -> FnvHashMap<&'static str, u64> {
+        self.instruction_counts.clone()
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// All the instructions of the program along with their addresses, in order.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, &'a CodeInstruction)> + '_ {
+        self.program.iter().enumerate()
+    }
+
+    /// Like [`instructions`](ThreadState::instructions), but also gives the
+    /// registers each instruction uses.
+    pub fn instructions_with_registers(
+        &self,
+    ) -> impl Iterator<Item = (usize, &'a CodeInstruction, SmallVec<[RegisterRef<'a>; 3]>)> + '_ {
+        self.instructions().map(|(addr, x)| (addr, x, x.instruction.used_registers()))
+    }
+
+    /// The instruction the thread will execute on its next step. `None` if it has halted.
+    /// It borrows the program rather than the thread, so it can be kept across the
+    /// [`step`](ThreadState::step) executing it.
+    pub fn next_instruction(&self) -> Option<&'a Instruction> {
+        self.program.get(self.pc).map(|x| &x.instruction)
+    }
+
+    /// Whether the thread has run past its last instruction.
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// The labels the program defines along with their addresses, sorted by address.
+    pub fn labels(&self) -> Vec<(LabelRef<'_>, usize)> {
+        let mut labels = self.label_map.iter()
+            .map(|(label, addr)| (label.as_ref(), *addr))
+            .collect::<Vec<_>>();
+        labels.sort_unstable_by_key(|(_, addr)| *addr);
+
+        labels
+    }
+
+    /// Renders the program one instruction per line, prefixed with its address
+    /// and label. Branches are annotated with the address they jump to.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+
+        for (addr, code_instruction) in self.program.iter().enumerate() {
+            let label = code_instruction.label.as_ref()
+                .map(|x| format!("{x}:"))
+                .unwrap_or_default();
+            out.push_str(&format!("{addr:0>5} {label:<12} {}", code_instruction.instruction));
+
+            if let Some(target) = code_instruction.instruction.referenced_label() {
+                if let Some(target_addr) = self.label_map.get(&target.to_box()) {
+                    out.push_str(&format!("    // -> {target_addr:0>5}"));
+                }
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// The registers and their values, sorted by register name.
+    pub fn registers(&self) -> Vec<(&Register, Value)> {
+        let mut registers = self.reg_map.iter()
+            .map(|(register, value)| (register, *value))
+            .collect::<Vec<_>>();
+        registers.sort_unstable_by(|(l, _), (r, _)| l.as_str().cmp(r.as_str()));
+
+        registers
+    }
+
+    /// Sets every register in `values`, like [`set_register`](ThreadState::set_register)
+    /// does for one. Fails without changing anything if some register isn't
+    /// used by the program.
+    pub fn set_registers(&mut self, values: &FnvHashMap<Register, Value>) -> Result<(), ThreadStateError> {
+        if let Some(register) = values.keys().find(|x| !self.reg_map.contains_key(*x)) {
+            return Err(ThreadStateError::UnboundRegister { register: register.clone() });
+        }
+
+        for (register, value) in values {
+            self.set_register(register.as_ref(), *value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders the registers (sorted by name), the PC and the halted status.
+    pub fn dump(&self) -> String {
+        let registers = self.registers();
+
+        let mut out = format!("pc = {}, halted = {}\n", self.pc, self.is_halted());
+        for (register, value) in registers {
+            out.push_str(&format!("{register} = {value}\n"));
+        }
+
+        out
+    }
+
     pub fn step(&mut self) -> Result<Option<MemoryQuery<'a>>, ThreadStateError> {
         let instruction_to_run = self.pc;
         let instruction = self.program.get(instruction_to_run)
             .ok_or(ThreadStateError::PcOutOfRange { address: self.pc })?;
 
         self.pc += 1;
+        *self.instruction_counts.entry(instruction.instruction.name()).or_default() += 1;
         let res = instruction.instruction.execute(self);
         trace!(
             "{instruction_to_run:0>5} -> {:0>5} {:>32}: {res:?}",
@@ -146,4 +680,485 @@ impl<'a> ThreadState<'a> {
 
         res
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::{Machine, MachineError, machine_memory::{AccessMode, sc::ScMemory}, register};
+
+    use super::{builder::ProgramBuilder, dependency::{DepKind, Dependency}, *};
+
+    /// Steps the thread until it halts. The program must not access the memory.
+    fn run_to_end(state: &mut ThreadState) {
+        while !state.is_halted() {
+            assert!(state.step().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn dump_shows_pc_and_sorted_registers() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("b", 2)
+                .set("a", 1)
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        state.step().unwrap();
+
+        assert_eq!(state.dump(), "pc = 1, halted = false\na = 0\nb = 2\n");
+        run_to_end(&mut state);
+        assert_eq!(state.dump(), "pc = 2, halted = true\na = 1\nb = 2\n");
+    }
+
+    #[test]
+    fn address_register_never_set_is_reported() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+
+        assert!(matches!(
+            ThreadState::new(&program[0]),
+            Err(ThreadStateCreationError::AddressRegisterNeverSet { register, at: 0 }) if register.as_str() == "x",
+        ));
+        assert!(matches!(
+            Machine::<ScMemory>::new(&program, 4),
+            Err(MachineError::ThreadCreation {
+                tid: 0,
+                err: ThreadStateCreationError::AddressRegisterNeverSet { at: 0, .. },
+            }),
+        ));
+    }
+
+    #[test]
+    fn address_register_set_later_is_accepted() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+        let inits = [(RegisterRef::new("x").to_box().immortalize(), Value(2))]
+            .into_iter()
+            .collect::<FnvHashMap<_, _>>();
+        let mut state = ThreadState::new_with_registers(&program[0], &inits).unwrap();
+        assert!(matches!(state.step(), Ok(Some(MemoryQuery::Load { addr: 2, .. }))));
+
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 1)
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+        assert!(ThreadState::new(&program[0]).is_ok());
+        assert!(Machine::<ScMemory>::new(&program, 4).is_ok());
+    }
+
+    #[test]
+    fn dangling_fallthrough_label_is_rejected() {
+        let mut program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+            .build();
+        program[0][0].fallthrough = Some(LabelRef::new("next").to_box().immortalize());
+
+        let err = ThreadState::new(&program[0]).unwrap_err();
+        assert!(matches!(
+            err,
+            ThreadStateCreationError::UndefinedLabelReference { label, at: 0 } if label.as_str() == "next",
+        ));
+    }
+
+    #[test]
+    fn branch_to_undefined_label_is_rejected() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+                .label("loop")
+                .branch("a", "lopp")
+            .build();
+
+        let err = ThreadState::new(&program[0]).unwrap_err();
+        assert!(matches!(
+            err,
+            ThreadStateCreationError::UndefinedLabelReference { label, at: 1 } if label.as_str() == "lopp",
+        ));
+    }
+
+    #[test]
+    fn analyze_reports_write_only_and_read_before_write() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+                .bop("c", BinOp::Add, "a", "d")
+            .build();
+        let state = ThreadState::new(&program[0]).unwrap();
+
+        let warnings = state.analyze();
+        assert_eq!(warnings.len(), 2);
+        assert!(matches!(&warnings[0], RegisterWarning::WriteOnly { register } if register.as_str() == "c"));
+        assert!(matches!(&warnings[1], RegisterWarning::ReadBeforeWrite { register, at: 1 } if register.as_str() == "d"));
+    }
+
+    #[test]
+    fn disassembly_resolves_branch_targets() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("one", 1)
+                .label("loop")
+                .branch("one", "loop")
+            .build();
+        let state = ThreadState::new(&program[0]).unwrap();
+
+        assert_eq!(state.disassemble(), concat!(
+            "00000              one = 1\n",
+            "00001 loop:        if one goto loop    // -> 00001\n",
+        ));
+    }
+
+    #[test]
+    fn negative_address_is_rejected_in_signed_mode() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .push(Instruction::Set { dest: register::parse("p").unwrap(), value: Value::from_i64(-1) })
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        run_to_end(&mut state);
+
+        assert_eq!(state.get_address(RegisterRef::new("p")).unwrap(), u64::MAX as usize);
+        state.set_signed_mode(true);
+        assert!(matches!(
+            state.get_address(RegisterRef::new("p")),
+            Err(ThreadStateError::AddressError { err: AddressError::NegativeAddress { value: -1 } }),
+        ));
+    }
+
+    #[test]
+    fn get_pc_captures_the_next_address() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 0)
+                .get_pc("here")
+                .set("b", 0)
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        assert_eq!(program[0][1].instruction.to_string(), "here = pc");
+
+        run_to_end(&mut state);
+        assert_eq!(state.get_register(RegisterRef::new("here")).unwrap(), Value(2));
+    }
+
+    #[test]
+    fn instruction_counts_of_a_loop() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("n", 4)
+                .set("one", 1)
+                .label("loop")
+                .bop("n", BinOp::Sub, "n", "one")
+                .branch("n", "loop")
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        run_to_end(&mut state);
+
+        let counts = state.instruction_counts();
+        assert_eq!(counts.len(), 3);
+        assert_eq!((counts["set"], counts["bop"], counts["branch"]), (2, 4, 4));
+    }
+
+    #[test]
+    fn registers_start_with_their_inits() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .bop("r1", BinOp::Add, "r0", "r0")
+            .build();
+        let r0 = register::parse("r0").unwrap();
+
+        let inits = FnvHashMap::from_iter([(r0.clone(), Value(42))]);
+        let state = ThreadState::new_with_registers(&program[0], &inits).unwrap();
+        assert_eq!(state.get_register(r0.as_ref()).unwrap(), Value(42));
+        assert_eq!(state.get_register(RegisterRef::new("r1")).unwrap(), Value(0));
+
+        let inits = FnvHashMap::from_iter([(register::parse("r9").unwrap(), Value(1))]);
+        assert!(matches!(
+            ThreadState::new_with_registers(&program[0], &inits),
+            Err(ThreadStateCreationError::UnusedRegisterInit { register }) if register.as_str() == "r9",
+        ));
+    }
+
+    #[test]
+    fn labels_are_sorted_by_address() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("one", 1)
+                .label("zeta")
+                .set("a", 0)
+                .label("alpha")
+                .branch("one", "zeta")
+            .build();
+        let state = ThreadState::new(&program[0]).unwrap();
+
+        let labels = state.labels()
+            .into_iter()
+            .map(|(label, addr)| (label.to_string(), addr))
+            .collect::<Vec<_>>();
+        assert_eq!(labels, [("zeta".to_owned(), 1), ("alpha".to_owned(), 2)]);
+    }
+
+    #[test]
+    fn division_by_zero_traps_or_fails() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 7)
+                .bop("q", BinOp::Div, "a", "zero")
+            .build();
+
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        state.step().unwrap();
+        assert!(matches!(state.step(), Err(ThreadStateError::BinOpError { err: BinOpError::DivisionByZero, .. })));
+
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        state.set_division_trap(Some(Value::MAX));
+        run_to_end(&mut state);
+        assert!(state.trapped());
+        assert_eq!(state.get_register(RegisterRef::new("q")).unwrap(), Value::MAX);
+    }
+
+    #[test]
+    fn cmov_moves_only_when_taken() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("src", 7)
+                .set("yes", 1)
+                .set("taken", 3)
+                .set("untaken", 3)
+                .cmov("taken", "yes", "src")
+                .cmov("untaken", "no", "src")
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        let cmov = &program[0][4].instruction;
+        assert_eq!(cmov.to_string(), "taken = src if yes");
+        assert_eq!(cmov.used_registers().iter().map(|x| x.as_str()).collect::<Vec<_>>(), ["taken", "yes", "src"]);
+
+        run_to_end(&mut state);
+        assert_eq!(state.get_register(RegisterRef::new("taken")).unwrap(), Value(7));
+        assert_eq!(state.get_register(RegisterRef::new("untaken")).unwrap(), Value(3));
+        assert_eq!(state.pc(), 6);
+    }
+
+    #[test]
+    fn thread_starts_at_the_given_pc() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+                .set("b", 2)
+                .set("c", 3)
+            .build();
+        let mut state = ThreadState::new_at(&program[0], 2).unwrap();
+
+        assert_eq!(state.next_instruction().map(|x| x.to_string()).as_deref(), Some("c = 3"));
+        run_to_end(&mut state);
+        assert_eq!(state.dump(), "pc = 3, halted = true\na = 0\nb = 0\nc = 3\n");
+
+        assert!(matches!(state.set_pc(4), Err(ThreadStateError::PcOutOfRange { address: 4 })));
+        assert!(matches!(
+            ThreadState::new_at(&program[0], 4),
+            Err(ThreadStateCreationError::StartPcOutOfRange { address: 4, len: 3 }),
+        ));
+    }
+
+    #[test]
+    fn branch_jumps_on_any_truthy_value() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("two", 2)
+                .branch("two", "skip")
+                .set("skipped", 1)
+                .label("skip")
+                .branch("zero", "end")
+                .set("reached", 1)
+                .label("end")
+                .set("done", 1)
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        run_to_end(&mut state);
+
+        let register = |name| state.get_register(RegisterRef::new(name)).unwrap();
+        assert_eq!((register("skipped"), register("reached")), (Value::ZERO, Value::ONE));
+    }
+
+    #[test]
+    fn jump_table_reaches_both_entries() {
+        // The table in the local memory holds the addresses of the two cases
+        let program = |entry| ProgramBuilder::new()
+            .thread()
+                .set("case_a", 9)
+                .set("case_b", 12)
+                .set("zero", 0)
+                .set("one", 1)
+                .store_local("zero", "case_a")
+                .store_local("one", "case_b")
+                .set("entry", entry)
+                .load_local("entry", "target")
+                .branch_reg("target")
+                .set("a", 1)
+                .set("end", 13)
+                .branch_reg("end")
+                .set("b", 1)
+                .set("done", 1)
+            .build();
+
+        for (entry, expected) in [(0, (Value::ONE, Value::ZERO)), (1, (Value::ZERO, Value::ONE))] {
+            let program = program(entry);
+            assert_eq!(program[0][8].instruction.to_string(), "goto *target");
+            let mut state = ThreadState::new(&program[0]).unwrap();
+            state.set_local_memory_size(2);
+            run_to_end(&mut state);
+
+            let register = |name| state.get_register(RegisterRef::new(name)).unwrap();
+            assert_eq!((register("a"), register("b")), expected);
+            assert_eq!(register("done"), Value::ONE);
+        }
+    }
+
+    #[test]
+    fn dependency_edges_of_a_small_program() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .load(AccessMode::SeqCst, "p", "a")
+                .set("one", 1)
+                .bop("b", BinOp::Add, "a", "one")
+                .branch("b", "skip")
+                .store(AccessMode::SeqCst, "p", "b")
+                .label("skip")
+                .set("done", 1)
+            .build();
+        let state = ThreadState::new(&program[0]).unwrap();
+
+        let edges = state.dependency_graph()
+            .edges()
+            .map(|Dependency { from, to, kind }| (*from, *to, *kind))
+            .collect::<Vec<_>>();
+        assert_eq!(edges, [
+            (0, 1, DepKind::Address),
+            (0, 5, DepKind::Address),
+            (1, 3, DepKind::Data),
+            (2, 3, DepKind::Data),
+            (3, 4, DepKind::Data),
+            (3, 5, DepKind::Data),
+            (4, 5, DepKind::Control),
+        ]);
+    }
+
+    #[test]
+    fn binop_error_names_the_instruction() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 7)
+                .set("b", 1)
+                .bop("q", BinOp::Div, "a", "zero")
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        state.step().unwrap();
+        state.step().unwrap();
+
+        let err = state.step().unwrap_err();
+        let ThreadStateError::BinOpError { binop, pc, dest, src_l, src_r, err: source } = &err else {
+            panic!("unexpected error {err}");
+        };
+        assert_eq!((*binop, *pc), (BinOp::Div, 2));
+        assert!(matches!(source, BinOpError::DivisionByZero));
+        assert_eq!([dest, src_l, src_r].map(|x| x.as_str()), ["q", "a", "zero"]);
+        assert!(err.to_string().ends_with("at instruction 2: q = a / zero"), "{err}");
+    }
+
+    #[test]
+    fn instructions_with_their_registers() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .load(AccessMode::SeqCst, "p", "a")
+                .bop("b", BinOp::Add, "a", "a")
+            .build();
+        let state = ThreadState::new(&program[0]).unwrap();
+
+        let listed = state.instructions_with_registers()
+            .map(|(addr, x, registers)| {
+                let registers = registers.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+                format!("{addr} {}: {}", x.instruction.name(), registers.join(" "))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(listed, ["0 set: p", "1 load: p a", "2 bop: b a a"]);
+        assert!(state.instructions().all(|(addr, x)| std::ptr::eq(x, &program[0][addr])));
+    }
+
+    #[test]
+    fn poisoned_register_read_before_write() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("a", 1)
+                .bop("c", BinOp::Add, "a", "b")
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        state.set_init_policy(InitPolicy::PoisonInit);
+
+        assert!(matches!(
+            state.get_register(RegisterRef::new("a")),
+            Err(ThreadStateError::UninitializedRegister { register }) if register.as_str() == "a",
+        ));
+        state.step().unwrap();
+        assert_eq!(state.get_register(RegisterRef::new("a")).unwrap(), Value(1));
+        assert!(matches!(
+            state.step(),
+            Err(ThreadStateError::UninitializedRegister { register }) if register.as_str() == "b",
+        ));
+
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        run_to_end(&mut state);
+        assert_eq!(state.get_register(RegisterRef::new("c")).unwrap(), Value(1));
+    }
+
+    #[test]
+    fn next_instruction_is_the_one_stepped() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .store(AccessMode::Rel, "p", "p")
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+
+        let next = state.next_instruction().unwrap();
+        assert!(std::ptr::eq(next, &program[0][0].instruction));
+        assert!(state.step().unwrap().is_none());
+
+        let next = state.next_instruction().unwrap();
+        assert_eq!(next.to_string(), "store REL ##p p");
+        assert!(matches!(state.step().unwrap(), Some(MemoryQuery::Store { mode: AccessMode::Rel, addr: 0, .. })));
+        assert!(state.is_halted());
+        assert!(state.next_instruction().is_none());
+    }
+
+    #[test]
+    fn register_file_is_set_in_bulk() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("b", 2)
+                .set("a", 1)
+            .build();
+        let mut state = ThreadState::new(&program[0]).unwrap();
+        run_to_end(&mut state);
+
+        let file = state.registers()
+            .into_iter()
+            .map(|(register, value)| (register.clone(), value))
+            .collect::<Vec<_>>();
+        assert_eq!(file.iter().map(|(x, v)| (x.as_str(), *v)).collect::<Vec<_>>(), [("a", Value(1)), ("b", Value(2))]);
+
+        let mut update = file.into_iter().collect::<FnvHashMap<_, _>>();
+        update.values_mut().for_each(|x| *x = Value(x.0 * 10));
+        state.set_registers(&update).unwrap();
+        assert_eq!(state.get_register(RegisterRef::new("a")).unwrap(), Value(10));
+        assert_eq!(state.get_register(RegisterRef::new("b")).unwrap(), Value(20));
+
+        update.insert(register::parse("c").unwrap(), Value(3));
+        assert!(matches!(state.set_registers(&update), Err(ThreadStateError::UnboundRegister { register }) if register.as_str() == "c"));
+        assert_eq!(state.get_register(RegisterRef::new("a")).unwrap(), Value(10));
+    }
+}
@@ -0,0 +1,636 @@
+use std::fmt;
+
+use byteyarn::YarnRef;
+use thiserror::Error;
+
+use crate::{value::Value, machine_memory::{AccessMode, FenceKind}, register::RegisterRef, label::LabelRef};
+
+use super::{CodeInstruction, Register, Label, binop::{BinOp, OverflowPolicy}, builder::{ProgramBuilder, Symbols}, instruction::Instruction};
+
+/// A position in the program source, both 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("Line {line}: instruction outside of a thread, start one with \"thread\"")]
+    NoThread {
+        line: usize,
+    },
+    #[error("Line {line}: can't parse instruction \"{text}\"")]
+    BadInstruction {
+        line: usize,
+        text: String,
+    },
+    #[error("Line {line}: \"{text}\" is not a value")]
+    BadValue {
+        line: usize,
+        text: String,
+    },
+    #[error("Line {line}: unknown access mode \"{text}\"")]
+    BadAccessMode {
+        line: usize,
+        text: String,
+    },
+    #[error("Line {line}: unknown fence kind \"{text}\"")]
+    BadFenceKind {
+        line: usize,
+        text: String,
+    },
+    #[error("Line {line}: unknown overflow policy \"{text}\"")]
+    BadOverflowPolicy {
+        line: usize,
+        text: String,
+    },
+    #[error("Line {line}: variable \"{name}\" is not declared, declare it with \".var\"")]
+    UnknownVariable {
+        line: usize,
+        name: String,
+    },
+    #[error("Line {line}: the block of \".repeat\" is never closed with \"}}\"")]
+    UnclosedRepeat {
+        line: usize,
+    },
+    #[error("Line {line}: \".repeat\" is limited to {MAX_REPEAT} copies, got {count}")]
+    RepeatTooLong {
+        line: usize,
+        count: usize,
+    },
+}
+
+/// The most copies a `.repeat` may expand to, so that a typo can't produce
+/// an enormous program.
+pub const MAX_REPEAT: usize = 4096;
+
+/// Parses a program in the syntax produced by the [`Display`](std::fmt::Display)
+/// implementation of the instructions. Every thread starts with a `thread` line,
+/// any instruction may be prefixed with a `label:`, and `//` starts a comment.
+/// The [`span`](CodeInstruction::span) of an instruction points at its label
+/// if it has one.
+///
+/// Two directives are understood on top of the instructions:
+/// * `.var name addr` binds `name` to the memory cell `addr`, after which
+///   `r = &name` sets `r` to that address.
+/// * `.repeat N {`, followed by instructions and a closing `}` line, expands to
+///   `N` copies of the instructions, see [`ProgramBuilder::repeat`]. `N` is at
+///   most [`MAX_REPEAT`].
+///
+/// ```
+/// .var x 0
+/// thread
+///     one = 1
+///     x = &x
+///     store SEQ_CST ##x one
+/// loop:
+///     load SEQ_CST ##x r
+///     if r goto loop
+/// ```
+pub fn parse_program(src: &str) -> Result<Vec<Vec<CodeInstruction>>, ParseError> {
+    parse_program_with_symbols(src).map(|(program, _)| program)
+}
+
+/// Like [`parse_program`], but also returns the variables declared with `.var`,
+/// so that the final memory can be inspected by name.
+pub fn parse_program_with_symbols(src: &str) -> Result<(Vec<Vec<CodeInstruction>>, Symbols), ParseError> {
+    let mut lines = src.lines().enumerate();
+    let mut symbols = Symbols::default();
+    let builder = parse_lines(&mut lines, ProgramBuilder::new(), &mut symbols, None)?;
+
+    Ok(builder.build_with_symbols())
+}
+
+/// Parses the lines into `builder` until the `}` closing the `.repeat` at line
+/// `repeat`, or until the end of the source if there's none.
+fn parse_lines<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    mut builder: ProgramBuilder,
+    symbols: &mut Symbols,
+    repeat: Option<usize>,
+) -> Result<ProgramBuilder, ParseError> {
+    let mut in_thread = repeat.is_some();
+    let mut label_span = None;
+
+    while let Some((idx, raw)) = lines.next() {
+        let line = idx + 1;
+        let text = raw.split("//").next().unwrap_or_default().trim();
+        let column = |text: &str| raw.find(text).unwrap_or_default() + 1;
+        match text.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => continue,
+            ["thread"] if repeat.is_none() => {
+                builder = builder.thread();
+                in_thread = true;
+                label_span = None;
+                continue;
+            },
+            [".var", name, addr] if repeat.is_none() => {
+                let addr = parse_address(line, addr)?;
+                symbols.insert(YarnRef::new(*name).to_box().immortalize(), addr);
+                builder = builder.variable(name, addr);
+                continue;
+            },
+            ["}"] if repeat.is_some() => return Ok(builder),
+            _ => (),
+        }
+        if !in_thread {
+            return Err(ParseError::NoThread { line });
+        }
+
+        let (label, rest) = match text.split_once(':') {
+            Some((label, rest)) => (Some(label.trim()), rest.trim()),
+            None => (None, text),
+        };
+        if let Some(label) = label {
+            builder = builder.label(label);
+            label_span = Some(Span { line, column: column(label) });
+        }
+        if rest.is_empty() {
+            continue;
+        }
+
+        if let [".repeat", count, "{"] = rest.split_whitespace().collect::<Vec<_>>().as_slice() {
+            let count = parse_address(line, count)?;
+            if count > MAX_REPEAT {
+                return Err(ParseError::RepeatTooLong { line, count });
+            }
+            let body = parse_lines(lines, ProgramBuilder::new().thread(), symbols, Some(line))?
+                .build()
+                .pop()
+                .unwrap_or_default();
+            builder = builder.repeat(count, |block| body.iter().cloned().fold(block, ProgramBuilder::push_code));
+            continue;
+        }
+
+        let span = label_span.take().unwrap_or(Span { line, column: column(rest) });
+        builder = builder.span(span).push(parse_instruction(line, rest, symbols)?);
+    }
+
+    match repeat {
+        Some(line) => Err(ParseError::UnclosedRepeat { line }),
+        None => Ok(builder),
+    }
+}
+
+fn reg(name: &str) -> Register {
+    RegisterRef::new(name).to_box().immortalize()
+}
+
+fn label(name: &str) -> Label {
+    LabelRef::new(name).to_box().immortalize()
+}
+
+fn addr_reg<'a>(line: usize, text: &str, token: &'a str) -> Result<&'a str, ParseError> {
+    token.strip_prefix("##").ok_or_else(|| ParseError::BadInstruction { line, text: text.to_owned() })
+}
+
+/// Parses `##reg` or `##reg+offset`.
+fn addr_reg_offset<'a>(line: usize, text: &str, token: &'a str) -> Result<(&'a str, Value), ParseError> {
+    let addr = addr_reg(line, text, token)?;
+
+    match addr.split_once('+') {
+        Some((addr, offset)) => Ok((addr, parse_value(line, offset)?)),
+        None => Ok((addr, Value(0))),
+    }
+}
+
+/// Negative literals are stored as two's complement.
+fn parse_value(line: usize, text: &str) -> Result<Value, ParseError> {
+    let value = if text.starts_with('-') {
+        text.parse().map(Value::from_i64)
+    } else {
+        text.parse().map(Value)
+    };
+
+    value.map_err(|_| ParseError::BadValue { line, text: text.to_owned() })
+}
+
+/// Parses a literal address, bank or count, which can't be negative.
+fn parse_address(line: usize, text: &str) -> Result<usize, ParseError> {
+    parse_value(line, text)?
+        .try_to_address_signed()
+        .map_err(|_| ParseError::BadValue { line, text: text.to_owned() })
+}
+
+fn parse_access_mode(line: usize, text: &str) -> Result<AccessMode, ParseError> {
+    match text {
+        "SEQ_CST" => Ok(AccessMode::SeqCst),
+        "REL" => Ok(AccessMode::Rel),
+        "ACQ" => Ok(AccessMode::Acq),
+        "REL_ACQ" => Ok(AccessMode::RelAcq),
+        "RLX" => Ok(AccessMode::Rlx),
+        "NA" => Ok(AccessMode::NonAtomic),
+        _ => Err(ParseError::BadAccessMode { line, text: text.to_owned() }),
+    }
+}
+
+fn parse_overflow_policy(line: usize, text: &str) -> Result<OverflowPolicy, ParseError> {
+    match text {
+        "WRAP" => Ok(OverflowPolicy::Wrapping),
+        "SAT" => Ok(OverflowPolicy::Saturating),
+        _ => Err(ParseError::BadOverflowPolicy { line, text: text.to_owned() }),
+    }
+}
+
+/// Parses a bit number, which has to be below 64.
+fn parse_bit(line: usize, text: &str) -> Result<u32, ParseError> {
+    match parse_value(line, text)? {
+        Value(bit @ 0..=63) => Ok(bit as u32),
+        _ => Err(ParseError::BadValue { line, text: text.to_owned() }),
+    }
+}
+
+fn parse_fence_kind(line: usize, text: &str) -> Result<FenceKind, ParseError> {
+    match text {
+        "FULL" => Ok(FenceKind::Full),
+        "LOAD_LOAD" => Ok(FenceKind::LoadLoad),
+        "LOAD_STORE" => Ok(FenceKind::LoadStore),
+        "STORE_LOAD" => Ok(FenceKind::StoreLoad),
+        "STORE_STORE" => Ok(FenceKind::StoreStore),
+        _ => Err(ParseError::BadFenceKind { line, text: text.to_owned() }),
+    }
+}
+
+fn parse_binop(text: &str) -> Option<BinOp> {
+    match text {
+        "+" => Some(BinOp::Add),
+        "-" => Some(BinOp::Sub),
+        "*" => Some(BinOp::Mul),
+        "/" => Some(BinOp::Div),
+        "+%" => Some(BinOp::WrappingAdd),
+        "-%" => Some(BinOp::WrappingSub),
+        "*%" => Some(BinOp::WrappingMul),
+        _ => None,
+    }
+}
+
+/// The read-modify-writes which have a banked form.
+const BANKED_RMWS: [&str; 7] = ["cas", "casw", "fai", "bts", "btc", "fas", "fmax"];
+
+fn parse_instruction(line: usize, text: &str, symbols: &Symbols) -> Result<Instruction, ParseError> {
+    let bad = || ParseError::BadInstruction { line, text: text.to_owned() };
+    let tokens = text.split_whitespace().collect::<Vec<_>>();
+    // The read-modify-writes with a `b` suffix take a bank after the mode,
+    // like "loadb" and "storeb"
+    let (tokens, bank) = match tokens.as_slice() {
+        [op, mode, bank, rest @ ..] if BANKED_RMWS.iter().any(|x| op.strip_suffix('b') == Some(x)) => (
+            [&op[..op.len() - 1], mode].into_iter().chain(rest.iter().copied()).collect(),
+            parse_address(line, bank)?,
+        ),
+        _ => (tokens, 0),
+    };
+
+    let instruction = match tokens.as_slice() {
+        [dest, "=", "pc"] => Instruction::GetPc {
+            dest: reg(dest),
+        },
+        [dest, "=", name] if name.starts_with('&') => Instruction::Set {
+            dest: reg(dest),
+            value: symbols.get(&name[1..])
+                .map(|addr| Value(*addr as u64))
+                .ok_or_else(|| ParseError::UnknownVariable { line, name: name[1..].to_owned() })?,
+        },
+        [dest, "=", value] => Instruction::Set {
+            dest: reg(dest),
+            value: parse_value(line, value)?,
+        },
+        [dest, "=", src, "if", cond] => Instruction::CMov {
+            dest: reg(dest),
+            cond: reg(cond),
+            src: reg(src),
+        },
+        [dest, "=", src_l, op, src_r] => Instruction::Bop {
+            dest: reg(dest),
+            binop: parse_binop(op).ok_or_else(bad)?,
+            src_l: reg(src_l),
+            src_r: reg(src_r),
+        },
+        ["goto", target] if target.starts_with('*') => Instruction::BranchReg {
+            target: reg(&target[1..]),
+        },
+        ["if", src, "goto", target] => Instruction::Branch {
+            src: reg(src),
+            label: label(target),
+        },
+        ["load", mode, addr, dest] => {
+            let (addr, offset) = addr_reg_offset(line, text, addr)?;
+
+            Instruction::Load {
+                mode: parse_access_mode(line, mode)?,
+                addr: reg(addr),
+                offset,
+                dest: reg(dest),
+            }
+        },
+        ["store", mode, addr, value] if value.starts_with('=') => Instruction::StoreImm {
+            mode: parse_access_mode(line, mode)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            value: parse_value(line, &value[1..])?,
+        },
+        ["store", mode, addr, src] => {
+            let (addr, offset) = addr_reg_offset(line, text, addr)?;
+
+            Instruction::Store {
+                mode: parse_access_mode(line, mode)?,
+                addr: reg(addr),
+                offset,
+                src: reg(src),
+            }
+        },
+        ["cas", mode, addr, expected, new_value] => Instruction::Cas {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            expected: reg(expected),
+            new_value: reg(new_value),
+        },
+        ["casw", mode, addr, expected, new_value, ok] => Instruction::CasWeak {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            expected: reg(expected),
+            new_value: reg(new_value),
+            ok: reg(ok),
+        },
+        ["fai", mode, addr, dest] => Instruction::Fai {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            dest: reg(dest),
+            policy: OverflowPolicy::Checked,
+        },
+        ["fai", mode, addr, dest, policy] => Instruction::Fai {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            dest: reg(dest),
+            policy: parse_overflow_policy(line, policy)?,
+        },
+        ["bts", mode, addr, bit, dest] => Instruction::TestAndSetBit {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            bit: parse_bit(line, bit)?,
+            dest: reg(dest),
+        },
+        ["btc", mode, addr, bit, dest] => Instruction::TestAndClearBit {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            bit: parse_bit(line, bit)?,
+            dest: reg(dest),
+        },
+        ["fas", mode, addr, src, dest] => Instruction::Fas {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            src: reg(src),
+            dest: reg(dest),
+            policy: OverflowPolicy::Checked,
+        },
+        ["fas", mode, addr, src, dest, policy] => Instruction::Fas {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            src: reg(src),
+            dest: reg(dest),
+            policy: parse_overflow_policy(line, policy)?,
+        },
+        ["fmax", mode, addr, src, dest] => Instruction::Fmax {
+            mode: parse_access_mode(line, mode)?,
+            bank,
+            addr: reg(addr_reg(line, text, addr)?),
+            src: reg(src),
+            dest: reg(dest),
+        },
+        ["ll", mode, addr, dest] => Instruction::LoadLinked {
+            mode: parse_access_mode(line, mode)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            dest: reg(dest),
+        },
+        ["sc", mode, addr, src, ok] => Instruction::StoreConditional {
+            mode: parse_access_mode(line, mode)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            src: reg(src),
+            ok: reg(ok),
+        },
+        ["loadn", mode, addr, dests @ ..] if !dests.is_empty() => Instruction::LoadN {
+            mode: parse_access_mode(line, mode)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            dests: dests.iter().map(|x| reg(x)).collect(),
+        },
+        ["loadp", mode, addr1, addr2, dest1, dest2] => Instruction::LoadPair {
+            mode: parse_access_mode(line, mode)?,
+            addr1: reg(addr_reg(line, text, addr1)?),
+            addr2: reg(addr_reg(line, text, addr2)?),
+            dest1: reg(dest1),
+            dest2: reg(dest2),
+        },
+        ["storen", mode, addr, srcs @ ..] if !srcs.is_empty() => Instruction::StoreN {
+            mode: parse_access_mode(line, mode)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            srcs: srcs.iter().map(|x| reg(x)).collect(),
+        },
+        ["loadl", addr, dest] => Instruction::LoadLocal {
+            addr: reg(addr_reg(line, text, addr)?),
+            dest: reg(dest),
+        },
+        ["storel", addr, src] => Instruction::StoreLocal {
+            addr: reg(addr_reg(line, text, addr)?),
+            src: reg(src),
+        },
+        ["loadb", mode, bank, addr, dest] => Instruction::LoadBank {
+            mode: parse_access_mode(line, mode)?,
+            bank: parse_address(line, bank)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            dest: reg(dest),
+        },
+        ["storeb", mode, bank, addr, src] => Instruction::StoreBank {
+            mode: parse_access_mode(line, mode)?,
+            bank: parse_address(line, bank)?,
+            addr: reg(addr_reg(line, text, addr)?),
+            src: reg(src),
+        },
+        ["assert", src, "==", expected] => Instruction::Assert {
+            src: reg(src),
+            expected: parse_value(line, expected)?,
+        },
+        ["fence", mode, kind] => Instruction::Fence {
+            mode: parse_access_mode(line, mode)?,
+            kind: parse_fence_kind(line, kind)?,
+        },
+        ["barrier", id, participants] => Instruction::Barrier {
+            id: parse_value(line, id)?.0,
+            participants: match parse_address(line, participants)? {
+                0 => return Err(ParseError::BadValue { line, text: participants.to_string() }),
+                participants => participants,
+            },
+        },
+        ["yield"] => Instruction::Yield,
+        ["print", src] => Instruction::Print { src: reg(src) },
+        _ => return Err(bad()),
+    };
+
+    Ok(instruction)
+}
+#[cfg(test)]
+mod tests {
+    use crate::{Machine, driver, machine_memory::sc::ScMemory, machine_thread::{ThreadState, ThreadStateCreationError}, trace::Trace};
+
+    use super::*;
+
+    #[test]
+    fn repeat_makes_copies_with_distinct_labels() {
+        let program = parse_program("
+            thread
+                one = 1
+                .repeat 3 {
+                loop:
+                    if one goto loop
+                }
+        ").unwrap();
+
+        let labels = program[0].iter()
+            .filter_map(|x| x.label.as_ref().map(|x| x.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(program[0].len(), 4);
+        assert_eq!(labels, ["loop_0", "loop_1", "loop_2"]);
+        for (copy, code_instruction) in program[0][1..].iter().enumerate() {
+            assert!(matches!(
+                &code_instruction.instruction,
+                Instruction::Branch { label, .. } if *label == format!("loop_{copy}"),
+            ));
+        }
+    }
+
+    #[test]
+    fn unclosed_repeat_is_rejected() {
+        let err = parse_program("thread\n.repeat 2 {\none = 1\n").unwrap_err();
+
+        assert!(matches!(err, ParseError::UnclosedRepeat { line: 2 }));
+    }
+
+    #[test]
+    fn variables_name_cells() {
+        let (program, symbols) = parse_program_with_symbols("
+            .var x 1
+            .var y 3
+            thread
+                x = &x
+                one = 1
+                store SEQ_CST ##x one
+            thread
+                y = &y
+                two = 2
+                store SEQ_CST ##y two
+        ").unwrap();
+        let mut machine = Machine::<ScMemory>::new(&program, 4).unwrap();
+        driver::run_round_robin(&mut machine, 100, &mut Trace::skipping_silent()).unwrap();
+
+        let cells = machine.memory().global().cells();
+        assert_eq!(cells[symbols["x"]], Value(1));
+        assert_eq!(cells[symbols["y"]], Value(2));
+    }
+
+    #[test]
+    fn undeclared_variable_is_rejected() {
+        let err = parse_program("thread\nx = &x\n").unwrap_err();
+
+        assert!(matches!(err, ParseError::UnknownVariable { line: 2, name } if name == "x"));
+    }
+
+    #[test]
+    fn negative_literals_are_twos_complement() {
+        let program = parse_program("
+            thread
+                a = -5
+                b = -9223372036854775808
+        ").unwrap();
+
+        let values = program[0].iter()
+            .map(|x| match &x.instruction {
+                Instruction::Set { value, .. } => value.as_signed(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, [-5, i64::MIN]);
+        assert!(matches!(parse_program("thread\na = -"), Err(ParseError::BadValue { line: 2, .. })));
+    }
+
+    #[test]
+    fn negative_addresses_are_rejected() {
+        for (src, text) in [
+            (".var x -1\nthread\nx = &x", "-1"),
+            ("thread\nx = 0\nloadb SEQ_CST -1 ##x r", "-1"),
+            ("thread\nx = 0\nstoreb SEQ_CST -2 ##x x", "-2"),
+        ] {
+            let err = parse_program(src).unwrap_err();
+            assert!(matches!(&err, ParseError::BadValue { text: bad, .. } if bad == text), "{err}");
+        }
+    }
+
+    #[test]
+    fn banked_rmws_take_a_bank_after_the_mode() {
+        let program = parse_program("
+            thread
+                p = 0
+                faib RLX 1 ##p a
+                fai RLX ##p b
+                casb SEQ_CST 2 ##p a b
+        ").unwrap();
+
+        assert!(matches!(program[0][1].instruction, Instruction::Fai { bank: 1, .. }));
+        assert!(matches!(program[0][2].instruction, Instruction::Fai { bank: 0, .. }));
+        assert!(matches!(program[0][3].instruction, Instruction::Cas { bank: 2, .. }));
+        assert_eq!(program[0][1].instruction.to_string(), "faib RLX 1 ##p a");
+        assert_eq!(program[0][2].instruction.to_string(), "fai RLX ##p b");
+        assert!(matches!(parse_program("thread
+fmaxb RLX -1 ##p a b"), Err(ParseError::BadValue { line: 2, .. })));
+    }
+
+    #[test]
+    fn repeat_count_is_bounded() {
+        let repeat = |count: &str| parse_program(&format!("thread\n.repeat {count} {{\na = 1\n}}"));
+
+        assert_eq!(repeat(&MAX_REPEAT.to_string()).unwrap()[0].len(), MAX_REPEAT);
+        assert!(matches!(repeat("4097"), Err(ParseError::RepeatTooLong { line: 2, count: 4097 })));
+        assert!(matches!(repeat("-1"), Err(ParseError::BadValue { line: 2, .. })));
+    }
+
+    #[test]
+    fn barrier_needs_participants() {
+        assert!(matches!(parse_program("thread\nbarrier 0 2").unwrap()[0][0].instruction, Instruction::Barrier { id: 0, participants: 2 }));
+        for count in ["0", "-1"] {
+            let err = parse_program(&format!("thread\nbarrier 0 {count}")).unwrap_err();
+            assert!(matches!(&err, ParseError::BadValue { line: 2, text } if text == count), "{err}");
+        }
+    }
+
+    #[test]
+    fn duplicate_label_points_at_both_definitions() {
+        let program = parse_program("
+            thread
+            again:
+                one = 1
+              again: one = 2
+        ").unwrap();
+
+        let Err(err) = ThreadState::new(&program[0]) else {
+            panic!("the duplicate label is accepted");
+        };
+        let ThreadStateCreationError::DuplicateLabel { first_span, second_span, .. } = &err else {
+            panic!("unexpected error {err}");
+        };
+        let mut spans = [first_span.unwrap(), second_span.unwrap()];
+        spans.sort_by_key(|x| x.line);
+        assert_eq!(spans, [Span { line: 3, column: 13 }, Span { line: 5, column: 15 }]);
+        assert!(err.to_string().ends_with("(at 5:15 and 3:13)"), "{err}");
+    }
+}
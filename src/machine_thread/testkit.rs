@@ -0,0 +1,74 @@
+//! Helpers for checking what single instructions do.
+
+use fnv::FnvHashMap;
+use thiserror::Error;
+
+use crate::{machine_memory::MemoryQuery, register::RegisterRef, value::Value};
+
+use super::{CodeInstruction, Instruction, ThreadState, ThreadStateCreationError, ThreadStateError};
+
+#[derive(Debug, Error)]
+pub enum ExecuteError {
+    #[error("Can't run the instruction alone")]
+    Creation(#[source] ThreadStateCreationError),
+    #[error("The instruction has failed")]
+    Step(#[source] ThreadStateError),
+}
+
+/// Runs `instruction` as the only instruction of a thread whose registers
+/// in `initial_regs` are set first, and gives the query the step has made (if
+/// any) and the state of the thread after it. A branch jumps to the instruction
+/// itself, so a taken branch leaves the thread at `pc` 0 and a fallen through
+/// one halts it. The address registers of a memory access have to be in
+/// `initial_regs`, as nothing else sets them.
+///
+/// The program is leaked to let the thread outlive the call, which is fine for
+/// the handful of instructions a test runs.
+pub fn execute_one(
+    instruction: Instruction,
+    initial_regs: &[(&str, Value)],
+) -> Result<(Option<MemoryQuery<'static>>, ThreadState<'static>), ExecuteError> {
+    let label = instruction.referenced_label().map(|x| x.to_box().immortalize());
+    let program: &'static [CodeInstruction] = Box::leak(Box::new([CodeInstruction {
+        label,
+        fallthrough: None,
+        instruction,
+        span: None,
+    }]));
+    let inits = initial_regs.iter()
+        .map(|(register, value)| (RegisterRef::new(register).to_box().immortalize(), *value))
+        .collect::<FnvHashMap<_, _>>();
+    let mut state = ThreadState::new_with_registers(program, &inits)
+        .map_err(ExecuteError::Creation)?;
+
+    let query = state.step().map_err(ExecuteError::Step)?;
+
+    Ok((query, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{machine_thread::{BinOp, binop::BinOpError}, register};
+
+    use super::*;
+
+    fn bop(binop: BinOp) -> Instruction {
+        let reg = |name| register::parse(name).unwrap();
+
+        Instruction::Bop { dest: reg("d"), binop, src_l: reg("l"), src_r: reg("r") }
+    }
+
+    #[test]
+    fn bop_of_two_operands() {
+        let operands = [("l", Value(7)), ("r", Value(3))];
+        for (binop, expected) in [(BinOp::Add, Value(10)), (BinOp::Sub, Value(4)), (BinOp::Mul, Value(21))] {
+            let (query, state) = execute_one(bop(binop), &operands).unwrap();
+            assert!(query.is_none());
+            assert!(state.is_halted());
+            assert_eq!(state.get_register(RegisterRef::new("d")).unwrap(), expected);
+        }
+
+        let err = execute_one(bop(BinOp::Div), &[("l", Value(7))]).err().unwrap();
+        assert!(matches!(err, ExecuteError::Step(ThreadStateError::BinOpError { err: BinOpError::DivisionByZero, .. })));
+    }
+}
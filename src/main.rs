@@ -1,14 +1,34 @@
-use machine_memory::{Threads, Memory, MemorySubsystem, AccessMode};
-use machine_thread::CodeInstruction;
+use any_machine::AnyMachine;
+use anyhow::Context;
+use clap::{Arg, ArgAction, Command, value_parser};
+use machine_memory::{Threads, Memory, MemoryLayout, MemorySubsystem, AccessMode, RmwKind, MemoryStep, MemoryError, ErasedMemoryError};
+use machine_thread::{CodeInstruction, InitPolicy, Instruction, ThreadState, ThreadStateCreationError, ThreadStateError, parser::parse_program};
+use register::Register;
+use trace::Trace;
+use latency::{Cycles, LatencyModel};
 use value::Value;
+use thiserror::Error;
+use std::{collections::BTreeSet, hash::{Hash, Hasher}};
+use fnv::{FnvHashMap, FnvHasher};
 
+mod any_machine;
+#[cfg(test)] // The CLI has no interactive mode to drive the debugger from
+mod debugger;
+#[allow(dead_code)] // Only the round-robin driver is reachable from the CLI
+mod driver;
+#[allow(dead_code)] // Nothing but the tests explores the state space yet
+mod explorer;
+#[allow(dead_code)] // Litmus tests are only run by the unit tests so far
+mod litmus;
 mod machine_thread;
 mod machine_memory;
 mod value;
 mod register;
+mod trace;
 mod label;
+mod latency;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MachineEvent {
     Silent,
     Read {
@@ -16,12 +36,23 @@ pub enum MachineEvent {
         location: usize,
         value: Value,
         mode: AccessMode,
+        /// The timestamp of the write the value was read from, `0` for the
+        /// initial value. See [`MachineEvent::Write::ts`] and [`MemorySubsystem::restamp`].
+        ts: u64,
     },
     Write {
         tid: usize,
         location: usize,
         value: Value,
         mode: AccessMode,
+        /// The position of the write in the order the writes have become visible,
+        /// starting from `1`. Assigned by [`Machine::step`], subsystems leave it `0`.
+        ts: u64,
+        /// Whether a relaxed write synchronizes like a release one, because
+        /// the thread has executed a release fence before it and no other fence
+        /// since. Assigned by [`Machine::step`] from
+        /// [`MemorySubsystem::is_release_fenced`], subsystems leave it `false`.
+        promoted: bool,
     },
     Fence {
         tid: usize,
@@ -33,11 +64,179 @@ pub enum MachineEvent {
         read_value: Value,
         write_value: Value,
         mode: AccessMode,
+        op: RmwKind,
+        /// See [`MachineEvent::Write::ts`]. A failed compare-and-swap only
+        /// reads, so it is stamped like a [`MachineEvent::Read`].
+        ts: u64,
+    },
+    /// A group read of `count` cells starting at `location`.
+    ReadN {
+        tid: usize,
+        location: usize,
+        count: usize,
+        mode: AccessMode,
+    },
+    /// A read of two cells in one access, see [`Instruction::LoadPair`].
+    ReadPair {
+        tid: usize,
+        locations: [usize; 2],
+        values: [Value; 2],
+        mode: AccessMode,
+    },
+    /// A group write of `count` cells starting at `location`.
+    WriteN {
+        tid: usize,
+        location: usize,
+        count: usize,
+        mode: AccessMode,
+        /// The timestamp of the write to `location`. The cells after it get
+        /// the following ones, see [`MachineEvent::Write::ts`].
+        ts: u64,
+    },
+    /// The thread has executed [`Instruction::Yield`](Instruction::Yield).
+    Yield {
+        tid: usize,
+    },
+    /// The thread has executed [`Instruction::Print`](Instruction::Print),
+    /// appending `value` to the [`output`](Machine::output).
+    Output {
+        tid: usize,
+        value: Value,
+    },
+    /// The initial value of a cell set before the run, see [`Machine::set_initial`].
+    /// Happens before every thread event and carries the timestamp `0`.
+    Init {
+        location: usize,
+        value: Value,
+    },
+    /// An injected fault, which has XORed `mask` into the cell at `location`,
+    /// leaving `value` there. See [`Machine::inject_fault`].
+    Fault {
+        location: usize,
+        mask: u64,
+        value: Value,
+    },
+}
+
+impl MachineEvent {
+    /// The address the event has accessed along with the value involved: the
+    /// value read for reads and read-modify-writes, the value written for writes.
+    /// `None` for the events which don't access a single cell.
+    pub fn accessed(&self) -> Option<(usize, Value)> {
+        match *self {
+            MachineEvent::Read { location, value, .. } |
+            MachineEvent::Write { location, value, .. } |
+            MachineEvent::Init { location, value } |
+            MachineEvent::Fault { location, value, .. } => Some((location, value)),
+            MachineEvent::Rmw { location, read_value, .. } => Some((location, read_value)),
+            MachineEvent::Silent |
+            MachineEvent::Yield { .. } |
+            MachineEvent::Output { .. } |
+            MachineEvent::Fence { .. } |
+            MachineEvent::ReadN { .. } |
+            MachineEvent::ReadPair { .. } |
+            MachineEvent::WriteN { .. } => None,
+        }
     }
 }
 
+/// Renders the event in the instruction notation, e.g. `T0: R ##5 = 3 [ACQ]`.
+/// Read-modify-writes are shown as `U` with both values (`T0: U ##5 = 3 -> 4 [SEQ_CST, FAI]`)
+/// and group accesses with the half-open range of cells (`T1: W ##2..4 [RLX]`).
+impl std::fmt::Display for MachineEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MachineEvent::Silent => write!(f, "silent"),
+            MachineEvent::Read { tid, location, value, mode, .. } =>
+                write!(f, "T{tid}: R ##{location} = {value} [{mode}]"),
+            MachineEvent::Write { tid, location, value, mode, promoted: true, .. } =>
+                write!(f, "T{tid}: W ##{location} = {value} [{mode}, fenced]"),
+            MachineEvent::Write { tid, location, value, mode, .. } =>
+                write!(f, "T{tid}: W ##{location} = {value} [{mode}]"),
+            MachineEvent::Fence { tid, mode } => write!(f, "T{tid}: F [{mode}]"),
+            MachineEvent::Rmw { tid, location, read_value, write_value, mode, op, .. } =>
+                write!(f, "T{tid}: U ##{location} = {read_value} -> {write_value} [{mode}, {op}]"),
+            MachineEvent::ReadN { tid, location, count, mode } =>
+                write!(f, "T{tid}: R ##{location}..{} [{mode}]", location + count),
+            MachineEvent::ReadPair { tid, locations: [l1, l2], values: [v1, v2], mode } =>
+                write!(f, "T{tid}: R ##{l1} = {v1}, ##{l2} = {v2} [{mode}]"),
+            MachineEvent::WriteN { tid, location, count, mode, .. } =>
+                write!(f, "T{tid}: W ##{location}..{} [{mode}]", location + count),
+            MachineEvent::Yield { tid } => write!(f, "T{tid}: YIELD"),
+            MachineEvent::Output { tid, value } => write!(f, "T{tid}: OUT {value}"),
+            MachineEvent::Init { location, value } => write!(f, "INIT ##{location} = {value}"),
+            MachineEvent::Fault { location, mask, value } =>
+                write!(f, "FAULT ##{location} ^= {mask:#x} -> {value}"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
 pub enum MachineError {
+    #[error("Failed to create thread {tid}")]
+    ThreadCreation {
+        tid: usize,
+        #[source] err: ThreadStateCreationError,
+    },
+    #[error("The machine has run out of its step budget")]
+    StepBudgetExhausted,
+    #[error("Thread {tid} has failed to step")]
+    Thread {
+        tid: usize,
+        #[source] err: ThreadStateError,
+    },
+    #[error("Thread {tid} has failed an assertion: expected {register} to be {expected}, found {actual}")]
+    AssertionFailed {
+        tid: usize,
+        register: Register,
+        expected: Value,
+        actual: Value,
+    },
+    #[error("Thread {tid} is waiting and can't be stepped")]
+    ThreadWaiting {
+        tid: usize,
+    },
+    #[error("No memory region is named \"{name}\"")]
+    UnknownRegion {
+        name: String,
+    },
+    #[error("Memory region \"{name}\" doesn't fit into the memory")]
+    RegionOutOfRange {
+        name: String,
+    },
+    #[error("Unknown memory subsystem \"{name}\"")]
+    UnknownSubsystem {
+        name: String,
+    },
+    #[error("Thread {tid} waits at a barrier of {participants} threads at {at}, but there are only {threads} threads")]
+    BarrierTooLarge {
+        tid: usize,
+        at: usize,
+        participants: usize,
+        threads: usize,
+    },
+    #[error("Expected a weight per thread, got {weights} weights for {threads} threads")]
+    WeightCountMismatch {
+        weights: usize,
+        threads: usize,
+    },
+    #[error("The scheduler has picked step {choice}, but only {enabled} are enabled")]
+    NoSuchStep {
+        choice: usize,
+        enabled: usize,
+    },
+    #[error("Memory subsystem has failed to step")]
+    Memory(#[source] ErasedMemoryError),
+    #[error("Internal error: {context}")]
+    Internal {
+        context: String,
+    },
+}
 
+impl<E: std::fmt::Display> From<MemoryError<E>> for MachineError {
+    fn from(err: MemoryError<E>) -> Self {
+        MachineError::Memory(err.erase())
+    }
 }
 
 #[derive(Debug)]
@@ -46,21 +245,970 @@ pub enum MachineStep<Mem: MemorySubsystem> {
     Memory(Mem::Independent),
 }
 
+impl<Mem: MemorySubsystem> Clone for MachineStep<Mem> {
+    fn clone(&self) -> Self {
+        match self {
+            MachineStep::Thread(tid) => MachineStep::Thread(*tid),
+            MachineStep::Memory(x) => MachineStep::Memory(x.clone()),
+        }
+    }
+}
+
+impl<Mem: MemorySubsystem> std::fmt::Display for MachineStep<Mem> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MachineStep::Thread(tid) => write!(f, "T{tid}"),
+            MachineStep::Memory(x) => write!(f, "{} {x}", Mem::name()),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Machine<'a, Mem> {
     threads: Threads<'a, Mem>,
     memory: Memory<Mem>,
+    /// How many more steps the machine may take. `None` means unlimited.
+    step_budget: Option<usize>,
+    /// Names of the memory regions.
+    layout: MemoryLayout,
+    /// The timestamp of the last write.
+    write_clock: u64,
+    /// The timestamp of the last write to each address.
+    last_write_ts: FnvHashMap<usize, u64>,
+    /// The costs of the steps counted in `cycles`.
+    latency: LatencyModel,
+    cycles: Cycles,
+    /// The values printed by the threads, in order.
+    output: Vec<Value>,
+}
+
+/// Hashes the state of the threads, the memory and the output. The step
+/// budget and the cycles are not a part of the state.
+impl<'a, Mem: Hash> Hash for Machine<'a, Mem> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.threads.hash(state);
+        self.memory.hash(state);
+        self.output.hash(state);
+    }
+}
+
+impl<'a, Mem: MemorySubsystem + Hash> Machine<'a, Mem> {
+    /// A hash of the whole state of the machine: the registers and the PCs
+    /// (and so whether the threads have halted), the memory cells, the state
+    /// of the memory subsystem and the output. It is computed with FNV, so
+    /// equal states give equal hashes across runs and builds.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The whole state of the machine (the same data [`state_hash`](Machine::state_hash)
+    /// covers) as a key which can be compared and stored. Unlike the hash, two keys
+    /// are only equal if the states are.
+    pub fn state_key(&self) -> StateKey {
+        let mut recorder = StateRecorder::default();
+        self.hash(&mut recorder);
+        let mut hasher = FnvHasher::default();
+        hasher.write(&recorder.0);
+
+        StateKey {
+            hash: hasher.finish(),
+            bytes: recorder.0,
+        }
+    }
+}
+
+/// The state of a machine, see [`Machine::state_key`]. It is hashed with the
+/// precomputed hash of the state and compared by the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateKey {
+    hash: u64,
+    bytes: Vec<u8>,
+}
+
+impl Hash for StateKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+/// A "hasher" which keeps all the data it is fed.
+#[derive(Default)]
+struct StateRecorder(Vec<u8>);
+
+impl Hasher for StateRecorder {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("The recorded state is hashed by Machine::state_key")
+    }
 }
 
 impl<'a, Mem: MemorySubsystem> Machine<'a, Mem> {
-    pub fn new(program: &'a [Vec<CodeInstruction>]) -> Result<Machine<'a, Mem>, MachineError> {
-        todo!()
+    pub fn new(program: &'a [Vec<CodeInstruction>], memory_size: usize) -> Result<Machine<'a, Mem>, MachineError> {
+        Self::new_with_empty_threads(program, memory_size, false)
+    }
+
+    /// Checks that no barrier waits for more threads than there are, as it
+    /// would never release them.
+    fn check_barriers(program: &[Vec<CodeInstruction>]) -> Result<(), MachineError> {
+        for (tid, code) in program.iter().enumerate() {
+            for (at, code_instruction) in code.iter().enumerate() {
+                if let Instruction::Barrier { participants, .. } = code_instruction.instruction {
+                    if participants > program.len() {
+                        return Err(MachineError::BarrierTooLarge { tid, at, participants, threads: program.len() });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`new`](Machine::new), but if `allow_empty` is set, the threads
+    /// with no instructions are created halted instead of failing with
+    /// [`ThreadStateCreationError::EmptyProgram`].
+    pub fn new_with_empty_threads(
+        program: &'a [Vec<CodeInstruction>],
+        memory_size: usize,
+        allow_empty: bool,
+    ) -> Result<Machine<'a, Mem>, MachineError> {
+        let threads = program.iter()
+            .enumerate()
+            .map(|(tid, code)| {
+                let thread = if allow_empty {
+                    ThreadState::new_allow_empty(code)
+                } else {
+                    ThreadState::new(code)
+                };
+
+                thread.map_err(|err| MachineError::ThreadCreation { tid, err })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::check_barriers(program)?;
+
+        Ok(Machine {
+            threads: Threads::new(threads),
+            memory: Memory::new(memory_size),
+            step_budget: None,
+            layout: MemoryLayout::default(),
+            write_clock: 0,
+            last_write_ts: FnvHashMap::default(),
+            latency: LatencyModel::default(),
+            cycles: Cycles { total: 0, per_thread: vec![0; program.len()] },
+            output: Vec::new(),
+        })
+    }
+
+    pub fn threads(&self) -> &Threads<'a, Mem> {
+        &self.threads
+    }
+
+    pub(crate) fn thread_mut(&mut self, tid: usize) -> Option<&mut ThreadState<'a>> {
+        self.threads.get_thread_mut(tid).ok()
+    }
+
+    pub fn memory(&self) -> &Memory<Mem> {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory<Mem> {
+        &mut self.memory
+    }
+
+    pub fn layout(&self) -> &MemoryLayout {
+        &self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: MemoryLayout) {
+        self.layout = layout;
+    }
+
+    /// The cells of the memory region called `name` in the [`layout`](Machine::layout).
+    pub fn region(&self, name: &str) -> Result<&[Value], MachineError> {
+        let (start, len) = self.layout.get(name)
+            .ok_or_else(|| MachineError::UnknownRegion { name: name.to_owned() })?;
+
+        start.checked_add(len)
+            .and_then(|end| self.memory.global().cells().get(start..end))
+            .ok_or_else(|| MachineError::RegionOutOfRange { name: name.to_owned() })
+    }
+
+    /// Brings the threads and the memory back to the state the machine was
+    /// created in. The step budget is left untouched.
+    pub fn reset(&mut self) {
+        self.threads.reset();
+        self.memory.reset();
+        self.write_clock = 0;
+        self.last_write_ts.clear();
+        self.output.clear();
+        self.reset_cycles();
+    }
+
+    /// The cells accessed since the machine has been created or last
+    /// [`reset`](Machine::reset), see [`GlobalMemory::touched`](machine_memory::GlobalMemory::touched).
+    pub fn touched_addresses(&self) -> BTreeSet<usize> {
+        self.memory.global().touched().clone()
+    }
+
+    /// The values printed with [`Instruction::Print`] since the machine has been
+    /// created or last [`reset`](Machine::reset).
+    pub fn output(&self) -> &[Value] {
+        &self.output
+    }
+
+    /// Sets the costs of the steps from now on. The already counted cycles are kept.
+    pub fn set_latency_model(&mut self, latency: LatencyModel) {
+        self.latency = latency;
+    }
+
+    pub fn latency_model(&self) -> &LatencyModel {
+        &self.latency
+    }
+
+    /// The cycles spent by the steps since the machine has been created or
+    /// last [`reset`](Machine::reset), according to the [`LatencyModel`].
+    pub fn cycles(&self) -> &Cycles {
+        &self.cycles
+    }
+
+    pub fn reset_cycles(&mut self) {
+        self.cycles.total = 0;
+        self.cycles.per_thread.fill(0);
+    }
+
+    /// Fills in the timestamps of the event. The writes (including the faults)
+    /// get the next ticks of the clock, one per cell, the initial values get `0`
+    /// and the reads get the timestamp of the last write to their address. The
+    /// subsystems whose reads may see older writes correct the latter in
+    /// [`MemorySubsystem::restamp`]. Also marks the relaxed writes which a release
+    /// fence applies to, see [`MachineEvent::Write::promoted`].
+    fn stamp(&mut self, event: &mut MachineEvent) {
+        if let MachineEvent::Write { tid, mode: AccessMode::Rlx, promoted, .. } = event {
+            *promoted = self.memory.subsystem().is_release_fenced(*tid);
+        }
+
+        match event {
+            MachineEvent::Read { location, ts, .. } |
+            MachineEvent::Rmw { location, ts, op: RmwKind::CompareSwap { succeeded: false }, .. } => {
+                *ts = self.last_write_ts.get(location).copied().unwrap_or(0);
+            },
+            MachineEvent::Write { location, ts, .. } |
+            MachineEvent::Rmw { location, ts, .. } => {
+                self.write_clock += 1;
+                *ts = self.write_clock;
+                self.last_write_ts.insert(*location, *ts);
+            },
+            MachineEvent::WriteN { location, count, ts, .. } => {
+                *ts = self.write_clock + 1;
+                for cell in *location..*location + *count {
+                    self.write_clock += 1;
+                    self.last_write_ts.insert(cell, self.write_clock);
+                }
+            },
+            MachineEvent::Fault { location, .. } => {
+                self.write_clock += 1;
+                self.last_write_ts.insert(*location, self.write_clock);
+            },
+            MachineEvent::Init { location, .. } => {
+                self.last_write_ts.insert(*location, 0);
+            },
+            _ => (),
+        }
+        self.memory.subsystem_mut().restamp(event);
+    }
+
+    /// See [`ThreadState::set_signed_mode`].
+    pub fn set_signed_mode(&mut self, signed_mode: bool) {
+        self.threads.set_signed_mode(signed_mode);
+    }
+
+    /// See [`ThreadState::set_division_trap`].
+    pub fn set_division_trap(&mut self, trap: Option<Value>) {
+        self.threads.set_division_trap(trap);
+    }
+
+    /// See [`ThreadState::set_local_memory_size`].
+    pub fn set_local_memory_size(&mut self, size: usize) {
+        self.threads.set_local_memory_size(size);
+    }
+
+    /// See [`ThreadState::set_init_policy`].
+    pub fn set_init_policy(&mut self, policy: InitPolicy) {
+        self.threads.set_init_policy(policy);
+    }
+
+    /// Limits the amount of steps the machine may perform from now on.
+    /// `None` removes the limit.
+    pub fn set_step_budget(&mut self, budget: Option<usize>) {
+        self.step_budget = budget;
+    }
+
+    pub fn step_budget(&self) -> Option<usize> {
+        self.step_budget
+    }
+
+    /// All the steps that can be taken from the current state: one per live thread
+    /// which isn't waiting and one per independent step the memory subsystem has enabled.
+    pub fn enabled_steps(&self) -> Vec<MachineStep<Mem>> {
+        self.threads.live_tids()
+            .filter(|tid| !self.memory.subsystem().is_waiting(*tid))
+            .map(MachineStep::Thread)
+            .chain(
+                self.memory.subsystem()
+                    .pending_independent(&self.threads, self.memory.global())
+                    .into_iter()
+                    .map(MachineStep::Memory)
+            )
+            .collect()
+    }
+
+    /// See [`GlobalMemory::set_banks`](machine_memory::GlobalMemory::set_banks).
+    pub fn set_banks(&mut self, banks: usize) {
+        self.memory.global_mut().set_banks(banks);
+    }
+
+    /// Sets the initial value of the cell at `addr`. The value survives
+    /// [`reset`](Machine::reset) and is reported by [`init_events`](Machine::init_events).
+    pub fn set_initial(&mut self, addr: usize, value: Value) -> Result<(), MachineError> {
+        self.memory.global_mut().set_initial(addr, value)?;
+        self.stamp(&mut MachineEvent::Init { location: addr, value });
+
+        Ok(())
+    }
+
+    /// See [`GlobalMemory::init_region`](machine_memory::GlobalMemory::init_region).
+    pub fn init_region(&mut self, start: usize, values: &[Value]) -> Result<(), MachineError> {
+        self.memory.global_mut().init_region(start, values)?;
+        for (location, value) in (start..).zip(values) {
+            self.stamp(&mut MachineEvent::Init { location, value: *value });
+        }
+
+        Ok(())
+    }
+
+    /// A [`MachineEvent::Init`] for every cell with a non-zero initial value,
+    /// in the order of addresses.
+    pub fn init_events(&self) -> Vec<MachineEvent> {
+        self.memory.global().initial()
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value != Value(0))
+            .map(|(location, value)| MachineEvent::Init { location, value: *value })
+            .collect()
+    }
+
+    /// Flips the bits of `mask` in the memory cell at `addr`, bypassing the
+    /// memory subsystem. This is meant for studying programs under memory
+    /// corruption and is never done by the drivers.
+    pub fn inject_fault(&mut self, addr: usize, mask: u64) -> Result<MachineEvent, MachineError> {
+        let value = self.memory.global_mut().corrupt(addr, mask)?;
+        let mut event = MachineEvent::Fault { location: addr, mask, value };
+        self.stamp(&mut event);
+
+        Ok(event)
+    }
+
+    /// Whether some threads haven't halted, but nothing can be stepped.
+    pub fn is_deadlocked(&self) -> bool {
+        self.threads.live_tids().next().is_some() && self.enabled_steps().is_empty()
+    }
+
+    /// See [`driver::run_scheduler`].
+    pub fn run_with(
+        &mut self,
+        scheduler: &mut impl driver::Scheduler<Mem>,
+        max_steps: usize,
+        trace: &mut Trace,
+    ) -> Result<driver::RunSummary, MachineError> {
+        driver::run_scheduler(self, scheduler, max_steps, trace)
+    }
+
+    /// Performs the steps of `schedule` in order, stopping at the first error.
+    pub fn run_schedule(&mut self, schedule: &[MachineStep<Mem>]) -> Result<Vec<MachineEvent>, MachineError> {
+        schedule.iter()
+            .map(|step| self.step(step.clone()))
+            .collect()
+    }
+
+    /// Like [`step`](Machine::step), but a panic inside the machine is caught and
+    /// reported as [`MachineError::Internal`]. The machine may be left in an
+    /// inconsistent state afterwards and should be [`reset`](Machine::reset).
+    pub fn try_step(&mut self, step: MachineStep<Mem>) -> Result<MachineEvent, MachineError> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.step(step)))
+            .unwrap_or_else(|payload| {
+                let context = payload.downcast_ref::<&str>()
+                    .map(|x| x.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_owned());
+
+                Err(MachineError::Internal { context })
+            })
     }
 
     pub fn step(&mut self, step: MachineStep<Mem>) -> Result<MachineEvent, MachineError> {
-        todo!()
+        if let MachineStep::Thread(tid) = step {
+            if self.memory.subsystem().is_waiting(tid) {
+                return Err(MachineError::ThreadWaiting { tid });
+            }
+        }
+        if let Some(budget) = &mut self.step_budget {
+            if *budget == 0 {
+                return Err(MachineError::StepBudgetExhausted);
+            }
+            *budget -= 1;
+        }
+
+        let memory_step = match step {
+            MachineStep::Thread(tid) => {
+                let thread = self.threads.get_thread_mut(tid)?;
+                let yielding = matches!(thread.next_instruction(), Some(Instruction::Yield));
+                let printed = match thread.next_instruction() {
+                    Some(Instruction::Print { src }) => Some(src),
+                    _ => None,
+                };
+                let cost = thread.next_instruction()
+                    .map(|x| self.latency.instruction_cost(x))
+                    .unwrap_or_default();
+                let query = thread
+                    .step()
+                    .map_err(|err| match err {
+                        ThreadStateError::AssertionFailed { register, expected, actual } =>
+                            MachineError::AssertionFailed { tid, register, expected, actual },
+                        err => MachineError::Thread { tid, err },
+                    })?;
+                self.cycles.total += cost;
+                self.cycles.per_thread[tid] += cost;
+
+                match (query, printed) {
+                    (Some(query), _) => MemoryStep::ThreadRequest { tid, query },
+                    (None, Some(src)) => {
+                        let value = thread.get_register(src.as_ref())
+                            .map_err(|err| MachineError::Thread { tid, err })?;
+                        self.output.push(value);
+
+                        return Ok(MachineEvent::Output { tid, value });
+                    },
+                    (None, None) if yielding => return Ok(MachineEvent::Yield { tid }),
+                    (None, None) => return Ok(MachineEvent::Silent),
+                }
+            },
+            MachineStep::Memory(x) => {
+                self.cycles.total += self.latency.memory_step;
+                MemoryStep::Independent(x)
+            },
+        };
+
+        let is_rmw = matches!(&memory_step, MemoryStep::ThreadRequest { query, .. } if query.is_rmw());
+        let mut event = self.memory.execute_step(memory_step, &mut self.threads)?;
+        self.stamp(&mut event);
+        debug_assert!(
+            !is_rmw || matches!(event, MachineEvent::Rmw { .. }),
+            "{} has split a read-modify-write: {event:?}",
+            Mem::name(),
+        );
+
+        Ok(event)
     }
 }
 
-fn main() {
-    println!("Hello, world!");
+fn main() -> anyhow::Result<()> {
+    let matches = Command::new("wmm23-ex1")
+        .about("Runs a program on a machine with the selected memory model")
+        .arg(Arg::new("program").required(true).help("Path to the program"))
+        .arg(Arg::new("memory-size")
+            .long("memory-size")
+            .value_parser(value_parser!(usize))
+            .default_value("16")
+            .help("Amount of memory cells"))
+        .arg(Arg::new("model")
+            .long("model")
+            .default_value(AnyMachine::names()[0])
+            .help("Memory subsystem to use"))
+        .arg(Arg::new("max-steps")
+            .long("max-steps")
+            .value_parser(value_parser!(usize))
+            .default_value("10000")
+            .help("Amount of steps after which the run is stopped"))
+        .arg(Arg::new("skip-silent")
+            .long("skip-silent")
+            .action(ArgAction::SetTrue)
+            .help("Only print the memory events"))
+        .arg(Arg::new("history-cap")
+            .long("history-cap")
+            .value_parser(value_parser!(usize))
+            .help("Amount of messages per location after which the histories are pruned"))
+        .arg(Arg::new("spurious-cas-failures")
+            .long("spurious-cas-failures")
+            .action(ArgAction::SetTrue)
+            .help("Let weak CASes fail spuriously (SC only)"))
+        .arg(Arg::new("coherence-check")
+            .long("coherence-check")
+            .action(ArgAction::SetTrue)
+            .help("Check that no thread reads past its own last write (SC only)"))
+        .arg(Arg::new("certification-depth")
+            .long("certification-depth")
+            .value_parser(value_parser!(usize))
+            .help("Amount of steps a thread may take to fulfill its promises (Promising only)"))
+        .get_matches();
+
+    let path = matches.get_one::<String>("program").unwrap();
+    let memory_size = *matches.get_one::<usize>("memory-size").unwrap();
+    let max_steps = *matches.get_one::<usize>("max-steps").unwrap();
+    let model = matches.get_one::<String>("model").unwrap();
+    let mut trace = if matches.get_flag("skip-silent") {
+        Trace::skipping_silent()
+    } else {
+        Trace::default()
+    };
+
+    let src = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {path}"))?;
+    let program = parse_program(&src)?;
+
+    let mut machine = match AnyMachine::from_name(model, &program, memory_size) {
+        Err(MachineError::UnknownSubsystem { name }) => anyhow::bail!(
+            "Unknown memory subsystem \"{name}\", available ones are: {}",
+            AnyMachine::names().join(", "),
+        ),
+        x => x?,
+    };
+    machine.set_history_cap(matches.get_one::<usize>("history-cap").copied());
+    machine.set_spurious_failures(matches.get_flag("spurious-cas-failures"));
+    machine.set_coherence_check(matches.get_flag("coherence-check"));
+    if let Some(depth) = matches.get_one::<usize>("certification-depth") {
+        machine.set_certification_depth(*depth);
+    }
+    let summary = machine.run_round_robin(max_steps, &mut trace)?;
+    if summary.budget_exhausted {
+        eprintln!("The run has been stopped after {} steps", summary.steps_taken);
+    }
+
+    println!("Trace:");
+    for event in trace.events {
+        println!("{event}");
+    }
+
+    for tid in 0..machine.thread_count() {
+        println!("Thread {tid}:");
+        print!("{}", machine.thread(tid).unwrap().dump());
+    }
+
+    println!("Memory:");
+    for (addr, value) in machine.memory().iter().enumerate() {
+        println!("{addr} = {value}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use machine_memory::{AccessMode, GlobalMemory, mixed::{self, MixedMemory}, sc::ScMemory};
+    use litmus::LITMUS_MEMORY_SIZE;
+    use machine_thread::builder::ProgramBuilder;
+    use register::RegisterRef;
+    use trace::Trace;
+
+    use super::*;
+
+    #[test]
+    fn step_budget_stops_an_infinite_loop() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("one", 1)
+                .label("loop")
+                .branch("one", "loop")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.set_step_budget(Some(7));
+
+        for _ in 0..7 {
+            machine.step(MachineStep::Thread(0)).unwrap();
+        }
+        assert!(matches!(machine.step(MachineStep::Thread(0)), Err(MachineError::StepBudgetExhausted)));
+        assert_eq!(machine.step_budget(), Some(0));
+    }
+
+    #[test]
+    fn memory_step_is_enabled_while_a_thread_is_behind() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .thread()
+                .set("x", 0)
+                .load(AccessMode::Rlx, "x", "r")
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, 1).unwrap();
+        let memory_steps = |machine: &Machine<MixedMemory>| machine.enabled_steps()
+            .into_iter()
+            .filter_map(|x| match x {
+                MachineStep::Memory(x) => Some(x),
+                MachineStep::Thread(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        machine.step(MachineStep::Thread(0)).unwrap();
+        assert!(memory_steps(&machine).is_empty());
+        machine.step(MachineStep::Thread(0)).unwrap();
+        let steps = memory_steps(&machine);
+        assert!(matches!(steps[..], [mixed::IndependentStep::Observe { tid: 1, addr: 0, ts: 1 }]));
+
+        machine.step(MachineStep::Memory(steps[0].clone())).unwrap();
+        assert!(memory_steps(&machine).is_empty());
+        assert_eq!(machine.enabled_steps().len(), 1);
+    }
+
+    #[test]
+    fn reset_matches_a_fresh_machine() {
+        let (program, _, _) = litmus::mp();
+        let fresh = || {
+            let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+            machine.set_initial(litmus::X, Value(7)).unwrap();
+            machine
+        };
+        let mut machine = fresh();
+        machine.set_step_budget(Some(100));
+
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+        assert_ne!(machine.state_key(), fresh().state_key());
+        let budget = machine.step_budget();
+
+        machine.reset();
+        assert!(machine.state_key() == fresh().state_key());
+        assert_eq!(machine.memory().global().cells()[litmus::X], Value(7));
+        assert_eq!(machine.step_budget(), budget);
+    }
+
+    #[test]
+    fn assertion_passes_or_stops_the_machine() {
+        let program = |expected| ProgramBuilder::new()
+            .thread()
+                .set("r", 3)
+                .assert("r", expected)
+            .build();
+        let run = |program: &[Vec<CodeInstruction>]| {
+            let mut machine = Machine::<ScMemory>::new(program, 1).unwrap();
+            driver::run_round_robin(&mut machine, 10, &mut Trace::default()).map(|x| x.threads_halted)
+        };
+
+        let passing = program(3);
+        assert_eq!(passing[0][1].instruction.to_string(), "assert r == 3");
+        assert_eq!(run(&passing).unwrap(), 1);
+
+        let err = run(&program(4)).unwrap_err();
+        assert!(matches!(
+            err,
+            MachineError::AssertionFailed { tid: 0, register, expected: Value(4), actual: Value(3) } if register.as_str() == "r",
+        ));
+    }
+
+    #[test]
+    fn schedule_of_store_buffering() {
+        let (program, _, _) = litmus::sb();
+        let mut machine = Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        let schedule = [0, 0, 0, 1, 1, 1, 1, 0].map(MachineStep::Thread);
+
+        let events = machine.run_schedule(&schedule).unwrap();
+        let memory_events = events.into_iter()
+            .filter(|x| !matches!(x, MachineEvent::Silent))
+            .collect::<Vec<_>>();
+        let write = |tid, location, ts| MachineEvent::Write { tid, location, value: Value(1), mode: AccessMode::Rlx, ts, promoted: false };
+        let read = |tid, location, ts| MachineEvent::Read { tid, location, value: Value(1), mode: AccessMode::Rlx, ts };
+        assert_eq!(memory_events, [write(0, litmus::X, 1), write(1, litmus::Y, 2), read(1, litmus::X, 1), read(0, litmus::Y, 2)]);
+
+        assert_eq!(machine.memory().global().cells(), [Value(1), Value(1)]);
+        for thread in machine.threads().iter() {
+            assert!(thread.is_halted());
+            assert_eq!(thread.get_register(RegisterRef::new("r")).unwrap(), Value(1));
+        }
+    }
+
+    #[test]
+    fn rmw_is_not_split_by_memory_steps() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .thread()
+                .set("x", 0)
+                .fai(AccessMode::Rlx, "x", "old")
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, 1).unwrap();
+        machine.run_schedule(&[MachineStep::Thread(0), MachineStep::Thread(0), MachineStep::Thread(1)]).unwrap();
+        // The store is still pending for thread 1, but the RMW reads the latest write in one event
+        assert!(!machine.enabled_steps().iter().all(|x| matches!(x, MachineStep::Thread(_))));
+        let event = machine.step(MachineStep::Thread(1)).unwrap();
+        assert!(matches!(event, MachineEvent::Rmw { read_value: Value(1), write_value: Value(2), .. }));
+
+        // No schedule of any subsystem loses an increment
+        let program = litmus::fai_stress(2, 1, AccessMode::Rlx);
+        for name in AnyMachine::names() {
+            let outcomes = AnyMachine::from_name(name, &program, 1).unwrap().explore(50).unwrap();
+            assert!(outcomes.iter().all(|x| x.memory == [Value(2)]), "{name}");
+        }
+    }
+
+    #[test]
+    fn events_are_formatted_like_instructions() {
+        let read = MachineEvent::Read { tid: 0, location: 5, value: Value(3), mode: AccessMode::Acq, ts: 1 };
+        let write = MachineEvent::Write { tid: 1, location: 5, value: Value(1), mode: AccessMode::Rel, ts: 1, promoted: false };
+        let fence = MachineEvent::Fence { tid: 0, mode: AccessMode::SeqCst };
+        let rmw = MachineEvent::Rmw {
+            tid: 2,
+            location: 0,
+            read_value: Value(1),
+            write_value: Value(2),
+            mode: AccessMode::RelAcq,
+            op: RmwKind::FetchAdd { overflowed: false },
+            ts: 2,
+        };
+
+        assert_eq!(read.to_string(), "T0: R ##5 = 3 [ACQ]");
+        assert_eq!(write.to_string(), "T1: W ##5 = 1 [REL]");
+        assert_eq!(fence.to_string(), "T0: F [SEQ_CST]");
+        assert_eq!(rmw.to_string(), "T2: U ##0 = 1 -> 2 [REL_ACQ, FAI]");
+    }
+
+    #[test]
+    fn event_carries_the_value_read() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .thread()
+                .set("x", 0)
+                .load(AccessMode::Rlx, "x", "r")
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, 1).unwrap();
+        let events = machine.run_schedule(&[0, 0, 1, 1].map(MachineStep::Thread)).unwrap();
+
+        // Thread 1 hasn't observed the store, so it reads the old value, not the cell
+        assert_eq!(events[1].accessed(), Some((0, Value(1))));
+        assert_eq!(events[3].accessed(), Some((0, Value(0))));
+        assert_eq!(machine.memory().global().cells(), [Value(1)]);
+        assert_eq!(machine.threads().get(1).unwrap().get_register(RegisterRef::new("r")).unwrap(), Value(0));
+    }
+
+    #[test]
+    fn empty_thread_is_halted_from_the_start() {
+        let mut program = ProgramBuilder::new()
+            .thread()
+                .set("r", 1)
+            .build();
+        program.push(Vec::new());
+
+        assert!(matches!(
+            Machine::<ScMemory>::new(&program, 1),
+            Err(MachineError::ThreadCreation { tid: 1, err: ThreadStateCreationError::EmptyProgram }),
+        ));
+
+        let mut machine = Machine::<ScMemory>::new_with_empty_threads(&program, 1, true).unwrap();
+        assert!(machine.threads().get(1).unwrap().is_halted());
+        assert_eq!(machine.enabled_steps().len(), 1);
+        let summary = driver::run_round_robin(&mut machine, 10, &mut Trace::default()).unwrap();
+        assert_eq!((summary.steps_taken, summary.threads_halted), (1, 2));
+    }
+
+    #[test]
+    fn local_memories_are_independent() {
+        let thread = |builder: ProgramBuilder, value| builder
+            .thread()
+                .set("p", 0)
+                .set("v", value)
+                .store_local("p", "v")
+                .load_local("p", "r");
+        let program = thread(thread(ProgramBuilder::new(), 1), 2).build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.set_local_memory_size(1);
+
+        let mut trace = Trace::skipping_silent();
+        driver::run_round_robin(&mut machine, 100, &mut trace).unwrap();
+        assert!(trace.events.is_empty());
+        for (tid, value) in [(0, Value(1)), (1, Value(2))] {
+            let thread = machine.threads().get(tid).unwrap();
+            assert_eq!(thread.local_memory(), [value]);
+            assert_eq!(thread.get_register(RegisterRef::new("r")).unwrap(), value);
+        }
+        assert_eq!(machine.memory().global().cells(), [Value(0)]);
+    }
+
+    #[test]
+    fn load_observes_an_injected_fault() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 0)
+                .store_imm(AccessMode::SeqCst, "p", 0b1010)
+                .load(AccessMode::SeqCst, "p", "r")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        machine.run_schedule(&[MachineStep::Thread(0), MachineStep::Thread(0)]).unwrap();
+
+        let fault = machine.inject_fault(0, 0b0110).unwrap();
+        assert!(matches!(fault, MachineEvent::Fault { location: 0, mask: 0b0110, value: Value(0b1100) }));
+        machine.step(MachineStep::Thread(0)).unwrap();
+        assert_eq!(machine.threads().get(0).unwrap().get_register(RegisterRef::new("r")).unwrap(), Value(0b1100));
+        assert!(machine.inject_fault(1, 1).is_err());
+    }
+
+    #[test]
+    fn named_region_shows_the_written_cell() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x2", 3)
+                .store_imm(AccessMode::SeqCst, "x2", 5)
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 6).unwrap();
+        let mut layout = MemoryLayout::new();
+        layout.declare("x", 1, 4);
+        layout.declare("past_end", 4, 4);
+        machine.set_layout(layout);
+
+        driver::run_round_robin(&mut machine, 10, &mut Trace::default()).unwrap();
+        assert_eq!(machine.region("x").unwrap(), [Value(0), Value(0), Value(5), Value(0)]);
+        assert!(matches!(machine.region("y"), Err(MachineError::UnknownRegion { name }) if name == "y"));
+        assert!(matches!(machine.region("past_end"), Err(MachineError::RegionOutOfRange { .. })));
+    }
+
+    /// A subsystem breaking down on the first thread request.
+    #[derive(Hash)]
+    struct Broken;
+
+    impl MemorySubsystem for Broken {
+        type Err = std::convert::Infallible;
+        type Independent = std::convert::Infallible;
+
+        fn name() -> &'static str { "Broken" }
+
+        fn init(memory_size: usize) -> (Self, GlobalMemory<Self>) {
+            (Broken, GlobalMemory::new(memory_size))
+        }
+
+        fn reset(&mut self) {}
+
+        fn pending_independent(&self, _threads: &Threads<Self>, _memory: &GlobalMemory<Self>) -> Vec<Self::Independent> {
+            Vec::new()
+        }
+
+        fn execute_step(
+            &mut self,
+            _step: MemoryStep<Self::Independent>,
+            _threads: &mut Threads<Self>,
+            _memory: &mut GlobalMemory<Self>,
+        ) -> Result<MachineEvent, MemoryError<Self::Err>> {
+            panic!("broken invariant")
+        }
+    }
+
+    #[test]
+    fn panic_in_the_subsystem_is_an_internal_error() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .load(AccessMode::Rlx, "x", "r")
+            .build();
+        let mut machine = Machine::<Broken>::new(&program, 1).unwrap();
+
+        machine.try_step(MachineStep::Thread(0)).unwrap();
+        assert!(matches!(
+            machine.try_step(MachineStep::Thread(0)),
+            Err(MachineError::Internal { context }) if context == "broken invariant",
+        ));
+    }
+
+    #[test]
+    fn barrier_of_too_many_threads_is_rejected() {
+        let waiter = |builder: ProgramBuilder| builder
+            .thread()
+                .set("r", 0)
+                .barrier(0, 3);
+        let program = waiter(waiter(ProgramBuilder::new())).build();
+
+        assert!(matches!(
+            Machine::<ScMemory>::new(&program, 1),
+            Err(MachineError::BarrierTooLarge { tid: 0, at: 1, participants: 3, threads: 2 }),
+        ));
+    }
+
+    #[test]
+    fn output_follows_the_schedule() {
+        let printer = |builder: ProgramBuilder, value| builder
+            .thread()
+                .set("v", value)
+                .print("v");
+        let program = printer(printer(ProgramBuilder::new(), 1), 2).build();
+        let [t0, t1] = [MachineStep::Thread(0), MachineStep::Thread(1)];
+
+        for (schedule, expected) in [
+            ([t0.clone(), t0.clone(), t1.clone(), t1.clone()], [Value(1), Value(2)]),
+            ([t1.clone(), t0.clone(), t1.clone(), t0.clone()], [Value(2), Value(1)]),
+        ] {
+            let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+            let events = machine.run_schedule(&schedule).unwrap();
+            assert_eq!(machine.output(), expected);
+
+            let printed = events.iter()
+                .filter_map(|x| match x {
+                    MachineEvent::Output { tid, value } => Some((*tid, *value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(printed, expected.map(|x| (x.0 as usize - 1, x)));
+        }
+    }
+
+    #[test]
+    fn equal_states_hash_equal() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("b", 2)
+                .set("a", 1)
+            .thread()
+                .set("p", 1)
+                .store_imm(AccessMode::SeqCst, "p", 3)
+            .build();
+        let run = |schedule: &[usize]| {
+            let mut machine = Machine::<ScMemory>::new(&program, 2).unwrap();
+            for tid in schedule {
+                machine.step(MachineStep::Thread(*tid)).unwrap();
+            }
+            machine
+        };
+
+        let l = run(&[0, 0, 1, 1]);
+        let r = run(&[1, 0, 1, 0]);
+        assert_eq!(l.state_hash(), r.state_hash());
+        assert_eq!(l.state_key(), r.state_key());
+        assert_ne!(l.state_hash(), run(&[0, 0, 1]).state_hash());
+
+        let mut changed = run(&[0, 0, 1, 1]);
+        changed.memory_mut().global_mut().write(0, Value(1), AccessMode::SeqCst).unwrap();
+        assert_ne!(l.state_hash(), changed.state_hash());
+    }
+
+    #[test]
+    fn touched_addresses_of_a_run() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("p", 1)
+                .set("q", 4)
+                .set("r", 6)
+                .store_imm(AccessMode::SeqCst, "p", 3)
+                .load(AccessMode::SeqCst, "q", "a")
+                .fai(AccessMode::SeqCst, "r", "b")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 8).unwrap();
+        machine.set_initial(2, Value(5)).unwrap();
+        assert!(machine.touched_addresses().is_empty());
+
+        driver::run_round_robin(&mut machine, 100, &mut Trace::default()).unwrap();
+        assert_eq!(machine.touched_addresses(), BTreeSet::from([1, 4, 6]));
+
+        machine.reset();
+        assert!(machine.touched_addresses().is_empty());
+    }
 }
@@ -1,3 +1,53 @@
+use thiserror::Error;
 
 pub type Register = byteyarn::Yarn;
-pub type RegisterRef<'a> = byteyarn::YarnRef<'a, str>;
\ No newline at end of file
+pub type RegisterRef<'a> = byteyarn::YarnRef<'a, str>;
+
+/// Why a name can't be used for a register or a label.
+#[derive(Debug, Clone, Error)]
+pub enum NameError {
+    #[error("Name is empty")]
+    Empty,
+    #[error("Name \"{name}\" contains whitespace")]
+    Whitespace {
+        name: String,
+    },
+}
+
+/// Checks that `name` is non-empty and has no whitespace.
+pub(crate) fn validate_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.chars().any(char::is_whitespace) {
+        return Err(NameError::Whitespace { name: name.to_owned() });
+    }
+
+    Ok(())
+}
+
+/// Makes a register out of a well-formed name.
+pub fn parse(name: &str) -> Result<Register, NameError> {
+    validate_name(name)?;
+
+    Ok(RegisterRef::new(name).to_box().immortalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::label;
+
+    use super::*;
+
+    #[test]
+    fn names_are_validated() {
+        assert_eq!(parse("r0").unwrap().as_str(), "r0");
+        assert_eq!(label::parse("loop_1").unwrap().as_str(), "loop_1");
+
+        for parse in [|x| parse(x).map(drop), |x| label::parse(x).map(drop)] {
+            assert!(matches!(parse(""), Err(NameError::Empty)));
+            assert!(matches!(parse("a b"), Err(NameError::Whitespace { name }) if name == "a b"));
+            assert!(matches!(parse("a\t"), Err(NameError::Whitespace { .. })));
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use smallvec::{SmallVec, smallvec};
+use thiserror::Error;
+
+use std::fmt::Write;
+
+use crate::{Machine, MachineError, MachineEvent, machine_memory::MemorySubsystem, value::Value};
+
+/// Why a trace couldn't have been produced by a memory subsystem.
+#[derive(Debug, Clone, Error)]
+pub enum ValidationError {
+    #[error("The trace is inconsistent: program order, reads-from and coherence order form a cycle")]
+    Inconsistent,
+    #[error("Event {event} has read {actual} at address {location}, but the latest write there was {expected}")]
+    StaleRead {
+        event: usize,
+        location: usize,
+        expected: Value,
+        actual: Value,
+    },
+}
+
+/// Why [`Trace::replay`] hasn't reproduced a trace.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("Step {at} of the schedule picks step {choice}, but only {enabled} are enabled")]
+    NoSuchStep {
+        at: usize,
+        choice: usize,
+        enabled: usize,
+    },
+    #[error("The replay has diverged at event {at}")]
+    Diverged {
+        at: EventIndex,
+    },
+    #[error("The machine has failed during the replay")]
+    Machine(#[from] MachineError),
+}
+
+/// The position of an event in [`Trace::events`].
+pub type EventIndex = usize;
+
+/// Where two traces part ways, see [`Trace::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DivergenceReport {
+    pub at: EventIndex,
+    /// The event of the first trace, `None` if it has ended there.
+    pub left: Option<MachineEvent>,
+    /// The event of the second trace, `None` if it has ended there.
+    pub right: Option<MachineEvent>,
+}
+
+/// The events produced by a run of a machine, in the order they happened.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub events: Vec<MachineEvent>,
+    /// Whether [`record`](Trace::record) drops [`MachineEvent::Silent`].
+    skip_silent: bool,
+    /// The seed of the random run which has produced the trace, if any.
+    pub seed: Option<u64>,
+    /// The index in [`Machine::enabled_steps`] of every step taken by
+    /// [`run_scheduler`](crate::driver::run_scheduler), see [`replay`](Trace::replay).
+    pub choices: Vec<usize>,
+}
+
+impl Trace {
+    pub fn new(events: Vec<MachineEvent>) -> Self {
+        Trace { events, ..Trace::default() }
+    }
+
+    /// An empty trace which only records the memory events.
+    pub fn skipping_silent() -> Self {
+        Trace { skip_silent: true, ..Trace::default() }
+    }
+
+    /// Takes the steps of [`choices`](Trace::choices) on `machine`, which should
+    /// be in the state the trace has started from, and checks that they produce
+    /// the same events. Only the traces recorded by
+    /// [`run_scheduler`](crate::driver::run_scheduler) (and the drivers built on it)
+    /// have the choices.
+    pub fn replay<Mem: MemorySubsystem>(&self, machine: &mut Machine<Mem>) -> Result<(), ReplayError> {
+        let mut replayed = Trace { skip_silent: self.skip_silent, ..Trace::default() };
+        machine.init_events().into_iter().for_each(|x| replayed.record(x));
+
+        for (at, &choice) in self.choices.iter().enumerate() {
+            let mut enabled = machine.enabled_steps();
+            if choice >= enabled.len() {
+                return Err(ReplayError::NoSuchStep { at, choice, enabled: enabled.len() });
+            }
+            replayed.record(machine.step(enabled.swap_remove(choice))?);
+        }
+
+        match self.diff(&replayed) {
+            Some(DivergenceReport { at, .. }) => Err(ReplayError::Diverged { at }),
+            None => Ok(()),
+        }
+    }
+
+    /// Finds the first event at which the traces differ. A trace which is a
+    /// prefix of the other diverges from it right after its last event.
+    pub fn diff(&self, other: &Trace) -> Option<DivergenceReport> {
+        let at = self.events.iter()
+            .zip(&other.events)
+            .position(|(l, r)| l != r)
+            .or_else(|| (self.events.len() != other.events.len())
+                .then(|| self.events.len().min(other.events.len())))?;
+
+        Some(DivergenceReport {
+            at,
+            left: self.events.get(at).cloned(),
+            right: other.events.get(at).cloned(),
+        })
+    }
+
+    pub fn record(&mut self, event: MachineEvent) {
+        if self.skip_silent && matches!(event, MachineEvent::Silent) {
+            return;
+        }
+
+        self.events.push(event);
+    }
+
+    /// Checks that the union of program order, reads-from and coherence order
+    /// is acyclic.
+    ///
+    /// The relations are reconstructed from the events alone:
+    /// * program order is the order of the events of each thread;
+    /// * coherence order is the order in which the writes to each location appear;
+    /// * a read reads from the first write of the same value to the same location,
+    ///   or from the initial (zero) value if there is none. So the check is only
+    ///   precise for programs which never write the same value to a location twice.
+    ///
+    /// A read of a non-zero value nobody has written makes the trace inconsistent.
+    /// A pair read reads each of its cells. Group accesses only contribute to the
+    /// program order, as their events don't carry the values.
+    pub fn is_consistent(&self) -> bool {
+        let n = self.events.len();
+        let mut edges = vec![Vec::new(); n];
+        let mut last_of_thread = FnvHashMap::<usize, usize>::default();
+        let mut last_write = FnvHashMap::<usize, usize>::default();
+        let mut writes = FnvHashMap::<(usize, Value), usize>::default();
+
+        for (idx, event) in self.events.iter().enumerate() {
+            if let Some(tid) = event_tid(event) {
+                if let Some(prev) = last_of_thread.insert(tid, idx) {
+                    edges[prev].push(idx);
+                }
+            }
+            if let Some((location, value)) = written(event) {
+                if let Some(prev) = last_write.insert(location, idx) {
+                    edges[prev].push(idx);
+                }
+                writes.entry((location, value)).or_insert(idx);
+            }
+        }
+
+        for (idx, event) in self.events.iter().enumerate() {
+            for (location, value) in reads(event) {
+                match writes.get(&(location, value)) {
+                    Some(&write) if write != idx => edges[write].push(idx),
+                    Some(_) => (),
+                    None if value == Value(0) => (),
+                    None => return false,
+                }
+            }
+        }
+
+        is_acyclic(&edges)
+    }
+
+    /// The writes and read-modify-writes no read has read from, in trace order.
+    /// The reads-from is reconstructed as in [`is_consistent`](Trace::is_consistent).
+    /// Group writes are never reported, as their values aren't known.
+    pub fn dead_stores(&self) -> Vec<EventIndex> {
+        let mut writes = FnvHashMap::<(usize, Value), EventIndex>::default();
+        for (idx, event) in self.events.iter().enumerate() {
+            if let Some(key) = written(event) {
+                writes.entry(key).or_insert(idx);
+            }
+        }
+
+        let mut observed = FnvHashSet::<EventIndex>::default();
+        for (idx, event) in self.events.iter().enumerate() {
+            let sources = reads(event).into_iter().filter_map(|key| writes.get(&key));
+            observed.extend(sources.filter(|x| **x != idx));
+        }
+
+        self.events.iter()
+            .enumerate()
+            .filter(|(idx, event)| {
+                let write = matches!(event, MachineEvent::Write { .. })
+                    || matches!(event, MachineEvent::Rmw { op, .. } if op.writes());
+                write && !observed.contains(idx)
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Renders the memory events as CSV with the header
+    /// `step,tid,op,address,value,mode`, one row per event in the order they
+    /// were recorded. `step` is the index of the event in the trace and `op` is
+    /// one of `R`, `W`, `RMW`, `F`, `RN`, `RP`, `WN`, `Y`, `OUT`, `INIT` and `FAULT`. A read-modify-write has
+    /// both values (`old->new`), a pair read has both addresses and values
+    /// separated by `;`, group accesses have the cell count as the
+    /// value and the columns an event doesn't have are left empty. Silent events are omitted.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("step,tid,op,address,value,mode\n");
+
+        for (step, event) in self.events.iter().enumerate() {
+            match event {
+                MachineEvent::Silent => continue,
+                MachineEvent::Read { tid, location, value, mode, .. } =>
+                    writeln!(out, "{step},{tid},R,{location},{value},{mode}"),
+                MachineEvent::Write { tid, location, value, mode, .. } =>
+                    writeln!(out, "{step},{tid},W,{location},{value},{mode}"),
+                MachineEvent::Rmw { tid, location, read_value, write_value, mode, .. } =>
+                    writeln!(out, "{step},{tid},RMW,{location},{read_value}->{write_value},{mode}"),
+                MachineEvent::Fence { tid, mode } =>
+                    writeln!(out, "{step},{tid},F,,,{mode}"),
+                MachineEvent::ReadN { tid, location, count, mode } =>
+                    writeln!(out, "{step},{tid},RN,{location},{count},{mode}"),
+                MachineEvent::ReadPair { tid, locations: [l1, l2], values: [v1, v2], mode } =>
+                    writeln!(out, "{step},{tid},RP,{l1};{l2},{v1};{v2},{mode}"),
+                MachineEvent::WriteN { tid, location, count, mode, .. } =>
+                    writeln!(out, "{step},{tid},WN,{location},{count},{mode}"),
+                MachineEvent::Yield { tid } =>
+                    writeln!(out, "{step},{tid},Y,,,"),
+                MachineEvent::Output { tid, value } =>
+                    writeln!(out, "{step},{tid},OUT,,{value},"),
+                MachineEvent::Init { location, value } =>
+                    writeln!(out, "{step},,INIT,{location},{value},"),
+                MachineEvent::Fault { location, value, .. } =>
+                    writeln!(out, "{step},,FAULT,{location},{value},"),
+            }.unwrap();
+        }
+
+        out
+    }
+
+    /// Renders the trace as a Graphviz graph: a node per non-silent event, with
+    /// program order edges in black, reads-from edges in red and coherence order
+    /// edges in blue. The reads-from edges are reconstructed as in
+    /// [`is_consistent`](Trace::is_consistent). The coherence order of a location
+    /// is taken from [`MemorySubsystem::coherence_order`] if `subsystem` tracks it,
+    /// otherwise it is the order in which the writes appear.
+    pub fn to_dot<Mem: MemorySubsystem>(&self, subsystem: &Mem) -> String {
+        let mut out = String::from("digraph trace {\n");
+        let mut last_of_thread = FnvHashMap::<usize, usize>::default();
+        let mut writes = FnvHashMap::<usize, Vec<(usize, Value)>>::default();
+        let mut edges = String::new();
+
+        for (idx, event) in self.events.iter().enumerate() {
+            if matches!(event, MachineEvent::Silent) {
+                continue;
+            }
+
+            writeln!(out, "    e{idx} [label=\"{event}\"];").unwrap();
+            let prev = event_tid(event).and_then(|tid| last_of_thread.insert(tid, idx));
+            if let Some(prev) = prev {
+                writeln!(edges, "    e{prev} -> e{idx} [label=\"po\"];").unwrap();
+            }
+            if let Some((location, value)) = written(event) {
+                writes.entry(location).or_default().push((idx, value));
+            }
+        }
+
+        for (idx, event) in self.events.iter().enumerate() {
+            for (location, value) in reads(event) {
+                let source = writes.get(&location)
+                    .and_then(|x| x.iter().find(|(_, written)| *written == value));
+
+                if let Some((write, _)) = source.filter(|(write, _)| *write != idx) {
+                    writeln!(edges, "    e{write} -> e{idx} [label=\"rf\", color=red];").unwrap();
+                }
+            }
+        }
+
+        let mut locations = writes.keys().copied().collect::<Vec<_>>();
+        locations.sort_unstable();
+        for location in locations {
+            let mut chain = writes[&location].clone();
+            if let Some(order) = subsystem.coherence_order(location) {
+                chain = coherence_chain(chain, &order);
+            }
+
+            for pair in chain.windows(2) {
+                writeln!(edges, "    e{} -> e{} [label=\"co\", color=blue];", pair[0].0, pair[1].0).unwrap();
+            }
+        }
+
+        out.push_str(&edges);
+        out.push_str("}\n");
+
+        out
+    }
+}
+
+/// Orders the writes (event index and value) by `order`, matching each value
+/// to the earliest write of it which hasn't been matched yet. The writes
+/// `order` doesn't mention are left out.
+fn coherence_chain(mut writes: Vec<(usize, Value)>, order: &[Value]) -> Vec<(usize, Value)> {
+    let mut chain = Vec::new();
+
+    for value in order {
+        if let Some(pos) = writes.iter().position(|(_, x)| x == value) {
+            chain.push(writes.remove(pos));
+        }
+    }
+
+    chain
+}
+
+pub(crate) fn event_tid(event: &MachineEvent) -> Option<usize> {
+    match event {
+        MachineEvent::Silent |
+        MachineEvent::Init { .. } |
+        MachineEvent::Fault { .. } => None,
+        MachineEvent::Read { tid, .. } |
+        MachineEvent::Write { tid, .. } |
+        MachineEvent::Fence { tid, .. } |
+        MachineEvent::Yield { tid } |
+        MachineEvent::Output { tid, .. } |
+        MachineEvent::Rmw { tid, .. } |
+        MachineEvent::ReadN { tid, .. } |
+        MachineEvent::ReadPair { tid, .. } |
+        MachineEvent::WriteN { tid, .. } => Some(*tid),
+    }
+}
+
+/// The cells the event has read along with the values.
+pub(crate) fn reads(event: &MachineEvent) -> SmallVec<[(usize, Value); 2]> {
+    match event {
+        MachineEvent::Read { location, value, .. } => smallvec![(*location, *value)],
+        MachineEvent::Rmw { location, read_value, .. } => smallvec![(*location, *read_value)],
+        MachineEvent::ReadPair { locations, values, .. } => locations.iter().copied().zip(values.iter().copied()).collect(),
+        _ => SmallVec::new(),
+    }
+}
+
+pub(crate) fn written(event: &MachineEvent) -> Option<(usize, Value)> {
+    match event {
+        MachineEvent::Write { location, value, .. } => Some((*location, *value)),
+        MachineEvent::Rmw { location, write_value, op, .. } if op.writes() => Some((*location, *write_value)),
+        MachineEvent::Init { location, value } |
+        MachineEvent::Fault { location, value, .. } => Some((*location, *value)),
+        _ => None,
+    }
+}
+
+/// Kahn's algorithm.
+fn is_acyclic(edges: &[Vec<usize>]) -> bool {
+    let mut in_degree = vec![0usize; edges.len()];
+    for to in edges.iter().flatten() {
+        in_degree[*to] += 1;
+    }
+
+    let mut ready = (0..edges.len())
+        .filter(|x| in_degree[*x] == 0)
+        .collect::<Vec<_>>();
+    let mut sorted = 0;
+
+    while let Some(node) = ready.pop() {
+        sorted += 1;
+        for to in &edges[node] {
+            in_degree[*to] -= 1;
+            if in_degree[*to] == 0 {
+                ready.push(*to);
+            }
+        }
+    }
+
+    sorted == edges.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MachineStep,
+        driver,
+        litmus::{self, LITMUS_MEMORY_SIZE, X, Y},
+        machine_memory::{AccessMode, FenceKind, mixed::MixedMemory, sc::ScMemory},
+        machine_thread::{BinOp, builder::ProgramBuilder},
+    };
+
+    use super::*;
+
+    fn read(tid: usize, location: usize, value: u64) -> MachineEvent {
+        MachineEvent::Read { tid, location, value: Value(value), mode: AccessMode::Rlx, ts: 0 }
+    }
+
+    fn write(tid: usize, location: usize, value: u64) -> MachineEvent {
+        MachineEvent::Write { tid, location, value: Value(value), mode: AccessMode::Rlx, ts: 0, promoted: false }
+    }
+
+    #[test]
+    fn load_buffering_cycle_is_inconsistent() {
+        // Each thread reads the value the other one writes after its read
+        let trace = Trace::new(vec![read(0, X, 1), write(0, Y, 1), read(1, Y, 1), write(1, X, 1)]);
+
+        assert!(!trace.is_consistent());
+    }
+
+    #[test]
+    fn message_passing_run_is_consistent() {
+        let (program, _, _) = litmus::mp();
+        let mut machine = Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        let mut trace = Trace::skipping_silent();
+        driver::run_round_robin(&mut machine, 100, &mut trace).unwrap();
+
+        assert_eq!(trace.events.len(), 4);
+        assert!(trace.is_consistent());
+    }
+
+    #[test]
+    fn skipping_silent_keeps_the_memory_events() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .set("one", 1)
+                .load(AccessMode::SeqCst, "x", "r")
+                .bop("r", BinOp::Add, "r", "one")
+                .bop("r", BinOp::Add, "r", "one")
+                .store(AccessMode::SeqCst, "x", "r")
+                .fence(AccessMode::SeqCst, FenceKind::Full)
+            .build();
+        let run = |mut trace: Trace| {
+            let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+            driver::run_round_robin(&mut machine, 100, &mut trace).unwrap();
+            trace
+        };
+
+        let full = run(Trace::default());
+        let filtered = run(Trace::skipping_silent());
+        assert_eq!(full.events.len(), 7);
+        assert_eq!(filtered.events.len(), 3);
+        assert!(filtered.events.iter().all(|x| !matches!(x, MachineEvent::Silent)));
+        assert_eq!(
+            filtered.events,
+            full.events.into_iter().filter(|x| !matches!(x, MachineEvent::Silent)).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn dot_links_two_writes_by_coherence() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 1)
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rlx, "x", 2)
+            .build();
+        let mut machine = Machine::<MixedMemory>::new(&program, 1).unwrap();
+        let trace = Trace::new(machine.run_schedule(&[1, 1, 0, 0].map(MachineStep::Thread)).unwrap());
+
+        let dot = trace.to_dot(machine.memory().subsystem());
+        assert!(dot.contains("    e1 [label=\"T1: W ##0 = 2 [RLX]\"];\n"));
+        assert!(dot.contains("    e3 [label=\"T0: W ##0 = 1 [RLX]\"];\n"));
+        assert!(dot.contains("    e1 -> e3 [label=\"co\", color=blue];\n"));
+        assert!(!dot.contains("e3 -> e1"));
+    }
+
+    #[test]
+    fn csv_of_a_short_run() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", 0)
+                .store_imm(AccessMode::Rel, "x", 2)
+                .fai(AccessMode::SeqCst, "x", "old")
+                .fence(AccessMode::SeqCst, FenceKind::Full)
+                .load(AccessMode::Acq, "x", "r")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, 1).unwrap();
+        let mut trace = Trace::default();
+        driver::run_round_robin(&mut machine, 100, &mut trace).unwrap();
+
+        assert_eq!(trace.to_csv(), concat!(
+            "step,tid,op,address,value,mode\n",
+            "1,0,W,0,2,REL\n",
+            "2,0,RMW,0,2->3,SEQ_CST\n",
+            "3,0,F,,,SEQ_CST\n",
+            "4,0,R,0,3,ACQ\n",
+        ));
+    }
+
+    #[test]
+    fn init_events_come_first() {
+        let (program, _, _) = litmus::mp();
+        let mut machine = Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        machine.set_initial(X, Value(7)).unwrap();
+        machine.set_initial(Y, Value(8)).unwrap();
+        let mut trace = Trace::skipping_silent();
+        driver::run_round_robin(&mut machine, 100, &mut trace).unwrap();
+
+        assert_eq!(trace.events[..2], [
+            MachineEvent::Init { location: X, value: Value(7) },
+            MachineEvent::Init { location: Y, value: Value(8) },
+        ]);
+        assert!(trace.events[2..].iter().all(|x| !matches!(x, MachineEvent::Init { .. })));
+        // The reads of the initial values read from the init events
+        assert!(trace.is_consistent());
+    }
+
+    #[test]
+    fn overwritten_store_is_dead() {
+        let program = ProgramBuilder::new()
+            .thread()
+                .set("x", X as u64)
+                .store_imm(AccessMode::SeqCst, "x", 1)
+                .store_imm(AccessMode::SeqCst, "x", 2)
+                .load(AccessMode::SeqCst, "x", "r")
+            .build();
+        let mut machine = Machine::<ScMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        let mut trace = Trace::skipping_silent();
+        driver::run_round_robin(&mut machine, 100, &mut trace).unwrap();
+
+        assert_eq!(trace.events.len(), 3);
+        assert_eq!(trace.dead_stores(), [0]);
+        assert!(matches!(trace.events[0], MachineEvent::Write { value: Value(1), .. }));
+    }
+
+    #[test]
+    fn random_run_replays() {
+        let (program, _, _) = litmus::sb();
+        let mut machine = Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        let mut trace = Trace::default();
+        driver::run_random(&mut machine, 7, 100, &mut trace).unwrap();
+        assert_eq!(trace.seed, Some(7));
+        assert!(!trace.choices.is_empty());
+
+        let fresh = || Machine::<MixedMemory>::new(&program, LITMUS_MEMORY_SIZE).unwrap();
+        trace.replay(&mut fresh()).unwrap();
+
+        let last = trace.events.len() - 1;
+        trace.events[last] = MachineEvent::Silent;
+        assert!(matches!(trace.replay(&mut fresh()), Err(ReplayError::Diverged { at }) if at == last));
+    }
+
+    #[test]
+    fn diff_finds_the_first_divergence() {
+        let l = Trace::new(vec![write(0, X, 1), read(1, X, 1), write(1, Y, 1)]);
+        let r = Trace::new(vec![write(0, X, 1), read(1, X, 0), write(1, Y, 1)]);
+        assert_eq!(l.diff(&r), Some(DivergenceReport { at: 1, left: Some(read(1, X, 1)), right: Some(read(1, X, 0)) }));
+        assert_eq!(l.diff(&l.clone()), None);
+
+        let prefix = Trace::new(l.events[..2].to_vec());
+        assert_eq!(prefix.diff(&l), Some(DivergenceReport { at: 2, left: None, right: Some(write(1, Y, 1)) }));
+    }
+}
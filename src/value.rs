@@ -1,11 +1,60 @@
 use std::fmt;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum AddressError {
+    #[error("Value {value} does not fit into the address space")]
+    OutOfAddressSpace {
+        value: Value,
+    },
+    #[error("Negative value {value} can't be used as an address")]
+    NegativeAddress {
+        value: i64,
+    },
+}
+
+/// A machine word. Values are ordered as unsigned integers, see
+/// [`as_signed`](Value::as_signed) for the signed view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Value(pub u64);
 
 impl Value {
+    /// The value every register and memory cell starts with.
+    pub const ZERO: Value = Value(0);
+    pub const ONE: Value = Value(1);
+    /// The largest value, `u64::MAX`, or `-1` in the signed view.
+    pub const MAX: Value = Value(u64::MAX);
+
     pub fn to_address(self) -> usize { self.0 as usize }
+
+    /// Like [`try_to_address`](Value::try_to_address), but treats the value as
+    /// signed and rejects the negative ones.
+    pub fn try_to_address_signed(self) -> Result<usize, AddressError> {
+        match self.as_signed() {
+            value if value < 0 => Err(AddressError::NegativeAddress { value }),
+            _ => self.try_to_address(),
+        }
+    }
+
+    /// Stores the two's complement bit pattern of `value`.
+    pub fn from_i64(value: i64) -> Self { Self(value as u64) }
+
+    /// Reinterprets the value as a two's complement signed integer.
+    pub fn as_signed(self) -> i64 { self.0 as i64 }
+
+    /// Whether the value is non-zero, i.e. true when used as a flag.
+    pub fn is_truthy(self) -> bool { self.0 != 0 }
+
+    /// `1` for true and `0` for false.
+    pub fn from_bool(value: bool) -> Self { Self(value as u64) }
+
+    /// Like [`to_address`](Value::to_address), but fails instead of truncating
+    /// the value on targets where `usize` is narrower than 64 bits.
+    pub fn try_to_address(self) -> Result<usize, AddressError> {
+        usize::try_from(self.0).map_err(|_| AddressError::OutOfAddressSpace { value: self })
+    }
 }
 
 impl fmt::Display for Value {
@@ -14,6 +63,80 @@ impl fmt::Display for Value {
     }
 }
 
+impl fmt::LowerHex for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::UpperHex for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
 impl From<u64> for Value {
     fn from(value: u64) -> Self { Self(value) }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn address_beyond_usize_is_rejected() {
+        let value = Value(1 << 40);
+
+        if usize::BITS < 64 {
+            assert_eq!(value.try_to_address(), Err(AddressError::OutOfAddressSpace { value }));
+        } else {
+            assert_eq!(value.try_to_address(), Ok(1 << 40));
+        }
+    }
+
+    #[test]
+    fn hex_and_signed_formatting() {
+        let value = Value(0xdead_beef);
+        assert_eq!(format!("{value:x}"), "deadbeef");
+        assert_eq!(format!("{value:#X}"), "0xDEADBEEF");
+        assert_eq!(value.to_string(), "3735928559");
+
+        assert_eq!(Value(u64::MAX).as_signed(), -1);
+        assert_eq!(Value(u64::MAX - 4).as_signed(), -5);
+        assert_eq!(Value(7).as_signed(), 7);
+    }
+
+    #[test]
+    fn negative_values_round_trip() {
+        for x in [-1, -5, -128, i64::MIN] {
+            assert_eq!(Value::from_i64(x).as_signed(), x);
+        }
+        assert_eq!(Value::from_i64(-1), Value(u64::MAX));
+    }
+
+    #[test]
+    fn bool_conversions() {
+        assert_eq!(Value::from_bool(true), Value::ONE);
+        assert_eq!(Value::from_bool(false), Value::ZERO);
+        assert!(Value(2).is_truthy());
+        assert!(Value::MAX.is_truthy());
+        assert!(!Value::ZERO.is_truthy());
+    }
+
+    #[test]
+    fn values_are_ordered_as_unsigned() {
+        let mut values = vec![Value(3), Value::MAX, Value(0), Value(2)];
+        values.sort();
+        assert_eq!(values, [Value(0), Value(2), Value(3), Value::MAX]);
+        assert!(Value::from_i64(-1) > Value(1));
+
+        let set = BTreeSet::from([Value(5), Value(1), Value(5)]);
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), [Value(1), Value(5)]);
+    }
+
+    #[test]
+    fn constants_have_their_values() {
+        assert_eq!([Value::ZERO, Value::ONE, Value::MAX].map(|x| x.0), [0, 1, u64::MAX]);
+    }
+}
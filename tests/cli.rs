@@ -0,0 +1,101 @@
+use std::process::{Command, Output};
+
+/// Writes `src` into a temporary file and runs the interpreter on it with `args`.
+fn run(name: &str, src: &str, args: &[&str]) -> Output {
+    let path = std::env::temp_dir().join(format!("wmm23-ex1-{}-{name}.txt", std::process::id()));
+    std::fs::write(&path, src).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_wmm23-ex1"))
+        .arg(&path)
+        .args(args)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    output
+}
+
+#[test]
+fn prints_the_final_state() {
+    let output = run("store", "
+        thread
+            x = 1
+            store SEQ_CST ##x =5
+    ", &["--model", "SC", "--memory-size", "2", "--skip-silent"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Trace:\n"));
+    assert!(stdout.contains("Thread 0:\npc = 2, halted = true\nx = 1\n"));
+    assert!(stdout.ends_with("Memory:\n0 = 0\n1 = 5\n"));
+}
+
+#[test]
+fn unknown_model_lists_the_available_ones() {
+    let output = run("unknown", "thread\n", &["--model", "TSO"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Unknown memory subsystem \"TSO\", available ones are: SC"));
+}
+
+#[test]
+fn history_cap_keeps_the_final_state() {
+    let output = run("cap", "
+        thread
+            x = 1
+            store RLX ##x =5
+            store RLX ##x =6
+            store RLX ##x =7
+    ", &["--model", "Mixed", "--memory-size", "2", "--history-cap", "1"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("Memory:\n0 = 0\n1 = 7\n"));
+}
+
+#[test]
+fn spurious_cas_failures_fail_a_weak_cas() {
+    let src = "
+        thread
+            x = 1
+            expected = 0
+            new = 5
+            casw SEQ_CST ##x expected new ok
+    ";
+    let memory = |args: &[&str]| {
+        let output = run("casw", src, &[&["--model", "SC", "--memory-size", "2"], args].concat());
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(memory(&[]).ends_with("Memory:\n0 = 0\n1 = 5\n"));
+    assert!(memory(&["--spurious-cas-failures"]).ends_with("Memory:\n0 = 0\n1 = 0\n"));
+}
+
+#[test]
+fn certification_depth_is_accepted_by_promising() {
+    let output = run("depth", "
+        thread
+            x = 1
+            store RLX ##x =5
+    ", &["--model", "Promising", "--memory-size", "2", "--certification-depth", "0"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("Memory:\n0 = 0\n1 = 5\n"));
+}
+
+#[test]
+fn coherence_check_passes_a_correct_run() {
+    let output = run("coherence", "
+        thread
+            x = 1
+            store SEQ_CST ##x =5
+            load SEQ_CST ##x r
+    ", &["--model", "SC", "--memory-size", "2", "--coherence-check"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("r = 5\n"));
+}